@@ -6,6 +6,7 @@ use fxa_client::Profile;
 use fxa_client::{OAuthInfo, SyncKeys};
 use fxa_str_free;
 use libc::c_char;
+use serde_json;
 use std;
 use util::*;
 
@@ -25,7 +26,7 @@ impl Drop for SyncKeysC {
 impl From<SyncKeys> for SyncKeysC {
     fn from(sync_keys: SyncKeys) -> Self {
         SyncKeysC {
-            sync_key: string_to_c_char(sync_keys.0),
+            sync_key: string_to_c_char(sync_keys.0.expose().as_str()),
             xcs: string_to_c_char(sync_keys.1),
         }
     }
@@ -50,9 +51,12 @@ impl From<OAuthInfo> for OAuthInfoC {
     fn from(info: OAuthInfo) -> Self {
         let scopes = info.scopes.join(" ");
         OAuthInfoC {
-            access_token: string_to_c_char(info.access_token),
+            access_token: string_to_c_char(info.access_token.expose().as_str()),
             keys: match info.keys {
-                Some(keys) => string_to_c_char(keys),
+                // Callers expect the keys as JSON, like the `keys_jwe` payload.
+                Some(keys) => string_to_c_char(
+                    serde_json::to_string(&keys).expect("Scoped keys are always serializable"),
+                ),
                 None => std::ptr::null_mut(),
             },
             scope: string_to_c_char(scopes),
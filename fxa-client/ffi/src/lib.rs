@@ -4,6 +4,7 @@
 
 extern crate fxa_client;
 extern crate libc;
+extern crate serde_json;
 
 mod ctypes;
 mod util;
@@ -14,7 +15,10 @@ use std::ptr;
 use ctypes::*;
 use fxa_client::errors::Error as InternalError;
 use fxa_client::errors::ErrorKind as InternalErrorKind;
-use fxa_client::{Config, FirefoxAccount, PersistCallback, WebChannelResponse};
+use fxa_client::{
+    Config, FirefoxAccount, PairingChannel, PairingKey, PersistCallback, Transport,
+    WebChannelResponse,
+};
 use libc::c_char;
 use util::*;
 
@@ -443,6 +447,127 @@ pub unsafe extern "C" fn fxa_begin_oauth_flow(
     })
 }
 
+/// Carries the messages of a pairing channel through callbacks, which the app implements with a
+/// websocket to the channel server.
+pub struct CallbackTransport {
+    send: extern "C" fn(message: *const c_char),
+    receive: extern "C" fn() -> *const c_char,
+}
+
+impl Transport for CallbackTransport {
+    fn send(&mut self, message: &str) -> Result<(), InternalError> {
+        let s = string_to_c_char(message);
+        (self.send)(s);
+        unsafe { drop(CString::from_raw(s)) };
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<String, InternalError> {
+        let message = (self.receive)();
+        if message.is_null() {
+            return Err(InternalErrorKind::PairingChannelClosed.into());
+        }
+        Ok(unsafe { c_char_to_string(message) }.to_string())
+    }
+}
+
+pub type PairingChannelC = PairingChannel<CallbackTransport>;
+
+/// Join the pairing channel of the `pairing_url` scanned from the QR code of a signed-in device.
+///
+/// `send` is called with each message for the channel server. `receive` should block until the
+/// channel server sends a message and return it, or return null if the connection was closed; the
+/// string must stay valid until the next call. Connect to the channel with the id from
+/// [fxa_pairing_channel_id] before starting the flow with [fxa_begin_pairing_flow].
+///
+/// # Safety
+///
+/// A destructor [fxa_pairing_channel_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_pairing_channel_join(
+    pairing_url: *const c_char,
+    send: extern "C" fn(message: *const c_char),
+    receive: extern "C" fn() -> *const c_char,
+    error: *mut ExternError,
+) -> *mut PairingChannelC {
+    call_with_result(error, || {
+        let pairing_url = c_char_to_string(pairing_url);
+        let key = PairingKey::from_url(pairing_url)?;
+        Ok(PairingChannel::join(CallbackTransport { send, receive }, key))
+    })
+}
+
+/// The id of the channel to connect to on the channel server.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_pairing_channel_id(
+    channel: *mut PairingChannelC,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!channel.is_null());
+        let channel = &*channel;
+        Ok(channel.channel_id().to_string())
+    })
+}
+
+/// Start a pairing flow over a channel from [fxa_pairing_channel_join].
+///
+/// This returns the account the other device is signed in to, as a JSON string, for the user to
+/// check. The flow is completed with [fxa_complete_pairing_flow].
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_begin_pairing_flow(
+    fxa: *mut FirefoxAccount,
+    channel: *mut PairingChannelC,
+    scope: *const c_char,
+    wants_keys: bool,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        assert!(!channel.is_null());
+        let fxa = &mut *fxa;
+        let channel = &mut *channel;
+        let scope = c_char_to_string(scope);
+        let scopes: Vec<&str> = scope.split(" ").collect();
+        let metadata = fxa.begin_pairing_flow(channel, &scopes, wants_keys)?;
+        Ok(serde_json::to_string(&metadata)?)
+    })
+}
+
+/// Wait for the other device to approve a pairing flow started with [fxa_begin_pairing_flow], and
+/// return the token/keys.
+///
+/// # Safety
+///
+/// A destructor [fxa_oauth_info_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_complete_pairing_flow(
+    fxa: *mut FirefoxAccount,
+    channel: *mut PairingChannelC,
+    error: *mut ExternError,
+) -> *mut OAuthInfoC {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        assert!(!channel.is_null());
+        let fxa = &mut *fxa;
+        let channel = &mut *channel;
+        let info = fxa.complete_pairing_flow(channel)?;
+        Ok(info.into())
+    })
+}
+
 /// Finish an OAuth flow initiated by [fxa_begin_oauth_flow] and returns token/keys.
 ///
 /// This resulting token might not have all the `scopes` the caller have requested (e.g. the user
@@ -526,3 +651,4 @@ define_destructor!(fxa_config_free, Config);
 define_destructor!(fxa_oauth_info_free, OAuthInfoC);
 define_destructor!(fxa_profile_free, ProfileC);
 define_destructor!(fxa_sync_keys_free, SyncKeysC);
+define_destructor!(fxa_pairing_channel_free, PairingChannelC);
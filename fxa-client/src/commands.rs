@@ -0,0 +1,189 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use base64;
+use ring::rand::SecureRandom;
+use serde_json;
+
+use ece;
+#[cfg(feature = "stored-keys")]
+use ece::KeyPair;
+use errors::*;
+#[cfg(feature = "stored-keys")]
+use http_client::CommandData;
+
+pub const SEND_TAB: &str = "https://identity.mozilla.com/cmd/open-uri";
+
+/// A command sent from one device to another.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    SendTab(SendTab),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SendTab {
+    pub title: String,
+    pub url: String,
+}
+
+// The send tab payload holds a list of entries, so that we can send a tab's
+// history one day. For now, we only send and read the latest.
+#[derive(Serialize, Deserialize)]
+struct SendTabPayload {
+    entries: Vec<SendTab>,
+}
+
+// What a device publishes for each command it accepts: the keys to encrypt
+// the command with.
+#[derive(Serialize, Deserialize)]
+struct PublishedKeys {
+    #[serde(rename = "publicKey")]
+    public_key: String,
+    #[serde(rename = "authSecret")]
+    auth_secret: String,
+}
+
+/// The data to publish in the device's available commands, so that other
+/// devices can encrypt commands to `keys`.
+#[cfg(feature = "stored-keys")]
+pub(crate) fn published_keys(keys: &KeyPair) -> Result<String> {
+    Ok(serde_json::to_string(&PublishedKeys {
+        public_key: keys.public_key().to_string(),
+        auth_secret: keys.auth_secret().expose().clone(),
+    })?)
+}
+
+impl Command {
+    /// The name devices publish in their available commands.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Command::SendTab(_) => SEND_TAB,
+        }
+    }
+
+    pub(crate) fn to_payload(&self) -> Result<Vec<u8>> {
+        match *self {
+            Command::SendTab(ref tab) => Ok(serde_json::to_vec(&SendTabPayload {
+                entries: vec![tab.clone()],
+            })?),
+        }
+    }
+
+    /// Encrypts the command to the keys a device published for it, returning
+    /// the payload to send.
+    pub(crate) fn encrypt(&self, published: &str, rng: &SecureRandom) -> Result<serde_json::Value> {
+        let keys: PublishedKeys = serde_json::from_str(published)?;
+        let encrypted = ece::encrypt(&keys.public_key, &keys.auth_secret, &self.to_payload()?, rng)?;
+        Ok(json!({
+            "encrypted": base64::encode_config(&encrypted, base64::URL_SAFE_NO_PAD),
+        }))
+    }
+
+    /// Parses the payload of a command called `name`, returning `None` for
+    /// commands we don't know about.
+    #[cfg(feature = "stored-keys")]
+    pub(crate) fn from_payload(name: &str, payload: &[u8]) -> Result<Option<Command>> {
+        match name {
+            SEND_TAB => {
+                let payload: SendTabPayload = serde_json::from_slice(payload)?;
+                match payload.entries.into_iter().last() {
+                    Some(tab) => Ok(Some(Command::SendTab(tab))),
+                    None => Err(ErrorKind::InvalidCommand(name.to_string()).into()),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// A command received from another device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReceivedCommand {
+    /// The id of the device that sent the command, if it's still connected.
+    pub sender: Option<String>,
+    pub command: Command,
+}
+
+#[cfg(feature = "stored-keys")]
+impl ReceivedCommand {
+    /// Decrypts a command sent to `keys`, returning `None` for commands we
+    /// don't know about.
+    pub(crate) fn decrypt(keys: &KeyPair, data: CommandData) -> Result<Option<ReceivedCommand>> {
+        let encrypted = match data.payload["encrypted"].as_str() {
+            Some(encrypted) => base64::decode_config(encrypted, base64::URL_SAFE_NO_PAD)?,
+            None => return Err(ErrorKind::InvalidCommand(data.command).into()),
+        };
+        let payload = keys.decrypt_aes128gcm(&encrypted)?;
+        Ok(Command::from_payload(&data.command, &payload)?.map(|command| ReceivedCommand {
+            sender: data.sender,
+            command,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_tab_payload() {
+        let command = Command::SendTab(SendTab {
+            title: "Example".into(),
+            url: "https://example.com/".into(),
+        });
+        let payload = command.to_payload().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&payload).unwrap(),
+            json!({"entries": [{"title": "Example", "url": "https://example.com/"}]})
+        );
+    }
+
+    #[cfg(feature = "stored-keys")]
+    #[test]
+    fn test_from_payload() {
+        let command = Command::SendTab(SendTab {
+            title: "Example".into(),
+            url: "https://example.com/".into(),
+        });
+        let payload = command.to_payload().unwrap();
+        assert_eq!(Command::from_payload(SEND_TAB, &payload).unwrap(), Some(command));
+
+        assert_eq!(Command::from_payload("https://example.com/cmd/unknown", b"{}").unwrap(), None);
+        assert!(Command::from_payload(SEND_TAB, br#"{"entries": []}"#).is_err());
+    }
+
+    #[cfg(feature = "stored-keys")]
+    #[test]
+    fn test_encrypt_decrypt() {
+        use ring::rand::SystemRandom;
+
+        let rng = SystemRandom::new();
+        let keys = KeyPair::generate(&rng).unwrap();
+        let published = published_keys(&keys).unwrap();
+        let command = Command::SendTab(SendTab {
+            title: "Example".into(),
+            url: "https://example.com/".into(),
+        });
+        let payload = command.encrypt(&published, &rng).unwrap();
+
+        let data = CommandData {
+            command: SEND_TAB.into(),
+            payload: payload.clone(),
+            sender: Some("device1".into()),
+        };
+        assert_eq!(ReceivedCommand::decrypt(&keys, data).unwrap(), Some(ReceivedCommand {
+            sender: Some("device1".into()),
+            command,
+        }));
+
+        // Only the device we encrypted to can read the command.
+        let other = KeyPair::generate(&rng).unwrap();
+        let data = CommandData {
+            command: SEND_TAB.into(),
+            payload,
+            sender: None,
+        };
+        assert!(ReceivedCommand::decrypt(&other, data).is_err());
+    }
+}
@@ -4,7 +4,9 @@
 
 use super::errors::*;
 use reqwest;
+use serde_json;
 use url::Url;
+use util::now;
 
 #[derive(Deserialize)]
 struct ClientConfigurationResponse {
@@ -35,6 +37,10 @@ pub struct Config {
     jwks_uri: String,
     token_endpoint: String,
     userinfo_endpoint: String,
+    // When we fetched the discovery documents, in ms since the epoch, or
+    // `None` if the config didn't come from discovery.
+    #[serde(default)]
+    fetched_at: Option<u64>,
 }
 
 impl Config {
@@ -50,13 +56,27 @@ impl Config {
         Config::import_from("https://accounts.stage.mozaws.net")
     }
 
+    /// Fetches the config from the content server's discovery documents.
     pub fn import_from(content_url: &str) -> Result<Config> {
         let config_url = Url::parse(content_url)?.join(".well-known/fxa-client-configuration")?;
-        let resp: ClientConfigurationResponse = reqwest::get(config_url)?.json()?;
+        let client_configuration = reqwest::get(config_url)?.text()?;
 
         let openid_config_url = Url::parse(content_url)?.join(".well-known/openid-configuration")?;
-        let openid_resp: OpenIdConfigurationResponse = reqwest::get(openid_config_url)?.json()?;
+        let openid_configuration = reqwest::get(openid_config_url)?.text()?;
 
+        Config::from_discovery(content_url, &client_configuration, &openid_configuration)
+    }
+
+    /// Builds the config from discovery documents fetched earlier: the
+    /// content server's `.well-known/fxa-client-configuration` and
+    /// `.well-known/openid-configuration`.
+    pub fn from_discovery(
+        content_url: &str,
+        client_configuration: &str,
+        openid_configuration: &str,
+    ) -> Result<Config> {
+        let resp: ClientConfigurationResponse = serde_json::from_str(client_configuration)?;
+        let openid_resp: OpenIdConfigurationResponse = serde_json::from_str(openid_configuration)?;
         Ok(Config {
             content_url: content_url.to_string(),
             auth_url: format!("{}/", resp.auth_server_base_url),
@@ -68,9 +88,77 @@ impl Config {
             jwks_uri: openid_resp.jwks_uri,
             token_endpoint: openid_resp.token_endpoint,
             userinfo_endpoint: openid_resp.userinfo_endpoint,
+            fetched_at: Some(now()),
         })
     }
 
+    /// Builds a config without discovery, for servers laid out like a
+    /// self-hosted FxA stack: the auth, OAuth and profile servers under
+    /// `auth/`, `oauth/` and `profile/`, and the token server under
+    /// `syncserver/token/`. Use the `with_*` methods to move them elsewhere.
+    pub fn new_static(content_url: &str) -> Config {
+        let content_url = content_url.trim_right_matches('/');
+        Config {
+            content_url: format!("{}/", content_url),
+            auth_url: String::new(),
+            oauth_url: String::new(),
+            profile_url: String::new(),
+            token_server_endpoint_url: String::new(),
+            authorization_endpoint: String::new(),
+            issuer: format!("{}/", content_url),
+            jwks_uri: String::new(),
+            token_endpoint: String::new(),
+            userinfo_endpoint: String::new(),
+            fetched_at: None,
+        }.with_auth_url(&format!("{}/auth", content_url))
+            .with_oauth_url(&format!("{}/oauth", content_url))
+            .with_profile_url(&format!("{}/profile", content_url))
+            .with_token_server_url(&format!("{}/syncserver/token", content_url))
+    }
+
+    pub fn with_auth_url(mut self, auth_url: &str) -> Config {
+        self.auth_url = format!("{}/", auth_url.trim_right_matches('/'));
+        self
+    }
+
+    /// Sets the OAuth server, and the OpenID endpoints under it.
+    pub fn with_oauth_url(mut self, oauth_url: &str) -> Config {
+        let oauth_url = oauth_url.trim_right_matches('/');
+        self.oauth_url = format!("{}/", oauth_url);
+        self.authorization_endpoint = format!("{}/v1/authorization", oauth_url);
+        self.jwks_uri = format!("{}/v1/jwks", oauth_url);
+        self.token_endpoint = format!("{}/v1/token", oauth_url);
+        self
+    }
+
+    /// Sets the profile server, and the OpenID user info endpoint under it.
+    pub fn with_profile_url(mut self, profile_url: &str) -> Config {
+        let profile_url = profile_url.trim_right_matches('/');
+        self.profile_url = format!("{}/", profile_url);
+        self.userinfo_endpoint = format!("{}/v1/profile", profile_url);
+        self
+    }
+
+    pub fn with_token_server_url(mut self, token_server_url: &str) -> Config {
+        self.token_server_endpoint_url =
+            format!("{}/1.0/sync/1.5", token_server_url.trim_right_matches('/'));
+        self
+    }
+
+    /// True if the config came from discovery more than `max_age` ms ago, and
+    /// so should be fetched again. Static configs are never stale.
+    pub fn is_stale(&self, max_age: u64) -> bool {
+        match self.fetched_at {
+            Some(fetched_at) => now() >= fetched_at.saturating_add(max_age),
+            None => false,
+        }
+    }
+
+    /// Fetches the discovery documents again.
+    pub fn refresh(&self) -> Result<Config> {
+        Config::import_from(&self.content_url)
+    }
+
     pub fn content_url(&self) -> Result<Url> {
         Url::parse(&self.content_url).map_err(|e| e.into())
     }
@@ -147,6 +235,7 @@ mod tests {
             jwks_uri: "https://oauth-stable.dev.lcip.org/v1/jwks".to_string(),
             token_endpoint: "https://oauth-stable.dev.lcip.org/v1/token".to_string(),
             userinfo_endpoint: "https://stable.dev.lcip.org/profile/v1/profile".to_string(),
+            fetched_at: None,
         };
         assert_eq!(
             config.auth_url_path("v1/account/keys").unwrap().to_string(),
@@ -169,4 +258,78 @@ mod tests {
             "https://stable.dev.lcip.org/syncserver/token/1.0/sync/1.5"
         );
     }
+
+    #[test]
+    fn test_new_static() {
+        let config = Config::new_static("http://localhost:3030/");
+        assert_eq!(config.content_url().unwrap().as_str(), "http://localhost:3030/");
+        assert_eq!(
+            config.auth_url_path("v1/account/keys").unwrap().as_str(),
+            "http://localhost:3030/auth/v1/account/keys"
+        );
+        assert_eq!(
+            config.token_endpoint().unwrap().as_str(),
+            "http://localhost:3030/oauth/v1/token"
+        );
+        assert_eq!(
+            config.userinfo_endpoint().unwrap().as_str(),
+            "http://localhost:3030/profile/v1/profile"
+        );
+        assert_eq!(
+            config.token_server_endpoint_url().unwrap().as_str(),
+            "http://localhost:3030/syncserver/token/1.0/sync/1.5"
+        );
+        assert!(!config.is_stale(0));
+
+        let config = config
+            .with_oauth_url("https://oauth.example.com")
+            .with_token_server_url("https://token.example.com/");
+        assert_eq!(
+            config.authorization_endpoint().unwrap().as_str(),
+            "https://oauth.example.com/v1/authorization"
+        );
+        assert_eq!(
+            config.token_server_endpoint_url().unwrap().as_str(),
+            "https://token.example.com/1.0/sync/1.5"
+        );
+    }
+
+    #[test]
+    fn test_from_discovery() {
+        let client_configuration = r#"{
+            "auth_server_base_url": "https://api.accounts.example.com",
+            "oauth_server_base_url": "https://oauth.accounts.example.com",
+            "profile_server_base_url": "https://profile.accounts.example.com",
+            "sync_tokenserver_base_url": "https://token.services.example.com"
+        }"#;
+        let openid_configuration = r#"{
+            "authorization_endpoint": "https://accounts.example.com/authorization",
+            "issuer": "https://accounts.example.com",
+            "jwks_uri": "https://oauth.accounts.example.com/v1/jwks",
+            "token_endpoint": "https://oauth.accounts.example.com/v1/token",
+            "userinfo_endpoint": "https://profile.accounts.example.com/v1/profile",
+            "response_types_supported": ["code", "token"]
+        }"#;
+        let config = Config::from_discovery(
+            "https://accounts.example.com",
+            client_configuration,
+            openid_configuration,
+        ).unwrap();
+        assert_eq!(
+            config.auth_url_path("v1/account/devices").unwrap().as_str(),
+            "https://api.accounts.example.com/v1/account/devices"
+        );
+        assert_eq!(
+            config.authorization_endpoint().unwrap().as_str(),
+            "https://accounts.example.com/authorization"
+        );
+        assert_eq!(
+            config.token_server_endpoint_url().unwrap().as_str(),
+            "https://token.services.example.com/1.0/sync/1.5"
+        );
+        assert!(!config.is_stale(60 * 1000));
+        assert!(config.is_stale(0));
+
+        assert!(Config::from_discovery("https://accounts.example.com", "{}", openid_configuration).is_err());
+    }
 }
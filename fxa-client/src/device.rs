@@ -0,0 +1,252 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    Desktop,
+    Mobile,
+    Tablet,
+    VR,
+    TV,
+    /// A type we don't know about, or a device that didn't set one.
+    Unknown,
+}
+
+impl DeviceType {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DeviceType::Desktop => "desktop",
+            DeviceType::Mobile => "mobile",
+            DeviceType::Tablet => "tablet",
+            DeviceType::VR => "vr",
+            DeviceType::TV => "tv",
+            DeviceType::Unknown => "unknown",
+        }
+    }
+
+    pub fn from_str(s: &str) -> DeviceType {
+        match s {
+            "desktop" => DeviceType::Desktop,
+            "mobile" => DeviceType::Mobile,
+            "tablet" => DeviceType::Tablet,
+            "vr" => DeviceType::VR,
+            "tv" => DeviceType::TV,
+            _ => DeviceType::Unknown,
+        }
+    }
+}
+
+impl Default for DeviceType {
+    fn default() -> DeviceType {
+        DeviceType::Unknown
+    }
+}
+
+impl Serialize for DeviceType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+// Implemented by hand so that new types added on the server don't make us
+// fail to parse the whole device list.
+impl<'de> Deserialize<'de> for DeviceType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DeviceType, D::Error> {
+        struct DeviceTypeVisitor;
+
+        impl<'de> Visitor<'de> for DeviceTypeVisitor {
+            type Value = DeviceType;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a device type")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<DeviceType, E> {
+                Ok(DeviceType::from_str(value))
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<DeviceType, E> {
+                Ok(DeviceType::Unknown)
+            }
+        }
+
+        deserializer.deserialize_any(DeviceTypeVisitor)
+    }
+}
+
+/// A Web Push subscription, which the server uses to notify the device of
+/// account events.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PushSubscription {
+    pub endpoint: String,
+    /// The subscription's P-256 public key, base64url-encoded.
+    pub public_key: String,
+    /// The subscription's auth secret, base64url-encoded.
+    pub auth_key: String,
+}
+
+/// A device connected to the account, as returned by `get_devices_list` and
+/// `register_device`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Device {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub device_type: DeviceType,
+    #[serde(rename = "isCurrentDevice", default)]
+    pub is_current_device: bool,
+    /// Milliseconds since the epoch, if the server knows.
+    #[serde(rename = "lastAccessTime", default)]
+    pub last_access_time: Option<u64>,
+    #[serde(rename = "pushCallback", default)]
+    pub push_callback: Option<String>,
+    #[serde(rename = "pushPublicKey", default)]
+    pub push_public_key: Option<String>,
+    #[serde(rename = "pushAuthKey", default)]
+    pub push_auth_key: Option<String>,
+    #[serde(rename = "pushEndpointExpired", default)]
+    pub push_endpoint_expired: bool,
+    /// The commands the device can handle, mapped to command-specific data
+    /// (e.g. the keys to encrypt the command with).
+    #[serde(rename = "availableCommands", default)]
+    pub available_commands: HashMap<String, String>,
+}
+
+impl Device {
+    /// The device's push subscription, if it has a complete one that hasn't
+    /// expired.
+    pub fn push_subscription(&self) -> Option<PushSubscription> {
+        if self.push_endpoint_expired {
+            return None;
+        }
+        match (&self.push_callback, &self.push_public_key, &self.push_auth_key) {
+            (&Some(ref endpoint), &Some(ref public_key), &Some(ref auth_key)) => {
+                Some(PushSubscription {
+                    endpoint: endpoint.clone(),
+                    public_key: public_key.clone(),
+                    auth_key: auth_key.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The fields to set when registering or updating the current device. Fields
+/// that aren't set are left unchanged on the server, except that a new device
+/// must have a name and type.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DeviceUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    device_type: Option<DeviceType>,
+    #[serde(rename = "pushCallback", skip_serializing_if = "Option::is_none")]
+    push_callback: Option<String>,
+    #[serde(rename = "pushPublicKey", skip_serializing_if = "Option::is_none")]
+    push_public_key: Option<String>,
+    #[serde(rename = "pushAuthKey", skip_serializing_if = "Option::is_none")]
+    push_auth_key: Option<String>,
+    #[serde(rename = "availableCommands", skip_serializing_if = "Option::is_none")]
+    available_commands: Option<HashMap<String, String>>,
+}
+
+impl DeviceUpdate {
+    #[inline]
+    pub fn new() -> DeviceUpdate {
+        DeviceUpdate::default()
+    }
+
+    pub fn name(mut self, name: &str) -> DeviceUpdate {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn device_type(mut self, device_type: DeviceType) -> DeviceUpdate {
+        self.device_type = Some(device_type);
+        self
+    }
+
+    pub fn push_subscription(mut self, subscription: &PushSubscription) -> DeviceUpdate {
+        self.push_callback = Some(subscription.endpoint.clone());
+        self.push_public_key = Some(subscription.public_key.clone());
+        self.push_auth_key = Some(subscription.auth_key.clone());
+        self
+    }
+
+    /// Replaces the device's commands with `commands`.
+    pub fn available_commands(mut self, commands: HashMap<String, String>) -> DeviceUpdate {
+        self.available_commands = Some(commands);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_parse_devices() {
+        let devices: Vec<Device> = serde_json::from_str(r#"[{
+            "id": "device1", "name": "Laptop", "type": "desktop", "isCurrentDevice": true,
+            "lastAccessTime": 1531000000000, "pushCallback": "https://push.example.com/abc",
+            "pushPublicKey": "pub", "pushAuthKey": "auth", "pushEndpointExpired": false,
+            "availableCommands": {"https://identity.mozilla.com/cmd/open-uri": "keys"}
+        }, {
+            "id": "device2", "name": "Headset", "type": "hologram", "lastAccessTime": null,
+            "pushCallback": null, "pushPublicKey": null, "pushAuthKey": null
+        }]"#).unwrap();
+
+        assert_eq!(devices[0].device_type, DeviceType::Desktop);
+        assert!(devices[0].is_current_device);
+        assert_eq!(devices[0].push_subscription(), Some(PushSubscription {
+            endpoint: "https://push.example.com/abc".into(),
+            public_key: "pub".into(),
+            auth_key: "auth".into(),
+        }));
+        assert_eq!(devices[0].available_commands.len(), 1);
+
+        assert_eq!(devices[1].device_type, DeviceType::Unknown);
+        assert!(!devices[1].is_current_device);
+        assert_eq!(devices[1].last_access_time, None);
+        assert_eq!(devices[1].push_subscription(), None);
+    }
+
+    #[test]
+    fn test_serialize_update() {
+        let update = DeviceUpdate::new().name("Phone").device_type(DeviceType::Mobile);
+        assert_eq!(
+            serde_json::to_value(&update).unwrap(),
+            json!({"name": "Phone", "type": "mobile"})
+        );
+
+        let update = DeviceUpdate {
+            id: Some("device1".into()),
+            ..DeviceUpdate::new()
+        }.push_subscription(&PushSubscription {
+            endpoint: "https://push.example.com/abc".into(),
+            public_key: "pub".into(),
+            auth_key: "auth".into(),
+        });
+        assert_eq!(
+            serde_json::to_value(&update).unwrap(),
+            json!({
+                "id": "device1",
+                "pushCallback": "https://push.example.com/abc",
+                "pushPublicKey": "pub",
+                "pushAuthKey": "auth",
+            })
+        );
+    }
+}
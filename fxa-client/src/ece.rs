@@ -0,0 +1,596 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Encrypted Content-Encoding (RFC 8188) with the `aes128gcm` scheme, keyed
+//! the way Web Push does it (RFC 8291): the sender does ECDH with the
+//! receiver's P-256 public key, and mixes in an auth secret they share.
+//!
+//! We can also decrypt the older `aesgcm` scheme, from drafts of the same
+//! specs, which some push services still deliver.
+//!
+//! Encrypting only needs a throwaway key, so ring does it. Decrypting needs
+//! a key we saved, which ring can't import, so it needs the `stored-keys`
+//! feature and OpenSSL.
+
+#[cfg(feature = "stored-keys")]
+use std::cmp::Ordering;
+
+use base64;
+use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "stored-keys")]
+use openssl::bn::{BigNum, BigNumContext};
+#[cfg(feature = "stored-keys")]
+use openssl::derive::Deriver;
+#[cfg(feature = "stored-keys")]
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+#[cfg(feature = "stored-keys")]
+use openssl::nid::Nid;
+#[cfg(feature = "stored-keys")]
+use openssl::pkey::{PKey, Private};
+use ring::agreement::{self, EphemeralPrivateKey};
+use ring::rand::SecureRandom;
+use ring::{aead, digest, hmac};
+#[cfg(feature = "stored-keys")]
+use secret::{SecretBytes, SecretString};
+use untrusted::Input;
+
+use errors::*;
+
+const RECORD_SIZE: u32 = 4096;
+const SALT_LENGTH: usize = 16;
+const KEY_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+#[cfg(feature = "stored-keys")]
+const PRIVATE_KEY_LENGTH: usize = 32;
+const PUBLIC_KEY_LENGTH: usize = 65;
+// The salt, record size and key id length.
+const HEADER_LENGTH: usize = SALT_LENGTH + 4 + 1;
+#[cfg(feature = "stored-keys")]
+const AUTH_SECRET_LENGTH: usize = 16;
+
+/// A P-256 key pair and auth secret for receiving encrypted content.
+///
+/// The private key is saved as its raw scalar, and imported again whenever
+/// we need it.
+#[cfg(feature = "stored-keys")]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KeyPair {
+    // All base64url-encoded.
+    private_key: SecretString,
+    public_key: String,
+    auth_secret: SecretString,
+}
+
+#[cfg(feature = "stored-keys")]
+impl KeyPair {
+    pub fn generate(rng: &SecureRandom) -> Result<KeyPair> {
+        let private_key = PrivateKey::generate(rng)?;
+        let public_key = private_key.public_key()?;
+        let mut auth_secret = vec![0u8; AUTH_SECRET_LENGTH];
+        rng.fill(&mut auth_secret).map_err(|_| ErrorKind::RngFailure)?;
+        Ok(KeyPair {
+            private_key: encode(private_key.to_raw()?.expose()).into(),
+            public_key: encode(&public_key),
+            auth_secret: encode(&auth_secret).into(),
+        })
+    }
+
+    /// The uncompressed public key, base64url-encoded.
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// The auth secret, base64url-encoded. Only the sender should see this.
+    pub fn auth_secret(&self) -> &SecretString {
+        &self.auth_secret
+    }
+
+    /// Decrypts `aes128gcm` content, which was encrypted to this key pair.
+    pub fn decrypt_aes128gcm(&self, content: &[u8]) -> Result<Vec<u8>> {
+        if content.len() < HEADER_LENGTH {
+            return Err(ErrorKind::InvalidEncryptedContent("Truncated header").into());
+        }
+        let salt = &content[0..SALT_LENGTH];
+        let record_size = BigEndian::read_u32(&content[SALT_LENGTH..]) as usize;
+        let key_id_length = content[HEADER_LENGTH - 1] as usize;
+        if key_id_length != PUBLIC_KEY_LENGTH || content.len() < HEADER_LENGTH + key_id_length {
+            return Err(ErrorKind::InvalidEncryptedContent("Missing sender public key").into());
+        }
+        let sender_public_key = &content[HEADER_LENGTH..HEADER_LENGTH + key_id_length];
+        let ecdh_secret = self.agree(sender_public_key)?;
+        let ikm = aes128gcm_ikm(
+            ecdh_secret.expose(),
+            &decode(self.auth_secret.expose())?,
+            &decode(&self.public_key)?,
+            sender_public_key,
+        );
+        let records = &content[HEADER_LENGTH + key_id_length..];
+        decrypt_aes128gcm_records(&ikm, salt, record_size, records)
+    }
+
+    /// Decrypts `aesgcm` content, which was encrypted to this key pair. The
+    /// salt, sender's public key and record size come from the message's
+    /// headers, rather than the content.
+    pub fn decrypt_aesgcm(
+        &self,
+        salt: &[u8],
+        sender_public_key: &[u8],
+        record_size: usize,
+        body: &[u8],
+    ) -> Result<Vec<u8>> {
+        let ecdh_secret = self.agree(sender_public_key)?;
+        let (key, nonce) = aesgcm_key_and_nonce(
+            ecdh_secret.expose(),
+            &decode(self.auth_secret.expose())?,
+            salt,
+            &decode(&self.public_key)?,
+            sender_public_key,
+        );
+        decrypt_aesgcm_records(&key, &nonce, record_size, body)
+    }
+
+    fn agree(&self, peer_public_key: &[u8]) -> Result<SecretBytes> {
+        let private_key = PrivateKey::from_raw(&decode(self.private_key.expose())?)?;
+        private_key.agree(peer_public_key)
+    }
+}
+
+/// Encrypts `plaintext` to the receiver with the given public key and auth
+/// secret, both base64url-encoded.
+pub fn encrypt(
+    public_key: &str,
+    auth_secret: &str,
+    plaintext: &[u8],
+    rng: &SecureRandom,
+) -> Result<Vec<u8>> {
+    let private_key = EphemeralPrivateKey::generate(&agreement::ECDH_P256, rng)
+        .map_err(|_| ErrorKind::KeyGenerationFailed)?;
+    let mut salt = [0u8; SALT_LENGTH];
+    rng.fill(&mut salt).map_err(|_| ErrorKind::RngFailure)?;
+    encrypt_with_key(
+        private_key,
+        &salt,
+        &decode(public_key)?,
+        &decode(auth_secret)?,
+        plaintext,
+        RECORD_SIZE,
+    )
+}
+
+fn encrypt_with_key(
+    private_key: EphemeralPrivateKey,
+    salt: &[u8],
+    receiver_public_key: &[u8],
+    auth_secret: &[u8],
+    plaintext: &[u8],
+    record_size: u32,
+) -> Result<Vec<u8>> {
+    let mut sender_public_key = vec![0u8; private_key.public_key_len()];
+    private_key
+        .compute_public_key(&mut sender_public_key)
+        .map_err(|_| ErrorKind::PublicKeyComputationFailed)?;
+    agreement::agree_ephemeral(
+        private_key,
+        &agreement::ECDH_P256,
+        Input::from(receiver_public_key),
+        ErrorKind::KeyAgreementFailed.into(),
+        |ecdh_secret| {
+            let ikm = aes128gcm_ikm(
+                ecdh_secret,
+                auth_secret,
+                receiver_public_key,
+                &sender_public_key,
+            );
+            encrypt_records(&ikm, salt, &sender_public_key, plaintext, record_size)
+        },
+    )
+}
+
+fn encrypt_records(
+    ikm: &[u8],
+    salt: &[u8],
+    sender_public_key: &[u8],
+    plaintext: &[u8],
+    record_size: u32,
+) -> Result<Vec<u8>> {
+    let (key, nonce) = aes128gcm_key_and_nonce(ikm, salt);
+    let sealing_key = aead::SealingKey::new(&aead::AES_128_GCM, &key)
+        .map_err(|_| ErrorKind::KeyImportFailed)?;
+
+    let mut content = Vec::with_capacity(HEADER_LENGTH + PUBLIC_KEY_LENGTH + plaintext.len());
+    content.extend_from_slice(salt);
+    let mut buf = [0u8; 4];
+    BigEndian::write_u32(&mut buf, record_size);
+    content.extend_from_slice(&buf);
+    content.push(PUBLIC_KEY_LENGTH as u8);
+    content.extend_from_slice(sender_public_key);
+
+    // Each record holds a chunk of the plaintext, a delimiter, and the tag.
+    let chunk_size = record_size as usize - TAG_LENGTH - 1;
+    let chunks: Vec<&[u8]> = if plaintext.is_empty() {
+        vec![plaintext]
+    } else {
+        plaintext.chunks(chunk_size).collect()
+    };
+    let last = chunks.len() - 1;
+    for (seq, chunk) in chunks.into_iter().enumerate() {
+        let mut record = chunk.to_vec();
+        record.push(if seq == last { 2 } else { 1 });
+        record.extend_from_slice(&[0u8; TAG_LENGTH]);
+        let len = aead::seal_in_place(
+            &sealing_key,
+            &record_nonce(&nonce, seq),
+            &[],
+            &mut record,
+            TAG_LENGTH,
+        ).map_err(|_| ErrorKind::AEADSealFailure)?;
+        content.extend_from_slice(&record[..len]);
+    }
+    Ok(content)
+}
+
+#[cfg(feature = "stored-keys")]
+fn decrypt_aes128gcm_records(
+    ikm: &[u8],
+    salt: &[u8],
+    record_size: usize,
+    records: &[u8],
+) -> Result<Vec<u8>> {
+    if record_size < TAG_LENGTH + 2 {
+        return Err(ErrorKind::InvalidEncryptedContent("Record size too small").into());
+    }
+    if records.is_empty() {
+        return Err(ErrorKind::InvalidEncryptedContent("No records").into());
+    }
+    let (key, nonce) = aes128gcm_key_and_nonce(ikm, salt);
+    let opening_key = aead::OpeningKey::new(&aead::AES_128_GCM, &key)
+        .map_err(|_| ErrorKind::KeyImportFailed)?;
+    let mut plaintext = Vec::new();
+    let count = (records.len() + record_size - 1) / record_size;
+    for (seq, record) in records.chunks(record_size).enumerate() {
+        let mut record = record.to_vec();
+        let padded = aead::open_in_place(&opening_key, &record_nonce(&nonce, seq), &[], 0, &mut record)
+            .map_err(|_| ErrorKind::AEADOpenFailure)?;
+        // Strip the padding, then check the delimiter says whether this
+        // should be the last record.
+        let end = match padded.iter().rposition(|&b| b != 0) {
+            Some(end) => end,
+            None => return Err(ErrorKind::InvalidEncryptedContent("Missing delimiter").into()),
+        };
+        let expected = if seq == count - 1 { 2 } else { 1 };
+        if padded[end] != expected {
+            return Err(ErrorKind::InvalidEncryptedContent("Unexpected delimiter").into());
+        }
+        plaintext.extend_from_slice(&padded[..end]);
+    }
+    Ok(plaintext)
+}
+
+// Each `aesgcm` record starts with the length of its padding, then the
+// padding, then the data.
+#[cfg(feature = "stored-keys")]
+fn decrypt_aesgcm_records(
+    key: &[u8],
+    nonce: &[u8],
+    record_size: usize,
+    records: &[u8],
+) -> Result<Vec<u8>> {
+    if record_size < 2 {
+        return Err(ErrorKind::InvalidEncryptedContent("Record size too small").into());
+    }
+    if records.is_empty() {
+        return Err(ErrorKind::InvalidEncryptedContent("No records").into());
+    }
+    let opening_key = aead::OpeningKey::new(&aead::AES_128_GCM, key)
+        .map_err(|_| ErrorKind::KeyImportFailed)?;
+    let mut plaintext = Vec::new();
+    for (seq, record) in records.chunks(record_size + TAG_LENGTH).enumerate() {
+        let mut record = record.to_vec();
+        let padded = aead::open_in_place(&opening_key, &record_nonce(nonce, seq), &[], 0, &mut record)
+            .map_err(|_| ErrorKind::AEADOpenFailure)?;
+        if padded.len() < 2 {
+            return Err(ErrorKind::InvalidEncryptedContent("Truncated record").into());
+        }
+        let start = 2 + BigEndian::read_u16(padded) as usize;
+        if start > padded.len() {
+            return Err(ErrorKind::InvalidEncryptedContent("Invalid padding").into());
+        }
+        plaintext.extend_from_slice(&padded[start..]);
+    }
+    Ok(plaintext)
+}
+
+// Combines the ECDH shared secret with the auth secret into the input keying
+// material, as in RFC 8291, section 3.4.
+fn aes128gcm_ikm(
+    ecdh_secret: &[u8],
+    auth_secret: &[u8],
+    receiver_public_key: &[u8],
+    sender_public_key: &[u8],
+) -> Vec<u8> {
+    let prk = hmac_sha256(auth_secret, &[ecdh_secret]);
+    hmac_sha256(
+        &prk,
+        &[b"WebPush: info\0", receiver_public_key, sender_public_key, &[1]],
+    )
+}
+
+// Derives the `aesgcm` content encryption key and base nonce, as in
+// draft-ietf-webpush-encryption-04. Unlike `aes128gcm`, both public keys go
+// into the key and nonce, rather than the input keying material.
+#[cfg(feature = "stored-keys")]
+fn aesgcm_key_and_nonce(
+    ecdh_secret: &[u8],
+    auth_secret: &[u8],
+    salt: &[u8],
+    receiver_public_key: &[u8],
+    sender_public_key: &[u8],
+) -> (Vec<u8>, Vec<u8>) {
+    let prk = hmac_sha256(auth_secret, &[ecdh_secret]);
+    let ikm = hmac_sha256(&prk, &[b"Content-Encoding: auth\0", &[1]]);
+    let mut context = b"P-256\0".to_vec();
+    for public_key in &[receiver_public_key, sender_public_key] {
+        let mut len = [0u8; 2];
+        BigEndian::write_u16(&mut len, public_key.len() as u16);
+        context.extend_from_slice(&len);
+        context.extend_from_slice(public_key);
+    }
+    let prk = hmac_sha256(salt, &[&ikm]);
+    let key = hmac_sha256(&prk, &[b"Content-Encoding: aesgcm\0", &context, &[1]]);
+    let nonce = hmac_sha256(&prk, &[b"Content-Encoding: nonce\0", &context, &[1]]);
+    (key[..KEY_LENGTH].to_vec(), nonce[..NONCE_LENGTH].to_vec())
+}
+
+// Derives the `aes128gcm` content encryption key and base nonce (RFC 8188,
+// section 2.2 and 2.3). Both are shorter than a SHA-256 hash, so HKDF-Expand
+// is just one HMAC, truncated.
+fn aes128gcm_key_and_nonce(ikm: &[u8], salt: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let prk = hmac_sha256(salt, &[ikm]);
+    let key = hmac_sha256(&prk, &[b"Content-Encoding: aes128gcm\0", &[1]]);
+    let nonce = hmac_sha256(&prk, &[b"Content-Encoding: nonce\0", &[1]]);
+    (key[..KEY_LENGTH].to_vec(), nonce[..NONCE_LENGTH].to_vec())
+}
+
+// XORs the record's sequence number into the end of the base nonce.
+fn record_nonce(nonce: &[u8], seq: usize) -> Vec<u8> {
+    let mut buf = [0u8; 8];
+    BigEndian::write_u64(&mut buf, seq as u64);
+    let mut nonce = nonce.to_vec();
+    for (n, s) in nonce[NONCE_LENGTH - 8..].iter_mut().zip(buf.iter()) {
+        *n ^= s;
+    }
+    nonce
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let key = hmac::SigningKey::new(&digest::SHA256, key);
+    let mut context = hmac::SigningContext::with_key(&key);
+    for part in parts {
+        context.update(part);
+    }
+    context.sign().as_ref().to_vec()
+}
+
+/// A P-256 private key, for ECDH, that can be saved and used again.
+///
+/// ring can't import private keys, so we use OpenSSL, which can build one
+/// from its raw scalar.
+#[cfg(feature = "stored-keys")]
+pub(crate) struct PrivateKey(PKey<Private>);
+
+#[cfg(feature = "stored-keys")]
+impl PrivateKey {
+    pub(crate) fn generate(rng: &SecureRandom) -> Result<PrivateKey> {
+        let group = p256()?;
+        let mut scalar = vec![0u8; PRIVATE_KEY_LENGTH];
+        // Almost every 32-byte value is a valid key, so this only loops if
+        // we're astronomically unlucky.
+        loop {
+            rng.fill(&mut scalar).map_err(|_| ErrorKind::RngFailure)?;
+            let scalar = BigNum::from_slice(&scalar)?;
+            if is_valid_scalar(&group, &scalar)? {
+                return PrivateKey::from_scalar(&group, &scalar);
+            }
+        }
+    }
+
+    /// Imports a private key from its big-endian scalar.
+    pub(crate) fn from_raw(bytes: &[u8]) -> Result<PrivateKey> {
+        if bytes.len() != PRIVATE_KEY_LENGTH {
+            return Err(ErrorKind::KeyImportFailed.into());
+        }
+        let group = p256()?;
+        let scalar = BigNum::from_slice(bytes)?;
+        if !is_valid_scalar(&group, &scalar)? {
+            return Err(ErrorKind::KeyImportFailed.into());
+        }
+        PrivateKey::from_scalar(&group, &scalar)
+    }
+
+    fn from_scalar(group: &EcGroup, scalar: &BigNum) -> Result<PrivateKey> {
+        let ctx = BigNumContext::new()?;
+        let mut public_key = EcPoint::new(group)?;
+        public_key.mul_generator(group, scalar, &ctx)?;
+        let key = EcKey::from_private_components(group, scalar, &public_key)?;
+        Ok(PrivateKey(PKey::from_ec_key(key)?))
+    }
+
+    /// The big-endian scalar, padded to 32 bytes.
+    pub(crate) fn to_raw(&self) -> Result<SecretBytes> {
+        let scalar = self.0.ec_key()?.private_key().to_vec();
+        let mut bytes = vec![0u8; PRIVATE_KEY_LENGTH - scalar.len()];
+        bytes.extend_from_slice(&scalar);
+        Ok(bytes.into())
+    }
+
+    /// The uncompressed public key.
+    pub(crate) fn public_key(&self) -> Result<Vec<u8>> {
+        let key = self.0.ec_key()?;
+        let mut ctx = BigNumContext::new()?;
+        let public_key = key
+            .public_key()
+            .to_bytes(key.group(), PointConversionForm::UNCOMPRESSED, &mut ctx)?;
+        Ok(public_key)
+    }
+
+    /// Does ECDH with the peer's uncompressed public key, returning the
+    /// shared secret.
+    pub(crate) fn agree(&self, peer_public_key: &[u8]) -> Result<SecretBytes> {
+        let group = p256()?;
+        let mut ctx = BigNumContext::new()?;
+        // This also checks the point is on the curve.
+        let point = EcPoint::from_bytes(&group, peer_public_key, &mut ctx)
+            .map_err(|_| ErrorKind::KeyAgreementFailed)?;
+        let peer_public_key = PKey::from_ec_key(EcKey::from_public_key(&group, &point)?)?;
+        let mut deriver = Deriver::new(&self.0)?;
+        deriver.set_peer(&peer_public_key)?;
+        let ecdh_secret = deriver
+            .derive_to_vec()
+            .map_err(|_| ErrorKind::KeyAgreementFailed)?;
+        Ok(ecdh_secret.into())
+    }
+}
+
+#[cfg(feature = "stored-keys")]
+fn p256() -> Result<EcGroup> {
+    Ok(EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)
+}
+
+// A private key must be in [1, n), where n is the order of the curve.
+#[cfg(feature = "stored-keys")]
+fn is_valid_scalar(group: &EcGroup, scalar: &BigNum) -> Result<bool> {
+    let mut ctx = BigNumContext::new()?;
+    let mut order = BigNum::new()?;
+    group.order(&mut order, &mut ctx)?;
+    Ok(scalar.num_bits() > 0 && scalar.ucmp(&order) == Ordering::Less)
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(s: &str) -> Result<Vec<u8>> {
+    base64::decode_config(s, base64::URL_SAFE_NO_PAD).map_err(|e| e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::test::rand::FixedSliceRandom;
+
+    // From RFC 8291, appendix A.
+    const SENDER_PRIVATE_KEY: &str = "yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw";
+    #[cfg(feature = "stored-keys")]
+    const RECEIVER_PRIVATE_KEY: &str = "q1dXpw3UpT5VOmu_cf_v6ih07Aems3njxI-JWgLcM94";
+    const RECEIVER_PUBLIC_KEY: &str = "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4";
+    const SENDER_PUBLIC_KEY: &str = "BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8";
+    const AUTH_SECRET: &str = "BTBZMqHH6r4Tts7J_aSIgg";
+    const SALT: &str = "DGv6ra1nlYgDCS1FRnbzlw";
+    const PLAINTEXT: &str = "When I grow up, I want to be a watermelon";
+    const CONTENT: &str = "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN";
+
+    #[test]
+    fn test_encrypt_rfc8291() {
+        // ring uses the random bytes as the scalar, so this is the sender's
+        // key from the RFC.
+        let sender_private_key = decode(SENDER_PRIVATE_KEY).unwrap();
+        let rng = FixedSliceRandom {
+            bytes: &sender_private_key,
+        };
+        let private_key = EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng).unwrap();
+        let content = encrypt_with_key(
+            private_key,
+            &decode(SALT).unwrap(),
+            &decode(RECEIVER_PUBLIC_KEY).unwrap(),
+            &decode(AUTH_SECRET).unwrap(),
+            PLAINTEXT.as_bytes(),
+            RECORD_SIZE,
+        ).unwrap();
+        let sender_public_key = &content[HEADER_LENGTH..HEADER_LENGTH + PUBLIC_KEY_LENGTH];
+        assert_eq!(encode(sender_public_key), SENDER_PUBLIC_KEY);
+        assert_eq!(encode(&content), CONTENT);
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let rng = SystemRandom::new();
+        let content = encrypt(RECEIVER_PUBLIC_KEY, AUTH_SECRET, b"hello", &rng).unwrap();
+        assert_eq!(content[HEADER_LENGTH - 1] as usize, PUBLIC_KEY_LENGTH);
+        let again = encrypt(RECEIVER_PUBLIC_KEY, AUTH_SECRET, b"hello", &rng).unwrap();
+        assert_ne!(content, again);
+        assert!(encrypt(&RECEIVER_PUBLIC_KEY[1..], AUTH_SECRET, b"hello", &rng).is_err());
+    }
+
+    #[cfg(feature = "stored-keys")]
+    #[test]
+    fn test_decrypt_rfc8291() {
+        let receiver = KeyPair {
+            private_key: RECEIVER_PRIVATE_KEY.into(),
+            public_key: RECEIVER_PUBLIC_KEY.into(),
+            auth_secret: AUTH_SECRET.into(),
+        };
+        let content = decode(CONTENT).unwrap();
+        assert_eq!(receiver.decrypt_aes128gcm(&content).unwrap(), PLAINTEXT.as_bytes());
+    }
+
+    #[cfg(feature = "stored-keys")]
+    #[test]
+    fn test_private_key_raw() {
+        let raw = decode(RECEIVER_PRIVATE_KEY).unwrap();
+        let private_key = PrivateKey::from_raw(&raw).unwrap();
+        assert_eq!(private_key.to_raw().unwrap().expose(), &raw);
+        assert_eq!(encode(&private_key.public_key().unwrap()), RECEIVER_PUBLIC_KEY);
+
+        // Zero, the order of the curve, and the wrong length aren't keys.
+        assert!(PrivateKey::from_raw(&[0u8; 32]).is_err());
+        let order = decode("_____wAAAAD__________7zm-q2nF56E87nKwvxjJVE").unwrap();
+        assert!(PrivateKey::from_raw(&order).is_err());
+        assert!(PrivateKey::from_raw(&raw[1..]).is_err());
+    }
+
+    #[cfg(feature = "stored-keys")]
+    #[test]
+    fn test_decrypt_aesgcm() {
+        // The same keys and salt as above, encrypted with `aesgcm` instead.
+        let receiver = KeyPair {
+            private_key: RECEIVER_PRIVATE_KEY.into(),
+            public_key: RECEIVER_PUBLIC_KEY.into(),
+            auth_secret: AUTH_SECRET.into(),
+        };
+        let body = decode("4qwOLFm_mNy0vf1A8f3Bm6B5UD15y3aV_xZy14pixUhcPTIoZKHzq5i3dZ6PzqSMxBI_-VDUZ4jW04M").unwrap();
+        let plaintext = receiver.decrypt_aesgcm(
+            &decode(SALT).unwrap(),
+            &decode(SENDER_PUBLIC_KEY).unwrap(),
+            4096,
+            &body,
+        ).unwrap();
+        assert_eq!(plaintext, PLAINTEXT.as_bytes());
+        let wrong_key = decode(RECEIVER_PUBLIC_KEY).unwrap();
+        assert!(receiver.decrypt_aesgcm(&decode(SALT).unwrap(), &wrong_key, 4096, &body).is_err());
+    }
+
+    #[cfg(feature = "stored-keys")]
+    #[test]
+    fn test_round_trip() {
+        let rng = SystemRandom::new();
+        let receiver = KeyPair::generate(&rng).unwrap();
+        let auth_secret = receiver.auth_secret().expose().clone();
+        // Cover an empty plaintext, a single record, and several.
+        for len in &[0, 100, 4079, 4080, 10000] {
+            let plaintext: Vec<u8> = (0..*len).map(|i| (i % 251) as u8).collect();
+            let content = encrypt(receiver.public_key(), &auth_secret, &plaintext, &rng).unwrap();
+            assert_eq!(receiver.decrypt_aes128gcm(&content).unwrap(), plaintext);
+        }
+
+        let content = encrypt(receiver.public_key(), &auth_secret, b"hello", &rng).unwrap();
+        let other = KeyPair::generate(&rng).unwrap();
+        assert!(other.decrypt_aes128gcm(&content).is_err());
+        let mut tampered = content.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(receiver.decrypt_aes128gcm(&tampered).is_err());
+        assert!(receiver.decrypt_aes128gcm(&content[..HEADER_LENGTH]).is_err());
+    }
+}
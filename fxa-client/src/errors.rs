@@ -10,7 +10,7 @@ use failure::{Backtrace, Context, Fail, SyncFailure};
 #[cfg(feature = "browserid")]
 use hawk;
 use hex;
-#[cfg(feature = "browserid")]
+#[cfg(any(feature = "browserid", feature = "stored-keys"))]
 use openssl;
 use reqwest;
 use serde_json;
@@ -62,6 +62,24 @@ impl From<Context<ErrorKind>> for Error {
 
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
+    #[fail(display = "Invalid scoped key: {}", _0)]
+    InvalidScopedKey(&'static str),
+
+    #[fail(display = "Invalid pairing URL")]
+    InvalidPairingUrl,
+
+    #[fail(display = "The other device declined to pair")]
+    PairingDeclined,
+
+    #[fail(display = "Unexpected message on the pairing channel")]
+    UnexpectedPairingMessage,
+
+    #[fail(display = "The pairing channel was closed")]
+    PairingChannelClosed,
+
+    #[fail(display = "No session token; sign in with a password first")]
+    NoSessionToken,
+
     #[fail(display = "Unknown OAuth State")]
     UnknownOAuthState,
 
@@ -74,6 +92,9 @@ pub enum ErrorKind {
     #[fail(display = "No cached token for scope {}", _0)]
     NoCachedToken(&'static str),
 
+    #[fail(display = "No refresh token available; complete an OAuth flow first")]
+    NoRefreshToken,
+
     #[fail(display = "Unrecoverable server error")]
     UnrecoverableServerError,
 
@@ -110,6 +131,27 @@ pub enum ErrorKind {
     #[fail(display = "AEAD open failure")]
     AEADOpenFailure,
 
+    #[fail(display = "AEAD seal failure")]
+    AEADSealFailure,
+
+    #[fail(display = "Invalid encrypted content: {}", _0)]
+    InvalidEncryptedContent(&'static str),
+
+    #[fail(display = "No push subscription keys; call push_subscription first")]
+    NoPushKeys,
+
+    #[fail(display = "Unsupported push message encoding {}", _0)]
+    UnsupportedContentEncoding(String),
+
+    #[fail(display = "The target device doesn't support the command {}", _0)]
+    UnsupportedCommand(String),
+
+    #[fail(display = "Invalid payload for the command {}", _0)]
+    InvalidCommand(String),
+
+    #[fail(display = "This device can't receive commands until it enables them")]
+    CommandsNotEnabled,
+
     #[fail(display = "Random number generation failure")]
     RngFailure,
 
@@ -131,7 +173,7 @@ pub enum ErrorKind {
     #[fail(display = "Hex decode error: {}", _0)]
     HexDecodeError(#[fail(cause)] hex::FromHexError),
 
-    #[cfg(feature = "browserid")]
+    #[cfg(any(feature = "browserid", feature = "stored-keys"))]
     #[fail(display = "OpenSSL error: {}", _0)]
     OpensslError(#[fail(cause)] openssl::error::ErrorStack),
 
@@ -182,7 +224,7 @@ impl_from_error! {
     (MalformedUrl, ::reqwest::UrlError)
 }
 
-#[cfg(feature = "browserid")]
+#[cfg(any(feature = "browserid", feature = "stored-keys"))]
 impl_from_error! {
     (OpensslError, ::openssl::error::ErrorStack)
 }
@@ -0,0 +1,134 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use serde_json;
+
+use commands::ReceivedCommand;
+use errors::*;
+
+/// Something that happened to the account, which the app may want to react
+/// to. Returned by `FirefoxAccount::handle_push_message`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AccountEvent {
+    CommandReceived(ReceivedCommand),
+    DeviceConnected {
+        device_name: String,
+    },
+    DeviceDisconnected {
+        device_id: String,
+        /// If true, this device was disconnected and must sign in again.
+        is_local_device: bool,
+    },
+    ProfileUpdated,
+    /// The password was changed, so the user must sign in again.
+    PasswordChanged,
+    /// The password was reset, so the user must sign in again.
+    PasswordReset,
+    AccountDestroyed,
+}
+
+/// An event from a push message, before we've acted on it.
+#[derive(Debug, PartialEq)]
+pub(crate) enum PushEvent {
+    CommandReceived { index: u64 },
+    DeviceConnected { device_name: String },
+    DeviceDisconnected { device_id: String },
+    ProfileUpdated,
+    PasswordChanged,
+    PasswordReset,
+    AccountDestroyed,
+}
+
+#[derive(Deserialize)]
+struct PushPayload {
+    command: String,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct CommandReceivedData {
+    index: u64,
+}
+
+#[derive(Deserialize)]
+struct DeviceConnectedData {
+    #[serde(rename = "deviceName")]
+    device_name: String,
+}
+
+#[derive(Deserialize)]
+struct DeviceDisconnectedData {
+    id: String,
+}
+
+impl PushEvent {
+    /// Parses a decrypted push message, returning `None` for messages we
+    /// don't know about.
+    pub(crate) fn parse(payload: &str) -> Result<Option<PushEvent>> {
+        let payload: PushPayload = serde_json::from_str(payload)?;
+        Ok(Some(match payload.command.as_str() {
+            "fxaccounts:command_received" => {
+                let data: CommandReceivedData = serde_json::from_value(payload.data)?;
+                PushEvent::CommandReceived { index: data.index }
+            }
+            "fxaccounts:device_connected" => {
+                let data: DeviceConnectedData = serde_json::from_value(payload.data)?;
+                PushEvent::DeviceConnected {
+                    device_name: data.device_name,
+                }
+            }
+            "fxaccounts:device_disconnected" => {
+                let data: DeviceDisconnectedData = serde_json::from_value(payload.data)?;
+                PushEvent::DeviceDisconnected { device_id: data.id }
+            }
+            "fxaccounts:profile_updated" => PushEvent::ProfileUpdated,
+            "fxaccounts:password_changed" => PushEvent::PasswordChanged,
+            "fxaccounts:password_reset" => PushEvent::PasswordReset,
+            "fxaccounts:account_destroyed" => PushEvent::AccountDestroyed,
+            command => {
+                warn!("Ignoring unknown push message {}", command);
+                return Ok(None);
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let parse = |payload: &str| PushEvent::parse(payload).unwrap();
+        assert_eq!(
+            parse(r#"{"version":1,"command":"fxaccounts:command_received","data":{"command":"https://identity.mozilla.com/cmd/open-uri","index":42,"sender":"device1","url":"https://example.com/v1/account/device/commands?index=42&limit=1"}}"#),
+            Some(PushEvent::CommandReceived { index: 42 })
+        );
+        assert_eq!(
+            parse(r#"{"version":1,"command":"fxaccounts:device_connected","data":{"deviceName":"Laptop"}}"#),
+            Some(PushEvent::DeviceConnected { device_name: "Laptop".into() })
+        );
+        assert_eq!(
+            parse(r#"{"version":1,"command":"fxaccounts:device_disconnected","data":{"id":"device1"}}"#),
+            Some(PushEvent::DeviceDisconnected { device_id: "device1".into() })
+        );
+        assert_eq!(
+            parse(r#"{"version":1,"command":"fxaccounts:profile_updated"}"#),
+            Some(PushEvent::ProfileUpdated)
+        );
+        assert_eq!(
+            parse(r#"{"version":1,"command":"fxaccounts:password_reset"}"#),
+            Some(PushEvent::PasswordReset)
+        );
+        assert_eq!(
+            parse(r#"{"version":1,"command":"fxaccounts:account_destroyed","data":{"uid":"abc"}}"#),
+            Some(PushEvent::AccountDestroyed)
+        );
+        assert_eq!(parse(r#"{"version":1,"command":"fxaccounts:something_new"}"#), None);
+
+        assert!(PushEvent::parse(r#"{"command":"fxaccounts:device_connected"}"#).is_err());
+        assert!(PushEvent::parse("not json").is_err());
+    }
+}
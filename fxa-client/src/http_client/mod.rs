@@ -4,10 +4,12 @@
 
 use hex;
 use reqwest;
-use reqwest::{header, Client as ReqwestClient, Method, Request, Response, StatusCode};
+use reqwest::{header, Client as ReqwestClient, Method, Request, RequestBuilder, Response, StatusCode};
 use ring::{digest, hkdf, hmac};
+use secret::SecretString;
 use serde_json;
 use std;
+use url::Url;
 use util::Xorable;
 
 #[cfg(feature = "browserid")]
@@ -17,7 +19,10 @@ use self::browser_id::{jwt_utils, BrowserIDKeyPair};
 #[cfg(feature = "browserid")]
 use self::hawk_request::HAWKRequestBuilder;
 use config::Config;
+use device::{Device, DeviceUpdate};
 use errors::*;
+#[cfg(feature = "browserid")]
+use pairing::AuthorizationParams;
 
 #[cfg(feature = "browserid")]
 pub mod browser_id;
@@ -65,8 +70,30 @@ impl<'a> Client<'a> {
         hex::encode(digest::digest(&digest::SHA256, &kb).as_ref()[0..16].to_vec())
     }
 
-    pub fn sign_out(&self) {
-        panic!("Not implemented yet!");
+    #[cfg(feature = "browserid")]
+    pub fn destroy_session(&self, session_token: &[u8]) -> Result<()> {
+        let url = self.config.auth_url_path("v1/session/destroy")?;
+        let key = Client::derive_key_from_session_token(session_token)?;
+        let request = HAWKRequestBuilder::new(Method::Post, url, &key)
+            .body(json!({}))
+            .build()?;
+        Client::make_request(request)?;
+        Ok(())
+    }
+
+    /// Revokes an OAuth access or refresh token.
+    pub fn destroy_oauth_token(&self, token: &str) -> Result<()> {
+        let url = self.config.oauth_url_path("v1/destroy")?;
+        let body = json!({
+            "token": token,
+        });
+        let request = ReqwestClient::new()
+            .request(Method::Post, url)
+            .header(header::ContentType::json())
+            .body(body.to_string())
+            .build()?;
+        Client::make_request(request)?;
+        Ok(())
     }
 
     #[cfg(feature = "browserid")]
@@ -174,6 +201,72 @@ impl<'a> Client<'a> {
         }))
     }
 
+    pub fn update_device(&self, refresh_token: &str, update: &DeviceUpdate) -> Result<Device> {
+        let url = self.config.auth_url_path("v1/account/device")?;
+        let request = Client::bearer_request(Method::Post, url, refresh_token)
+            .header(header::ContentType::json())
+            .body(serde_json::to_string(update)?)
+            .build()?;
+        Client::make_request(request)?.json().map_err(|e| e.into())
+    }
+
+    pub fn devices(&self, refresh_token: &str) -> Result<Vec<Device>> {
+        let url = self.config.auth_url_path("v1/account/devices")?;
+        let request = Client::bearer_request(Method::Get, url, refresh_token).build()?;
+        Client::make_request(request)?.json().map_err(|e| e.into())
+    }
+
+    pub fn destroy_device(&self, refresh_token: &str, id: &str) -> Result<()> {
+        let url = self.config.auth_url_path("v1/account/device/destroy")?;
+        let body = json!({
+            "id": id,
+        });
+        let request = Client::bearer_request(Method::Post, url, refresh_token)
+            .header(header::ContentType::json())
+            .body(body.to_string())
+            .build()?;
+        Client::make_request(request)?;
+        Ok(())
+    }
+
+    pub fn invoke_command(
+        &self,
+        refresh_token: &str,
+        command: &str,
+        target: &str,
+        payload: &serde_json::Value,
+    ) -> Result<()> {
+        let url = self.config.auth_url_path("v1/account/devices/invoke_command")?;
+        let body = json!({
+            "command": command,
+            "target": target,
+            "payload": payload,
+        });
+        let request = Client::bearer_request(Method::Post, url, refresh_token)
+            .header(header::ContentType::json())
+            .body(body.to_string())
+            .build()?;
+        Client::make_request(request)?;
+        Ok(())
+    }
+
+    pub fn pending_commands(
+        &self,
+        refresh_token: &str,
+        index: u64,
+        limit: Option<u64>,
+    ) -> Result<PendingCommandsResponse> {
+        let url = self.config.auth_url_path("v1/account/device/commands")?;
+        let mut query = vec![("index", index)];
+        if let Some(limit) = limit {
+            query.push(("limit", limit));
+        }
+        let request = Client::bearer_request(Method::Get, url, refresh_token)
+            .query(&query)
+            .build()?;
+        Client::make_request(request)?.json().map_err(|e| e.into())
+    }
+
     #[cfg(feature = "browserid")]
     pub fn oauth_token_with_session_token(
         &self,
@@ -199,6 +292,36 @@ impl<'a> Client<'a> {
         Client::make_request(request)?.json().map_err(|e| e.into())
     }
 
+    /// Gets an authorization code for another client's OAuth flow, such as
+    /// a device we're pairing with. `keys_jwe` is returned with the token
+    /// that the code is exchanged for.
+    #[cfg(feature = "browserid")]
+    pub fn authorization_code_using_session_token(
+        &self,
+        session_token: &[u8],
+        params: &AuthorizationParams,
+        keys_jwe: Option<&str>,
+    ) -> Result<AuthorizationCodeResponse> {
+        let mut parameters = json!({
+          "client_id": params.client_id,
+          "state": params.state,
+          "scope": params.scope,
+          "response_type": "code",
+          "code_challenge": params.code_challenge,
+          "code_challenge_method": params.code_challenge_method,
+          "access_type": params.access_type
+        });
+        if let Some(keys_jwe) = keys_jwe {
+            parameters["keys_jwe"] = json!(keys_jwe);
+        }
+        let key = Client::derive_key_from_session_token(session_token)?;
+        let url = self.config.auth_url_path("v1/oauth/authorization")?;
+        let request = HAWKRequestBuilder::new(Method::Post, url, &key)
+            .body(parameters)
+            .build()?;
+        Client::make_request(request)?.json().map_err(|e| e.into())
+    }
+
     pub fn oauth_token_with_code(
         &self,
         code: &str,
@@ -282,6 +405,14 @@ impl<'a> Client<'a> {
         out.to_vec()
     }
 
+    fn bearer_request(method: Method, url: Url, token: &str) -> RequestBuilder {
+        let mut builder = ReqwestClient::new().request(method, url);
+        builder.header(header::Authorization(header::Bearer {
+            token: token.to_string(),
+        }));
+        builder
+    }
+
     fn make_request(request: Request) -> Result<Response> {
         let client = ReqwestClient::new();
         let mut resp = client.execute(request)?;
@@ -332,10 +463,36 @@ pub struct AccountStatusResponse {
 #[derive(Deserialize)]
 pub struct OAuthTokenResponse {
     pub keys_jwe: Option<String>,
-    pub refresh_token: Option<String>,
+    pub refresh_token: Option<SecretString>,
     pub expires_in: u64,
     pub scope: String,
-    pub access_token: String,
+    pub access_token: SecretString,
+}
+
+#[derive(Deserialize)]
+pub struct AuthorizationCodeResponse {
+    pub code: String,
+    pub state: String,
+}
+
+#[derive(Deserialize)]
+pub struct PendingCommandsResponse {
+    pub index: u64,
+    pub last: Option<bool>,
+    pub messages: Vec<PendingCommand>,
+}
+
+#[derive(Deserialize)]
+pub struct PendingCommand {
+    pub index: u64,
+    pub data: CommandData,
+}
+
+#[derive(Deserialize)]
+pub struct CommandData {
+    pub command: String,
+    pub payload: serde_json::Value,
+    pub sender: Option<String>,
 }
 
 #[derive(Deserialize)]
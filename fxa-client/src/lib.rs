@@ -14,11 +14,12 @@ extern crate hex;
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
-#[cfg(feature = "browserid")]
+#[cfg(any(feature = "browserid", feature = "stored-keys"))]
 extern crate openssl;
 extern crate regex;
 extern crate reqwest;
 extern crate ring;
+extern crate secret;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -27,7 +28,7 @@ extern crate serde_json;
 extern crate untrusted;
 extern crate url;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 use std::panic::RefUnwindSafe;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -36,33 +37,57 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use self::login_sm::LoginState::*;
 #[cfg(feature = "browserid")]
 use self::login_sm::*;
+#[cfg(feature = "stored-keys")]
+use commands::ReceivedCommand;
+use commands::{Command, SendTab};
+use device::{Device, DeviceUpdate};
 use errors::*;
+use events::PushEvent;
 #[cfg(feature = "browserid")]
 use http_client::browser_id::jwt_utils;
 use http_client::{Client, OAuthTokenResponse, ProfileResponse};
+use pairing::PairingMessage;
 use ring::digest;
 use ring::rand::{SecureRandom, SystemRandom};
-use scoped_keys::ScopedKeysFlow;
+use scoped_keys::{ScopedKey, ScopedKeysFlow};
+use secret::SecretString;
 use url::Url;
 use util::now;
 
+pub mod commands;
 mod config;
+pub mod device;
+mod ece;
 pub mod errors;
+mod events;
 mod http_client;
 #[cfg(feature = "browserid")]
 mod login_sm;
+#[cfg(test)]
+mod mock_server;
 mod oauth;
+pub mod pairing;
+#[cfg(feature = "stored-keys")]
+mod push;
 mod scoped_keys;
 mod util;
 
 pub use config::Config;
+pub use device::{Device, DeviceType, DeviceUpdate, PushSubscription};
+pub use events::AccountEvent;
 pub use http_client::ProfileResponse as Profile;
+pub use pairing::{AuthorizationParams, PairingChannel, PairingKey, PairingMetadata, Transport};
+pub use scoped_keys::{ScopedKey, OLDSYNC_SCOPE};
+pub use secret::{Secret, SecretBytes, SecretString};
 
 // If a cached token has less than `OAUTH_MIN_TIME_LEFT` seconds left to live,
 // it will be considered already expired.
 const OAUTH_MIN_TIME_LEFT: u64 = 60;
 // A cached profile response is considered fresh for `PROFILE_FRESHNESS_THRESHOLD` ms.
 const PROFILE_FRESHNESS_THRESHOLD: u64 = 120000; // 2 minutes
+// An OAuth flow the user hasn't completed after `OAUTH_FLOW_LIFETIME`
+// seconds is dropped.
+const OAUTH_FLOW_LIFETIME: u64 = 3600; // 1 hour
 
 lazy_static! {
     static ref RNG: SystemRandom = SystemRandom::new();
@@ -76,6 +101,29 @@ struct StateV1 {
     #[cfg(feature = "browserid")]
     login_state: LoginState,
     oauth_cache: HashMap<String, OAuthInfo>,
+    // Kept apart from the tokens, which come and go without keys.
+    #[serde(default)]
+    scoped_keys: HashMap<String, ScopedKey>,
+    // Flows we started, keyed by their state, so the user can finish
+    // signing in even if we're restarted in the meantime.
+    #[serde(default)]
+    oauth_flows: HashMap<String, OAuthFlow>,
+    #[serde(default)]
+    current_device_id: Option<String>,
+    #[cfg(feature = "stored-keys")]
+    #[serde(default)]
+    command_keys: Option<ece::KeyPair>,
+    // The index of the last command we received, so we can ask for the ones
+    // after it.
+    #[serde(default)]
+    last_handled_command: Option<u64>,
+    // Set when we learn our tokens were (or may have been) revoked, until the
+    // user signs in again.
+    #[serde(default)]
+    needs_reauth: bool,
+    #[cfg(feature = "stored-keys")]
+    #[serde(default)]
+    push_keys: Option<ece::KeyPair>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -84,17 +132,17 @@ enum State {
     V1(StateV1),
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
 pub struct WebChannelResponse {
     uid: String,
     email: String,
     verified: bool,
     #[serde(rename = "sessionToken")]
-    session_token: String,
+    session_token: SecretString,
     #[serde(rename = "keyFetchToken")]
-    key_fetch_token: String,
+    key_fetch_token: SecretString,
     #[serde(rename = "unwrapBKey")]
-    unwrap_kb: String,
+    unwrap_kb: SecretString,
 }
 
 impl WebChannelResponse {
@@ -111,12 +159,11 @@ struct CachedResponse<T> {
 
 pub struct FirefoxAccount {
     state: StateV1,
-    flow_store: HashMap<String, OAuthFlow>,
     persist_callback: Option<PersistCallback>,
     profile_cache: Option<CachedResponse<ProfileResponse>>,
 }
 
-pub type SyncKeys = (String, String);
+pub type SyncKeys = (SecretString, String);
 
 pub struct PersistCallback {
     callback_fn: Box<Fn(&str) + Send + RefUnwindSafe>,
@@ -141,7 +188,6 @@ impl FirefoxAccount {
     fn from_state(state: StateV1) -> FirefoxAccount {
         FirefoxAccount {
             state,
-            flow_store: HashMap::new(),
             persist_callback: None,
             profile_cache: None,
         }
@@ -155,6 +201,15 @@ impl FirefoxAccount {
             #[cfg(feature = "browserid")]
             login_state: Unknown,
             oauth_cache: HashMap::new(),
+            scoped_keys: HashMap::new(),
+            oauth_flows: HashMap::new(),
+            current_device_id: None,
+            #[cfg(feature = "stored-keys")]
+            command_keys: None,
+            last_handled_command: None,
+            needs_reauth: false,
+            #[cfg(feature = "stored-keys")]
+            push_keys: None,
         })
     }
 
@@ -167,9 +222,9 @@ impl FirefoxAccount {
         redirect_uri: &str,
         credentials: WebChannelResponse,
     ) -> Result<FirefoxAccount> {
-        let session_token = hex::decode(credentials.session_token)?;
-        let key_fetch_token = hex::decode(credentials.key_fetch_token)?;
-        let unwrap_kb = hex::decode(credentials.unwrap_kb)?;
+        let session_token = hex::decode(credentials.session_token.expose())?;
+        let key_fetch_token = hex::decode(credentials.key_fetch_token.expose())?;
+        let unwrap_kb = hex::decode(credentials.unwrap_kb.expose())?;
         let login_state_data = ReadyForKeysState::new(
             credentials.uid,
             credentials.email,
@@ -189,16 +244,58 @@ impl FirefoxAccount {
             config,
             login_state,
             oauth_cache: HashMap::new(),
+            scoped_keys: HashMap::new(),
+            oauth_flows: HashMap::new(),
+            current_device_id: None,
+            #[cfg(feature = "stored-keys")]
+            command_keys: None,
+            last_handled_command: None,
+            needs_reauth: false,
+            #[cfg(feature = "stored-keys")]
+            push_keys: None,
         }))
     }
 
     pub fn from_json(data: &str) -> Result<FirefoxAccount> {
         let fxa_state: State = serde_json::from_str(data)?;
         match fxa_state {
-            State::V1(state) => Ok(FirefoxAccount::from_state(state)),
+            State::V1(mut state) => {
+                if state.scoped_keys.is_empty() {
+                    state.scoped_keys = FirefoxAccount::legacy_scoped_keys(data);
+                }
+                let now = util::now_secs();
+                state.oauth_flows.retain(|_, flow| flow.expires_at > now);
+                // Without stored keys, flows that asked for keys can't be
+                // completed after a restart.
+                #[cfg(not(feature = "stored-keys"))]
+                {
+                    state.oauth_flows.retain(|_, flow| {
+                        flow.scoped_keys_flow.as_ref().map_or(true, |flow| flow.has_key())
+                    });
+                }
+                Ok(FirefoxAccount::from_state(state))
+            }
         }
     }
 
+    // We used to save scoped keys with each cached token, as the JSON from
+    // `keys_jwe`.
+    fn legacy_scoped_keys(data: &str) -> HashMap<String, ScopedKey> {
+        let mut scoped_keys = HashMap::new();
+        let state: serde_json::Value = match serde_json::from_str(data) {
+            Ok(state) => state,
+            Err(_) => return scoped_keys,
+        };
+        if let Some(oauth_cache) = state["oauth_cache"].as_object() {
+            for info in oauth_cache.values() {
+                if let Some(Ok(keys)) = info["keys"].as_str().map(ScopedKey::parse_keys) {
+                    scoped_keys.extend(keys);
+                }
+            }
+        }
+        scoped_keys
+    }
+
     pub fn to_json(&self) -> Result<String> {
         let state = State::V1(self.state.clone());
         serde_json::to_string(&state).map_err(|e| e.into())
@@ -255,7 +352,7 @@ impl FirefoxAccount {
         let mut refresh_token = None;
         if let Some(cached_oauth_info) = self.oauth_cache_find(scopes) {
             if cached_oauth_info.expires_at > util::now_secs() + OAUTH_MIN_TIME_LEFT {
-                return Ok(Some(cached_oauth_info.clone()));
+                return Ok(Some(self.with_scoped_keys(cached_oauth_info.clone())));
             }
             refresh_token = cached_oauth_info.refresh_token.clone();
         }
@@ -266,7 +363,7 @@ impl FirefoxAccount {
                 let client = Client::new(&self.state.config);
                 resp = client.oauth_token_with_refresh_token(
                     &self.state.client_id,
-                    &refresh_token,
+                    refresh_token.expose(),
                     &scopes,
                 )?;
             } else {
@@ -295,59 +392,207 @@ impl FirefoxAccount {
     }
 
     pub fn begin_oauth_flow(&mut self, scopes: &[&str], wants_keys: bool) -> Result<String> {
-        let state = FirefoxAccount::random_base64_url_string(16)?;
-        let code_verifier = FirefoxAccount::random_base64_url_string(43)?;
-        let code_challenge = digest::digest(&digest::SHA256, &code_verifier.as_bytes());
-        let code_challenge = base64::encode_config(&code_challenge, base64::URL_SAFE_NO_PAD);
+        let params = self.new_oauth_flow(scopes, wants_keys)?;
         let mut url = self.state.config.authorization_endpoint()?;
         url.query_pairs_mut()
             .append_pair("action", "email")
-            .append_pair("client_id", &self.state.client_id)
+            .append_pair("client_id", &params.client_id)
             .append_pair("redirect_uri", &self.state.redirect_uri)
-            .append_pair("scope", &scopes.join(" "))
+            .append_pair("scope", &params.scope)
             .append_pair("response_type", "code")
-            .append_pair("state", &state)
-            .append_pair("code_challenge_method", "S256")
-            .append_pair("code_challenge", &code_challenge)
-            .append_pair("access_type", "offline");
-        let scoped_keys_flow = match wants_keys {
+            .append_pair("state", &params.state)
+            .append_pair("code_challenge_method", &params.code_challenge_method)
+            .append_pair("code_challenge", &params.code_challenge)
+            .append_pair("access_type", &params.access_type);
+        if let Some(ref keys_jwk) = params.keys_jwk {
+            url.query_pairs_mut().append_pair("keys_jwk", keys_jwk);
+        }
+        Ok(url.to_string())
+    }
+
+    // Starts an OAuth flow, returning what the user needs to authorize.
+    fn new_oauth_flow(&mut self, scopes: &[&str], wants_keys: bool) -> Result<AuthorizationParams> {
+        let state = FirefoxAccount::random_base64_url_string(16)?;
+        let code_verifier = SecretString::from(FirefoxAccount::random_base64_url_string(43)?);
+        let code_challenge = digest::digest(&digest::SHA256, code_verifier.expose().as_bytes());
+        let code_challenge = base64::encode_config(&code_challenge, base64::URL_SAFE_NO_PAD);
+        let (scoped_keys_flow, keys_jwk) = match wants_keys {
             true => {
                 let flow = ScopedKeysFlow::with_random_key(&*RNG)?;
                 let jwk_json = flow.generate_keys_jwk()?;
                 let keys_jwk = base64::encode_config(&jwk_json, base64::URL_SAFE_NO_PAD);
-                url.query_pairs_mut().append_pair("keys_jwk", &keys_jwk);
-                Some(flow)
+                (Some(flow), Some(keys_jwk))
             }
-            false => None,
+            false => (None, None),
         };
-        self.flow_store.insert(
+        let now = util::now_secs();
+        self.state.oauth_flows.retain(|_, flow| flow.expires_at > now);
+        self.state.oauth_flows.insert(
             state.clone(), // Since state is supposed to be unique, we use it to key our flows.
             OAuthFlow {
                 scoped_keys_flow,
                 code_verifier,
+                expires_at: now + OAUTH_FLOW_LIFETIME,
             },
         );
-        Ok(url.to_string())
+        self.maybe_call_persist_callback();
+        Ok(AuthorizationParams {
+            client_id: self.state.client_id.clone(),
+            state,
+            scope: scopes.join(" "),
+            code_challenge,
+            code_challenge_method: "S256".to_string(),
+            access_type: "offline".to_string(),
+            keys_jwk,
+        })
     }
 
     pub fn complete_oauth_flow(&mut self, code: &str, state: &str) -> Result<OAuthInfo> {
         let resp;
         // Needs non-lexical borrow checking.
         {
-            let flow = match self.flow_store.get(state) {
-                Some(flow) => flow,
-                None => return Err(ErrorKind::UnknownOAuthState.into()),
+            let flow = match self.state.oauth_flows.get(state) {
+                Some(flow) if flow.expires_at > util::now_secs() => flow,
+                _ => return Err(ErrorKind::UnknownOAuthState.into()),
             };
             let client = Client::new(&self.state.config);
-            resp = client.oauth_token_with_code(&code, &flow.code_verifier, &self.state.client_id)?;
+            resp = client.oauth_token_with_code(
+                &code,
+                flow.code_verifier.expose(),
+                &self.state.client_id,
+            )?;
         }
-        let oauth_flow = match self.flow_store.remove(state) {
+        let oauth_flow = match self.state.oauth_flows.remove(state) {
             Some(oauth_flow) => oauth_flow,
             None => return Err(ErrorKind::UnknownOAuthState.into()),
         };
         self.handle_oauth_token_response(resp, oauth_flow.scoped_keys_flow)
     }
 
+    /// Opens a pairing channel as the authority, for a device to sign in to
+    /// our account with. `transport` should be connected to the channel
+    /// server without a channel id. Show `pairing_url` as a QR code, then
+    /// wait for the other device with `handle_pairing_request`.
+    pub fn open_pairing_channel<T: Transport>(&self, transport: T) -> Result<PairingChannel<T>> {
+        PairingChannel::open(transport, &*RNG)
+    }
+
+    /// The URL to show as a QR code for the other device to scan.
+    pub fn pairing_url<T: Transport>(&self, channel: &PairingChannel<T>) -> Result<String> {
+        Ok(channel.pairing_url(&self.state.config)?.to_string())
+    }
+
+    /// Waits for the device we're pairing with to ask to sign in, and tells
+    /// it which account it would sign in to. The app should ask the user to
+    /// confirm the returned request, then approve or decline it.
+    pub fn handle_pairing_request<T: Transport>(
+        &mut self,
+        channel: &mut PairingChannel<T>,
+    ) -> Result<AuthorizationParams> {
+        let params = match channel.receive()? {
+            PairingMessage::Request(params) => params,
+            _ => return Err(ErrorKind::UnexpectedPairingMessage.into()),
+        };
+        let metadata = match self.get_profile(false) {
+            Ok(profile) => PairingMetadata {
+                email: Some(profile.email),
+                avatar: Some(profile.avatar),
+                display_name: profile.display_name,
+            },
+            Err(e) => {
+                warn!("Pairing without our profile: {}", e);
+                PairingMetadata::default()
+            }
+        };
+        channel.send(&PairingMessage::Metadata(metadata), &*RNG)?;
+        Ok(params)
+    }
+
+    /// Signs the device we're pairing with in: authorizes its OAuth flow
+    /// with our session, along with our keys for the scopes it asked for,
+    /// and sends it the code.
+    #[cfg(feature = "browserid")]
+    pub fn approve_pairing_request<T: Transport>(
+        &mut self,
+        channel: &mut PairingChannel<T>,
+        params: &AuthorizationParams,
+    ) -> Result<()> {
+        let keys_jwe = match params.keys_jwk {
+            Some(ref keys_jwk) => {
+                let keys: HashMap<&str, &ScopedKey> = params
+                    .scope
+                    .split(' ')
+                    .filter_map(|scope| self.state.scoped_keys.get(scope).map(|key| (scope, key)))
+                    .collect();
+                if keys.is_empty() {
+                    None
+                } else {
+                    Some(scoped_keys::encrypt_keys_jwe(
+                        keys_jwk,
+                        &serde_json::to_string(&keys)?,
+                        &*RNG,
+                    )?)
+                }
+            }
+            None => None,
+        };
+        let resp = match FirefoxAccount::session_token_from_state(&self.state.login_state) {
+            Some(session_token) => Client::new(&self.state.config)
+                .authorization_code_using_session_token(
+                    session_token,
+                    params,
+                    keys_jwe.as_ref().map(|jwe| jwe.as_str()),
+                )?,
+            None => return Err(ErrorKind::NoSessionToken.into()),
+        };
+        channel.send(
+            &PairingMessage::Authorize {
+                code: resp.code,
+                state: resp.state,
+            },
+            &*RNG,
+        )
+    }
+
+    pub fn decline_pairing_request<T: Transport>(
+        &self,
+        channel: &mut PairingChannel<T>,
+    ) -> Result<()> {
+        channel.send(&PairingMessage::Decline, &*RNG)
+    }
+
+    /// Starts signing in by pairing with a signed-in device, over the channel
+    /// joined with the key from its QR code. Returns the account that device
+    /// is signed in to, for the user to check.
+    pub fn begin_pairing_flow<T: Transport>(
+        &mut self,
+        channel: &mut PairingChannel<T>,
+        scopes: &[&str],
+        wants_keys: bool,
+    ) -> Result<PairingMetadata> {
+        let params = self.new_oauth_flow(scopes, wants_keys)?;
+        channel.send(&PairingMessage::Request(params), &*RNG)?;
+        match channel.receive()? {
+            PairingMessage::Metadata(metadata) => Ok(metadata),
+            PairingMessage::Decline => Err(ErrorKind::PairingDeclined.into()),
+            _ => Err(ErrorKind::UnexpectedPairingMessage.into()),
+        }
+    }
+
+    /// Waits for the user to approve pairing on the other device, then
+    /// finishes signing in with the code it sends. The keys come with the
+    /// token, like for any other OAuth flow.
+    pub fn complete_pairing_flow<T: Transport>(
+        &mut self,
+        channel: &mut PairingChannel<T>,
+    ) -> Result<OAuthInfo> {
+        match channel.receive()? {
+            PairingMessage::Authorize { code, state } => self.complete_oauth_flow(&code, &state),
+            PairingMessage::Decline => Err(ErrorKind::PairingDeclined.into()),
+            _ => Err(ErrorKind::UnexpectedPairingMessage.into()),
+        }
+    }
+
     fn handle_oauth_token_response(
         &mut self,
         resp: OAuthTokenResponse,
@@ -355,19 +600,18 @@ impl FirefoxAccount {
     ) -> Result<OAuthInfo> {
         let granted_scopes = resp.scope.split(" ").map(|s| s.to_string()).collect();
         // This assumes that if the server returns keys_jwe, the jwk argument is Some.
-        let keys = match resp.keys_jwe {
+        match resp.keys_jwe {
             Some(jwe) => {
                 let scoped_keys_flow = scoped_keys_flow.expect(
                     "Insane state! If we are getting back a JWE this means we should have a JWK private key.",
                 );
-                Some(scoped_keys_flow.decrypt_keys_jwe(&jwe)?)
+                let keys = ScopedKey::parse_keys(&scoped_keys_flow.decrypt_keys_jwe(&jwe)?)?;
+                self.state.scoped_keys.extend(keys);
             }
             None => {
                 if scoped_keys_flow.is_some() {
                     error!("Expected to get keys back alongside the token but the server didn't send them.");
                     return Err(ErrorKind::TokenWithoutKeys.into());
-                } else {
-                    None
                 }
             }
         };
@@ -377,14 +621,40 @@ impl FirefoxAccount {
         let expires_at = since_epoch.as_secs() + resp.expires_in;
         let oauth_info = OAuthInfo {
             access_token: resp.access_token,
-            keys,
+            keys: None,
             refresh_token: resp.refresh_token,
             expires_at,
             scopes: granted_scopes,
         };
         self.oauth_cache_store(&oauth_info);
+        self.state.needs_reauth = false;
         self.maybe_call_persist_callback();
-        Ok(oauth_info)
+        Ok(self.with_scoped_keys(oauth_info))
+    }
+
+    // Adds the keys we have for the token's scopes.
+    fn with_scoped_keys(&self, mut info: OAuthInfo) -> OAuthInfo {
+        let keys: HashMap<String, ScopedKey> = info
+            .scopes
+            .iter()
+            .filter_map(|scope| {
+                self.state
+                    .scoped_keys
+                    .get(scope)
+                    .map(|key| (scope.clone(), key.clone()))
+            })
+            .collect();
+        if !keys.is_empty() {
+            info.keys = Some(keys);
+        }
+        info
+    }
+
+    /// The key for `scope`, if an OAuth flow that asked for keys was granted
+    /// it. Use `ScopedKey::ksync` and `ScopedKey::key_id` on the
+    /// `OLDSYNC_SCOPE` key to sync.
+    pub fn get_scoped_key(&self, scope: &str) -> Option<&ScopedKey> {
+        self.state.scoped_keys.get(scope)
     }
 
     fn random_base64_url_string(len: usize) -> Result<String> {
@@ -436,7 +706,7 @@ impl FirefoxAccount {
             etag = Some(cached_profile.etag.clone());
         }
         let client = Client::new(&self.state.config);
-        match client.profile(&profile_access_token, etag)? {
+        match client.profile(profile_access_token.expose(), etag)? {
             Some(response_and_etag) => {
                 if let Some(etag) = response_and_etag.etag {
                     self.profile_cache = Some(CachedResponse {
@@ -464,31 +734,296 @@ impl FirefoxAccount {
             None => return Err(ErrorKind::NotMarried.into()),
         };
         let sync_key = hex::encode(married.sync_key());
-        Ok((sync_key, married.xcs().to_string()))
+        Ok((sync_key.into(), married.xcs().to_string()))
+    }
+
+    /// Fetches the config's discovery documents again if we last fetched
+    /// them more than `max_age` ms ago, returning true if we did.
+    pub fn refresh_config_if_stale(&mut self, max_age: u64) -> Result<bool> {
+        if !self.state.config.is_stale(max_age) {
+            return Ok(false);
+        }
+        self.state.config = self.state.config.refresh()?;
+        self.maybe_call_persist_callback();
+        Ok(true)
     }
 
     pub fn get_token_server_endpoint_url(&self) -> Result<Url> {
         self.state.config.token_server_endpoint_url()
     }
 
-    pub fn handle_push_message(&self) {
-        panic!("Not implemented yet!")
+    /// True if the user needs to sign in again, e.g. after changing their
+    /// password on another device.
+    pub fn needs_reauth(&self) -> bool {
+        self.state.needs_reauth
     }
 
-    pub fn register_device(&self) {
-        panic!("Not implemented yet!")
+    /// The push subscription to register with this device (see
+    /// `DeviceUpdate::push_subscription`), for the push service's `endpoint`.
+    /// The keys are generated the first time, then saved with the account.
+    #[cfg(feature = "stored-keys")]
+    pub fn push_subscription(&mut self, endpoint: &str) -> Result<PushSubscription> {
+        let keys = match self.state.push_keys.clone() {
+            Some(keys) => keys,
+            None => {
+                let keys = ece::KeyPair::generate(&*RNG)?;
+                self.state.push_keys = Some(keys.clone());
+                self.maybe_call_persist_callback();
+                keys
+            }
+        };
+        Ok(PushSubscription {
+            endpoint: endpoint.to_string(),
+            public_key: keys.public_key().to_string(),
+            auth_key: keys.auth_secret().expose().clone(),
+        })
     }
 
-    pub fn get_devices_list(&self) {
-        panic!("Not implemented yet!")
+    /// Decrypts a push message sent to our push subscription, given its body
+    /// and the values of its `Content-Encoding`, `Encryption` and
+    /// `Crypto-Key` headers. The result can be passed to
+    /// `handle_push_message`.
+    #[cfg(feature = "stored-keys")]
+    pub fn decrypt_push_message(
+        &self,
+        body: &[u8],
+        content_encoding: &str,
+        encryption: Option<&str>,
+        crypto_key: Option<&str>,
+    ) -> Result<String> {
+        let keys = match self.state.push_keys {
+            Some(ref keys) => keys,
+            None => return Err(ErrorKind::NoPushKeys.into()),
+        };
+        let payload = push::decrypt(keys, body, content_encoding, encryption, crypto_key)?;
+        Ok(String::from_utf8(payload)?)
     }
 
-    pub fn send_message(&self) {
-        panic!("Not implemented yet!")
+    /// Handles a decrypted push message sent to this device, updating our
+    /// state to match. If the message says we received a command, we fetch
+    /// it (and any others we missed).
+    pub fn handle_push_message(&mut self, payload: &str) -> Result<Vec<AccountEvent>> {
+        let event = match PushEvent::parse(payload)? {
+            Some(event) => event,
+            None => return Ok(Vec::new()),
+        };
+        let events = match event {
+            #[cfg(feature = "stored-keys")]
+            PushEvent::CommandReceived { .. } => {
+                return Ok(self
+                    .retrieve_messages()?
+                    .into_iter()
+                    .map(AccountEvent::CommandReceived)
+                    .collect());
+            }
+            // We can't have published command keys to be sent this.
+            #[cfg(not(feature = "stored-keys"))]
+            PushEvent::CommandReceived { .. } => {
+                return Err(ErrorKind::CommandsNotEnabled.into());
+            }
+            PushEvent::DeviceConnected { device_name } => {
+                vec![AccountEvent::DeviceConnected { device_name }]
+            }
+            PushEvent::DeviceDisconnected { device_id } => {
+                let is_local_device = self.current_device_id() == Some(device_id.as_str());
+                if is_local_device {
+                    // Disconnecting a device also revokes its tokens.
+                    self.forget_device();
+                    self.state.oauth_cache.clear();
+                    self.state.needs_reauth = true;
+                }
+                vec![AccountEvent::DeviceDisconnected {
+                    device_id,
+                    is_local_device,
+                }]
+            }
+            PushEvent::ProfileUpdated => {
+                self.profile_cache = None;
+                vec![AccountEvent::ProfileUpdated]
+            }
+            PushEvent::PasswordChanged => {
+                // Changing the password destroys the session token.
+                #[cfg(feature = "browserid")]
+                {
+                    self.to_separated();
+                }
+                self.state.needs_reauth = true;
+                vec![AccountEvent::PasswordChanged]
+            }
+            PushEvent::PasswordReset => {
+                // Resetting the password revokes every token, and changes
+                // the keys.
+                #[cfg(feature = "browserid")]
+                {
+                    self.to_separated();
+                }
+                self.state.oauth_cache.clear();
+                self.state.scoped_keys.clear();
+                self.state.needs_reauth = true;
+                vec![AccountEvent::PasswordReset]
+            }
+            PushEvent::AccountDestroyed => {
+                self.forget_device();
+                self.state.oauth_cache.clear();
+                self.state.scoped_keys.clear();
+                self.state.needs_reauth = true;
+                vec![AccountEvent::AccountDestroyed]
+            }
+        };
+        self.maybe_call_persist_callback();
+        Ok(events)
+    }
+
+    // Drops the session token and keys, so the BrowserID flows have to sign
+    // in again.
+    #[cfg(feature = "browserid")]
+    fn to_separated(&mut self) {
+        self.state.login_state = match mem::replace(&mut self.state.login_state, Unknown) {
+            Unknown => Unknown,
+            login_state => login_state.to_separated(),
+        };
+    }
+
+    // Forgets the device record, and the commands sent to it.
+    fn forget_device(&mut self) {
+        self.state.current_device_id = None;
+        #[cfg(feature = "stored-keys")]
+        {
+            self.state.command_keys = None;
+        }
+        self.state.last_handled_command = None;
+    }
+
+    // The device endpoints only accept refresh tokens that were granted
+    // Sync's scope.
+    fn refresh_token(&self) -> Result<&str> {
+        for info in self.state.oauth_cache.values() {
+            if let Some(ref refresh_token) = info.refresh_token {
+                let scope = info.scopes.join(" ");
+                if FirefoxAccount::scope_implies_scopes(&scope, &[OLDSYNC_SCOPE])? {
+                    return Ok(refresh_token.expose().as_str());
+                }
+            }
+        }
+        Err(ErrorKind::NoRefreshToken.into())
+    }
+
+    /// The id of this device, if it has been registered.
+    pub fn current_device_id(&self) -> Option<&str> {
+        self.state.current_device_id.as_ref().map(|id| id.as_str())
+    }
+
+    /// Registers this device with the account, or updates it if it's already
+    /// registered. A new device must have a name and type.
+    pub fn register_device(&mut self, update: DeviceUpdate) -> Result<Device> {
+        let update = DeviceUpdate {
+            id: self.state.current_device_id.clone(),
+            ..update
+        };
+        let device = {
+            let client = Client::new(&self.state.config);
+            client.update_device(self.refresh_token()?, &update)?
+        };
+        if self.state.current_device_id.as_ref() != Some(&device.id) {
+            self.state.current_device_id = Some(device.id.clone());
+            self.maybe_call_persist_callback();
+        }
+        Ok(device)
+    }
+
+    pub fn get_devices_list(&self) -> Result<Vec<Device>> {
+        let client = Client::new(&self.state.config);
+        client.devices(self.refresh_token()?)
+    }
+
+    /// Disconnects a device from the account. If it's this device, it will
+    /// need to be registered again.
+    pub fn disconnect_device(&mut self, id: &str) -> Result<()> {
+        {
+            let client = Client::new(&self.state.config);
+            client.destroy_device(self.refresh_token()?, id)?;
+        }
+        if self.current_device_id() == Some(id) {
+            self.state.current_device_id = None;
+            self.maybe_call_persist_callback();
+        }
+        Ok(())
+    }
+
+    /// Lets other devices send commands to this one, by publishing the keys
+    /// to encrypt them with. The device must already be registered.
+    #[cfg(feature = "stored-keys")]
+    pub fn enable_commands(&mut self) -> Result<Device> {
+        let keys = match self.state.command_keys.clone() {
+            Some(keys) => keys,
+            None => {
+                let keys = ece::KeyPair::generate(&*RNG)?;
+                self.state.command_keys = Some(keys.clone());
+                self.maybe_call_persist_callback();
+                keys
+            }
+        };
+        let published = commands::published_keys(&keys)?;
+        let mut available_commands = HashMap::new();
+        available_commands.insert(commands::SEND_TAB.to_string(), published);
+        self.register_device(DeviceUpdate::new().available_commands(available_commands))
+    }
+
+    pub fn send_message(&self, target: &Device, command: &Command) -> Result<()> {
+        let published = match target.available_commands.get(command.name()) {
+            Some(published) => published,
+            None => return Err(ErrorKind::UnsupportedCommand(command.name().to_string()).into()),
+        };
+        let payload = command.encrypt(published, &*RNG)?;
+        let client = Client::new(&self.state.config);
+        client.invoke_command(self.refresh_token()?, command.name(), &target.id, &payload)
+    }
+
+    pub fn send_tab(&self, target: &Device, title: &str, url: &str) -> Result<()> {
+        let command = Command::SendTab(SendTab {
+            title: title.to_string(),
+            url: url.to_string(),
+        });
+        self.send_message(target, &command)
     }
 
-    pub fn retrieve_messages(&self) {
-        panic!("Not implemented yet!")
+    /// Fetches the commands sent to this device since the last call. Commands
+    /// we can't read are logged and skipped, so that they don't stop us from
+    /// receiving later ones. If fetching fails part way, we keep our place,
+    /// so the next call gets the same commands again.
+    #[cfg(feature = "stored-keys")]
+    pub fn retrieve_messages(&mut self) -> Result<Vec<ReceivedCommand>> {
+        let keys = match self.state.command_keys {
+            Some(ref keys) => keys.clone(),
+            None => return Err(ErrorKind::CommandsNotEnabled.into()),
+        };
+        let mut last_handled_command = self.state.last_handled_command;
+        let mut received = Vec::new();
+        loop {
+            let index = last_handled_command.map_or(0, |index| index + 1);
+            let resp = {
+                let client = Client::new(&self.state.config);
+                client.pending_commands(self.refresh_token()?, index, None)?
+            };
+            let done = resp.messages.is_empty() || resp.last != Some(false);
+            for message in resp.messages {
+                last_handled_command = Some(message.index);
+                let command = message.data.command.clone();
+                match ReceivedCommand::decrypt(&keys, message.data) {
+                    Ok(Some(received_command)) => received.push(received_command),
+                    Ok(None) => warn!("Ignoring unknown command {}", command),
+                    Err(e) => error!("Could not read command {} at {}: {}", command, message.index, e),
+                }
+            }
+            if done {
+                break;
+            }
+        }
+        if self.state.last_handled_command != last_handled_command {
+            self.state.last_handled_command = last_handled_command;
+            self.maybe_call_persist_callback();
+        }
+        Ok(received)
     }
 
     pub fn register_persist_callback(&mut self, persist_callback: PersistCallback) {
@@ -512,17 +1047,61 @@ impl FirefoxAccount {
         }
     }
 
-    #[cfg(feature = "browserid")]
-    pub fn sign_out(mut self) {
-        let client = Client::new(&self.state.config);
-        client.sign_out();
-        self.state.login_state = self.state.login_state.to_separated();
+    /// Signs out: destroys the session and revokes our OAuth tokens on the
+    /// servers, then forgets them along with our keys, device and cached
+    /// profile. Server errors are logged rather than returned, since we're
+    /// signed out locally either way.
+    pub fn sign_out(&mut self) {
+        // Needs non-lexical borrow checking.
+        {
+            let client = Client::new(&self.state.config);
+            let mut tokens = HashSet::new();
+            for info in self.state.oauth_cache.values() {
+                if let Some(ref refresh_token) = info.refresh_token {
+                    tokens.insert(refresh_token.expose().clone());
+                }
+                tokens.insert(info.access_token.expose().clone());
+            }
+            for token in tokens {
+                if let Err(e) = client.destroy_oauth_token(&token) {
+                    warn!("Failed to revoke OAuth token: {}", e);
+                }
+            }
+            #[cfg(feature = "browserid")]
+            {
+                if let Some(session_token) =
+                    FirefoxAccount::session_token_from_state(&self.state.login_state)
+                {
+                    if let Err(e) = client.destroy_session(session_token) {
+                        warn!("Failed to destroy session: {}", e);
+                    }
+                }
+            }
+        }
+        #[cfg(feature = "browserid")]
+        {
+            self.to_separated();
+        }
+        self.state.oauth_cache.clear();
+        self.state.scoped_keys.clear();
+        #[cfg(feature = "stored-keys")]
+        {
+            self.state.push_keys = None;
+        }
+        self.state.needs_reauth = false;
+        self.forget_device();
+        self.state.oauth_flows.clear();
+        self.profile_cache = None;
+        self.maybe_call_persist_callback();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use mock_server::MockServer;
+    use pairing::tests::{loopback, Loopback};
+    use std::thread;
 
     #[test]
     fn test_fxa_is_send() {
@@ -532,8 +1111,11 @@ mod tests {
 
     #[test]
     fn test_serialize_deserialize() {
-        let mut fxa1 =
-            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        let mut fxa1 = FirefoxAccount::new(
+            Config::new_static("https://stable.dev.lcip.org"),
+            "12345678",
+            "https://foo.bar",
+        );
         let fxa1_json = fxa1.to_json().unwrap();
         drop(fxa1);
         let fxa2 = FirefoxAccount::from_json(&fxa1_json).unwrap();
@@ -541,12 +1123,27 @@ mod tests {
         assert_eq!(fxa1_json, fxa2_json);
     }
 
+    #[test]
+    fn test_refresh_config_if_stale() {
+        // Static configs don't need refreshing, so this doesn't touch the
+        // network.
+        let mut fxa = FirefoxAccount::new(
+            Config::new_static("http://localhost:3030"),
+            "12345678",
+            "https://foo.bar",
+        );
+        assert!(!fxa.refresh_config_if_stale(0).unwrap());
+    }
+
     #[test]
     fn test_oauth_cache_store_and_find() {
-        let mut fxa =
-            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        let mut fxa = FirefoxAccount::new(
+            Config::new_static("https://stable.dev.lcip.org"),
+            "12345678",
+            "https://foo.bar",
+        );
         let oauth_info = OAuthInfo {
-            access_token: "abcdef".to_string(),
+            access_token: "abcdef".into(),
             keys: None,
             refresh_token: None,
             expires_at: 1,
@@ -558,18 +1155,684 @@ mod tests {
         fxa.oauth_cache_store(&oauth_info);
         fxa.oauth_cache_find(&["profile"]).unwrap();
     }
+
+    #[test]
+    fn test_device_state() {
+        let mut fxa = FirefoxAccount::new(
+            Config::new_static("https://stable.dev.lcip.org"),
+            "12345678",
+            "https://foo.bar",
+        );
+        match fxa.get_devices_list() {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::NoRefreshToken => {}
+                ref kind => panic!("Unexpected error {}", kind),
+            },
+            Ok(_) => panic!("Should fail without a refresh token"),
+        }
+
+        // Only a token with Sync's scope will do.
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "abcdef".into(),
+            keys: None,
+            refresh_token: Some("654321".into()),
+            expires_at: 1,
+            scopes: vec!["profile".to_string()],
+        });
+        assert!(fxa.refresh_token().is_err());
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "ghijkl".into(),
+            keys: None,
+            refresh_token: Some("123456".into()),
+            expires_at: 1,
+            scopes: vec!["profile".to_string(), OLDSYNC_SCOPE.to_string()],
+        });
+        assert_eq!(fxa.refresh_token().unwrap(), "123456");
+
+        // The current device id survives a round trip, and older state
+        // without one still loads.
+        fxa.state.current_device_id = Some("device1".into());
+        let fxa = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        assert_eq!(fxa.current_device_id(), Some("device1"));
+
+        let mut json: serde_json::Value = serde_json::from_str(&fxa.to_json().unwrap()).unwrap();
+        json.as_object_mut().unwrap().remove("current_device_id");
+        let fxa = FirefoxAccount::from_json(&json.to_string()).unwrap();
+        assert_eq!(fxa.current_device_id(), None);
+    }
+
+    #[test]
+    fn test_handle_push_message() {
+        let mut fxa = FirefoxAccount::new(
+            Config::new_static("https://stable.dev.lcip.org"),
+            "12345678",
+            "https://foo.bar",
+        );
+        fxa.state.current_device_id = Some("device1".into());
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "abcdef".into(),
+            keys: None,
+            refresh_token: Some("123456".into()),
+            expires_at: 1,
+            scopes: vec![OLDSYNC_SCOPE.to_string()],
+        });
+
+        let events = fxa
+            .handle_push_message(r#"{"version":1,"command":"fxaccounts:device_disconnected","data":{"id":"device2"}}"#)
+            .unwrap();
+        assert_eq!(events, vec![AccountEvent::DeviceDisconnected {
+            device_id: "device2".into(),
+            is_local_device: false,
+        }]);
+        assert_eq!(fxa.current_device_id(), Some("device1"));
+
+        let events = fxa
+            .handle_push_message(r#"{"version":1,"command":"fxaccounts:password_changed"}"#)
+            .unwrap();
+        assert_eq!(events, vec![AccountEvent::PasswordChanged]);
+        assert!(fxa.needs_reauth());
+        assert!(fxa.refresh_token().is_ok());
+
+        let events = fxa
+            .handle_push_message(r#"{"version":1,"command":"fxaccounts:device_disconnected","data":{"id":"device1"}}"#)
+            .unwrap();
+        assert_eq!(events, vec![AccountEvent::DeviceDisconnected {
+            device_id: "device1".into(),
+            is_local_device: true,
+        }]);
+        assert_eq!(fxa.current_device_id(), None);
+        assert!(fxa.refresh_token().is_err());
+
+        let events = fxa
+            .handle_push_message(r#"{"version":1,"command":"fxaccounts:unknown"}"#)
+            .unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[cfg(feature = "stored-keys")]
+    #[test]
+    fn test_push_subscription() {
+        let mut fxa = FirefoxAccount::new(
+            Config::new_static("https://stable.dev.lcip.org"),
+            "12345678",
+            "https://foo.bar",
+        );
+        assert!(fxa.decrypt_push_message(b"", "aes128gcm", None, None).is_err());
+
+        let subscription = fxa.push_subscription("https://push.example.com/1").unwrap();
+        assert_eq!(subscription.endpoint, "https://push.example.com/1");
+        // The keys are kept, and saved with the account.
+        let mut fxa = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        let again = fxa.push_subscription("https://push.example.com/2").unwrap();
+        assert_eq!(again.public_key, subscription.public_key);
+        assert_eq!(again.auth_key, subscription.auth_key);
+
+        let body = ece::encrypt(
+            &subscription.public_key,
+            &subscription.auth_key,
+            br#"{"version":1,"command":"fxaccounts:profile_updated"}"#,
+            &*RNG,
+        ).unwrap();
+        let payload = fxa.decrypt_push_message(&body, "aes128gcm", None, None).unwrap();
+        assert_eq!(
+            fxa.handle_push_message(&payload).unwrap(),
+            vec![AccountEvent::ProfileUpdated]
+        );
+    }
+
+    #[test]
+    fn test_debug_redacts_secrets() {
+        let oauth_info = OAuthInfo {
+            access_token: "abcdef".into(),
+            keys: Some(ScopedKey::parse_keys(
+                r#"{"https://identity.mozilla.com/apps/oldsync":{"kty":"oct","scope":"https://identity.mozilla.com/apps/oldsync","k":"supersecret","kid":"1-AAAA"}}"#,
+            ).unwrap()),
+            refresh_token: Some("123456".into()),
+            expires_at: 1,
+            scopes: vec!["profile".to_string()],
+        };
+        let debug = format!("{:?}", oauth_info);
+        assert!(!debug.contains("abcdef"));
+        assert!(!debug.contains("supersecret"));
+        assert!(!debug.contains("123456"));
+
+        let credentials = WebChannelResponse::from_json(
+            r#"{"uid":"uid","email":"foo@bar.com","verified":true,"sessionToken":"aaaa","keyFetchToken":"bbbb","unwrapBKey":"cccc"}"#,
+        ).unwrap();
+        assert_eq!(credentials.session_token.expose(), "aaaa");
+        let debug = format!("{:?}", credentials);
+        assert!(debug.contains("foo@bar.com"));
+        assert!(!debug.contains("aaaa") && !debug.contains("bbbb") && !debug.contains("cccc"));
+    }
+
+    // Signs in with the OAuth flow against `server`, asking for `scopes`.
+    fn sign_in_with_oauth(
+        server: &MockServer,
+        scopes: &[&str],
+        wants_keys: bool,
+    ) -> (FirefoxAccount, OAuthInfo) {
+        let config = Config::import_from(server.url()).unwrap();
+        let mut fxa = FirefoxAccount::new(config, "12345678", "https://foo.bar");
+        let url = fxa.begin_oauth_flow(scopes, wants_keys).unwrap();
+        let (code, state) = server.authorize(&url);
+        let info = fxa.complete_oauth_flow(&code, &state).unwrap();
+        (fxa, info)
+    }
+
+    #[test]
+    fn test_oauth_flow_with_mock_server() {
+        let server = MockServer::start();
+        let (mut fxa, info) = sign_in_with_oauth(&server, &["profile", OLDSYNC_SCOPE], true);
+        assert_eq!(info.scopes, vec!["profile", OLDSYNC_SCOPE]);
+        assert!(info.refresh_token.is_some());
+        let key = &info.keys.as_ref().unwrap()[OLDSYNC_SCOPE];
+        assert_eq!(key.key_id().unwrap(), "1526414944666-zgTjf5oXmPmBjxwXWFsDWg");
+        assert_eq!(fxa.get_scoped_key(OLDSYNC_SCOPE), Some(key));
+        assert_eq!(server.requests_to("/oauth/v1/token"), 1);
+
+        match fxa.complete_oauth_flow("code", "unknown state") {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::UnknownOAuthState => {}
+                ref kind => panic!("Unexpected error {}", kind),
+            },
+            Ok(_) => panic!("Should fail with an unknown state"),
+        }
+
+        // The cached token is used until it expires, then refreshed.
+        let cached = fxa.get_oauth_token(&["profile"]).unwrap().unwrap();
+        assert_eq!(cached.access_token.expose(), info.access_token.expose());
+        assert_eq!(server.requests_to("/oauth/v1/token"), 1);
+        for info in fxa.state.oauth_cache.values_mut() {
+            info.expires_at = 0;
+        }
+        let refreshed = fxa.get_oauth_token(&["profile"]).unwrap().unwrap();
+        assert_ne!(refreshed.access_token.expose(), info.access_token.expose());
+        assert_eq!(refreshed.scopes, vec!["profile"]);
+        assert_eq!(server.requests_to("/oauth/v1/token"), 2);
+
+        // Refreshed tokens come with the keys we got the first time, and the
+        // keys survive a restart.
+        let refreshed = fxa.get_oauth_token(&[OLDSYNC_SCOPE]).unwrap().unwrap();
+        assert_eq!(server.requests_to("/oauth/v1/token"), 3);
+        assert_eq!(refreshed.keys.as_ref().unwrap()[OLDSYNC_SCOPE], *key);
+        let restored = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        assert_eq!(restored.get_scoped_key(OLDSYNC_SCOPE), Some(key));
+    }
+
+    // Signs in by pairing on another thread, with the pairing URL `url`,
+    // while the caller plays the authority.
+    fn pair_as_supplicant(
+        config: &Config,
+        url: &str,
+        transport: Loopback,
+        scopes: &'static [&'static str],
+        wants_keys: bool,
+    ) -> thread::JoinHandle<Result<OAuthInfo>> {
+        let config = config.clone();
+        let url = url.to_string();
+        thread::spawn(move || {
+            let mut fxa = FirefoxAccount::new(config, "12345678", "https://foo.bar");
+            let key = PairingKey::from_url(&url)?;
+            assert_eq!(key.channel_id, "abcdef");
+            let mut channel = PairingChannel::join(transport, key);
+            let metadata = fxa.begin_pairing_flow(&mut channel, scopes, wants_keys)?;
+            assert_eq!(metadata.email, Some("foo@example.com".to_string()));
+            fxa.complete_pairing_flow(&mut channel)
+        })
+    }
+
+    #[test]
+    fn test_pairing_as_supplicant() {
+        let server = MockServer::start();
+        let config = Config::import_from(server.url()).unwrap();
+        let (authority, supplicant) = loopback("abcdef");
+        let mut channel = PairingChannel::open(authority, &*RNG).unwrap();
+        let url = channel.pairing_url(&config).unwrap();
+        let supplicant = pair_as_supplicant(&config, url.as_str(), supplicant, &["profile"], false);
+
+        let params = match channel.receive().unwrap() {
+            PairingMessage::Request(params) => params,
+            message => panic!("Unexpected message {:?}", message),
+        };
+        assert_eq!(params.scope, "profile");
+        assert!(params.keys_jwk.is_none());
+        channel
+            .send(
+                &PairingMessage::Metadata(PairingMetadata {
+                    email: Some("foo@example.com".into()),
+                    ..PairingMetadata::default()
+                }),
+                &*RNG,
+            )
+            .unwrap();
+        let mut url = config.authorization_endpoint().unwrap();
+        url.query_pairs_mut()
+            .append_pair("client_id", &params.client_id)
+            .append_pair("scope", &params.scope)
+            .append_pair("state", &params.state)
+            .append_pair("code_challenge_method", &params.code_challenge_method)
+            .append_pair("code_challenge", &params.code_challenge);
+        let (code, state) = server.authorize(url.as_str());
+        channel
+            .send(&PairingMessage::Authorize { code, state }, &*RNG)
+            .unwrap();
+
+        let info = supplicant.join().unwrap().unwrap();
+        assert_eq!(info.scopes, vec!["profile"]);
+        assert!(info.refresh_token.is_some());
+    }
+
+    #[test]
+    fn test_pairing_declined() {
+        let config = Config::new_static("https://stable.dev.lcip.org");
+        let (authority, supplicant) = loopback("abcdef");
+        let fxa = FirefoxAccount::new(config.clone(), "12345678", "https://foo.bar");
+        let mut channel = fxa.open_pairing_channel(authority).unwrap();
+        let url = fxa.pairing_url(&channel).unwrap();
+        let supplicant = pair_as_supplicant(&config, &url, supplicant, &["profile"], false);
+        assert!(channel.receive().is_ok());
+        fxa.decline_pairing_request(&mut channel).unwrap();
+        match supplicant.join().unwrap() {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::PairingDeclined => {}
+                ref kind => panic!("Unexpected error {}", kind),
+            },
+            Ok(_) => panic!("Should fail when declined"),
+        }
+    }
+
+    #[test]
+    fn test_oauth_flow_survives_restart() {
+        let server = MockServer::start();
+        let config = Config::import_from(server.url()).unwrap();
+        let mut fxa = FirefoxAccount::new(config, "12345678", "https://foo.bar");
+        let url = fxa
+            .begin_oauth_flow(&["profile", OLDSYNC_SCOPE], true)
+            .unwrap();
+        let (code, state) = server.authorize(&url);
+
+        let mut restored = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        #[cfg(feature = "stored-keys")]
+        {
+            let info = restored.complete_oauth_flow(&code, &state).unwrap();
+            assert!(info.keys.unwrap().contains_key(OLDSYNC_SCOPE));
+        }
+        // Without stored keys, we can only finish flows that didn't ask for
+        // keys, and we find out before using the code.
+        #[cfg(not(feature = "stored-keys"))]
+        {
+            match restored.complete_oauth_flow(&code, &state) {
+                Err(ref e) => match *e.kind() {
+                    ErrorKind::UnknownOAuthState => {}
+                    ref kind => panic!("Unexpected error {}", kind),
+                },
+                Ok(_) => panic!("Should fail without the flow's key"),
+            }
+            assert_eq!(server.requests_to("/oauth/v1/token"), 0);
+            let url = fxa.begin_oauth_flow(&["profile"], false).unwrap();
+            let (code, state) = server.authorize(&url);
+            let mut restored = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+            restored.complete_oauth_flow(&code, &state).unwrap();
+        }
+        assert!(restored.state.oauth_flows.is_empty());
+
+        // Flows the user took too long to complete are forgotten.
+        let url = fxa.begin_oauth_flow(&["profile"], false).unwrap();
+        let (code, state) = server.authorize(&url);
+        for flow in fxa.state.oauth_flows.values_mut() {
+            flow.expires_at = 0;
+        }
+        match fxa.complete_oauth_flow(&code, &state) {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::UnknownOAuthState => {}
+                ref kind => panic!("Unexpected error {}", kind),
+            },
+            Ok(_) => panic!("Should fail with an expired flow"),
+        }
+        let restored = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        assert!(restored.state.oauth_flows.is_empty());
+    }
+
+    #[test]
+    fn test_legacy_scoped_keys() {
+        let mut fxa = FirefoxAccount::new(
+            Config::new_static("https://stable.dev.lcip.org"),
+            "12345678",
+            "https://foo.bar",
+        );
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "abcdef".into(),
+            keys: None,
+            refresh_token: None,
+            expires_at: 1,
+            scopes: vec![OLDSYNC_SCOPE.to_string()],
+        });
+        let mut state: serde_json::Value = serde_json::from_str(&fxa.to_json().unwrap()).unwrap();
+        state["oauth_cache"][OLDSYNC_SCOPE]["keys"] = json!(
+            r#"{"https://identity.mozilla.com/apps/oldsync":{"kty":"oct","scope":"https://identity.mozilla.com/apps/oldsync","k":"8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw-exR70Z2SSIfMSrJDTLEZid9lD05-hbA3n2Q4Esjlu1tA","kid":"1526414944666-zgTjf5oXmPmBjxwXWFsDWg"}}"#
+        );
+        state.as_object_mut().unwrap().remove("scoped_keys");
+
+        let fxa = FirefoxAccount::from_json(&state.to_string()).unwrap();
+        let key = fxa.get_scoped_key(OLDSYNC_SCOPE).unwrap();
+        assert_eq!(key.kid, "1526414944666-zgTjf5oXmPmBjxwXWFsDWg");
+        assert_eq!(key.ksync().unwrap().expose().len(), 64);
+    }
+
+    #[test]
+    fn test_profile_with_mock_server() {
+        let server = MockServer::start();
+        let (mut fxa, _) = sign_in_with_oauth(&server, &["profile"], false);
+        let profile = fxa.get_profile(false).unwrap();
+        assert_eq!(profile.email, "foo@example.com");
+        assert_eq!(server.requests_to("/profile/v1/profile"), 1);
+
+        // Fresh profiles come from the cache...
+        assert_eq!(fxa.get_profile(false).unwrap().uid, profile.uid);
+        assert_eq!(server.requests_to("/profile/v1/profile"), 1);
+
+        // ...and unchanged ones are revalidated with their ETag.
+        assert_eq!(fxa.get_profile(true).unwrap().uid, profile.uid);
+        assert_eq!(server.requests_to("/profile/v1/profile"), 2);
+        assert_eq!(fxa.profile_cache.as_ref().unwrap().etag, "profile-1");
+
+        server.update_profile("profile-2");
+        assert_eq!(fxa.get_profile(true).unwrap().uid, profile.uid);
+        assert_eq!(server.requests_to("/profile/v1/profile"), 3);
+        assert_eq!(fxa.profile_cache.as_ref().unwrap().etag, "profile-2");
+    }
+
+    #[test]
+    fn test_sign_out_with_mock_server() {
+        let server = MockServer::start();
+        let (mut fxa, info) = sign_in_with_oauth(&server, &["profile", OLDSYNC_SCOPE], true);
+        fxa.get_profile(false).unwrap();
+        fxa.state.current_device_id = Some("device1".into());
+        let refresh_token = info.refresh_token.unwrap();
+        assert!(server.is_token_valid(info.access_token.expose()));
+        assert!(server.is_token_valid(refresh_token.expose()));
+
+        fxa.sign_out();
+        assert!(!server.is_token_valid(info.access_token.expose()));
+        assert!(!server.is_token_valid(refresh_token.expose()));
+        assert_eq!(server.requests_to("/oauth/v1/destroy"), 2);
+        assert!(fxa.state.oauth_cache.is_empty());
+        assert!(fxa.profile_cache.is_none());
+        assert_eq!(fxa.current_device_id(), None);
+        match fxa.get_profile(false) {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::NoCachedToken(_) => {}
+                ref kind => panic!("Unexpected error {}", kind),
+            },
+            Ok(_) => panic!("Should fail after signing out"),
+        }
+
+        // Signing out again is harmless.
+        fxa.sign_out();
+        assert_eq!(server.requests_to("/oauth/v1/destroy"), 2);
+    }
+
+    #[test]
+    fn test_devices_with_mock_server() {
+        let server = MockServer::start();
+        let (mut fxa, _) = sign_in_with_oauth(&server, &["profile", OLDSYNC_SCOPE], true);
+
+        let device = fxa
+            .register_device(DeviceUpdate::new().name("Phone").device_type(DeviceType::Mobile))
+            .unwrap();
+        assert_eq!(device.name, "Phone");
+        assert_eq!(device.device_type, DeviceType::Mobile);
+        assert!(device.is_current_device);
+        assert_eq!(fxa.current_device_id(), Some(device.id.as_str()));
+
+        // Registering again updates the same device.
+        let renamed = fxa.register_device(DeviceUpdate::new().name("My Phone")).unwrap();
+        assert_eq!(renamed.id, device.id);
+        assert_eq!(renamed.name, "My Phone");
+        assert_eq!(renamed.device_type, DeviceType::Mobile);
+
+        // Another client signed in to the same account.
+        let (mut other, _) = sign_in_with_oauth(&server, &[OLDSYNC_SCOPE], false);
+        let laptop = other
+            .register_device(DeviceUpdate::new().name("Laptop").device_type(DeviceType::Desktop))
+            .unwrap();
+        let devices = fxa.get_devices_list().unwrap();
+        assert_eq!(devices.len(), 2);
+        let current: Vec<&str> = devices
+            .iter()
+            .filter(|d| d.is_current_device)
+            .map(|d| d.name.as_str())
+            .collect();
+        assert_eq!(current, vec!["My Phone"]);
+
+        fxa.disconnect_device(&laptop.id).unwrap();
+        assert_eq!(fxa.current_device_id(), Some(device.id.as_str()));
+        fxa.disconnect_device(&device.id).unwrap();
+        assert_eq!(fxa.current_device_id(), None);
+        assert!(fxa.get_devices_list().unwrap().is_empty());
+        assert_eq!(server.requests_to("/auth/v1/account/device/destroy"), 2);
+        assert!(fxa.disconnect_device(&device.id).is_err());
+
+        // A token without Sync's scope can't use the device endpoints.
+        let (fxa, _) = sign_in_with_oauth(&server, &["profile"], false);
+        match fxa.get_devices_list() {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::NoRefreshToken => {}
+                ref kind => panic!("Unexpected error {}", kind),
+            },
+            Ok(_) => panic!("Should fail without a Sync refresh token"),
+        }
+    }
+
+    #[cfg(feature = "stored-keys")]
+    #[test]
+    fn test_send_tab_with_mock_server() {
+        fn titles(received: &[ReceivedCommand]) -> Vec<&str> {
+            received
+                .iter()
+                .map(|received| match received.command {
+                    Command::SendTab(ref tab) => tab.title.as_str(),
+                })
+                .collect()
+        }
+
+        let server = MockServer::start();
+        let (mut phone, _) = sign_in_with_oauth(&server, &["profile", OLDSYNC_SCOPE], true);
+        phone
+            .register_device(DeviceUpdate::new().name("Phone").device_type(DeviceType::Mobile))
+            .unwrap();
+        match phone.retrieve_messages() {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::CommandsNotEnabled => {}
+                ref kind => panic!("Unexpected error {}", kind),
+            },
+            Ok(_) => panic!("Should fail before enabling commands"),
+        }
+        phone.enable_commands().unwrap();
+
+        let (mut laptop, _) = sign_in_with_oauth(&server, &[OLDSYNC_SCOPE], false);
+        let laptop_device = laptop
+            .register_device(DeviceUpdate::new().name("Laptop").device_type(DeviceType::Desktop))
+            .unwrap();
+        let devices = laptop.get_devices_list().unwrap();
+        let target = devices.iter().find(|d| d.name == "Phone").unwrap();
+        let source = devices.iter().find(|d| d.name == "Laptop").unwrap();
+        match phone.send_tab(source, "Example", "https://example.com/") {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::UnsupportedCommand(_) => {}
+                ref kind => panic!("Unexpected error {}", kind),
+            },
+            Ok(_) => panic!("Should fail to a device without commands"),
+        }
+
+        // The mock server returns two commands at a time.
+        for i in 0..5 {
+            let url = format!("https://example.com/{}", i);
+            laptop.send_tab(target, &format!("Tab {}", i), &url).unwrap();
+        }
+        assert_eq!(server.requests_to("/auth/v1/account/devices/invoke_command"), 5);
+        let received = phone.retrieve_messages().unwrap();
+        assert_eq!(titles(&received), vec!["Tab 0", "Tab 1", "Tab 2", "Tab 3", "Tab 4"]);
+        assert_eq!(received[0].sender, Some(laptop_device.id.clone()));
+        assert_eq!(server.requests_to("/auth/v1/account/device/commands"), 3);
+        assert!(phone.retrieve_messages().unwrap().is_empty());
+        assert_eq!(server.requests_to("/auth/v1/account/device/commands"), 4);
+
+        // If a later page fails, we don't skip the commands we already
+        // fetched.
+        for i in 5..8 {
+            let url = format!("https://example.com/{}", i);
+            laptop.send_tab(target, &format!("Tab {}", i), &url).unwrap();
+        }
+        server.fail_request_to("/auth/v1/account/device/commands", 6);
+        assert!(phone.retrieve_messages().is_err());
+        let mut phone = FirefoxAccount::from_json(&phone.to_json().unwrap()).unwrap();
+        let received = phone.retrieve_messages().unwrap();
+        assert_eq!(titles(&received), vec!["Tab 5", "Tab 6", "Tab 7"]);
+
+        // A push message tells us when to fetch.
+        laptop.send_tab(target, "Pushed", "https://example.com/pushed").unwrap();
+        let events = phone
+            .handle_push_message(r#"{"version":1,"command":"fxaccounts:command_received","data":{"command":"https://identity.mozilla.com/cmd/open-uri","index":9,"sender":"device1"}}"#)
+            .unwrap();
+        assert_eq!(events, vec![AccountEvent::CommandReceived(ReceivedCommand {
+            sender: Some(laptop_device.id.clone()),
+            command: Command::SendTab(SendTab {
+                title: "Pushed".into(),
+                url: "https://example.com/pushed".into(),
+            }),
+        })]);
+    }
+
+    #[cfg(feature = "browserid")]
+    #[test]
+    fn test_browserid_flow_with_mock_server() {
+        use mock_server::MockAccount;
+
+        let server = MockServer::start();
+        let login = Client::new(&Config::import_from(server.url()).unwrap())
+            .login("foo@example.com", "0123456789abcdef", true)
+            .unwrap();
+        assert_eq!(login.uid, "0123456789abcdef0123456789abcdef");
+
+        let credentials = WebChannelResponse::from_json(&server.web_channel_response()).unwrap();
+        let mut fxa = FirefoxAccount::from_credentials(
+            Config::import_from(server.url()).unwrap(),
+            "12345678",
+            "https://foo.bar",
+            credentials,
+        ).unwrap();
+        let (sync_key, xcs) = fxa.get_sync_keys().unwrap();
+        let kb = MockAccount::default().kb;
+        assert_eq!(sync_key.expose(), &hex::encode(Client::derive_sync_key(&kb)));
+        assert_eq!(xcs, Client::compute_client_state(&kb));
+        assert!(fxa.generate_assertion("https://example.com").is_ok());
+
+        // Without a refresh token, we get OAuth tokens with the session token.
+        let info = fxa.get_oauth_token(&["profile"]).unwrap().unwrap();
+        assert!(info.refresh_token.is_none());
+        assert_eq!(server.requests_to("/oauth/v1/authorization"), 1);
+        assert_eq!(fxa.get_profile(false).unwrap().email, "foo@example.com");
+
+        fxa.sign_out();
+        assert_eq!(server.requests_to("/auth/v1/session/destroy"), 1);
+        assert!(!server.is_token_valid(info.access_token.expose()));
+        match fxa.get_sync_keys() {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::NotMarried => {}
+                ref kind => panic!("Unexpected error {}", kind),
+            },
+            Ok(_) => panic!("Should fail after signing out"),
+        }
+    }
+
+    #[cfg(feature = "browserid")]
+    #[test]
+    fn test_pairing_as_authority() {
+        use mock_server::MockAccount;
+
+        let server = MockServer::start();
+        let config = Config::import_from(server.url()).unwrap();
+        let credentials = WebChannelResponse::from_json(&server.web_channel_response()).unwrap();
+        let mut fxa = FirefoxAccount::from_credentials(
+            config.clone(),
+            "12345678",
+            "https://foo.bar",
+            credentials,
+        ).unwrap();
+        let oldsync_key: ScopedKey =
+            serde_json::from_value(MockAccount::default().oldsync_key).unwrap();
+        fxa.state
+            .scoped_keys
+            .insert(OLDSYNC_SCOPE.to_string(), oldsync_key.clone());
+
+        let (authority, supplicant) = loopback("abcdef");
+        let mut channel = fxa.open_pairing_channel(authority).unwrap();
+        let url = fxa.pairing_url(&channel).unwrap();
+        let scopes = &["profile", OLDSYNC_SCOPE];
+        let supplicant = pair_as_supplicant(&config, &url, supplicant, scopes, true);
+        let params = fxa.handle_pairing_request(&mut channel).unwrap();
+        assert_eq!(params.scope, format!("profile {}", OLDSYNC_SCOPE));
+        fxa.approve_pairing_request(&mut channel, &params).unwrap();
+
+        // The keys come from us, through the auth server.
+        let info = supplicant.join().unwrap().unwrap();
+        assert_eq!(info.keys.unwrap()[OLDSYNC_SCOPE], oldsync_key);
+        assert_eq!(server.requests_to("/auth/v1/oauth/authorization"), 1);
+    }
+
+    #[cfg(feature = "browserid")]
+    #[test]
+    fn test_handle_push_message_separates() {
+        let server = MockServer::start();
+        let signed_in = || {
+            let credentials = WebChannelResponse::from_json(&server.web_channel_response()).unwrap();
+            FirefoxAccount::from_credentials(
+                Config::import_from(server.url()).unwrap(),
+                "12345678",
+                "https://foo.bar",
+                credentials,
+            ).unwrap()
+        };
+        let is_separated = |fxa: &FirefoxAccount| match fxa.state.login_state {
+            Separated(_) => true,
+            _ => false,
+        };
+
+        for command in &["fxaccounts:password_changed", "fxaccounts:password_reset"] {
+            let mut fxa = signed_in();
+            assert!(fxa.get_sync_keys().is_ok());
+            assert!(!is_separated(&fxa));
+
+            let payload = json!({ "version": 1, "command": command }).to_string();
+            fxa.handle_push_message(&payload).unwrap();
+            assert!(is_separated(&fxa), "{} should separate", command);
+            match fxa.get_sync_keys() {
+                Err(ref e) => match *e.kind() {
+                    ErrorKind::NotMarried => {}
+                    ref kind => panic!("Unexpected error {}", kind),
+                },
+                Ok(_) => panic!("Should fail after {}", command),
+            }
+        }
+    }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct OAuthFlow {
     pub scoped_keys_flow: Option<ScopedKeysFlow>,
-    pub code_verifier: String,
+    pub code_verifier: SecretString,
+    pub expires_at: u64, // seconds since epoch
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OAuthInfo {
-    pub access_token: String,
-    pub keys: Option<String>,
-    pub refresh_token: Option<String>,
+    pub access_token: SecretString,
+    /// The keys for the granted scopes that have them. Saved with the
+    /// account rather than with each token.
+    #[serde(skip)]
+    pub keys: Option<HashMap<String, ScopedKey>>,
+    pub refresh_token: Option<SecretString>,
     pub expires_at: u64, // seconds since epoch
     pub scopes: Vec<String>,
 }
@@ -8,6 +8,7 @@ use errors::*;
 use http_client::browser_id::rsa::RSABrowserIDKeyPair;
 use http_client::*;
 use login_sm::LoginState::*;
+use secret::SecretBytes;
 use util::{now, Xorable};
 
 pub struct LoginStateMachine<'a> {
@@ -71,7 +72,7 @@ impl<'a> LoginStateMachine<'a> {
                 debug!("Signing public key.");
                 let resp = self
                     .client
-                    .sign(&state.token_and_keys.session_token, &state.key_pair);
+                    .sign(state.token_and_keys.session_token(), &state.key_pair);
                 match resp {
                     Ok(resp) => {
                         info!("Signed public key! Transitioning to Married.");
@@ -113,22 +114,22 @@ impl<'a> LoginStateMachine<'a> {
         state: ReadyForKeysState,
     ) -> LoginState {
         debug!("Fetching keys.");
-        let resp = self.client.keys(&state.key_fetch_token);
+        let resp = self.client.keys(state.key_fetch_token.expose());
         match resp {
             Ok(resp) => {
-                let kb = match resp.wrap_kb.xored_with(&state.unwrap_kb) {
-                    Ok(kb) => kb,
+                let kb = match resp.wrap_kb.xored_with(state.unwrap_kb.expose()) {
+                    Ok(kb) => SecretBytes::from(kb),
                     Err(_) => {
                         error!("Failed to unwrap keys response!  Transitioning to Separated.");
                         return same(state);
                     }
                 };
                 info!("Unwrapped keys response.  Transition to CohabitingBeforeKeyPair.");
-                let sync_key = Client::derive_sync_key(&kb);
-                let xcs = Client::compute_client_state(&kb);
+                let sync_key = Client::derive_sync_key(kb.expose()).into();
+                let xcs = Client::compute_client_state(kb.expose());
                 CohabitingBeforeKeyPair(TokenAndKeysState {
                     base: state.base,
-                    session_token: state.session_token.to_vec(),
+                    session_token: state.session_token,
                     sync_key,
                     xcs,
                 })
@@ -181,16 +182,16 @@ pub type SeparatedState = BaseState;
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ReadyForKeysState {
     base: BaseState,
-    session_token: Vec<u8>,
-    key_fetch_token: Vec<u8>,
-    unwrap_kb: Vec<u8>,
+    session_token: SecretBytes,
+    key_fetch_token: SecretBytes,
+    unwrap_kb: SecretBytes,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TokenAndKeysState {
     base: BaseState,
-    session_token: Vec<u8>,
-    sync_key: Vec<u8>,
+    session_token: SecretBytes,
+    sync_key: SecretBytes,
     xcs: String,
 }
 
@@ -217,9 +218,9 @@ impl ReadyForKeysState {
     ) -> ReadyForKeysState {
         ReadyForKeysState {
             base: BaseState { uid, email },
-            session_token,
-            key_fetch_token,
-            unwrap_kb,
+            session_token: session_token.into(),
+            key_fetch_token: key_fetch_token.into(),
+            unwrap_kb: unwrap_kb.into(),
         }
     }
 }
@@ -230,13 +231,13 @@ pub trait SessionTokenState {
 
 impl SessionTokenState for ReadyForKeysState {
     fn session_token(&self) -> &[u8] {
-        &self.session_token
+        self.session_token.expose()
     }
 }
 
 impl SessionTokenState for TokenAndKeysState {
     fn session_token(&self) -> &[u8] {
-        &self.session_token
+        self.session_token.expose()
     }
 }
 
@@ -260,7 +261,7 @@ impl MarriedState {
         &self.certificate
     }
     pub fn sync_key(&self) -> &[u8] {
-        &self.token_keys_and_key_pair.token_and_keys.sync_key
+        self.token_keys_and_key_pair.token_and_keys.sync_key.expose()
     }
     pub fn xcs(&self) -> &str {
         &self.token_keys_and_key_pair.token_and_keys.xcs
@@ -0,0 +1,735 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A stand-in for the FxA content, auth, OAuth and profile servers, so that
+//! tests can run the OAuth and BrowserID flows without the network. It
+//! serves one account, over plain HTTP on a local port, one request at a
+//! time.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use base64;
+use hex;
+use reqwest;
+use ring::rand::SystemRandom;
+use ring::{digest, hkdf, hmac};
+use serde_json::{self, Value};
+use url::Url;
+
+use scoped_keys::{self, OLDSYNC_SCOPE};
+
+/// The account the server knows about.
+pub struct MockAccount {
+    pub uid: String,
+    pub email: String,
+    pub session_token: Vec<u8>,
+    pub key_fetch_token: Vec<u8>,
+    pub unwrap_kb: Vec<u8>,
+    pub kb: Vec<u8>,
+    /// The oldsync scoped key, as a JWK.
+    pub oldsync_key: Value,
+}
+
+impl Default for MockAccount {
+    fn default() -> MockAccount {
+        MockAccount {
+            uid: "0123456789abcdef0123456789abcdef".into(),
+            email: "foo@example.com".into(),
+            session_token: vec![0x11; 32],
+            key_fetch_token: vec![0x22; 32],
+            unwrap_kb: vec![0x33; 32],
+            kb: vec![0x44; 32],
+            oldsync_key: json!({
+                "kty": "oct",
+                "scope": OLDSYNC_SCOPE,
+                "k": "8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw-exR70Z2SSIfMSrJDTLEZid9lD05-hbA3n2Q4Esjlu1tA",
+                "kid": "1526414944666-zgTjf5oXmPmBjxwXWFsDWg",
+            }),
+        }
+    }
+}
+
+struct MockDevice {
+    id: String,
+    // The refresh token that registered the device. The device is the
+    // current one for requests made with it.
+    refresh_token: String,
+    record: Value,
+}
+
+// A command sent to a device, waiting for it to fetch.
+struct MockCommand {
+    index: u64,
+    target: String,
+    data: Value,
+}
+
+// How many commands we return at a time, unless asked for fewer. Small, so
+// that tests page through them.
+const COMMANDS_PAGE_SIZE: usize = 2;
+
+struct PendingCode {
+    scope: String,
+    code_challenge: String,
+    keys: PendingKeys,
+}
+
+// The keys to send with the token for a code.
+enum PendingKeys {
+    None,
+    // Ours, encrypted to the client's key when it asks for the token.
+    Jwk(String),
+    // Another device's, which it encrypted when it asked for the code.
+    Jwe(String),
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type".into(), "application/json".into())],
+            body: body.to_string(),
+        }
+    }
+
+    fn error(status: u16, errno: u64, message: &str) -> Response {
+        Response::json(status, json!({
+            "code": status,
+            "errno": errno,
+            "error": "Mock Error",
+            "message": message,
+        }))
+    }
+
+    fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+struct Request {
+    method: String,
+    url: Url,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+
+    fn query(&self, name: &str) -> Option<String> {
+        self.url
+            .query_pairs()
+            .find(|&(ref key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    fn bearer_token(&self) -> Option<&str> {
+        self.headers
+            .get("authorization")
+            .and_then(|value| if value.starts_with("Bearer ") { Some(&value[7..]) } else { None })
+    }
+}
+
+struct ServerState {
+    base_url: String,
+    account: MockAccount,
+    profile_etag: String,
+    codes: HashMap<String, PendingCode>,
+    refresh_tokens: HashMap<String, String>,
+    access_tokens: HashMap<String, String>,
+    devices: Vec<MockDevice>,
+    commands: Vec<MockCommand>,
+    next_token: u64,
+    // Each request's method and path, in order.
+    log: Vec<(String, String)>,
+    // Requests to fail, by path and how many requests had been made to it.
+    failures: Vec<(String, usize)>,
+}
+
+pub struct MockServer {
+    url: String,
+    state: Arc<Mutex<ServerState>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockServer {
+    pub fn start() -> MockServer {
+        MockServer::with_account(MockAccount::default())
+    }
+
+    pub fn with_account(account: MockAccount) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(ServerState {
+            base_url: url.clone(),
+            account,
+            profile_etag: "\"profile-1\"".into(),
+            codes: HashMap::new(),
+            refresh_tokens: HashMap::new(),
+            access_tokens: HashMap::new(),
+            devices: Vec::new(),
+            commands: Vec::new(),
+            next_token: 1,
+            log: Vec::new(),
+            failures: Vec::new(),
+        }));
+        let stopped = Arc::new(AtomicBool::new(false));
+        {
+            let state = state.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        handle_connection(stream, &state);
+                    }
+                }
+            });
+        }
+        MockServer {
+            url,
+            state,
+            stopped,
+        }
+    }
+
+    /// The content server's URL, to pass to `Config::import_from`.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The credentials the content server would send over the web channel
+    /// after signing in, as JSON for `WebChannelResponse::from_json`.
+    #[cfg(feature = "browserid")]
+    pub fn web_channel_response(&self) -> String {
+        let state = self.state.lock().unwrap();
+        json!({
+            "uid": state.account.uid,
+            "email": state.account.email,
+            "verified": true,
+            "sessionToken": hex::encode(&state.account.session_token),
+            "keyFetchToken": hex::encode(&state.account.key_fetch_token),
+            "unwrapBKey": hex::encode(&state.account.unwrap_kb),
+        }).to_string()
+    }
+
+    /// Does what the user would do with a URL from `begin_oauth_flow`: signs
+    /// in and grants access. Returns the code and state the browser would be
+    /// redirected with.
+    pub fn authorize(&self, authorization_url: &str) -> (String, String) {
+        let mut resp = reqwest::get(authorization_url).unwrap();
+        assert!(resp.status().is_success(), "Authorization failed");
+        let json: Value = resp.json().unwrap();
+        (
+            json["code"].as_str().unwrap().to_string(),
+            json["state"].as_str().unwrap().to_string(),
+        )
+    }
+
+    /// Changes the profile, as if the user edited it.
+    pub fn update_profile(&self, etag: &str) {
+        self.state.lock().unwrap().profile_etag = format!("\"{}\"", etag);
+    }
+
+    /// True if `token` is an access or refresh token that hasn't been revoked.
+    pub fn is_token_valid(&self, token: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.access_tokens.contains_key(token) || state.refresh_tokens.contains_key(token)
+    }
+
+    /// How many requests were made to `path`.
+    pub fn requests_to(&self, path: &str) -> usize {
+        self.state.lock().unwrap().requests_to(path)
+    }
+
+    /// Fails the `nth` request to `path`, counting from the first, with a
+    /// server error.
+    pub fn fail_request_to(&self, path: &str, nth: usize) {
+        self.state.lock().unwrap().failures.push((path.to_string(), nth));
+    }
+}
+
+impl ServerState {
+    fn requests_to(&self, path: &str) -> usize {
+        self.log.iter().filter(|&&(_, ref p)| p == path).count()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        // Wake up the listener, so its thread sees that we've stopped.
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(&self.url[7..]);
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: &Mutex<ServerState>) {
+    let mut reader = BufReader::new(stream);
+    let request = match read_request(&mut reader) {
+        Some(request) => request,
+        None => return,
+    };
+    let response = {
+        let mut state = state.lock().unwrap();
+        let path = request.url.path().to_string();
+        state.log.push((request.method.clone(), path.clone()));
+        let failure = (path.clone(), state.requests_to(&path));
+        if state.failures.contains(&failure) {
+            Response::error(500, 999, "Mock failure")
+        } else {
+            route(&mut state, &request)
+        }
+    };
+    let mut stream = reader.into_inner();
+    let mut head = format!("HTTP/1.1 {} Mock\r\n", response.status);
+    for &(ref name, ref value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    ));
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(response.body.as_bytes());
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_right();
+        if line.is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next()?.trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim().to_string();
+        headers.insert(name, value);
+    }
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request {
+        method,
+        url: Url::parse("http://localhost").unwrap().join(&target).ok()?,
+        headers,
+        body,
+    })
+}
+
+fn route(state: &mut ServerState, request: &Request) -> Response {
+    let base = state.base_url.clone();
+    match (request.method.as_str(), request.url.path()) {
+        ("GET", "/.well-known/fxa-client-configuration") => Response::json(200, json!({
+            "auth_server_base_url": format!("{}/auth", base),
+            "oauth_server_base_url": format!("{}/oauth", base),
+            "profile_server_base_url": format!("{}/profile", base),
+            "sync_tokenserver_base_url": format!("{}/syncserver/token", base),
+        })),
+        ("GET", "/.well-known/openid-configuration") => Response::json(200, json!({
+            "authorization_endpoint": format!("{}/oauth/v1/authorization", base),
+            "issuer": base,
+            "jwks_uri": format!("{}/oauth/v1/jwks", base),
+            "token_endpoint": format!("{}/oauth/v1/token", base),
+            "userinfo_endpoint": format!("{}/profile/v1/profile", base),
+        })),
+        ("POST", "/auth/v1/account/login") => login(state, request),
+        ("GET", "/auth/v1/account/keys") => Response::json(200, json!({
+            "bundle": keys_bundle(&state.account),
+        })),
+        ("GET", "/auth/v1/recovery_email/status") => Response::json(200, json!({
+            "email": state.account.email,
+            "verified": true,
+        })),
+        ("POST", "/auth/v1/certificate/sign") => Response::json(200, json!({
+            "cert": "mock.certificate.signature",
+        })),
+        ("GET", "/oauth/v1/authorization") => grant_code(state, request),
+        ("POST", "/auth/v1/oauth/authorization") => grant_code_with_session(state, request),
+        ("POST", "/oauth/v1/authorization") => token_from_assertion(state, request),
+        ("POST", "/oauth/v1/token") => token(state, request),
+        ("POST", "/oauth/v1/destroy") => destroy_token(state, request),
+        ("POST", "/auth/v1/session/destroy") => Response::json(200, json!({})),
+        ("POST", "/auth/v1/account/device") => update_device(state, request),
+        ("GET", "/auth/v1/account/devices") => devices(state, request),
+        ("POST", "/auth/v1/account/device/destroy") => destroy_device(state, request),
+        ("POST", "/auth/v1/account/devices/invoke_command") => invoke_command(state, request),
+        ("GET", "/auth/v1/account/device/commands") => device_commands(state, request),
+        ("GET", "/profile/v1/profile") => profile(state, request),
+        _ => Response::error(404, 999, "Unknown endpoint"),
+    }
+}
+
+fn login(state: &mut ServerState, request: &Request) -> Response {
+    let body = request.json();
+    if body["email"] != state.account.email.as_str() || body["authPW"].as_str().is_none() {
+        return Response::error(400, 102, "Unknown account");
+    }
+    let mut resp = json!({
+        "uid": state.account.uid,
+        "sessionToken": hex::encode(&state.account.session_token),
+        "verified": true,
+    });
+    if request.query("keys").as_ref().map(|keys| keys.as_str()) == Some("true") {
+        resp["keyFetchToken"] = json!(hex::encode(&state.account.key_fetch_token));
+    }
+    Response::json(200, resp)
+}
+
+// Encrypts kA and wrap(kB) to the key fetch token, as `/account/keys` does.
+fn keys_bundle(account: &MockAccount) -> String {
+    let key = hkdf_sha256(&account.key_fetch_token, b"keyFetchToken", 96);
+    let key_request_key = &key[64..96];
+    let bytes = hkdf_sha256(key_request_key, b"account/keys", 96);
+    let (hmac_key, xor_key) = bytes.split_at(32);
+
+    let mut plaintext = vec![0x55; 32]; // kA, which we don't use.
+    plaintext.extend(account.kb.iter().zip(&account.unwrap_kb).map(|(k, u)| k ^ u));
+    let mut bundle: Vec<u8> = plaintext.iter().zip(xor_key).map(|(p, x)| p ^ x).collect();
+    let mac = hmac::sign(&hmac::SigningKey::new(&digest::SHA256, hmac_key), &bundle);
+    bundle.extend_from_slice(mac.as_ref());
+    hex::encode(bundle)
+}
+
+fn hkdf_sha256(ikm: &[u8], name: &[u8], len: usize) -> Vec<u8> {
+    let salt = hmac::SigningKey::new(&digest::SHA256, &[0u8; 32]);
+    let mut info = b"identity.mozilla.com/picl/v1/".to_vec();
+    info.extend_from_slice(name);
+    let mut out = vec![0u8; len];
+    hkdf::extract_and_expand(&salt, ikm, &info, &mut out);
+    out
+}
+
+fn grant_code(state: &mut ServerState, request: &Request) -> Response {
+    let (scope, code_challenge, oauth_state) = match (
+        request.query("scope"),
+        request.query("code_challenge"),
+        request.query("state"),
+    ) {
+        (Some(scope), Some(code_challenge), Some(oauth_state)) => (scope, code_challenge, oauth_state),
+        _ => return Response::error(400, 109, "Missing parameters"),
+    };
+    if request.query("code_challenge_method").as_ref().map(|m| m.as_str()) != Some("S256") {
+        return Response::error(400, 109, "Unsupported code challenge method");
+    }
+    let keys = match request.query("keys_jwk") {
+        Some(keys_jwk) => PendingKeys::Jwk(keys_jwk),
+        None => PendingKeys::None,
+    };
+    new_code(state, scope, code_challenge, keys, oauth_state)
+}
+
+// What the auth server does for a signed-in device that asks for a code on
+// behalf of another, as in pairing.
+fn grant_code_with_session(state: &mut ServerState, request: &Request) -> Response {
+    if request.headers.get("authorization").map_or(true, |auth| !auth.starts_with("Hawk ")) {
+        return Response::error(401, 110, "Invalid authentication token");
+    }
+    let body = request.json();
+    let (scope, code_challenge, oauth_state) = match (
+        body["scope"].as_str(),
+        body["code_challenge"].as_str(),
+        body["state"].as_str(),
+    ) {
+        (Some(scope), Some(code_challenge), Some(oauth_state)) => (scope, code_challenge, oauth_state),
+        _ => return Response::error(400, 109, "Missing parameters"),
+    };
+    if body["code_challenge_method"] != "S256" || body["response_type"] != "code" {
+        return Response::error(400, 109, "Unsupported parameters");
+    }
+    let keys = match body["keys_jwe"].as_str() {
+        Some(keys_jwe) => PendingKeys::Jwe(keys_jwe.to_string()),
+        None => PendingKeys::None,
+    };
+    new_code(state, scope.into(), code_challenge.into(), keys, oauth_state.into())
+}
+
+fn new_code(
+    state: &mut ServerState,
+    scope: String,
+    code_challenge: String,
+    keys: PendingKeys,
+    oauth_state: String,
+) -> Response {
+    let code = new_token(state, "code");
+    state.codes.insert(code.clone(), PendingCode {
+        scope,
+        code_challenge,
+        keys,
+    });
+    Response::json(200, json!({
+        "code": code,
+        "state": oauth_state,
+    }))
+}
+
+fn token_from_assertion(state: &mut ServerState, request: &Request) -> Response {
+    let body = request.json();
+    if body["assertion"].as_str().is_none() || body["response_type"] != "token" {
+        return Response::error(400, 109, "Missing assertion");
+    }
+    let scope = body["scope"].as_str().unwrap_or("").to_string();
+    issue_tokens(state, scope, false, None)
+}
+
+fn token(state: &mut ServerState, request: &Request) -> Response {
+    let body = request.json();
+    if body["grant_type"] == "refresh_token" {
+        let granted = match body["refresh_token"].as_str().and_then(|t| state.refresh_tokens.get(t)) {
+            Some(granted) => granted.clone(),
+            None => return Response::error(400, 108, "Invalid refresh token"),
+        };
+        let requested = body["scope"].as_str().unwrap_or(&granted).to_string();
+        if requested.split(' ').any(|scope| !granted.split(' ').any(|g| g == scope)) {
+            return Response::error(400, 114, "Invalid scopes");
+        }
+        return issue_tokens(state, requested, false, None);
+    }
+
+    let pending = match body["code"].as_str().and_then(|code| state.codes.remove(code)) {
+        Some(pending) => pending,
+        None => return Response::error(400, 105, "Unknown code"),
+    };
+    let verifier = body["code_verifier"].as_str().unwrap_or("");
+    let challenge = digest::digest(&digest::SHA256, verifier.as_bytes());
+    if base64::encode_config(&challenge, base64::URL_SAFE_NO_PAD) != pending.code_challenge {
+        return Response::error(400, 107, "Incorrect code verifier");
+    }
+    let keys_jwe = match pending.keys {
+        PendingKeys::Jwk(ref jwk) if pending.scope.split(' ').any(|scope| scope == OLDSYNC_SCOPE) => {
+            let mut keys = json!({});
+            keys[OLDSYNC_SCOPE] = state.account.oldsync_key.clone();
+            let rng = SystemRandom::new();
+            Some(scoped_keys::encrypt_keys_jwe(jwk, &keys.to_string(), &rng).unwrap())
+        }
+        PendingKeys::Jwe(ref jwe) => Some(jwe.clone()),
+        _ => None,
+    };
+    issue_tokens(state, pending.scope, true, keys_jwe)
+}
+
+fn issue_tokens(
+    state: &mut ServerState,
+    scope: String,
+    with_refresh_token: bool,
+    keys_jwe: Option<String>,
+) -> Response {
+    let access_token = new_token(state, "access");
+    state.access_tokens.insert(access_token.clone(), scope.clone());
+    let mut resp = json!({
+        "access_token": access_token,
+        "token_type": "bearer",
+        "scope": scope,
+        "expires_in": 3600,
+    });
+    if with_refresh_token {
+        let refresh_token = new_token(state, "refresh");
+        state.refresh_tokens.insert(refresh_token.clone(), scope);
+        resp["refresh_token"] = json!(refresh_token);
+    }
+    if let Some(keys_jwe) = keys_jwe {
+        resp["keys_jwe"] = json!(keys_jwe);
+    }
+    Response::json(200, resp)
+}
+
+fn destroy_token(state: &mut ServerState, request: &Request) -> Response {
+    let token = request.json()["token"].as_str().unwrap_or("").to_string();
+    let removed = state.access_tokens.remove(&token).is_some()
+        || state.refresh_tokens.remove(&token).is_some();
+    if !removed {
+        return Response::error(400, 108, "Invalid token");
+    }
+    Response::json(200, json!({}))
+}
+
+// The device endpoints take a refresh token that was granted Sync's scope.
+fn device_token(state: &ServerState, request: &Request) -> Option<String> {
+    let token = request.bearer_token()?;
+    let scope = state.refresh_tokens.get(token)?;
+    if scope.split(' ').any(|scope| scope == OLDSYNC_SCOPE) {
+        Some(token.to_string())
+    } else {
+        None
+    }
+}
+
+fn update_device(state: &mut ServerState, request: &Request) -> Response {
+    let refresh_token = match device_token(state, request) {
+        Some(token) => token,
+        None => return Response::error(401, 110, "Invalid authentication token"),
+    };
+    let update = request.json();
+    let id = match update["id"].as_str() {
+        Some(id) => id.to_string(),
+        None => {
+            let id = new_token(state, "device");
+            state.devices.push(MockDevice {
+                id: id.clone(),
+                refresh_token: refresh_token.clone(),
+                record: json!({ "id": id }),
+            });
+            id
+        }
+    };
+    let device = match state.devices.iter_mut().find(|device| device.id == id) {
+        Some(device) => device,
+        None => return Response::error(400, 123, "Unknown device"),
+    };
+    if let Some(fields) = update.as_object() {
+        for (name, value) in fields {
+            device.record[name.as_str()] = value.clone();
+        }
+    }
+    device.refresh_token = refresh_token;
+    let mut record = device.record.clone();
+    record["isCurrentDevice"] = json!(true);
+    Response::json(200, record)
+}
+
+fn devices(state: &mut ServerState, request: &Request) -> Response {
+    let refresh_token = match device_token(state, request) {
+        Some(token) => token,
+        None => return Response::error(401, 110, "Invalid authentication token"),
+    };
+    let devices: Vec<Value> = state
+        .devices
+        .iter()
+        .map(|device| {
+            let mut record = device.record.clone();
+            record["isCurrentDevice"] = json!(device.refresh_token == refresh_token);
+            record
+        })
+        .collect();
+    Response::json(200, json!(devices))
+}
+
+fn destroy_device(state: &mut ServerState, request: &Request) -> Response {
+    if device_token(state, request).is_none() {
+        return Response::error(401, 110, "Invalid authentication token");
+    }
+    let id = request.json()["id"].as_str().unwrap_or("").to_string();
+    let count = state.devices.len();
+    state.devices.retain(|device| device.id != id);
+    if state.devices.len() == count {
+        return Response::error(400, 123, "Unknown device");
+    }
+    Response::json(200, json!({}))
+}
+
+fn invoke_command(state: &mut ServerState, request: &Request) -> Response {
+    let refresh_token = match device_token(state, request) {
+        Some(token) => token,
+        None => return Response::error(401, 110, "Invalid authentication token"),
+    };
+    let body = request.json();
+    let target = body["target"].as_str().unwrap_or("").to_string();
+    if !state.devices.iter().any(|device| device.id == target) {
+        return Response::error(400, 123, "Unknown device");
+    }
+    let sender = state
+        .devices
+        .iter()
+        .find(|device| device.refresh_token == refresh_token)
+        .map(|device| device.id.clone());
+    let index = state.commands.len() as u64 + 1;
+    state.commands.push(MockCommand {
+        index,
+        target,
+        data: json!({
+            "command": body["command"],
+            "payload": body["payload"],
+            "sender": sender,
+        }),
+    });
+    Response::json(200, json!({}))
+}
+
+// The commands sent to the current device, from `index` on.
+fn device_commands(state: &mut ServerState, request: &Request) -> Response {
+    let refresh_token = match device_token(state, request) {
+        Some(token) => token,
+        None => return Response::error(401, 110, "Invalid authentication token"),
+    };
+    let device_id = match state.devices.iter().find(|device| device.refresh_token == refresh_token) {
+        Some(device) => device.id.clone(),
+        None => return Response::error(400, 123, "Unknown device"),
+    };
+    let index = request.query("index").and_then(|index| index.parse().ok()).unwrap_or(0);
+    let limit = request
+        .query("limit")
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(COMMANDS_PAGE_SIZE);
+    let pending: Vec<&MockCommand> = state
+        .commands
+        .iter()
+        .filter(|command| command.target == device_id && command.index >= index)
+        .collect();
+    let page = &pending[..pending.len().min(limit)];
+    Response::json(200, json!({
+        "index": page.last().map_or(index, |command| command.index),
+        "last": page.len() == pending.len(),
+        "messages": page
+            .iter()
+            .map(|command| json!({ "index": command.index, "data": command.data }))
+            .collect::<Vec<Value>>(),
+    }))
+}
+
+fn new_token(state: &mut ServerState, kind: &str) -> String {
+    state.next_token += 1;
+    format!("{}-{}", kind, state.next_token)
+}
+
+fn profile(state: &mut ServerState, request: &Request) -> Response {
+    let granted = match request.bearer_token().and_then(|t| state.access_tokens.get(t)) {
+        Some(granted) => granted.clone(),
+        None => return Response::error(401, 110, "Invalid token"),
+    };
+    if !granted.split(' ').any(|scope| scope == "profile") {
+        return Response::error(403, 125, "Missing profile scope");
+    }
+    if request.headers.get("if-none-match") == Some(&state.profile_etag) {
+        return Response {
+            status: 304,
+            headers: Vec::new(),
+            body: String::new(),
+        }.header("ETag", &state.profile_etag);
+    }
+    Response::json(200, json!({
+        "uid": state.account.uid,
+        "email": state.account.email,
+        "locale": "en-US",
+        "displayName": Value::Null,
+        "avatar": format!("{}/profile/a/default", state.base_url),
+        "avatarDefault": true,
+        "amrValues": ["pwd", "email"],
+        "twoFactorAuthentication": false,
+    })).header("ETag", &state.profile_etag)
+}
@@ -0,0 +1,440 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pairing signs in a new device, the supplicant, by scanning a QR code shown
+//! on a signed-in one, the authority. The QR code holds a pairing URL, with
+//! the id of a channel on the pairing channel server and a key that only the
+//! two devices know.
+//!
+//! The channel server relays each message as-is to the other end of the
+//! channel, wrapped in JSON with where it came from. The messages themselves
+//! are JWEs, encrypted directly with the channel key (`dir` and `A256GCM`),
+//! so the channel server can't read or forge them.
+//!
+//! This module doesn't know how to reach the channel server: apps bring a
+//! websocket (or anything else that moves strings) as a `Transport`.
+
+use base64;
+use ring::aead;
+use ring::rand::SecureRandom;
+use serde_json;
+use url::Url;
+
+use config::Config;
+use errors::*;
+use secret::SecretBytes;
+
+const CHANNEL_KEY_LENGTH: usize = 32;
+const IV_LENGTH: usize = 96 / 8;
+
+/// Carries the messages of one pairing channel to and from the channel
+/// server, e.g. a websocket to `wss://channelserver.services.mozilla.com/v1/ws/`
+/// to open a new channel, or to that URL with the channel id appended to
+/// join one.
+pub trait Transport {
+    fn send(&mut self, message: &str) -> Result<()>;
+
+    /// Blocks until the channel server sends a message.
+    fn receive(&mut self) -> Result<String>;
+}
+
+// Which end of the channel sends a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Authority,
+    Supplicant,
+}
+
+/// The channel id and key from a pairing URL.
+pub struct PairingKey {
+    /// Where to join the channel on the channel server.
+    pub channel_id: String,
+    channel_key: SecretBytes,
+}
+
+impl PairingKey {
+    fn generate(channel_id: String, rng: &SecureRandom) -> Result<PairingKey> {
+        let mut channel_key = vec![0u8; CHANNEL_KEY_LENGTH];
+        rng.fill(&mut channel_key).map_err(|_| ErrorKind::RngFailure)?;
+        Ok(PairingKey {
+            channel_id,
+            channel_key: channel_key.into(),
+        })
+    }
+
+    /// Reads the key from a scanned pairing URL.
+    pub fn from_url(url: &str) -> Result<PairingKey> {
+        let url = Url::parse(url)?;
+        let mut channel_id = None;
+        let mut channel_key = None;
+        for param in url.fragment().unwrap_or("").split('&') {
+            let mut parts = param.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("channel_id"), Some(id)) if !id.is_empty() => channel_id = Some(id),
+                (Some("channel_key"), Some(key)) => {
+                    channel_key = base64::decode_config(key, base64::URL_SAFE_NO_PAD).ok()
+                }
+                _ => {}
+            }
+        }
+        match (channel_id, channel_key) {
+            (Some(channel_id), Some(channel_key)) => {
+                if channel_key.len() != CHANNEL_KEY_LENGTH {
+                    return Err(ErrorKind::InvalidPairingUrl.into());
+                }
+                Ok(PairingKey {
+                    channel_id: channel_id.to_string(),
+                    channel_key: channel_key.into(),
+                })
+            }
+            _ => Err(ErrorKind::InvalidPairingUrl.into()),
+        }
+    }
+
+    // The URL for the QR code. The key goes in the fragment, so it isn't
+    // sent to the content server if the URL is opened in a browser.
+    fn to_url(&self, config: &Config) -> Result<Url> {
+        let mut url = config.content_url_path("pair")?;
+        let fragment = format!(
+            "channel_id={}&channel_key={}",
+            self.channel_id,
+            base64::encode_config(self.channel_key.expose(), base64::URL_SAFE_NO_PAD)
+        );
+        url.set_fragment(Some(&fragment));
+        Ok(url)
+    }
+
+    fn encrypt(&self, plaintext: &[u8], rng: &SecureRandom) -> Result<String> {
+        let encode = |bytes: &[u8]| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+        let header = encode(json!({"alg": "dir", "enc": "A256GCM"}).to_string().as_bytes());
+        let mut iv = [0u8; IV_LENGTH];
+        rng.fill(&mut iv).map_err(|_| ErrorKind::RngFailure)?;
+        let sealing_key = aead::SealingKey::new(&aead::AES_256_GCM, self.channel_key.expose())
+            .map_err(|_| ErrorKind::KeyImportFailed)?;
+        let tag_len = aead::AES_256_GCM.tag_len();
+        let mut in_out = plaintext.to_vec();
+        in_out.extend_from_slice(&vec![0u8; tag_len]);
+        let len = aead::seal_in_place(&sealing_key, &iv, header.as_bytes(), &mut in_out, tag_len)
+            .map_err(|_| ErrorKind::AEADSealFailure)?;
+        let (ciphertext, auth_tag) = in_out[..len].split_at(len - tag_len);
+        Ok(format!(
+            "{}..{}.{}.{}",
+            header,
+            encode(&iv),
+            encode(ciphertext),
+            encode(auth_tag)
+        ))
+    }
+
+    fn decrypt(&self, jwe: &str) -> Result<Vec<u8>> {
+        let segments: Vec<&str> = jwe.split('.').collect();
+        if segments.len() != 5 || !segments[1].is_empty() {
+            return Err(ErrorKind::UnexpectedPairingMessage.into());
+        }
+        let decode = |segment: &str| base64::decode_config(segment, base64::URL_SAFE_NO_PAD);
+        let header: serde_json::Value = serde_json::from_slice(&decode(segments[0])?)?;
+        if header["alg"] != "dir" || header["enc"] != "A256GCM" {
+            return Err(ErrorKind::UnexpectedPairingMessage.into());
+        }
+        let iv = decode(segments[2])?;
+        if iv.len() != IV_LENGTH {
+            return Err(ErrorKind::UnexpectedPairingMessage.into());
+        }
+        let mut in_out = decode(segments[3])?;
+        in_out.extend(decode(segments[4])?);
+        let opening_key = aead::OpeningKey::new(&aead::AES_256_GCM, self.channel_key.expose())
+            .map_err(|_| ErrorKind::KeyImportFailed)?;
+        let plaintext =
+            aead::open_in_place(&opening_key, &iv, segments[0].as_bytes(), 0, &mut in_out)
+                .map_err(|_| ErrorKind::AEADOpenFailure)?;
+        Ok(plaintext.to_vec())
+    }
+}
+
+/// What the supplicant asks the authority to authorize: the parameters of
+/// an OAuth flow it started.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuthorizationParams {
+    pub client_id: String,
+    pub state: String,
+    pub scope: String,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+    pub access_type: String,
+    /// The base64url-encoded JWK to encrypt scoped keys to, if the
+    /// supplicant wants keys.
+    pub keys_jwk: Option<String>,
+}
+
+/// Shown on the supplicant, so the user can check which account they're
+/// signing in to.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PairingMetadata {
+    pub email: Option<String>,
+    pub avatar: Option<String>,
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "message", content = "data")]
+pub(crate) enum PairingMessage {
+    #[serde(rename = "pair:supp:request")]
+    Request(AuthorizationParams),
+    #[serde(rename = "pair:auth:metadata")]
+    Metadata(PairingMetadata),
+    #[serde(rename = "pair:auth:authorize")]
+    Authorize { code: String, state: String },
+    #[serde(rename = "pair:auth:decline")]
+    Decline,
+}
+
+impl PairingMessage {
+    fn sender(&self) -> Role {
+        match *self {
+            PairingMessage::Request(_) => Role::Supplicant,
+            _ => Role::Authority,
+        }
+    }
+}
+
+// What the channel server sends: the channel id when we connect, then the
+// messages from the other end.
+#[derive(Deserialize)]
+struct Envelope {
+    channelid: Option<String>,
+    message: Option<String>,
+}
+
+/// One end of an encrypted pairing channel.
+pub struct PairingChannel<T: Transport> {
+    transport: T,
+    key: PairingKey,
+    role: Role,
+}
+
+impl<T: Transport> PairingChannel<T> {
+    // Opens a new channel as the authority, with the id the channel server
+    // gives it and a new key.
+    pub(crate) fn open(mut transport: T, rng: &SecureRandom) -> Result<PairingChannel<T>> {
+        let envelope: Envelope = serde_json::from_str(&transport.receive()?)?;
+        let channel_id = match envelope.channelid {
+            Some(channel_id) => channel_id,
+            None => return Err(ErrorKind::UnexpectedPairingMessage.into()),
+        };
+        Ok(PairingChannel {
+            transport,
+            key: PairingKey::generate(channel_id, rng)?,
+            role: Role::Authority,
+        })
+    }
+
+    /// Joins the channel of a scanned pairing URL as the supplicant. The
+    /// transport should already be connected to `key.channel_id`.
+    pub fn join(transport: T, key: PairingKey) -> PairingChannel<T> {
+        PairingChannel {
+            transport,
+            key,
+            role: Role::Supplicant,
+        }
+    }
+
+    pub fn channel_id(&self) -> &str {
+        &self.key.channel_id
+    }
+
+    pub(crate) fn pairing_url(&self, config: &Config) -> Result<Url> {
+        self.key.to_url(config)
+    }
+
+    pub(crate) fn send(&mut self, message: &PairingMessage, rng: &SecureRandom) -> Result<()> {
+        let jwe = self.key.encrypt(&serde_json::to_vec(message)?, rng)?;
+        self.transport.send(&jwe)
+    }
+
+    pub(crate) fn receive(&mut self) -> Result<PairingMessage> {
+        loop {
+            let envelope: Envelope = serde_json::from_str(&self.transport.receive()?)?;
+            // The supplicant is told the channel id too, when it joins.
+            let jwe = match envelope.message {
+                Some(jwe) => jwe,
+                None => continue,
+            };
+            let message: PairingMessage = serde_json::from_slice(&self.key.decrypt(&jwe)?)?;
+            // Both ends share a key, so make sure our own messages can't be
+            // sent back to us.
+            if message.sender() == self.role {
+                return Err(ErrorKind::UnexpectedPairingMessage.into());
+            }
+            return Ok(message);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    /// Connects two ends in the same process, wrapping messages like the
+    /// channel server does.
+    pub struct Loopback {
+        sender: Sender<String>,
+        receiver: Receiver<String>,
+    }
+
+    impl Transport for Loopback {
+        fn send(&mut self, message: &str) -> Result<()> {
+            let envelope = json!({
+                "message": message,
+                "sender": {"remote": "127.0.0.1"},
+            });
+            // The other end may have hung up, like a closed websocket.
+            let _ = self.sender.send(envelope.to_string());
+            Ok(())
+        }
+
+        fn receive(&mut self) -> Result<String> {
+            match self.receiver.recv() {
+                Ok(message) => Ok(message),
+                Err(_) => Err(ErrorKind::PairingChannelClosed.into()),
+            }
+        }
+    }
+
+    /// The authority's and supplicant's transports for a new channel, each
+    /// told `channel_id` when it connects.
+    pub fn loopback(channel_id: &str) -> (Loopback, Loopback) {
+        let (a_sender, b_receiver) = channel();
+        let (b_sender, a_receiver) = channel();
+        let hello = json!({ "channelid": channel_id }).to_string();
+        b_sender.send(hello.clone()).unwrap();
+        a_sender.send(hello).unwrap();
+        (
+            Loopback {
+                sender: a_sender,
+                receiver: a_receiver,
+            },
+            Loopback {
+                sender: b_sender,
+                receiver: b_receiver,
+            },
+        )
+    }
+
+    fn request() -> PairingMessage {
+        PairingMessage::Request(AuthorizationParams {
+            client_id: "12345678".into(),
+            state: "state".into(),
+            scope: "profile".into(),
+            code_challenge: "challenge".into(),
+            code_challenge_method: "S256".into(),
+            access_type: "offline".into(),
+            keys_jwk: None,
+        })
+    }
+
+    #[test]
+    fn test_pairing_url() {
+        let config = Config::new_static("https://accounts.example.com");
+        let (a, _) = loopback("abcdef");
+        let channel = PairingChannel::open(a, &SystemRandom::new()).unwrap();
+        assert_eq!(channel.channel_id(), "abcdef");
+        let url = channel.pairing_url(&config).unwrap();
+        assert!(
+            url.as_str()
+                .starts_with("https://accounts.example.com/pair#channel_id=abcdef&channel_key=")
+        );
+        let key = PairingKey::from_url(url.as_str()).unwrap();
+        assert_eq!(key.channel_id, "abcdef");
+        assert_eq!(key.channel_key, channel.key.channel_key);
+
+        let missing_key = "https://accounts.example.com/pair#channel_id=abcdef";
+        assert!(PairingKey::from_url(missing_key).is_err());
+        let short_key = "https://accounts.example.com/pair#channel_id=abcdef&channel_key=AAAA";
+        assert!(PairingKey::from_url(short_key).is_err());
+    }
+
+    #[test]
+    fn test_channel() {
+        let rng = SystemRandom::new();
+        let config = Config::new_static("https://accounts.example.com");
+        let (a, b) = loopback("abcdef");
+        let mut authority = PairingChannel::open(a, &rng).unwrap();
+        let url = authority.pairing_url(&config).unwrap();
+        let key = PairingKey::from_url(url.as_str()).unwrap();
+        let mut supplicant = PairingChannel::join(b, key);
+
+        supplicant.send(&request(), &rng).unwrap();
+        assert_eq!(authority.receive().unwrap(), request());
+        authority
+            .send(&PairingMessage::Metadata(PairingMetadata::default()), &rng)
+            .unwrap();
+        authority.send(&PairingMessage::Decline, &rng).unwrap();
+        // The supplicant skips the channel id it was sent when it joined.
+        assert_eq!(
+            supplicant.receive().unwrap(),
+            PairingMessage::Metadata(PairingMetadata::default())
+        );
+        assert_eq!(supplicant.receive().unwrap(), PairingMessage::Decline);
+    }
+
+    #[test]
+    fn test_channel_rejects_other_messages() {
+        let rng = SystemRandom::new();
+        let config = Config::new_static("https://accounts.example.com");
+
+        // Only someone with the channel key can talk on the channel...
+        let (a, b) = loopback("abcdef");
+        let mut authority = PairingChannel::open(a, &rng).unwrap();
+        let (other, _) = loopback("abcdef");
+        let other = PairingChannel::open(other, &rng).unwrap();
+        let other_url = other.pairing_url(&config).unwrap();
+        let other_key = PairingKey::from_url(other_url.as_str()).unwrap();
+        let mut eavesdropper = PairingChannel::join(b, other_key);
+        eavesdropper.send(&request(), &rng).unwrap();
+        assert!(authority.receive().is_err());
+
+        // ...and our messages can't be reflected back to us.
+        let (a, mut b) = loopback("abcdef");
+        let mut authority = PairingChannel::open(a, &rng).unwrap();
+        authority.send(&PairingMessage::Decline, &rng).unwrap();
+        b.receive().unwrap();
+        let reflected: serde_json::Value = serde_json::from_str(&b.receive().unwrap()).unwrap();
+        b.send(reflected["message"].as_str().unwrap()).unwrap();
+        match authority.receive() {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::UnexpectedPairingMessage => {}
+                ref kind => panic!("Unexpected error {}", kind),
+            },
+            Ok(message) => panic!("Unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_message_format() {
+        let message = PairingMessage::Authorize {
+            code: "code".into(),
+            state: "state".into(),
+        };
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            json!({
+                "message": "pair:auth:authorize",
+                "data": {"code": "code", "state": "state"},
+            })
+        );
+
+        let rng = SystemRandom::new();
+        let (a, _) = loopback("abcdef");
+        let channel = PairingChannel::open(a, &rng).unwrap();
+        let jwe = channel.key.encrypt(b"hello", &rng).unwrap();
+        let header = jwe.split('.').next().unwrap();
+        let header = base64::decode_config(header, base64::URL_SAFE_NO_PAD).unwrap();
+        let header: serde_json::Value = serde_json::from_slice(&header).unwrap();
+        assert_eq!(header, json!({"alg": "dir", "enc": "A256GCM"}));
+        assert_eq!(channel.key.decrypt(&jwe).unwrap(), b"hello");
+    }
+}
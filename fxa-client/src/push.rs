@@ -0,0 +1,123 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use base64;
+
+use ece::KeyPair;
+use errors::*;
+
+// The record size `aesgcm` uses when the `Encryption` header doesn't say.
+const DEFAULT_AESGCM_RECORD_SIZE: usize = 4096;
+
+/// Decrypts the body of a push message sent to `keys`, using its
+/// `Content-Encoding`, `Encryption` and `Crypto-Key` headers. Only `aesgcm`
+/// needs the last two.
+pub(crate) fn decrypt(
+    keys: &KeyPair,
+    body: &[u8],
+    content_encoding: &str,
+    encryption: Option<&str>,
+    crypto_key: Option<&str>,
+) -> Result<Vec<u8>> {
+    match content_encoding.trim() {
+        "aes128gcm" => keys.decrypt_aes128gcm(body),
+        "aesgcm" => {
+            let salt = match encryption.and_then(|header| header_param(header, "salt")) {
+                Some(salt) => decode(salt)?,
+                None => return Err(ErrorKind::InvalidEncryptedContent("Missing salt").into()),
+            };
+            let record_size = match encryption.and_then(|header| header_param(header, "rs")) {
+                Some(rs) => rs.parse::<usize>()
+                    .map_err(|_| ErrorKind::InvalidEncryptedContent("Invalid record size"))?,
+                None => DEFAULT_AESGCM_RECORD_SIZE,
+            };
+            let sender_public_key = match crypto_key.and_then(|header| header_param(header, "dh")) {
+                Some(dh) => decode(dh)?,
+                None => {
+                    return Err(ErrorKind::InvalidEncryptedContent("Missing sender public key").into())
+                }
+            };
+            keys.decrypt_aesgcm(&salt, &sender_public_key, record_size, body)
+        }
+        encoding => Err(ErrorKind::UnsupportedContentEncoding(encoding.to_string()).into()),
+    }
+}
+
+// Finds a parameter in an `Encryption` or `Crypto-Key` header, which look
+// like `keyid=p256dh;dh=BDgp...,p256ecdsa=BF5o...`.
+fn header_param<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header
+        .split(|c| c == ',' || c == ';')
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => {
+                    Some(value.trim().trim_matches('"'))
+                }
+                _ => None,
+            }
+        })
+        .next()
+}
+
+// Push services use base64url, but some pad it.
+fn decode(value: &str) -> Result<Vec<u8>> {
+    base64::decode_config(value.trim_right_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|e| e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    // The receiver's keys from RFC 8291, appendix A.
+    fn keys() -> KeyPair {
+        serde_json::from_value(json!({
+            "private_key": "q1dXpw3UpT5VOmu_cf_v6ih07Aems3njxI-JWgLcM94",
+            "public_key": "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4",
+            "auth_secret": "BTBZMqHH6r4Tts7J_aSIgg",
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_header_param() {
+        let header = r#"keyid=p256dh;dh="BDgpRKok2GZZDmS4r63vbJSUtcQx4Fq1V58+6+3NbZzS",p256ecdsa=BF5o"#;
+        assert_eq!(header_param(header, "dh"), Some("BDgpRKok2GZZDmS4r63vbJSUtcQx4Fq1V58+6+3NbZzS"));
+        assert_eq!(header_param(header, "p256ecdsa"), Some("BF5o"));
+        assert_eq!(header_param("salt=abc; rs=24", "rs"), Some("24"));
+        assert_eq!(header_param("salt=abc", "rs"), None);
+    }
+
+    #[test]
+    fn test_decrypt_aes128gcm() {
+        // RFC 8291, appendix A.
+        let body = decode("DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN").unwrap();
+        let plaintext = decrypt(&keys(), &body, "aes128gcm", None, None).unwrap();
+        assert_eq!(plaintext, &b"When I grow up, I want to be a watermelon"[..]);
+    }
+
+    #[test]
+    fn test_decrypt_aesgcm() {
+        // The same message, with the legacy encoding.
+        let body = decode("4qwOLFm_mNy0vf1A8f3Bm6B5UD15y3aV_xZy14pixUhcPTIoZKHzq5i3dZ6PzqSMxBI_-VDUZ4jW04M").unwrap();
+        let plaintext = decrypt(
+            &keys(),
+            &body,
+            "aesgcm",
+            Some("salt=DGv6ra1nlYgDCS1FRnbzlw"),
+            Some("dh=BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8;p256ecdsa=BF5o"),
+        ).unwrap();
+        assert_eq!(plaintext, &b"When I grow up, I want to be a watermelon"[..]);
+
+        assert!(decrypt(&keys(), &body, "aesgcm", None, Some("dh=BP4z")).is_err());
+        match decrypt(&keys(), &body, "gzip", None, None) {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::UnsupportedContentEncoding(ref encoding) => assert_eq!(encoding, "gzip"),
+                ref kind => panic!("Unexpected error {}", kind),
+            },
+            Ok(_) => panic!("Should not decrypt gzip"),
+        }
+    }
+}
@@ -1,35 +1,222 @@
+use std::collections::HashMap;
+#[cfg(not(feature = "stored-keys"))]
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "stored-keys")]
+use ece;
 use errors::*;
 
 use base64;
 use byteorder::{BigEndian, ByteOrder};
-use ring::agreement::EphemeralPrivateKey;
+#[cfg(not(feature = "stored-keys"))]
+use ring::agreement::{self, EphemeralPrivateKey};
 use ring::rand::SecureRandom;
-use ring::{aead, agreement, digest};
+use ring::{aead, digest};
+use secret::{SecretBytes, SecretString};
+#[cfg(not(feature = "stored-keys"))]
+use serde::de::{Deserialize, Deserializer, IgnoredAny};
+#[cfg(not(feature = "stored-keys"))]
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 use serde_json;
+#[cfg(not(feature = "stored-keys"))]
 use untrusted::Input;
 
+/// The scope whose key is Sync's root key, kSync.
+pub const OLDSYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
+
+/// A key derived for one scope, as a JWK. We get these alongside tokens
+/// from OAuth flows that ask for keys.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScopedKey {
+    pub kty: String,
+    pub scope: String,
+    /// The key, as unpadded base64url.
+    pub k: SecretString,
+    pub kid: String,
+}
+
+impl ScopedKey {
+    /// Parses and checks the decrypted `keys_jwe` of a token response: a map
+    /// from each scope to its key.
+    pub(crate) fn parse_keys(json: &str) -> Result<HashMap<String, ScopedKey>> {
+        let keys: HashMap<String, ScopedKey> = serde_json::from_str(json)?;
+        for (scope, key) in &keys {
+            if key.scope != *scope {
+                return Err(ErrorKind::InvalidScopedKey("Key for the wrong scope").into());
+            }
+            if key.kty != "oct" {
+                return Err(ErrorKind::InvalidScopedKey("Unsupported key type").into());
+            }
+            if key.kid.is_empty() {
+                return Err(ErrorKind::InvalidScopedKey("Missing key id").into());
+            }
+            key.key_bytes()?;
+        }
+        Ok(keys)
+    }
+
+    pub fn key_bytes(&self) -> Result<SecretBytes> {
+        Ok(base64::decode_config(self.k.expose(), base64::URL_SAFE_NO_PAD)?.into())
+    }
+
+    /// kSync, in the form `KeyBundle::from_ksync_bytes` takes. Only the
+    /// oldsync key has one.
+    pub fn ksync(&self) -> Result<SecretBytes> {
+        if self.scope != OLDSYNC_SCOPE {
+            return Err(ErrorKind::InvalidScopedKey("Not a Sync key").into());
+        }
+        let ksync = self.key_bytes()?;
+        if ksync.expose().len() != 64 {
+            return Err(ErrorKind::BadKeyLength("kSync", ksync.expose().len(), 64).into());
+        }
+        Ok(ksync)
+    }
+
+    /// The `X-KeyID` header to send to the token server with kSync: the
+    /// key's timestamp and a hash of kB.
+    pub fn key_id(&self) -> Result<&str> {
+        if self.scope != OLDSYNC_SCOPE {
+            return Err(ErrorKind::InvalidScopedKey("Not a Sync key").into());
+        }
+        let mut parts = self.kid.splitn(2, '-');
+        match (parts.next(), parts.next()) {
+            (Some(timestamp), Some(hash))
+                if !timestamp.is_empty()
+                    && timestamp.bytes().all(|b| b.is_ascii_digit())
+                    && base64::decode_config(hash, base64::URL_SAFE_NO_PAD).is_ok() =>
+            {
+                Ok(&self.kid)
+            }
+            _ => Err(ErrorKind::InvalidScopedKey("Malformed key id").into()),
+        }
+    }
+}
+
+/// The ephemeral key pair an OAuth flow's keys are encrypted to. Like
+/// `ece::KeyPair`, it keeps the raw private key, so that a flow can be saved
+/// and completed after a restart.
+#[cfg(feature = "stored-keys")]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ScopedKeysFlow {
-    private_key: EphemeralPrivateKey,
+    private_key: SecretString,
 }
 
-/// Theorically, everything done in this file could and should be done in a JWT library.
-/// However, none of the existing rust JWT libraries can handle ECDH-ES encryption, and API choices
-/// made by their authors make it difficult to add this feature.
-/// In the past, we chose cjose to do that job, but it added three C dependencies to build and link
-/// against: jansson, openssl and cjose itself.
+#[cfg(feature = "stored-keys")]
+impl ScopedKeysFlow {
+    pub fn with_random_key(rng: &SecureRandom) -> Result<ScopedKeysFlow> {
+        let private_key = ece::PrivateKey::generate(rng)?;
+        Ok(ScopedKeysFlow {
+            private_key: base64::encode_config(
+                private_key.to_raw()?.expose(),
+                base64::URL_SAFE_NO_PAD,
+            ).into(),
+        })
+    }
+
+    fn private_key(&self) -> Result<ece::PrivateKey> {
+        let raw = base64::decode_config(self.private_key.expose(), base64::URL_SAFE_NO_PAD)?;
+        ece::PrivateKey::from_raw(&raw)
+    }
+
+    fn public_key(&self) -> Result<Vec<u8>> {
+        self.private_key()?.public_key()
+    }
+
+    // Does ECDH with the peer's public key, and derives a key from the
+    // shared secret with `kdf`.
+    fn agree<F, R>(self, peer_public_key: &[u8], kdf: F) -> Result<R>
+    where
+        F: FnOnce(&[u8]) -> Result<R>,
+    {
+        let z = self.private_key()?.agree(peer_public_key)?;
+        kdf(z.expose())
+    }
+}
+
+/// The ephemeral key pair an OAuth flow's keys are encrypted to. ring can't
+/// save private keys, so a saved flow comes back without its key, and can't
+/// be completed.
+#[cfg(not(feature = "stored-keys"))]
+#[derive(Clone)]
+pub struct ScopedKeysFlow {
+    // Taken when we decrypt the keys, since ring only lets us use it once.
+    private_key: Arc<Mutex<Option<EphemeralPrivateKey>>>,
+}
+
+#[cfg(not(feature = "stored-keys"))]
 impl ScopedKeysFlow {
     pub fn with_random_key(rng: &SecureRandom) -> Result<ScopedKeysFlow> {
         let private_key = EphemeralPrivateKey::generate(&agreement::ECDH_P256, rng)
             .map_err(|_| ErrorKind::KeyGenerationFailed)?;
-        Ok(ScopedKeysFlow { private_key })
+        Ok(ScopedKeysFlow {
+            private_key: Arc::new(Mutex::new(Some(private_key))),
+        })
     }
 
-    pub fn generate_keys_jwk(&self) -> Result<String> {
-        let mut pub_key = vec![0u8; self.private_key.public_key_len()];
-        &self
-            .private_key
+    /// False if the flow was saved, and lost its key.
+    pub fn has_key(&self) -> bool {
+        self.private_key.lock().unwrap().is_some()
+    }
+
+    fn public_key(&self) -> Result<Vec<u8>> {
+        let private_key = self.private_key.lock().unwrap();
+        let private_key = match *private_key {
+            Some(ref private_key) => private_key,
+            None => return Err(ErrorKind::UnknownOAuthState.into()),
+        };
+        let mut pub_key = vec![0u8; private_key.public_key_len()];
+        private_key
             .compute_public_key(&mut pub_key)
             .map_err(|_| ErrorKind::PublicKeyComputationFailed)?;
+        Ok(pub_key)
+    }
+
+    // Does ECDH with the peer's public key, and derives a key from the
+    // shared secret with `kdf`.
+    fn agree<F, R>(self, peer_public_key: &[u8], kdf: F) -> Result<R>
+    where
+        F: FnOnce(&[u8]) -> Result<R>,
+    {
+        let private_key = match self.private_key.lock().unwrap().take() {
+            Some(private_key) => private_key,
+            None => return Err(ErrorKind::UnknownOAuthState.into()),
+        };
+        agreement::agree_ephemeral(
+            private_key,
+            &agreement::ECDH_P256,
+            Input::from(peer_public_key),
+            ErrorKind::KeyAgreementFailed.into(),
+            kdf,
+        )
+    }
+}
+
+// Saved as an empty object, which comes back as a flow without a key.
+#[cfg(not(feature = "stored-keys"))]
+impl Serialize for ScopedKeysFlow {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_struct("ScopedKeysFlow", 0)?.end()
+    }
+}
+
+#[cfg(not(feature = "stored-keys"))]
+impl<'de> Deserialize<'de> for ScopedKeysFlow {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        IgnoredAny::deserialize(deserializer)?;
+        Ok(ScopedKeysFlow {
+            private_key: Arc::new(Mutex::new(None)),
+        })
+    }
+}
+
+/// Theorically, everything done in this file could and should be done in a JWT library.
+/// However, none of the existing rust JWT libraries can handle ECDH-ES encryption, and API choices
+/// made by their authors make it difficult to add this feature.
+/// In the past, we chose cjose to do that job, but it added three C dependencies to build and link
+/// against: jansson, openssl and cjose itself.
+impl ScopedKeysFlow {
+    pub fn generate_keys_jwk(&self) -> Result<String> {
+        let pub_key = self.public_key()?;
         // Uncompressed form (see SECG SEC1 section 2.3.3).
         // First byte is 4, then 32 bytes for x, and 32 bytes for y.
         assert_eq!(pub_key.len(), 1 + 32 + 32);
@@ -67,33 +254,14 @@ impl ScopedKeysFlow {
         let mut peer_pub_key: Vec<u8> = vec![0x04];
         peer_pub_key.extend_from_slice(&x);
         peer_pub_key.extend_from_slice(&y);
-        let peer_pub_key = Input::from(&peer_pub_key);
-        let secret = agreement::agree_ephemeral(
-            self.private_key,
-            &agreement::ECDH_P256,
-            peer_pub_key,
-            ErrorKind::KeyAgreementFailed,
-            |z| {
-                // ConcatKDF (1 iteration since keyLen <= hashLen).
-                // See rfc7518 section 4.6 for reference.
-                let counter = 1;
-                let alg = protected_header["enc"].as_str().unwrap();
-                let apu = protected_header["apu"].as_str().unwrap_or("");
-                let apv = protected_header["apv"].as_str().unwrap_or("");
-                let mut buf: Vec<u8> = vec![];
-                buf.extend_from_slice(&to_32b_buf(counter));
-                buf.extend_from_slice(&z);
-                // otherinfo
-                buf.extend_from_slice(&to_32b_buf(alg.len() as u32));
-                buf.extend_from_slice(alg.as_bytes());
-                buf.extend_from_slice(&to_32b_buf(apu.len() as u32));
-                buf.extend_from_slice(apu.as_bytes());
-                buf.extend_from_slice(&to_32b_buf(apv.len() as u32));
-                buf.extend_from_slice(apv.as_bytes());
-                buf.extend_from_slice(&to_32b_buf(256));
-                Ok(digest::digest(&digest::SHA256, &buf).as_ref()[0..32].to_vec())
-            },
-        )?;
+        let secret = self.agree(&peer_pub_key, |z| {
+            Ok(concat_kdf(
+                z,
+                protected_header["enc"].as_str().unwrap(),
+                protected_header["apu"].as_str().unwrap_or(""),
+                protected_header["apv"].as_str().unwrap_or(""),
+            ))
+        })?;
 
         // Part 2: decrypt the payload with the obtained secret
         assert_eq!(segments[1].len(), 0); // Encrypted Key is zero-length.
@@ -113,6 +281,95 @@ impl ScopedKeysFlow {
     }
 }
 
+/// Encrypts `plaintext` to a `keys_jwk` the way the OAuth server does for
+/// `keys_jwe`: with ECDH-ES and A256GCM, in the compact serialization.
+/// `keys_jwk` is base64url-encoded, as in the OAuth parameters.
+#[cfg(any(test, feature = "browserid"))]
+pub(crate) fn encrypt_keys_jwe(
+    keys_jwk: &str,
+    plaintext: &str,
+    rng: &SecureRandom,
+) -> Result<String> {
+    use ring::agreement::{self, EphemeralPrivateKey};
+    use untrusted::Input;
+
+    let jwk = base64::decode_config(keys_jwk, base64::URL_SAFE_NO_PAD)?;
+    let jwk: serde_json::Value = serde_json::from_slice(&jwk)?;
+    if jwk["kty"] != "EC" || jwk["crv"] != "P-256" {
+        return Err(ErrorKind::InvalidScopedKey("Unsupported JWK").into());
+    }
+    let mut peer_pub_key: Vec<u8> = vec![0x04];
+    for coord in &["x", "y"] {
+        let coord = match jwk[*coord].as_str() {
+            Some(coord) => base64::decode_config(coord, base64::URL_SAFE_NO_PAD)?,
+            None => return Err(ErrorKind::InvalidScopedKey("Unsupported JWK").into()),
+        };
+        peer_pub_key.extend_from_slice(&coord);
+    }
+
+    let private_key = EphemeralPrivateKey::generate(&agreement::ECDH_P256, rng)
+        .map_err(|_| ErrorKind::KeyGenerationFailed)?;
+    let mut pub_key = vec![0u8; private_key.public_key_len()];
+    private_key
+        .compute_public_key(&mut pub_key)
+        .map_err(|_| ErrorKind::PublicKeyComputationFailed)?;
+    let encode = |bytes: &[u8]| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+    let header = json!({
+        "alg": "ECDH-ES",
+        "enc": "A256GCM",
+        "epk": {
+            "kty": "EC",
+            "crv": "P-256",
+            "x": encode(&pub_key[1..33]),
+            "y": encode(&pub_key[33..]),
+        },
+    });
+    let header = encode(header.to_string().as_bytes());
+    let secret = agreement::agree_ephemeral(
+        private_key,
+        &agreement::ECDH_P256,
+        Input::from(&peer_pub_key),
+        Error::from(ErrorKind::KeyAgreementFailed),
+        |z| Ok(concat_kdf(z, "A256GCM", "", "")),
+    )?;
+
+    let mut iv = [0u8; 96 / 8];
+    rng.fill(&mut iv).map_err(|_| ErrorKind::RngFailure)?;
+    let sealing_key = aead::SealingKey::new(&aead::AES_256_GCM, &secret)
+        .map_err(|_| ErrorKind::KeyImportFailed)?;
+    let tag_len = aead::AES_256_GCM.tag_len();
+    let mut in_out = plaintext.as_bytes().to_vec();
+    in_out.extend_from_slice(&vec![0u8; tag_len]);
+    let len = aead::seal_in_place(&sealing_key, &iv, header.as_bytes(), &mut in_out, tag_len)
+        .map_err(|_| ErrorKind::AEADSealFailure)?;
+    let (ciphertext, auth_tag) = in_out[..len].split_at(len - tag_len);
+    Ok(format!(
+        "{}..{}.{}.{}",
+        header,
+        encode(&iv),
+        encode(ciphertext),
+        encode(auth_tag)
+    ))
+}
+
+// ConcatKDF (1 iteration since keyLen <= hashLen).
+// See rfc7518 section 4.6 for reference.
+fn concat_kdf(z: &[u8], alg: &str, apu: &str, apv: &str) -> Vec<u8> {
+    let counter = 1;
+    let mut buf: Vec<u8> = vec![];
+    buf.extend_from_slice(&to_32b_buf(counter));
+    buf.extend_from_slice(z);
+    // otherinfo
+    buf.extend_from_slice(&to_32b_buf(alg.len() as u32));
+    buf.extend_from_slice(alg.as_bytes());
+    buf.extend_from_slice(&to_32b_buf(apu.len() as u32));
+    buf.extend_from_slice(apu.as_bytes());
+    buf.extend_from_slice(&to_32b_buf(apv.len() as u32));
+    buf.extend_from_slice(apv.as_bytes());
+    buf.extend_from_slice(&to_32b_buf(256));
+    digest::digest(&digest::SHA256, &buf).as_ref()[0..32].to_vec()
+}
+
 fn to_32b_buf(n: u32) -> Vec<u8> {
     let mut buf = [0; 4];
     BigEndian::write_u32(&mut buf, n);
@@ -122,6 +379,7 @@ fn to_32b_buf(n: u32) -> Vec<u8> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ring::rand::SystemRandom;
     use ring::test::rand::FixedSliceRandom;
 
     #[test]
@@ -137,7 +395,58 @@ mod tests {
         assert_eq!(json, "{\"crv\":\"P-256\",\"kty\":\"EC\",\"x\":\"ARvGIPJ5eIFdp6YTM-INVDqwfun2R9FfCUvXbH7QCIU\",\"y\":\"hk8gP0Po8nBh-WSiTsvsyesC5c1L6fGOEVuX8FHsvTs\"}");
 
         let jwe = "eyJhbGciOiJFQ0RILUVTIiwia2lkIjoiNFBKTTl5dGVGeUtsb21ILWd2UUtyWGZ0a0N3ak9HNHRfTmpYVXhLM1VqSSIsImVwayI6eyJrdHkiOiJFQyIsImNydiI6IlAtMjU2IiwieCI6IlB3eG9Na1RjSVZ2TFlKWU4wM2R0Y3o2TEJrR0FHaU1hZWlNQ3lTZXEzb2MiLCJ5IjoiLUYtTllRRDZwNUdSQ2ZoYm1hN3NvNkhxdExhVlNub012S0pFcjFBeWlaSSJ9LCJlbmMiOiJBMjU2R0NNIn0..b9FPhjjpmAmo_rP8.ur9jTry21Y2trvtcanSFmAtiRfF6s6qqyg6ruRal7PCwa7PxDzAuMN6DZW5BiK8UREOH08-FyRcIgdDOm5Zq8KwVAn56PGfcH30aNDGQNkA_mpfjx5Tj2z8kI6ryLWew4PGZb-PsL1g-_eyXhktq7dAhetjNYttKwSREWQFokv7N3nJGpukBqnwL1ost-MjDXlINZLVJKAiMHDcu-q7Epitwid2c2JVGOSCJjbZ4-zbxVmZ4o9xhFb2lbvdiaMygH6bPlrjEK99uT6XKtaIZmyDwftbD6G3x4On-CqA2TNL6ILRaJMtmyX--ctL0IrngUIHg_F0Wz94v.zBD8NACkUcZTPLH0tceGnA";
+        let saved: ScopedKeysFlow =
+            serde_json::from_str(&serde_json::to_string(&flow).unwrap()).unwrap();
+        // With stored keys, a saved flow can still decrypt its keys...
+        #[cfg(feature = "stored-keys")]
+        let flow = {
+            assert_eq!(saved.generate_keys_jwk().unwrap(), json);
+            saved
+        };
+        // ...and without, it can't.
+        #[cfg(not(feature = "stored-keys"))]
+        {
+            assert!(!saved.has_key());
+            assert!(saved.decrypt_keys_jwe(jwe).is_err());
+        }
         let keys = flow.decrypt_keys_jwe(jwe).unwrap();
         assert_eq!(keys, "{\"https://identity.mozilla.com/apps/oldsync\":{\"kty\":\"oct\",\"scope\":\"https://identity.mozilla.com/apps/oldsync\",\"k\":\"8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw-exR70Z2SSIfMSrJDTLEZid9lD05-hbA3n2Q4Esjlu1tA\",\"kid\":\"1526414944666-zgTjf5oXmPmBjxwXWFsDWg\"}}");
     }
+
+    #[test]
+    fn test_encrypt_keys_jwe() {
+        let rng = SystemRandom::new();
+        let flow = ScopedKeysFlow::with_random_key(&rng).unwrap();
+        let jwk = base64::encode_config(&flow.generate_keys_jwk().unwrap(), base64::URL_SAFE_NO_PAD);
+        let jwe = encrypt_keys_jwe(&jwk, "{\"a\":1}", &rng).unwrap();
+        assert_eq!(flow.decrypt_keys_jwe(&jwe).unwrap(), "{\"a\":1}");
+
+        let other = ScopedKeysFlow::with_random_key(&rng).unwrap();
+        assert!(other.decrypt_keys_jwe(&jwe).is_err());
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let keys = ScopedKey::parse_keys(r#"{"https://identity.mozilla.com/apps/oldsync":{"kty":"oct","scope":"https://identity.mozilla.com/apps/oldsync","k":"8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw-exR70Z2SSIfMSrJDTLEZid9lD05-hbA3n2Q4Esjlu1tA","kid":"1526414944666-zgTjf5oXmPmBjxwXWFsDWg"}}"#).unwrap();
+        let key = &keys[OLDSYNC_SCOPE];
+        assert_eq!(key.ksync().unwrap().expose().len(), 64);
+        assert_eq!(key.key_id().unwrap(), "1526414944666-zgTjf5oXmPmBjxwXWFsDWg");
+
+        let other = ScopedKey {
+            scope: "https://identity.mozilla.com/apps/notes".into(),
+            ..key.clone()
+        };
+        assert!(other.ksync().is_err());
+        assert!(other.key_id().is_err());
+        let malformed = ScopedKey {
+            kid: "zgTjf5oXmPmBjxwXWFsDWg".into(),
+            ..key.clone()
+        };
+        assert!(malformed.key_id().is_err());
+
+        let mismatched = r#"{"https://identity.mozilla.com/apps/notes":{"kty":"oct","scope":"https://identity.mozilla.com/apps/oldsync","k":"AAAA","kid":"1-AAAA"}}"#;
+        assert!(ScopedKey::parse_keys(mismatched).is_err());
+        let bad_type = r#"{"https://identity.mozilla.com/apps/oldsync":{"kty":"EC","scope":"https://identity.mozilla.com/apps/oldsync","k":"AAAA","kid":"1-AAAA"}}"#;
+        assert!(ScopedKey::parse_keys(bad_type).is_err());
+    }
 }
@@ -17,6 +17,16 @@ class SyncUnlockInfo (
         val tokenserverURL: String
 )
 
+/**
+ * What happened during a sync.
+ */
+class SyncCounts (
+        /** Incoming logins we couldn't read, which we'll try again next sync. */
+        val quarantined: Int,
+        /** True if we didn't sync because a newer client owns the collection. */
+        val declined: Boolean
+)
+
 interface LoginsStorage : Closeable {
 
     fun lock(): SyncResult<Unit>
@@ -28,7 +38,7 @@ interface LoginsStorage : Closeable {
     /**
      * Synchronize the logins storage layer with a remote layer.
      */
-    fun sync(syncInfo: SyncUnlockInfo): SyncResult<Unit>
+    fun sync(syncInfo: SyncUnlockInfo): SyncResult<SyncCounts>
 
     /**
      * Delete all locally stored login sync metadata.
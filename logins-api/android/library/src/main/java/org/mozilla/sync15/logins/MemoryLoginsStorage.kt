@@ -61,11 +61,11 @@ class MemoryLoginsStorage(private var list: List<ServerPassword>) : Closeable, L
         }
     }
 
-    override fun sync(syncInfo: SyncUnlockInfo): SyncResult<Unit> {
+    override fun sync(syncInfo: SyncUnlockInfo): SyncResult<SyncCounts> {
         return asyncResult {
             checkUnlocked()
             Log.w("MemoryLoginsStorage", "Not syncing because this implementation can not sync")
-            Unit
+            SyncCounts(0, false)
         }
     }
 
@@ -57,15 +57,16 @@ class MentatLoginsStorage(private val dbPath: String) : Closeable, LoginsStorage
         }
     }
 
-    override fun sync(syncInfo: SyncUnlockInfo): SyncResult<Unit> {
+    override fun sync(syncInfo: SyncUnlockInfo): SyncResult<SyncCounts> {
         return safeAsync { error ->
             Log.d("LoginsAPI", "sync")
-            PasswordSyncAdapter.INSTANCE.sync15_passwords_sync(this.raw!!,
+            val counts = PasswordSyncAdapter.INSTANCE.sync15_passwords_sync(this.raw!!,
                     syncInfo.kid,
                     syncInfo.fxaAccessToken,
                     syncInfo.syncKey,
                     syncInfo.tokenserverURL,
                     error)
+            SyncCounts(counts.quarantined, counts.declined != 0.toByte())
         }
     }
 
@@ -47,7 +47,7 @@ internal interface PasswordSyncAdapter : Library {
                               access_token: String,
                               sync_key: String,
                               token_server_url: String,
-                              error: RustError.ByReference)
+                              error: RustError.ByReference): RawSyncCounts.ByValue
 
     fun sync15_passwords_wipe(state: RawLoginSyncState, error: RustError.ByReference)
     fun sync15_passwords_reset(state: RawLoginSyncState, error: RustError.ByReference)
@@ -0,0 +1,28 @@
+/* Copyright 2018 Mozilla
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+ * this file except in compliance with the License. You may obtain a copy of the
+ * License at http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software distributed
+ * under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+ * CONDITIONS OF ANY KIND, either express or implied. See the License for the
+ * specific language governing permissions and limitations under the License. */
+package org.mozilla.sync15.logins.rust
+
+import com.sun.jna.Structure
+import java.util.Arrays
+
+/**
+ * Mirrors `PasswordSyncCounts` on the rust side. This should be considered
+ * private, but it needs to be public for JNA.
+ */
+open class RawSyncCounts : Structure() {
+
+    class ByValue : RawSyncCounts(), Structure.ByValue
+
+    @JvmField var quarantined: Int = 0
+    @JvmField var declined: Byte = 0
+
+    override fun getFieldOrder(): List<String> {
+        return Arrays.asList("quarantined", "declined")
+    }
+}
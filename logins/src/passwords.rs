@@ -33,6 +33,8 @@
 //! `:sync.password/{metadata,password}Tx` markers are advanced, and the local change tracking moves
 //! forward.
 
+use std::collections::BTreeSet;
+
 use chrono::{
     TimeZone,
 };
@@ -95,6 +97,9 @@ use vocab::{
     SYNC_PASSWORD_TIME_PASSWORD_CHANGED,
     SYNC_PASSWORD_UUID,
     SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP,
+    SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP_MILLIS,
+    SYNC_PASSWORDS_QUARANTINED_UUID,
+    SYNC_PASSWORDS_SYNC_ID,
 };
 
 /// Fetch the Sync 1.5 password with given `uuid`, if one exists.
@@ -439,18 +444,45 @@ pub fn mark_synced_by_sync_uuids(in_progress: &mut InProgress, uuids: Vec<SyncGu
     in_progress.transact_builder(builder).map_err(|e| e.into()).and(Ok(()))
 }
 
-/// Return the last witnessed server timestamp, or `None` if such a timestamp has not been witnessed.
+/// Return the last witnessed server timestamp, in milliseconds, or `None` if such a timestamp has
+/// not been witnessed.
 ///
-/// The timestamp is returned as an `f64`, since that's what the Sync 1.5 service provides.
+/// Timestamps written before vocabulary version 3 were stored as float seconds; those are rounded
+/// to the nearest millisecond, which gives back exactly what the server sent.
 ///
 /// TODO: it would be better to store this as a string, or even as bytes, since it's just a token
 /// returned to the service.
-pub fn get_last_server_timestamp<Q>(queryable: &Q) -> Result<Option<f64>>
+pub fn get_last_server_timestamp<Q>(queryable: &Q) -> Result<Option<i64>>
+where Q: Queryable
+{
+    match get_last_server_timestamp_millis(queryable)? {
+        Some(t) => Ok(Some(t)),
+        None => Ok(get_legacy_last_server_timestamp(queryable)?.map(|t| (t * 1000.0).round() as i64)),
+    }
+}
+
+fn get_last_server_timestamp_millis<Q>(queryable: &Q) -> Result<Option<i64>>
 where Q: Queryable
 {
     // It's convenient to hang our last server timestamp off a known entity, and the attribute
     // entity itself is to hand... this is the kind of thing that could hang off a device identifier
     // instead.
+    let q = r#"[:find ?t . :where [:sync.passwords/lastServerTimestampMillis :sync.passwords/lastServerTimestampMillis ?t]]"#;
+
+    match queryable.q_once(q, None)?.into_scalar()? {
+        Some(Binding::Scalar(TypedValue::Long(t))) => Ok(Some(t)),
+        Some(other) => {
+            error!("Unexpected query result! {:?}", other);
+            bail!(Error::BadQueryResultType);
+        }
+        None => Ok(None),
+    }
+}
+
+fn get_legacy_last_server_timestamp<Q>(queryable: &Q) -> Result<Option<f64>>
+where Q: Queryable
+{
+    // See the comment in `get_last_server_timestamp_millis` for the choice of known entity.
     let q = r#"[:find ?t . :where [:sync.passwords/lastServerTimestamp :sync.passwords/lastServerTimestamp ?t]]"#;
 
     match queryable.q_once(q, None)?.into_scalar()? {
@@ -463,19 +495,102 @@ where Q: Queryable
     }
 }
 
-/// Set the last witnessed server timestamp.
-///
-/// The timestamp is stored as an `f64`, since that's what the Sync 1.5 service provides.
+/// Set the last witnessed server timestamp, in milliseconds.
 ///
 /// TODO: it would be better to store this as a string, or even as bytes, since it's just a token
 /// returned to the service.
-pub fn set_last_server_timestamp(in_progress: &mut InProgress, server_timestamp: f64) -> Result<TxReport> {
+pub fn set_last_server_timestamp(in_progress: &mut InProgress, server_timestamp: i64) -> Result<TxReport> {
+    let mut builder = TermBuilder::new();
+
+    // See the comment in `get_last_server_timestamp_millis` for the choice of known entity.
+    builder.add(SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP_MILLIS.clone(),
+                SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP_MILLIS.clone(),
+                TypedValue::Long(server_timestamp))?;
+
+    // Drop any float timestamp, so it can't shadow a later reset.
+    if let Some(t) = get_legacy_last_server_timestamp(in_progress)? {
+        builder.retract(SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP.clone(),
+                        SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP.clone(),
+                        TypedValue::Double(t.into()))?;
+    }
+
+    Ok(in_progress.transact_builder(builder)?)
+}
+
+/// Return the sync ID of the Sync 1.5 "passwords" engine (from `meta/global`) that we last synced
+/// against, or `None` if we haven't synced yet.
+pub fn get_sync_id<Q>(queryable: &Q) -> Result<Option<String>>
+where Q: Queryable
+{
+    // See the comment in `get_last_server_timestamp_millis` for the choice of known entity.
+    let q = r#"[:find ?id . :where [:sync.passwords/syncID :sync.passwords/syncID ?id]]"#;
+
+    match queryable.q_once(q, None)?.into_scalar()? {
+        Some(Binding::Scalar(TypedValue::String(id))) => Ok(Some((*id).clone())),
+        Some(other) => {
+            error!("Unexpected query result! {:?}", other);
+            bail!(Error::BadQueryResultType);
+        }
+        None => Ok(None),
+    }
+}
+
+/// Set the sync ID of the Sync 1.5 "passwords" engine that we're syncing against.
+pub fn set_sync_id(in_progress: &mut InProgress, sync_id: String) -> Result<TxReport> {
     let mut builder = TermBuilder::new();
 
-    // See the comment in `get_last_server_timestamp` for the choice of known entity.
-    builder.add(SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP.clone(),
-                SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP.clone(),
-                TypedValue::Double(server_timestamp.into()))?;
+    // See the comment in `get_last_server_timestamp_millis` for the choice of known entity.
+    builder.add(SYNC_PASSWORDS_SYNC_ID.clone(),
+                SYNC_PASSWORDS_SYNC_ID.clone(),
+                TypedValue::typed_string(sync_id))?;
+
+    Ok(in_progress.transact_builder(builder)?)
+}
+
+/// Return the `uuid`s of incoming Sync 1.5 password records that failed validation, so that they
+/// can be fetched again.
+pub fn get_quarantined_uuids<Q>(queryable: &Q) -> Result<Vec<SyncGuid>>
+where Q: Queryable
+{
+    // See the comment in `get_last_server_timestamp_millis` for the choice of known entity.
+    let q = r#"[:find
+                [?uuid ...]
+                :where
+                [:sync.passwords/quarantinedUUID :sync.passwords/quarantinedUUID ?uuid]
+                :order
+                ?uuid
+               ]"#;
+
+    let vs = queryable.q_once(q, None)?.into_coll()?;
+    vs.into_iter()
+        .map(|uuid| match uuid {
+            Binding::Scalar(TypedValue::String(uuid)) => Ok(SyncGuid((*uuid).clone())),
+            other => {
+                error!("Unexpected query result! {:?}", other);
+                bail!(Error::BadQueryResultType);
+            }
+        })
+        .collect()
+}
+
+/// Replace the set of quarantined Sync 1.5 password `uuid`s.
+pub fn set_quarantined_uuids(in_progress: &mut InProgress, uuids: Vec<SyncGuid>) -> Result<TxReport> {
+    let previous: BTreeSet<String> = get_quarantined_uuids(in_progress)?.into_iter().map(|x| x.0).collect();
+    let current: BTreeSet<String> = uuids.into_iter().map(|x| x.0).collect();
+
+    let mut builder = TermBuilder::new();
+
+    // See the comment in `get_last_server_timestamp_millis` for the choice of known entity.
+    for uuid in previous.difference(&current) {
+        builder.retract(SYNC_PASSWORDS_QUARANTINED_UUID.clone(),
+                        SYNC_PASSWORDS_QUARANTINED_UUID.clone(),
+                        TypedValue::typed_string(uuid))?;
+    }
+    for uuid in current.difference(&previous) {
+        builder.add(SYNC_PASSWORDS_QUARANTINED_UUID.clone(),
+                    SYNC_PASSWORDS_QUARANTINED_UUID.clone(),
+                    TypedValue::typed_string(uuid))?;
+    }
 
     Ok(in_progress.transact_builder(builder)?)
 }
@@ -520,7 +635,13 @@ pub fn reset_client(in_progress: &mut InProgress) -> Result<TxReport> {
     }
 
     // TODO: it would be nice to have https://github.com/mozilla/mentat/issues/631 here.
-    match get_last_server_timestamp(in_progress)? {
+    match get_last_server_timestamp_millis(in_progress)? {
+        Some(t) => builder.retract(SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP_MILLIS.clone(),
+                                   SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP_MILLIS.clone(),
+                                   TypedValue::Long(t))?,
+        None => {},
+    }
+    match get_legacy_last_server_timestamp(in_progress)? {
         Some(t) => builder.retract(SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP.clone(),
                                    SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP.clone(),
                                    TypedValue::Double(t.into()))?,
@@ -1280,6 +1401,41 @@ mod tests {
         // assert_eq!(t.into_vector().expect("vector").len(), 1); // Just the :db/txInstant.
     }
 
+    #[test]
+    fn test_sync_id() {
+        let mut store = testing_store();
+        let mut in_progress = store.begin_transaction().expect("begun successfully");
+
+        assert_eq!(get_sync_id(&in_progress).expect("to get"), None);
+
+        set_sync_id(&mut in_progress, "syncIDAAAAAA".into()).expect("to set");
+        assert_eq!(get_sync_id(&in_progress).expect("to get"), Some("syncIDAAAAAA".into()));
+
+        // The attribute isn't multi-valued, so setting it again replaces it.
+        set_sync_id(&mut in_progress, "syncIDBBBBBB".into()).expect("to set");
+        assert_eq!(get_sync_id(&in_progress).expect("to get"), Some("syncIDBBBBBB".into()));
+    }
+
+    #[test]
+    fn test_quarantined_uuids() {
+        let mut store = testing_store();
+        let mut in_progress = store.begin_transaction().expect("begun successfully");
+
+        assert_eq!(get_quarantined_uuids(&in_progress).expect("to get"), vec![]);
+
+        set_quarantined_uuids(&mut in_progress, vec!["{uuid-b}".into(), "{uuid-a}".into()]).expect("to set");
+        assert_eq!(get_quarantined_uuids(&in_progress).expect("to get"),
+                   vec!["{uuid-a}".into(), "{uuid-b}".into()]);
+
+        // Setting them again replaces the whole set.
+        set_quarantined_uuids(&mut in_progress, vec!["{uuid-b}".into(), "{uuid-c}".into()]).expect("to set");
+        assert_eq!(get_quarantined_uuids(&in_progress).expect("to get"),
+                   vec!["{uuid-b}".into(), "{uuid-c}".into()]);
+
+        set_quarantined_uuids(&mut in_progress, vec![]).expect("to set");
+        assert_eq!(get_quarantined_uuids(&in_progress).expect("to get"), vec![]);
+    }
+
     #[test]
     fn test_remote_evolved() {
         // Verify that when there are no local changes, applying a remote record that has evolved
@@ -336,19 +336,52 @@ lazy_static! {
         kw!(:sync.passwords/lastServerTimestamp)
     };
 
-    /// The vocabulary describing the last time the Sync 1.5 "passwords" collection was synced.
+    pub(crate) static ref SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP_MILLIS: Keyword = {
+        kw!(:sync.passwords/lastServerTimestampMillis)
+    };
+
+    pub(crate) static ref SYNC_PASSWORDS_QUARANTINED_UUID: Keyword = {
+        kw!(:sync.passwords/quarantinedUUID)
+    };
+
+    pub(crate) static ref SYNC_PASSWORDS_SYNC_ID: Keyword = {
+        kw!(:sync.passwords/syncID)
+    };
+
+    /// The vocabulary describing the last time the Sync 1.5 "passwords" collection was synced, the
+    /// incoming records that failed validation, and the engine sync ID it was synced against.
     ///
     /// Consumers should not use this vocabulary directly; it is here only to support Sync 1.5.
     pub(crate) static ref SYNC_PASSWORDS_VOCAB: vocabulary::Definition = {
         vocabulary::Definition {
             name: kw!(:org.mozilla/sync.passwords),
-            version: 1,
+            version: 4,
             attributes: vec![
+                // Float seconds; superseded by `lastServerTimestampMillis` in version 3, and only
+                // read to carry the timestamp over.
                 (SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP.clone(),
                  vocabulary::AttributeBuilder::helpful()
                  .value_type(ValueType::Double)
                  .multival(false)
                  .build()),
+                // Added in version 2.
+                (SYNC_PASSWORDS_QUARANTINED_UUID.clone(),
+                 vocabulary::AttributeBuilder::helpful()
+                 .value_type(ValueType::String)
+                 .multival(true)
+                 .build()),
+                // Added in version 3.
+                (SYNC_PASSWORDS_LAST_SERVER_TIMESTAMP_MILLIS.clone(),
+                 vocabulary::AttributeBuilder::helpful()
+                 .value_type(ValueType::Long)
+                 .multival(false)
+                 .build()),
+                // Added in version 4.
+                (SYNC_PASSWORDS_SYNC_ID.clone(),
+                 vocabulary::AttributeBuilder::helpful()
+                 .value_type(ValueType::String)
+                 .multival(false)
+                 .build()),
             ],
             pre: vocabulary::Definition::no_op,
             post: vocabulary::Definition::no_op,
@@ -0,0 +1,223 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Wrapper types for credentials and key material.
+//!
+//! A `Secret` zeroes its buffer when it's dropped, prints as `[REDACTED]`
+//! in `Debug` output, and doesn't implement `Display` or `Deref`, so the
+//! only way to get at the value is to call `expose()`. This keeps tokens and
+//! keys out of logs and crash reports even when the structs holding them are
+//! `{:?}`-printed.
+//!
+//! Note that `Serialize` writes the secret out in the clear, since we need
+//! that to persist account state.
+
+extern crate serde;
+
+use std::cmp;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ptr;
+use std::sync::atomic::{self, Ordering};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Data which can be held in a `Secret`.
+pub trait SecretData {
+    fn secret_bytes(&self) -> &[u8];
+
+    /// Overwrites the data, including any unused capacity, with zeroes.
+    fn zeroize(&mut self);
+}
+
+/// Zeroes `len` bytes starting at `p`. The volatile writes (and the fence)
+/// keep the compiler from eliding stores to memory that's about to be freed.
+unsafe fn zero_bytes(p: *mut u8, len: usize) {
+    for i in 0..len {
+        ptr::write_volatile(p.add(i), 0u8);
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+impl SecretData for Vec<u8> {
+    #[inline]
+    fn secret_bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn zeroize(&mut self) {
+        let capacity = self.capacity();
+        unsafe { zero_bytes(self.as_mut_ptr(), capacity) };
+        self.clear();
+    }
+}
+
+impl SecretData for String {
+    #[inline]
+    fn secret_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn zeroize(&mut self) {
+        // An empty string is valid UTF-8, and that's all we leave behind.
+        unsafe { self.as_mut_vec().zeroize() };
+    }
+}
+
+/// A value which is zeroed on drop and redacted when debug-printed.
+pub struct Secret<T: SecretData> {
+    value: T,
+}
+
+pub type SecretString = Secret<String>;
+pub type SecretBytes = Secret<Vec<u8>>;
+
+impl<T: SecretData> Secret<T> {
+    #[inline]
+    pub fn new(value: T) -> Secret<T> {
+        Secret { value }
+    }
+
+    /// Gives access to the secret value. Avoid copying the result into
+    /// values which aren't themselves `Secret`s.
+    #[inline]
+    pub fn expose(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: SecretData> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.value.zeroize();
+    }
+}
+
+impl<T: SecretData> From<T> for Secret<T> {
+    #[inline]
+    fn from(value: T) -> Secret<T> {
+        Secret::new(value)
+    }
+}
+
+impl<'a> From<&'a str> for SecretString {
+    #[inline]
+    fn from(value: &'a str) -> SecretString {
+        Secret::new(value.to_owned())
+    }
+}
+
+impl<'a> From<&'a [u8]> for SecretBytes {
+    #[inline]
+    fn from(value: &'a [u8]) -> SecretBytes {
+        Secret::new(value.to_vec())
+    }
+}
+
+impl<T: SecretData + Clone> Clone for Secret<T> {
+    #[inline]
+    fn clone(&self) -> Secret<T> {
+        Secret::new(self.value.clone())
+    }
+}
+
+impl<T: SecretData> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+/// Compares in constant time (for secrets of the same length).
+impl<T: SecretData> PartialEq for Secret<T> {
+    fn eq(&self, other: &Secret<T>) -> bool {
+        let a = self.value.secret_bytes();
+        let b = other.value.secret_bytes();
+        if a.len() != b.len() {
+            return false;
+        }
+        a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+}
+
+impl<T: SecretData> Eq for Secret<T> {}
+
+/// Orders by the secret's bytes. Unlike `eq`, this isn't constant time, so
+/// it's only for keeping secrets (and structs holding them) sorted.
+impl<T: SecretData> PartialOrd for Secret<T> {
+    #[inline]
+    fn partial_cmp(&self, other: &Secret<T>) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: SecretData> Ord for Secret<T> {
+    #[inline]
+    fn cmp(&self, other: &Secret<T>) -> cmp::Ordering {
+        self.value.secret_bytes().cmp(other.value.secret_bytes())
+    }
+}
+
+impl<T: SecretData + Hash> Hash for Secret<T> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.value.hash(state)
+    }
+}
+
+impl<T: SecretData + Serialize> Serialize for Secret<T> {
+    #[inline]
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(serializer)
+    }
+}
+
+impl<'de, T: SecretData + Deserialize<'de>> Deserialize<'de> for Secret<T> {
+    #[inline]
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Secret<T>, D::Error> {
+        T::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let s = SecretString::from("hunter2");
+        assert_eq!(format!("{:?}", s), "[REDACTED]");
+        assert_eq!(format!("{:?}", Some(SecretBytes::from(&b"key"[..]))), "Some([REDACTED])");
+        assert_eq!(s.expose(), "hunter2");
+    }
+
+    #[test]
+    fn test_zeroize() {
+        let mut v = Vec::with_capacity(16);
+        v.extend_from_slice(b"secret");
+        let p = v.as_ptr();
+        v.zeroize();
+        assert!(v.is_empty());
+        assert_eq!(v.capacity(), 16);
+        // The allocation is still alive, so we can check what's left in it.
+        let remains = unsafe { ::std::slice::from_raw_parts(p, 16) };
+        assert!(remains.iter().all(|&b| b == 0));
+
+        let mut s = String::from("secret");
+        s.zeroize();
+        assert_eq!(s, "");
+    }
+
+    #[test]
+    fn test_eq() {
+        assert_eq!(SecretString::from("abc"), SecretString::from("abc"));
+        assert_ne!(SecretString::from("abc"), SecretString::from("abd"));
+        assert_ne!(SecretString::from("abc"), SecretString::from("abcd"));
+    }
+
+    #[test]
+    fn test_ord() {
+        assert!(SecretString::from("abc") < SecretString::from("abd"));
+        assert!(SecretString::from("abc") < SecretString::from("abcd"));
+        assert_eq!(SecretString::from("abc").cmp(&SecretString::from("abc")), cmp::Ordering::Equal);
+    }
+}
@@ -26,22 +26,13 @@ use std::collections::HashMap;
 use std::borrow::Cow;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use fxa_client::{FirefoxAccount, Config, OAuthInfo};
+use fxa_client::{FirefoxAccount, Config, OAuthInfo, OLDSYNC_SCOPE};
 use sync::{error, ServerTimestamp, OutgoingChangeset, Payload, Store};
 
 const CLIENT_ID: &str = "3c8bd3fe92e1ddf1";
 const REDIRECT_URI: &str = "http://localhost:13131/oauth/complete";
-const SYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
 
 
-#[derive(Debug, Deserialize)]
-struct ScopedKeyData {
-    k: String,
-    kty: String,
-    kid: String,
-    scope: String,
-}
-
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PasswordRecord {
@@ -102,7 +93,7 @@ fn load_or_create_fxa_creds(cfg: Config) -> Result<FirefoxAccount, failure::Erro
 
 fn create_fxa_creds(cfg: Config) -> Result<FirefoxAccount, failure::Error> {
     let mut acct = FirefoxAccount::new(cfg, CLIENT_ID, REDIRECT_URI);
-    let oauth_uri = acct.begin_oauth_flow(&[SYNC_SCOPE], true)?;
+    let oauth_uri = acct.begin_oauth_flow(&[OLDSYNC_SCOPE], true)?;
     println!("Please visit this URL, sign in, and then copy-paste the final URL below.");
     println!("");
     println!("    {}", oauth_uri);
@@ -308,14 +299,14 @@ impl PasswordEngine {
     }
 
     pub fn reset(&mut self) -> Result<(), failure::Error> {
-        self.last_sync = 0.0.into();
+        self.last_sync = ServerTimestamp(0);
         self.changes.clear();
         self.save()?;
         Ok(())
     }
 
     pub fn wipe(&mut self) -> Result<(), failure::Error> {
-        self.last_sync = 0.0.into();
+        self.last_sync = ServerTimestamp(0);
         self.changes.clear();
         self.records.clear();
         self.save()?;
@@ -394,6 +385,16 @@ impl Store for PasswordEngine {
         self.save().map_err(sync::error::ErrorKind::StoreError)?;
         Ok(())
     }
+
+    fn reset(&mut self) -> sync::Result<()> {
+        let now = unix_time_ms();
+        for id in self.records.keys() {
+            self.changes.entry(id.clone()).or_insert(now);
+        }
+        self.last_sync = ServerTimestamp(0);
+        self.save().map_err(sync::error::ErrorKind::StoreError)?;
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -536,26 +537,26 @@ fn main() -> Result<(), failure::Error> {
 
     let mut acct = load_or_create_fxa_creds(cfg.clone())?;
     let token: OAuthInfo;
-    match acct.get_oauth_token(&[SYNC_SCOPE])? {
+    match acct.get_oauth_token(&[OLDSYNC_SCOPE])? {
         Some(t) => token = t,
         None => {
             // The cached credentials did not have appropriate scope, sign in again.
             println!("Credentials do not have appropriate scope, launching OAuth flow.");
             acct = create_fxa_creds(cfg.clone())?;
-            token = acct.get_oauth_token(&[SYNC_SCOPE])?.unwrap();
+            token = acct.get_oauth_token(&[OLDSYNC_SCOPE])?.unwrap();
         }
     }
-    let keys: HashMap<String, ScopedKeyData> = serde_json::from_str(&token.keys.unwrap())?;
-    let key = keys.get(SYNC_SCOPE).unwrap();
+    let key = acct.get_scoped_key(OLDSYNC_SCOPE)
+        .ok_or_else(|| failure::err_msg("No Sync key; sign in again"))?;
 
     let client = sync::Sync15StorageClient::new(sync::Sync15StorageClientInit {
-        key_id: key.kid.clone(),
+        key_id: key.key_id()?.to_string(),
         access_token: token.access_token.clone(),
         tokenserver_url,
     })?;
     let mut state = sync::GlobalState::default();
 
-    let root_sync_key = sync::KeyBundle::from_ksync_base64(&key.k)?;
+    let root_sync_key = sync::KeyBundle::from_ksync_bytes(key.ksync()?.expose())?;
 
     let mut state_machine = sync::SetupStateMachine::for_readonly_sync(&client, &root_sync_key);
     state = state_machine.to_ready(state)?;
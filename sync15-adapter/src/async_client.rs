@@ -0,0 +1,783 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::cell::Cell;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::vec;
+
+use futures::{future, Future};
+use futures::future::Loop;
+use hyper::{Method, StatusCode};
+use hyper::header::{Accept, ContentType, Headers};
+use reqwest::Url;
+use serde;
+use serde_json;
+
+use bso_record::{BsoRecord, EncryptedBso};
+use client::Sync15StorageClientInit;
+use error::{self, ErrorClass, ErrorKind};
+use http::{BoxFuture, HttpRequest, HttpResponse, HttpTransport};
+use record_types::MetaGlobalRecord;
+use retry::{self, RetryPolicy};
+use request::{self, BatchState, CollectionRequest, InfoCollections, InfoConfiguration,
+              NormalResponseHandler, PlannedPost, PostResponse, UploadInfo,
+              XIfUnmodifiedSince, XWeaveTimestamp};
+use telemetry::{RetryEvent, RetryReason, SyncTelemetry};
+use token;
+use util::ServerTimestamp;
+
+/// A storage client whose requests return futures, and which sends them
+/// using an arbitrary `HttpTransport`. `Sync15StorageClient` wraps this,
+/// blocking on each future.
+#[derive(Debug)]
+pub struct AsyncStorageClient<T> {
+    transport: T,
+    // We update this when we make requests
+    timestamp: Cell<ServerTimestamp>,
+    // Set when a response we didn't retry asked us to back off.
+    backoff: Cell<Option<SystemTime>>,
+    tsc: Arc<token::TokenProvider>,
+    retry_policy: RetryPolicy,
+    telemetry: Arc<SyncTelemetry>,
+}
+
+// The state threaded through the POSTs made by `upload_records`.
+struct UploadState {
+    posts: vec::IntoIter<PlannedPost>,
+    batch: BatchState,
+    last_modified: ServerTimestamp,
+    on_response: NormalResponseHandler,
+}
+
+impl<T: HttpTransport> AsyncStorageClient<T> {
+    pub fn new(init_params: Sync15StorageClientInit, transport: T) -> AsyncStorageClient<T> {
+        let tsc = token::TokenProvider::new(
+            init_params.tokenserver_url,
+            init_params.access_token,
+            init_params.key_id,
+        );
+        AsyncStorageClient::with_token_provider(Arc::new(tsc), transport)
+    }
+
+    /// Creates a client that shares `tsc` (and so its token) with other
+    /// clients, e.g. ones syncing other collections on other threads.
+    pub fn with_token_provider(tsc: Arc<token::TokenProvider>, transport: T) -> AsyncStorageClient<T> {
+        AsyncStorageClient {
+            transport,
+            timestamp: Cell::new(ServerTimestamp(0)),
+            backoff: Cell::new(None),
+            tsc,
+            retry_policy: RetryPolicy::default(),
+            telemetry: Arc::new(SyncTelemetry::new()),
+        }
+    }
+
+    /// Sets how requests that fail with network errors or 5xx responses are
+    /// retried. By default, we use `RetryPolicy::default()`.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> AsyncStorageClient<T> {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Records events (like retries) to `telemetry` instead of a telemetry
+    /// object of our own.
+    pub fn with_telemetry(mut self, telemetry: Arc<SyncTelemetry>) -> AsyncStorageClient<T> {
+        self.telemetry = telemetry;
+        self
+    }
+
+    #[inline]
+    pub fn token_provider(&self) -> &Arc<token::TokenProvider> {
+        &self.tsc
+    }
+
+    #[inline]
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    #[inline]
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    #[inline]
+    pub fn telemetry(&self) -> &Arc<SyncTelemetry> {
+        &self.telemetry
+    }
+
+    #[inline]
+    pub fn last_server_time(&self) -> ServerTimestamp {
+        self.timestamp.get()
+    }
+
+    /// When the server asked us to back off until, if it sent a backoff
+    /// header with a response we otherwise accepted. Until then, requests
+    /// fail with a `BackoffError`, and the next sync should wait.
+    #[inline]
+    pub fn backoff(&self) -> Option<SystemTime> {
+        self.backoff.get()
+    }
+
+    pub fn fetch_info_configuration(&self) -> BoxFuture<InfoConfiguration> {
+        self.fetch_info::<InfoConfiguration>("info/configuration")
+    }
+
+    pub fn fetch_info_collections(&self) -> BoxFuture<InfoCollections> {
+        self.fetch_info::<InfoCollections>("info/collections")
+    }
+
+    pub fn fetch_meta_global(&self) -> BoxFuture<BsoRecord<MetaGlobalRecord>> {
+        let request = self.relative_storage_request(Method::Get, "storage/meta/global");
+        Box::new(request.then(|result| -> error::Result<BsoRecord<MetaGlobalRecord>> {
+            let resp = match result {
+                Ok(r) => r,
+                Err(ref e) if e.is_not_found() => return Err(ErrorKind::NoMetaGlobal.into()),
+                Err(e) => return Err(e),
+            };
+            // Note: meta/global is not encrypted!
+            let meta_global: BsoRecord<MetaGlobalRecord> = resp.json()?;
+            info!("Meta global: {:?}", meta_global.payload);
+            Ok(meta_global)
+        }))
+    }
+
+    pub fn put_meta_global(&self, global: &BsoRecord<MetaGlobalRecord>) -> BoxFuture<()> {
+        self.put("storage/meta/global", None, global)
+    }
+
+    pub fn fetch_crypto_keys(&self) -> BoxFuture<EncryptedBso> {
+        Box::new(self.relative_storage_request(Method::Get, "storage/crypto/keys")
+                     .and_then(|resp| resp.json::<EncryptedBso>()))
+    }
+
+    pub fn put_crypto_keys(&self, keys: &EncryptedBso) -> BoxFuture<()> {
+        self.put("storage/crypto/keys", None, keys)
+    }
+
+    pub fn wipe_all_remote(&self) -> BoxFuture<()> {
+        Box::new(self.api_endpoint_url()
+            .and_then(move |url| self.make_storage_request(Method::Delete, url))
+            .then(|result| match result {
+                Ok(_) => Ok(()),
+                Err(ref e) if e.is_not_found() => Ok(()),
+                Err(e) => Err(e)
+            }))
+    }
+
+    pub fn get_encrypted_records(
+        &self,
+        collection: &str,
+        since: ServerTimestamp,
+    ) -> BoxFuture<Vec<EncryptedBso>> {
+        self.get_encrypted_records_for_request(
+            CollectionRequest::new(collection).full().newer_than(since))
+    }
+
+    /// Fetches the records matched by `request`, which should be `full()`.
+    pub fn get_encrypted_records_for_request(
+        &self,
+        request: &CollectionRequest,
+    ) -> BoxFuture<Vec<EncryptedBso>> {
+        let collection = request.collection.clone();
+        Box::new(self.collection_request(Method::Get, request)
+                     .and_then(|resp| resp.json::<Vec<EncryptedBso>>())
+                     .map(move |mut records| {
+                         // The server doesn't include the collection in each
+                         // record, but we want it for error reporting.
+                         for record in &mut records {
+                             if record.collection.is_empty() {
+                                 record.collection = collection.clone();
+                             }
+                         }
+                         records
+                     }))
+    }
+
+    /// Fetches specific records by id, regardless of when they were modified.
+    /// The server limits how many ids may be requested at once, so callers
+    /// with many ids should chunk them by `MAX_IDS_PER_REQUEST`.
+    pub fn get_encrypted_records_by_id(
+        &self,
+        collection: &str,
+        ids: &[String],
+    ) -> BoxFuture<Vec<EncryptedBso>> {
+        self.get_encrypted_records_for_request(
+            CollectionRequest::new(collection).full().ids(ids.to_vec()))
+    }
+
+    /// Makes a single POST to `collection`. Most callers want
+    /// `upload_records`, which splits records into POSTs and batches.
+    pub fn post_batch(
+        &self,
+        collection: &str,
+        body: Vec<u8>,
+        xius: ServerTimestamp,
+        batch: Option<String>,
+        commit: bool,
+    ) -> BoxFuture<PostResponse> {
+        let mut request = CollectionRequest::new(collection);
+        request.batch(batch).commit(commit);
+        Box::new(self.api_endpoint_url()
+            .and_then(move |base| request.build_url(base))
+            .and_then(move |url| self.build_request(Method::Post, url))
+            .and_then(move |req| {
+                let req = req.header(ContentType::json())
+                             .header(XIfUnmodifiedSince(xius))
+                             .body(body);
+                self.exec_request(req, false)
+            })
+            .and_then(|resp| PostResponse::from_response(&resp)))
+    }
+
+    /// Uploads `records` to `collection`, using as many POSTs (and batches)
+    /// as the server's limits require. POSTs are made one after another, as
+    /// each needs the batch id and timestamp from the response to the last.
+    ///
+    /// If `fully_atomic` is true, any failed record fails the whole upload.
+    /// Otherwise, the ids of records that failed (or were too large to
+    /// upload at all) are reported in the `UploadInfo`.
+    pub fn upload_records<'a>(
+        &'a self,
+        collection: &str,
+        config: &InfoConfiguration,
+        xius: ServerTimestamp,
+        records: &[EncryptedBso],
+        fully_atomic: bool,
+    ) -> BoxFuture<'a, UploadInfo> {
+        let (posts, mut too_large) = match request::plan_posts(config, records) {
+            Ok(planned) => planned,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        if fully_atomic && !too_large.is_empty() {
+            return Box::new(future::err(ErrorKind::RecordTooLargeError.into()));
+        }
+        let collection = collection.to_string();
+        let initial = UploadState {
+            posts: posts.into_iter(),
+            batch: BatchState::NoBatch,
+            last_modified: xius,
+            on_response: NormalResponseHandler::new(!fully_atomic),
+        };
+        let uploaded = future::loop_fn(initial, move |mut state| -> BoxFuture<'a, Loop<UploadState, UploadState>> {
+            let post = match state.posts.next() {
+                Some(post) => post,
+                None => return Box::new(future::ok(Loop::Break(state))),
+            };
+            let want_commit = post.commit;
+            let batch_id = state.batch.next_batch_id();
+            let is_commit = want_commit && batch_id.is_some();
+            let xius = state.last_modified;
+            Box::new(self.post_batch(&collection, post.body, xius, batch_id, is_commit)
+                .and_then(move |resp| -> error::Result<Loop<UploadState, UploadState>> {
+                    request::handle_post_response(&mut state.batch,
+                                                  &mut state.last_modified,
+                                                  &mut state.on_response,
+                                                  resp,
+                                                  want_commit)?;
+                    Ok(Loop::Continue(state))
+                }))
+        });
+        Box::new(uploaded.map(move |mut state| {
+            let mut info = state.on_response.upload_info(state.last_modified);
+            info.failed_ids.append(&mut too_large);
+            info
+        }))
+    }
+
+    fn api_endpoint_url(&self) -> BoxFuture<Url> {
+        Box::new(self.tsc.api_endpoint(&self.transport).and_then(|s| -> error::Result<Url> {
+            Ok(Url::parse(&s)?)
+        }))
+    }
+
+    fn build_request(&self, method: Method, url: Url) -> BoxFuture<HttpRequest> {
+        let req = HttpRequest::new(method, url).header(Accept::json());
+        let auth = self.tsc.authorization(&self.transport, &req);
+        Box::new(auth.map(move |header| req.header(header)))
+    }
+
+    fn relative_storage_request(&self, method: Method, relative_path: &str) -> BoxFuture<HttpResponse> {
+        let relative_path = relative_path.to_string();
+        Box::new(self.tsc.api_endpoint(&self.transport)
+            .and_then(move |s| -> error::Result<Url> {
+                Ok(Url::parse(&(s + "/"))?.join(&relative_path)?)
+            })
+            .and_then(move |url| self.make_storage_request(method, url)))
+    }
+
+    fn make_storage_request(&self, method: Method, url: Url) -> BoxFuture<HttpResponse> {
+        Box::new(self.build_request(method, url)
+                     .and_then(move |req| self.exec_request(req, true)))
+    }
+
+    // Sends `req`, retrying it according to our `RetryPolicy` if it's safe
+    // to do so.
+    fn exec_request<'a>(&'a self, req: HttpRequest, require_success: bool) -> BoxFuture<'a, HttpResponse> {
+        if let Some(until) = self.backoff.get() {
+            if until > SystemTime::now() {
+                return Box::new(future::err(ErrorKind::BackoffError(until).into()));
+            }
+        }
+        let idempotent = retry::is_idempotent(&req);
+        Box::new(future::loop_fn(1, move |attempt| -> BoxFuture<'a, Loop<HttpResponse, u32>> {
+            let can_retry = idempotent && attempt < self.retry_policy.max_attempts;
+            let method = req.method.clone();
+            let route = req.url.path().to_string();
+            Box::new(self.transport.execute(req.clone()).then(move |result| -> BoxFuture<'a, Loop<HttpResponse, u32>> {
+                let (reason, server_delay) = match result {
+                    Ok(resp) => {
+                        self.update_timestamp(&resp.headers);
+                        if !retry::is_retryable_status(resp.status) {
+                            return Box::new(future::result(
+                                self.check_response(resp, require_success).map(Loop::Break)));
+                        }
+                        let server_delay = retry::server_backoff(&resp.headers);
+                        if !can_retry {
+                            if let Some(delay) = server_delay {
+                                return Box::new(future::err(
+                                    ErrorKind::BackoffError(SystemTime::now() + delay).into()));
+                            }
+                            return Box::new(future::result(
+                                self.check_response(resp, require_success).map(Loop::Break)));
+                        }
+                        (RetryReason::Status(resp.status), server_delay)
+                    }
+                    Err(e) => {
+                        if !can_retry || e.class() != ErrorClass::Transient {
+                            return Box::new(future::err(e));
+                        }
+                        (RetryReason::Network(e.to_string()), None)
+                    }
+                };
+                let delay = match server_delay {
+                    Some(delay) if delay > self.retry_policy.max_delay => {
+                        return Box::new(future::err(
+                            ErrorKind::BackoffError(SystemTime::now() + delay).into()));
+                    }
+                    Some(delay) => delay,
+                    None => self.retry_policy.delay_for(attempt),
+                };
+                warn!("Storage request {} {} failed ({:?}); retrying in {:?}", method, route, reason, delay);
+                self.telemetry.record_retry(RetryEvent { method, route, attempt, delay, reason });
+                Box::new(self.transport.sleep(delay).map(move |_| Loop::Continue(attempt + 1)))
+            }))
+        }))
+    }
+
+    fn collection_request(&self, method: Method, r: &CollectionRequest) -> BoxFuture<HttpResponse> {
+        let r = r.clone();
+        Box::new(self.api_endpoint_url()
+            .and_then(move |base| r.build_url(base))
+            .and_then(move |url| self.make_storage_request(method, url)))
+    }
+
+    fn fetch_info<'a, D>(&'a self, path: &str) -> BoxFuture<'a, D>
+    where
+        for<'de> D: serde::de::Deserialize<'de>,
+        D: 'a,
+    {
+        Box::new(self.relative_storage_request(Method::Get, path)
+                     .and_then(|resp| resp.json::<D>()))
+    }
+
+    fn check_response(&self, resp: HttpResponse, require_success: bool) -> error::Result<HttpResponse> {
+        // Successful responses can ask us to back off, too. We still use the
+        // response, but don't make any more requests until the time is up.
+        if let Some(delay) = retry::server_backoff(&resp.headers) {
+            warn!("Server asked us to back off for {:?}", delay);
+            self.backoff.set(Some(SystemTime::now() + delay));
+        }
+
+        if resp.status == StatusCode::Unauthorized {
+            // Our token expired sooner than we thought, so get a new one
+            // next time.
+            self.tsc.drop_token();
+        }
+
+        if require_success && !resp.status.is_success() {
+            error!(
+                "HTTP error {} ({}) during storage request to {}",
+                resp.status.as_u16(),
+                resp.status,
+                resp.url.path()
+            );
+            return Err(ErrorKind::StorageHttpError {
+                code: resp.status,
+                route: resp.url.path().into(),
+            }.into());
+        }
+
+        // TODO:
+        // - x-weave-quota?
+        // - ... almost certainly other things too...
+
+        Ok(resp)
+    }
+
+    fn update_timestamp(&self, hs: &Headers) {
+        if let Some(ts) = hs.get::<XWeaveTimestamp>().map(|h| **h) {
+            self.timestamp.set(ts);
+        } else {
+            // Should we complain more here?
+            warn!("No X-Weave-Timestamp from storage server!");
+        }
+    }
+
+    fn put<B>(
+        &self,
+        relative_path: &str,
+        xius: Option<ServerTimestamp>,
+        body: &B,
+    ) -> BoxFuture<()>
+    where
+        B: serde::ser::Serialize,
+    {
+        let bytes = match serde_json::to_vec(body) {
+            Ok(bytes) => bytes,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        let relative_path = relative_path.to_string();
+
+        Box::new(self.tsc.api_endpoint(&self.transport)
+            .and_then(move |s| -> error::Result<Url> {
+                Ok(Url::parse(&(s + "/"))?.join(&relative_path)?)
+            })
+            .and_then(move |url| self.build_request(Method::Put, url))
+            .and_then(move |req| {
+                let mut req = req.header(ContentType::json()).body(bytes);
+                if let Some(ts) = xius {
+                    req = req.header(XIfUnmodifiedSince(ts));
+                }
+                self.exec_request(req, true)
+            })
+            .map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use client::{SetupStorageClient, Sync15StorageClient};
+    use key_bundle::KeyBundle;
+    use bso_record::Payload;
+    use mock_server::{init, MockServer};
+    use request::XWeaveBackoff;
+    use token::RetryAfter;
+
+    fn encrypted_records(ids: &[&str]) -> Vec<EncryptedBso> {
+        let key = KeyBundle::new_random().unwrap();
+        ids.iter().map(|id| {
+            Payload::from_json(json!({ "id": id, "value": 1 })).unwrap()
+                .into_bso("passwords".into())
+                .encrypt(&key).unwrap()
+        }).collect()
+    }
+
+    fn records_server() -> MockServer {
+        MockServer::new(|req| {
+            match req.url.path() {
+                "/1.5/12345/info/collections" => {
+                    (StatusCode::Ok, json!({ "passwords": 1234.5 }).to_string())
+                }
+                "/1.5/12345/storage/passwords" => {
+                    let records = encrypted_records(&["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+                    (StatusCode::Ok, serde_json::to_string(&records).unwrap())
+                }
+                "/1.5/12345/storage/meta/global" => {
+                    (StatusCode::NotFound, "0".into())
+                }
+                _ => (StatusCode::InternalServerError, "".into())
+            }
+        })
+    }
+
+    // The second POST commits the batch created by the first.
+    fn batch_server() -> MockServer {
+        MockServer::new(|req| {
+            match req.url.query() {
+                Some("batch=true") => {
+                    (StatusCode::Accepted, json!({
+                        "batch": "b1",
+                        "success": ["aaaaaaaaaaaa", "bbbbbbbbbbbb"],
+                        "failed": {},
+                    }).to_string())
+                }
+                Some("batch=b1&commit=true") => {
+                    (StatusCode::Ok, json!({
+                        "success": ["cccccccccccc"],
+                        "failed": {},
+                    }).to_string())
+                }
+                _ => (StatusCode::BadRequest, "".into())
+            }
+        })
+    }
+
+    fn small_posts() -> InfoConfiguration {
+        InfoConfiguration {
+            max_post_records: 2,
+            .. InfoConfiguration::default()
+        }
+    }
+
+    #[test]
+    fn test_fetch_records_async() {
+        let server = records_server();
+        let client = AsyncStorageClient::new(init(), &server);
+
+        let collections = client.fetch_info_collections().wait().unwrap();
+        assert_eq!(collections.get("passwords"), Some(&ServerTimestamp(1_234_500)));
+        assert_eq!(client.last_server_time(), ServerTimestamp(1_234_500));
+
+        let records = client.get_encrypted_records("passwords", ServerTimestamp(1_000_000))
+                            .wait().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].id, "bbbbbbbbbbbb");
+        assert_eq!(records[1].collection, "passwords");
+
+        match client.fetch_meta_global().wait() {
+            Err(ref e) => match e.kind() {
+                ErrorKind::NoMetaGlobal => {},
+                _ => panic!("Wrong error: {}", e),
+            },
+            Ok(_) => panic!("Should not have found meta/global"),
+        }
+
+        assert_eq!(server.storage_requests(), vec![
+            "GET /1.5/12345/info/collections",
+            "GET /1.5/12345/storage/passwords?full=1&newer=1000.00",
+            "GET /1.5/12345/storage/meta/global",
+        ]);
+    }
+
+    #[test]
+    fn test_fetch_records_blocking() {
+        let server = records_server();
+        let client = Sync15StorageClient::with_transport(init(), &server);
+
+        let collections = client.fetch_info_collections().unwrap();
+        assert_eq!(collections.get("passwords"), Some(&ServerTimestamp(1_234_500)));
+
+        let records = client.get_encrypted_records("passwords", ServerTimestamp(1_000_000)).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id, "aaaaaaaaaaaa");
+
+        assert!(client.fetch_meta_global().is_err());
+
+        assert_eq!(server.storage_requests(), vec![
+            "GET /1.5/12345/info/collections",
+            "GET /1.5/12345/storage/passwords?full=1&newer=1000.00",
+            "GET /1.5/12345/storage/meta/global",
+        ]);
+    }
+
+    #[test]
+    fn test_upload_async() {
+        let server = batch_server();
+        let client = AsyncStorageClient::new(init(), &server);
+        let records = encrypted_records(&["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"]);
+
+        let info = client.upload_records("passwords", &small_posts(), ServerTimestamp(1_000_000),
+                                         &records, true).wait().unwrap();
+        assert_eq!(info.successful_ids, vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"]);
+        assert!(info.failed_ids.is_empty());
+        assert_eq!(info.modified_timestamp, ServerTimestamp(1_234_500));
+
+        assert_eq!(server.storage_requests(), vec![
+            "POST /1.5/12345/storage/passwords?batch=true",
+            "POST /1.5/12345/storage/passwords?batch=b1&commit=true",
+        ]);
+    }
+
+    #[test]
+    fn test_upload_blocking() {
+        let server = batch_server();
+        let client = Sync15StorageClient::with_transport(init(), &server);
+        let records = encrypted_records(&["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"]);
+
+        let mut queue = client.new_post_queue("passwords", &small_posts(), ServerTimestamp(1_000_000),
+                                              NormalResponseHandler::new(false)).unwrap();
+        for record in &records {
+            assert!(queue.enqueue(record).unwrap());
+        }
+        queue.flush(true).unwrap();
+        let info = queue.completed_upload_info();
+        assert_eq!(info.successful_ids, vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"]);
+        assert_eq!(info.modified_timestamp, ServerTimestamp(1_234_500));
+
+        assert_eq!(server.storage_requests(), vec![
+            "POST /1.5/12345/storage/passwords?batch=true",
+            "POST /1.5/12345/storage/passwords?batch=b1&commit=true",
+        ]);
+    }
+
+    // Fails the first `failures` requests to each path with a 503.
+    fn flaky_server(failures: usize) -> MockServer {
+        let seen = Mutex::new(vec![]);
+        MockServer::new(move |req| {
+            let mut seen = seen.lock().unwrap();
+            seen.push(req.url.path().to_string());
+            if seen.iter().filter(|path| *path == req.url.path()).count() <= failures {
+                return (StatusCode::ServiceUnavailable, "".into());
+            }
+            match req.url.query() {
+                Some("batch=true") => (StatusCode::Accepted, json!({
+                    "batch": "b1",
+                    "success": ["aaaaaaaaaaaa"],
+                    "failed": {},
+                }).to_string()),
+                _ => (StatusCode::Ok, json!({ "passwords": 1234.5 }).to_string()),
+            }
+        })
+    }
+
+    fn no_jitter() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_retry_transient_failures() {
+        let server = flaky_server(2);
+        let client = Sync15StorageClient::with_transport(init(), &server)
+            .with_retry_policy(no_jitter());
+
+        let collections = client.fetch_info_collections().unwrap();
+        assert_eq!(collections.get("passwords"), Some(&ServerTimestamp(1_234_500)));
+        assert_eq!(server.storage_requests().len(), 3);
+        assert_eq!(server.sleeps(), vec![Duration::from_millis(100),
+                                                 Duration::from_millis(200)]);
+
+        let retries = client.telemetry().take_retries();
+        assert_eq!(retries.len(), 2);
+        assert_eq!(retries[1], RetryEvent {
+            method: Method::Get,
+            route: "/1.5/12345/info/collections".into(),
+            attempt: 2,
+            delay: Duration::from_millis(200),
+            reason: RetryReason::Status(StatusCode::ServiceUnavailable),
+        });
+        assert!(client.telemetry().retries().is_empty());
+    }
+
+    #[test]
+    fn test_retry_gives_up() {
+        let server = flaky_server(3);
+        let client = Sync15StorageClient::with_transport(init(), &server)
+            .with_retry_policy(no_jitter());
+
+        let err = client.fetch_info_collections().unwrap_err();
+        match err.kind() {
+            ErrorKind::StorageHttpError { code: StatusCode::ServiceUnavailable, .. } => {},
+            _ => panic!("Wrong error: {}", err),
+        }
+        assert_eq!(server.storage_requests().len(), 3);
+        assert_eq!(client.telemetry().retries().len(), 2);
+
+        let server = flaky_server(1);
+        let client = Sync15StorageClient::with_transport(init(), &server)
+            .with_retry_policy(RetryPolicy::never());
+        assert!(client.fetch_info_collections().is_err());
+        assert_eq!(server.storage_requests().len(), 1);
+    }
+
+    #[test]
+    fn test_retry_only_idempotent_posts() {
+        let server = flaky_server(1);
+        let client = AsyncStorageClient::new(init(), &server).with_retry_policy(no_jitter());
+        let body = b"[]".to_vec();
+
+        // Not part of a batch, so the 503 is handed back to the caller.
+        assert!(client.post_batch("passwords", body.clone(), ServerTimestamp(1_000_000), None, false)
+                      .wait().is_err());
+
+        let resp = client.post_batch("bookmarks", body, ServerTimestamp(1_000_000),
+                                     Some("true".into()), false).wait().unwrap();
+        assert_eq!(resp.status, StatusCode::Accepted);
+
+        assert_eq!(server.storage_requests(), vec![
+            "POST /1.5/12345/storage/passwords",
+            "POST /1.5/12345/storage/bookmarks?batch=true",
+            "POST /1.5/12345/storage/bookmarks?batch=true",
+        ]);
+        assert_eq!(client.telemetry().retries().len(), 1);
+    }
+
+    #[test]
+    fn test_retry_respects_server_backoff() {
+        let attempts = Mutex::new(0);
+        let server = MockServer::with_headers(move |_, headers| {
+            let mut attempts = attempts.lock().unwrap();
+            *attempts += 1;
+            match *attempts {
+                1 => headers.set(RetryAfter(2.0)),
+                _ => headers.set(XWeaveBackoff(3600)),
+            }
+            (StatusCode::ServiceUnavailable, "".into())
+        });
+        let client = Sync15StorageClient::with_transport(init(), &server)
+            .with_retry_policy(no_jitter());
+
+        // We wait as long as the server asks for the first retry, but give
+        // up rather than wait an hour for the second.
+        let err = client.fetch_info_collections().unwrap_err();
+        assert_eq!(err.class(), ErrorClass::Backoff);
+        assert!(err.retry_delay().unwrap() > Duration::from_secs(3500));
+        assert_eq!(server.sleeps(), vec![Duration::from_secs(2)]);
+        assert_eq!(server.storage_requests().len(), 2);
+    }
+
+    #[test]
+    fn test_unauthorized_drops_token() {
+        let attempts = Mutex::new(0);
+        let server = MockServer::new(move |_| {
+            let mut attempts = attempts.lock().unwrap();
+            *attempts += 1;
+            match *attempts {
+                1 => (StatusCode::Unauthorized, "".into()),
+                _ => (StatusCode::Ok, json!({ "passwords": 1234.5 }).to_string()),
+            }
+        });
+        let client = Sync15StorageClient::with_transport(init(), &server)
+            .with_retry_policy(no_jitter());
+
+        // The token was rejected, which the next try fixes by fetching a new
+        // one, rather than by having the user sign in again.
+        let err = client.fetch_info_collections().unwrap_err();
+        assert_eq!(err.class(), ErrorClass::Transient);
+        assert_eq!(server.token_requests(), 1);
+        assert!(client.fetch_info_collections().is_ok());
+        assert_eq!(server.token_requests(), 2);
+        assert_eq!(server.storage_requests().len(), 2);
+    }
+
+    #[test]
+    fn test_backoff_on_success() {
+        let server = MockServer::with_headers(|_, headers| {
+            headers.set(XWeaveBackoff(600));
+            (StatusCode::Ok, json!({ "passwords": 1234.5 }).to_string())
+        });
+        let client = Sync15StorageClient::with_transport(init(), &server)
+            .with_retry_policy(no_jitter());
+
+        // We keep the response, but don't make another request.
+        let collections = client.fetch_info_collections().unwrap();
+        assert_eq!(collections.get("passwords"), Some(&ServerTimestamp(1_234_500)));
+        let until = client.backoff().unwrap();
+        assert!(until > SystemTime::now() + Duration::from_secs(500));
+
+        let err = client.fetch_info_collections().unwrap_err();
+        assert_eq!(err.class(), ErrorClass::Backoff);
+        assert_eq!(server.storage_requests().len(), 1);
+        assert!(server.sleeps().is_empty());
+    }
+}
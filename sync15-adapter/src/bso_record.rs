@@ -5,7 +5,7 @@
 use serde::de::{Deserialize, DeserializeOwned};
 use serde::ser::Serialize;
 use serde_json::{self, Value as JsonValue, Map};
-use error;
+use error::{self, DecryptFailure, ErrorKind};
 use base64;
 use std::ops::{Deref, DerefMut};
 use std::convert::From;
@@ -180,7 +180,7 @@ impl Payload {
         CleartextBso {
             id,
             collection,
-            modified: 0.0.into(), // Doesn't matter.
+            modified: ServerTimestamp(0), // Doesn't matter.
             sortindex: None, // Should we let consumer's set this?
             ttl: None, // Should we let consumer's set this?
             payload: self,
@@ -267,16 +267,41 @@ impl EncryptedPayload {
 }
 
 impl EncryptedBso {
-    pub fn decrypt(self, key: &KeyBundle) -> error::Result<CleartextBso> {
+    fn decrypt_error(&self, reason: DecryptFailure) -> error::Error {
+        ErrorKind::DecryptionError {
+            id: self.id.clone(),
+            collection: self.collection.clone(),
+            reason,
+        }.into()
+    }
+
+    /// Verify the HMAC and decrypt the payload, without parsing the resulting JSON.
+    /// Failures are reported as `ErrorKind::DecryptionError`s.
+    pub fn decrypt_cleartext(&self, key: &KeyBundle) -> error::Result<String> {
         if !key.verify_hmac_string(&self.payload.hmac, &self.payload.ciphertext)? {
-            return Err(error::ErrorKind::HmacMismatch.into());
+            return Err(self.decrypt_error(DecryptFailure::HmacMismatch));
         }
 
-        let iv = base64::decode(&self.payload.iv)?;
-        let ciphertext = base64::decode(&self.payload.ciphertext)?;
-        let cleartext = key.decrypt(&ciphertext, &iv)?;
+        let iv = base64::decode(&self.payload.iv)
+            .map_err(|_| self.decrypt_error(DecryptFailure::BadBase64))?;
+        if iv.len() != 16 {
+            return Err(self.decrypt_error(DecryptFailure::BadIvLength(iv.len())));
+        }
+        let ciphertext = base64::decode(&self.payload.ciphertext)
+            .map_err(|_| self.decrypt_error(DecryptFailure::BadBase64))?;
+        // With a good key and IV, the only way decryption can fail is if the
+        // ciphertext isn't a whole number of blocks, or the padding is bad.
+        let cleartext_bytes = key.decrypt_bytes(&ciphertext, &iv)
+            .map_err(|_| self.decrypt_error(DecryptFailure::BadPadding))?;
+        String::from_utf8(cleartext_bytes)
+            .map_err(|_| self.decrypt_error(DecryptFailure::BadUtf8))
+    }
+
+    pub fn decrypt(self, key: &KeyBundle) -> error::Result<CleartextBso> {
+        let cleartext = self.decrypt_cleartext(key)?;
 
-        let new_payload = serde_json::from_str(&cleartext)?;
+        let new_payload = serde_json::from_str(&cleartext)
+            .map_err(|_| self.decrypt_error(DecryptFailure::BadJson))?;
 
         let result = self.with_payload(new_payload);
         Ok(result)
@@ -324,7 +349,7 @@ mod tests {
         let record: BsoRecord<EncryptedPayload> = serde_json::from_str(serialized).unwrap();
         assert_eq!(&record.id, "1234");
         assert_eq!(&record.collection, "passwords");
-        assert_eq!(record.modified.0, 12344321.0);
+        assert_eq!(record.modified, ServerTimestamp(12_344_321_000));
         assert_eq!(&record.payload.iv, "aaaaa");
         assert_eq!(&record.payload.hmac, "bbbbb");
         assert_eq!(&record.payload.ciphertext, "ccccc");
@@ -335,7 +360,7 @@ mod tests {
         let goal = r#"{"id":"1234","collection":"passwords","payload":"{\"IV\":\"aaaaa\",\"hmac\":\"bbbbb\",\"ciphertext\":\"ccccc\"}"}"#;
         let record = BsoRecord {
             id: "1234".into(),
-            modified: ServerTimestamp(999.0), // shouldn't be serialized by client no matter what it's value is
+            modified: ServerTimestamp(999_000), // shouldn't be serialized by client no matter what it's value is
             collection: "passwords".into(),
             sortindex: None,
             ttl: None,
@@ -409,5 +434,58 @@ mod tests {
         assert_eq!(serde_json::to_value(decrypted.payload).unwrap(), payload);
     }
 
+    fn raw_record(iv: &[u8], ciphertext: &[u8], key: &KeyBundle) -> EncryptedBso {
+        let ciphertext = base64::encode(ciphertext);
+        let hmac = key.hmac_string(ciphertext.as_bytes()).unwrap();
+        BsoRecord::new_record("record".into(), "passwords".into(), EncryptedPayload {
+            iv: base64::encode(iv),
+            hmac,
+            ciphertext,
+        })
+    }
+
+    fn encrypt_raw(cleartext: &[u8], key: &KeyBundle) -> EncryptedBso {
+        let iv = [7u8; 16];
+        let ciphertext = key.encrypt_bytes_with_iv(cleartext, &iv).unwrap();
+        raw_record(&iv, &ciphertext, key)
+    }
 
+    fn decrypt_failure(record: EncryptedBso, key: &KeyBundle) -> DecryptFailure {
+        match *record.decrypt(key).unwrap_err().kind() {
+            ErrorKind::DecryptionError { ref id, ref collection, reason } => {
+                assert_eq!(id, "record");
+                assert_eq!(collection, "passwords");
+                reason
+            }
+            ref other => panic!("Expected a decryption error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_failures() {
+        let key = KeyBundle::new_random().unwrap();
+
+        let other_key = KeyBundle::new_random().unwrap();
+        let failure = decrypt_failure(encrypt_raw(b"{\"id\":\"record\"}", &key), &other_key);
+        assert_eq!(failure, DecryptFailure::HmacMismatch);
+        assert!(failure.is_wrong_key());
+
+        let mut bad_iv = encrypt_raw(b"{}", &key);
+        bad_iv.payload.iv = "not base64!".into();
+        assert_eq!(decrypt_failure(bad_iv, &key), DecryptFailure::BadBase64);
+
+        let short_iv = raw_record(&[0u8; 8], &[0u8; 16], &key);
+        assert_eq!(decrypt_failure(short_iv, &key), DecryptFailure::BadIvLength(8));
+
+        let truncated = raw_record(&[0u8; 16], &[0u8; 15], &key);
+        assert_eq!(decrypt_failure(truncated, &key), DecryptFailure::BadPadding);
+
+        let not_utf8 = encrypt_raw(&[0xff, 0xfe, 0xfd], &key);
+        let failure = decrypt_failure(not_utf8, &key);
+        assert_eq!(failure, DecryptFailure::BadUtf8);
+        assert!(!failure.is_wrong_key());
+
+        let not_json = encrypt_raw(b"{\"id\": ", &key);
+        assert_eq!(decrypt_failure(not_json, &key), DecryptFailure::BadJson);
+    }
 }
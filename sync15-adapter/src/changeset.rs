@@ -5,10 +5,13 @@
 use bso_record::{EncryptedBso, Payload};
 use client::Sync15StorageClient;
 use error::{self, ErrorKind, Result};
+use http::HttpTransport;
 use key_bundle::KeyBundle;
-use request::{NormalResponseHandler, UploadInfo};
+use policy::{CollectionPolicy, OutgoingPolicy};
+use request::{CollectionRequest, NormalResponseHandler, UploadInfo, MAX_IDS_PER_REQUEST};
 use state::GlobalState;
 use util::ServerTimestamp;
+use validation::{self, QuarantinedRecord, RecordSchema, ValidatedRecord};
 
 #[derive(Debug, Clone)]
 pub struct RecordChangeset<Payload> {
@@ -40,6 +43,16 @@ impl<T> RecordChangeset<T> {
 
 impl OutgoingChangeset {
     pub fn encrypt(self, key: &KeyBundle) -> Result<Vec<EncryptedBso>> {
+        self.encrypt_with_policy(key, &CollectionPolicy::default())
+    }
+
+    /// Encrypts the changes, using `policy` to set each record's `ttl` and
+    /// `sortindex`.
+    pub fn encrypt_with_policy(
+        self,
+        key: &KeyBundle,
+        policy: &OutgoingPolicy,
+    ) -> Result<Vec<EncryptedBso>> {
         let RecordChangeset {
             changes,
             collection,
@@ -47,7 +60,14 @@ impl OutgoingChangeset {
         } = self;
         changes
             .into_iter()
-            .map(|change| change.into_bso(collection.clone()).encrypt(key))
+            .map(|change| {
+                let ttl = policy.ttl(&change);
+                let sortindex = policy.sortindex(&change);
+                let mut bso = change.into_bso(collection.clone());
+                bso.ttl = ttl;
+                bso.sortindex = sortindex;
+                bso.encrypt(key)
+            })
             .collect()
     }
 
@@ -80,6 +100,73 @@ impl IncomingChangeset {
         }
         Ok(result)
     }
+
+    /// Like `fetch`, but records that fail to parse, or don't match `schema`,
+    /// are quarantined instead of failing the whole fetch. `request` should
+    /// be a `full()` request, and may specify an order, e.g. `sort=index` to
+    /// receive the highest priority records first.
+    ///
+    /// Records in `retry_ids` (typically ones we quarantined during a previous
+    /// sync) are fetched again even if they haven't changed since the request's
+    /// `newer` timestamp, and are either included in the changeset or
+    /// quarantined again.
+    pub fn fetch_validated(
+        client: &Sync15StorageClient,
+        state: &GlobalState,
+        request: &CollectionRequest,
+        schema: Option<&RecordSchema>,
+        retry_ids: &[String],
+    ) -> Result<(IncomingChangeset, Vec<QuarantinedRecord>)> {
+        let records = fetch_with_retries(client, request, retry_ids)?;
+        let collection = request.collection.clone();
+        let timestamp = state.last_modified_or_zero(&collection);
+        let key = state.key_for_collection(&collection)?;
+        IncomingChangeset::decrypt_validated(collection, timestamp, key, records, schema)
+    }
+
+    /// Decrypts and validates records that have already been fetched, e.g.
+    /// by `fetch_collections`. `timestamp` is the collection's last modified
+    /// time from `info/collections`.
+    pub fn decrypt_validated(
+        collection: String,
+        timestamp: ServerTimestamp,
+        key: &KeyBundle,
+        records: Vec<EncryptedBso>,
+        schema: Option<&RecordSchema>,
+    ) -> Result<(IncomingChangeset, Vec<QuarantinedRecord>)> {
+        let mut result = IncomingChangeset::new(collection, timestamp);
+        let mut quarantined = vec![];
+        result.changes.reserve(records.len());
+        for record in records {
+            match validation::decrypt_and_validate(record, key, schema)? {
+                ValidatedRecord::Valid(payload, modified) => result.changes.push((payload, modified)),
+                ValidatedRecord::Quarantined(record) => quarantined.push(record),
+            }
+        }
+        Ok((result, quarantined))
+    }
+}
+
+/// Fetches the records matched by `request`, and any in `retry_ids` that
+/// it didn't match.
+pub(crate) fn fetch_with_retries<T: HttpTransport>(
+    client: &Sync15StorageClient<T>,
+    request: &CollectionRequest,
+    retry_ids: &[String],
+) -> Result<Vec<EncryptedBso>> {
+    let mut records = client.get_encrypted_records_for_request(request)?;
+    let to_retry: Vec<String> = retry_ids
+        .iter()
+        .filter(|id| !records.iter().any(|record| &record.id == *id))
+        .cloned()
+        .collect();
+    if !to_retry.is_empty() {
+        info!("Retrying {} quarantined records in {}", to_retry.len(), request.collection);
+        for ids in to_retry.chunks(MAX_IDS_PER_REQUEST) {
+            records.extend(client.get_encrypted_records_by_id(&request.collection, ids)?);
+        }
+    }
+    Ok(records)
 }
 
 #[derive(Debug, Clone)]
@@ -116,6 +203,17 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
         state: &'b GlobalState,
         changeset: OutgoingChangeset,
         fully_atomic: bool,
+    ) -> Result<CollectionUpdate<'a, 'b>> {
+        CollectionUpdate::new_from_changeset_with_policy(
+            client, state, changeset, &CollectionPolicy::default(), fully_atomic)
+    }
+
+    pub fn new_from_changeset_with_policy(
+        client: &'a Sync15StorageClient,
+        state: &'b GlobalState,
+        changeset: OutgoingChangeset,
+        policy: &OutgoingPolicy,
+        fully_atomic: bool,
     ) -> Result<CollectionUpdate<'a, 'b>> {
         let collection = changeset.collection.clone();
         let key_bundle = state.key_for_collection(&collection)?;
@@ -124,7 +222,7 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
             // Not actually interrupted, but we know we'd fail the XIUS check.
             return Err(ErrorKind::BatchInterrupted.into());
         }
-        let to_update = changeset.encrypt(&key_bundle)?;
+        let to_update = changeset.encrypt_with_policy(&key_bundle, policy)?;
         Ok(CollectionUpdate::new(
             client,
             state,
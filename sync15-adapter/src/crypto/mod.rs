@@ -0,0 +1,178 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The cryptographic primitives Sync needs: AES-256-CBC, HMAC-SHA256 and
+//! random bytes. These are implemented by one of two backends, chosen at
+//! build time:
+//!
+//! - `crypto-openssl` (the default) uses OpenSSL.
+//! - `crypto-rust` uses ring and the RustCrypto crates, which avoids having to
+//!   cross-compile OpenSSL for mobile platforms. If both features are
+//!   enabled, this backend is used.
+//!
+//! Note that our HTTP and Hawk dependencies may still link OpenSSL.
+
+use error::Result;
+
+#[cfg(feature = "crypto-openssl")]
+mod openssl_backend;
+#[cfg(feature = "crypto-rust")]
+mod rust_backend;
+
+#[cfg(feature = "crypto-openssl")]
+pub use self::openssl_backend::OpensslBackend;
+#[cfg(feature = "crypto-rust")]
+pub use self::rust_backend::RustBackend;
+
+#[cfg(feature = "crypto-rust")]
+pub type Backend = RustBackend;
+#[cfg(all(feature = "crypto-openssl", not(feature = "crypto-rust")))]
+pub type Backend = OpensslBackend;
+
+#[cfg(not(any(feature = "crypto-openssl", feature = "crypto-rust")))]
+compile_error!("Either the `crypto-openssl` or the `crypto-rust` feature must be enabled");
+
+/// A set of implementations of the primitives we need. Both backends must
+/// produce identical output for identical input.
+pub trait CryptoBackend {
+    /// Fills `buf` with cryptographically secure random bytes.
+    fn rand_bytes(buf: &mut [u8]) -> Result<()>;
+
+    fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<[u8; 32]>;
+
+    /// Encrypts `cleartext` with PKCS#7 padding.
+    fn aes_256_cbc_encrypt(key: &[u8], iv: &[u8], cleartext: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decrypts `ciphertext`, removing its PKCS#7 padding.
+    fn aes_256_cbc_decrypt(key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>>;
+
+    /// Compares `a` and `b` in time that depends only on their lengths.
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool;
+}
+
+#[inline]
+pub fn rand_bytes(buf: &mut [u8]) -> Result<()> {
+    Backend::rand_bytes(buf)
+}
+
+#[inline]
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<[u8; 32]> {
+    Backend::hmac_sha256(key, data)
+}
+
+#[inline]
+pub fn aes_256_cbc_encrypt(key: &[u8], iv: &[u8], cleartext: &[u8]) -> Result<Vec<u8>> {
+    Backend::aes_256_cbc_encrypt(key, iv, cleartext)
+}
+
+#[inline]
+pub fn aes_256_cbc_decrypt(key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    Backend::aes_256_cbc_decrypt(key, iv, ciphertext)
+}
+
+#[inline]
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    Backend::constant_time_eq(a, b)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use base16;
+
+    // (key, iv, cleartext, ciphertext), all hex encoded.
+    static AES_VECTORS: &'static [(&'static str, &'static str, &'static str, &'static str)] = &[
+        // Empty input is a single block of padding.
+        ("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+         "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+         "",
+         "edaf9e57d045ac857f023f9dc238b14e"),
+        // Exactly one block, so padding adds a second.
+        ("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+         "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+         "30313233343536373839616263646566",
+         "cd951146cc74046a56c93a30e4a7cd50e5644b147420cb090229d6c7b90ed616"),
+        ("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+         "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+         "7b226964223a226162636465666768696a6b6c222c2264656c65746564223a747275657d",
+         "45b891deb3d4c9fa9f4c5c72ede5aa90aa3ae21d56be5840e91479de4033fb8a\
+          d7c4a992dc46fe05e7942e82470be3bc"),
+    ];
+
+    // From RFC 4231. (key, data, hmac), all hex encoded.
+    static HMAC_VECTORS: &'static [(&'static str, &'static str, &'static str)] = &[
+        ("0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b",
+         "4869205468657265",
+         "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+        ("4a656665",
+         "7768617420646f2079612077616e7420666f72206e6f7468696e673f",
+         "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+    ];
+
+    fn hex(s: &str) -> Vec<u8> {
+        base16::decode(s).unwrap()
+    }
+
+    fn check_backend<B: CryptoBackend>() {
+        for &(key, iv, cleartext, ciphertext) in AES_VECTORS {
+            let encrypted = B::aes_256_cbc_encrypt(&hex(key), &hex(iv), &hex(cleartext)).unwrap();
+            assert_eq!(base16::encode_lower(&encrypted), ciphertext);
+            let decrypted = B::aes_256_cbc_decrypt(&hex(key), &hex(iv), &hex(ciphertext)).unwrap();
+            assert_eq!(base16::encode_lower(&decrypted), cleartext);
+        }
+
+        for &(key, data, hmac) in HMAC_VECTORS {
+            let computed = B::hmac_sha256(&hex(key), &hex(data)).unwrap();
+            assert_eq!(base16::encode_lower(&computed), hmac);
+        }
+
+        // Bad padding, and ciphertext that isn't a whole number of blocks.
+        let (key, iv, _, ciphertext) = AES_VECTORS[1];
+        let mut bad = hex(ciphertext);
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        assert!(B::aes_256_cbc_decrypt(&hex(key), &hex(iv), &bad).is_err());
+        assert!(B::aes_256_cbc_decrypt(&hex(key), &hex(iv), &bad[..last]).is_err());
+        // Bad key and IV lengths.
+        assert!(B::aes_256_cbc_encrypt(&hex(key)[..16], &hex(iv), b"").is_err());
+        assert!(B::aes_256_cbc_encrypt(&hex(key), &hex(iv)[..8], b"").is_err());
+
+        assert!(B::constant_time_eq(b"abc", b"abc"));
+        assert!(!B::constant_time_eq(b"abc", b"abd"));
+        assert!(!B::constant_time_eq(b"abc", b"abcd"));
+
+        let mut a = [0u8; 32];
+        let mut b = [0u8; 32];
+        B::rand_bytes(&mut a).unwrap();
+        B::rand_bytes(&mut b).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[cfg(feature = "crypto-openssl")]
+    #[test]
+    fn test_openssl_backend() {
+        check_backend::<OpensslBackend>();
+    }
+
+    #[cfg(feature = "crypto-rust")]
+    #[test]
+    fn test_rust_backend() {
+        check_backend::<RustBackend>();
+    }
+
+    #[cfg(all(feature = "crypto-openssl", feature = "crypto-rust"))]
+    #[test]
+    fn test_backends_agree() {
+        let key = [7u8; 32];
+        let iv = [9u8; 16];
+        for len in 0..100 {
+            let cleartext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let ours = RustBackend::aes_256_cbc_encrypt(&key, &iv, &cleartext).unwrap();
+            let theirs = OpensslBackend::aes_256_cbc_encrypt(&key, &iv, &cleartext).unwrap();
+            assert_eq!(ours, theirs);
+            assert_eq!(RustBackend::hmac_sha256(&key, &cleartext).unwrap(),
+                       OpensslBackend::hmac_sha256(&key, &cleartext).unwrap());
+        }
+    }
+}
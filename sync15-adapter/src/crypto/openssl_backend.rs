@@ -0,0 +1,45 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use openssl::{self, symm};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use error::Result;
+use super::CryptoBackend;
+
+#[derive(Debug, Clone, Copy)]
+pub struct OpensslBackend;
+
+impl CryptoBackend for OpensslBackend {
+    fn rand_bytes(buf: &mut [u8]) -> Result<()> {
+        openssl::rand::rand_bytes(buf)?;
+        Ok(())
+    }
+
+    fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<[u8; 32]> {
+        let mut out = [0u8; 32];
+        let key = PKey::hmac(key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(data)?;
+        let size = signer.sign(&mut out)?;
+        // This isn't an Err since it really should not be possible.
+        assert!(size == 32, "Somehow the 256 bits from sha256 do not add up into 32 bytes...");
+        Ok(out)
+    }
+
+    fn aes_256_cbc_encrypt(key: &[u8], iv: &[u8], cleartext: &[u8]) -> Result<Vec<u8>> {
+        Ok(symm::encrypt(symm::Cipher::aes_256_cbc(), key, Some(iv), cleartext)?)
+    }
+
+    fn aes_256_cbc_decrypt(key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        Ok(symm::decrypt(symm::Cipher::aes_256_cbc(), key, Some(iv), ciphertext)?)
+    }
+
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        // openssl::memcmp::eq panics if the lengths differ.
+        a.len() == b.len() && openssl::memcmp::eq(a, b)
+    }
+}
@@ -0,0 +1,57 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use aes::Aes256;
+use block_modes::{BlockMode, Cbc};
+use block_modes::block_padding::Pkcs7;
+use ring::{constant_time, digest, hmac};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use error::{Result, ErrorKind};
+use super::CryptoBackend;
+
+type Aes256Cbc = Cbc<Aes256, Pkcs7>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RustBackend;
+
+impl RustBackend {
+    fn cipher(key: &[u8], iv: &[u8]) -> Result<Aes256Cbc> {
+        // Unlike OpenSSL, block-modes doesn't tell us which was wrong.
+        if key.len() != 32 {
+            return Err(ErrorKind::BadKeyLength("enc_key", key.len(), 32).into());
+        }
+        Aes256Cbc::new_var(key, iv).map_err(|_|
+            ErrorKind::CryptoError(format!("Bad IV length: {}", iv.len())).into())
+    }
+}
+
+impl CryptoBackend for RustBackend {
+    fn rand_bytes(buf: &mut [u8]) -> Result<()> {
+        SystemRandom::new().fill(buf).map_err(|_|
+            ErrorKind::CryptoError("Failed to generate random bytes".into()))?;
+        Ok(())
+    }
+
+    fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<[u8; 32]> {
+        let key = hmac::SigningKey::new(&digest::SHA256, key);
+        let signature = hmac::sign(&key, data);
+        let mut out = [0u8; 32];
+        out.copy_from_slice(signature.as_ref());
+        Ok(out)
+    }
+
+    fn aes_256_cbc_encrypt(key: &[u8], iv: &[u8], cleartext: &[u8]) -> Result<Vec<u8>> {
+        Ok(RustBackend::cipher(key, iv)?.encrypt_vec(cleartext))
+    }
+
+    fn aes_256_cbc_decrypt(key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        RustBackend::cipher(key, iv)?.decrypt_vec(ciphertext).map_err(|_|
+            ErrorKind::CryptoError("Bad ciphertext or padding".into()).into())
+    }
+
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        constant_time::verify_slices_are_equal(a, b).is_ok()
+    }
+}
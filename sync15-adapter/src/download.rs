@@ -0,0 +1,325 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Downloads several collections at once. Each collection is fetched on one
+//! of a bounded number of threads (which share the client's token), and the
+//! records are decrypted and validated on a separate pool of threads, so that
+//! decrypting a large collection doesn't hold up the other downloads.
+//!
+//! Only downloads happen in parallel. Callers should still apply incoming
+//! changes and upload one collection at a time, as `synchronize_many` does.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use bso_record::EncryptedBso;
+use changeset::{self, IncomingChangeset};
+use client::Sync15StorageClient;
+use error::{self, ErrorKind};
+use http::HttpTransport;
+use key_bundle::KeyBundle;
+use request::CollectionRequest;
+use state::GlobalState;
+use util::ServerTimestamp;
+use validation::{QuarantinedRecord, RecordSchema};
+
+/// Describes what to download for a single collection.
+#[derive(Debug, Clone)]
+pub struct CollectionDownload {
+    /// The request to make, which should be `full()`.
+    pub request: CollectionRequest,
+    pub schema: Option<RecordSchema>,
+    /// Previously quarantined records to fetch again.
+    pub retry_ids: Vec<String>,
+}
+
+impl CollectionDownload {
+    #[inline]
+    pub fn new(request: CollectionRequest) -> CollectionDownload {
+        CollectionDownload { request, schema: None, retry_ids: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadOptions {
+    /// The maximum number of collections to fetch at once.
+    pub max_concurrent_requests: usize,
+    /// The number of threads used to decrypt and validate records.
+    pub decrypt_threads: usize,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> DownloadOptions {
+        DownloadOptions {
+            max_concurrent_requests: 4,
+            decrypt_threads: 2,
+        }
+    }
+}
+
+/// The decrypted contents of a collection.
+#[derive(Debug, Clone)]
+pub struct DownloadedCollection {
+    pub changeset: IncomingChangeset,
+    pub quarantined: Vec<QuarantinedRecord>,
+}
+
+// A downloaded collection waiting to be decrypted.
+struct DecryptJob {
+    index: usize,
+    collection: String,
+    timestamp: ServerTimestamp,
+    key: KeyBundle,
+    schema: Option<RecordSchema>,
+    records: Vec<EncryptedBso>,
+}
+
+type FetchResult = (usize, error::Result<DownloadedCollection>);
+
+/// Fetches, decrypts and validates each of `downloads`, returning the
+/// results in the same order. If any collection fails to download or
+/// decrypt, the first such error is returned, and no further downloads are
+/// started.
+///
+/// The fetching threads use their own clients, which share `client`'s token
+/// provider, retry policy and telemetry, and a clone of its transport.
+pub fn fetch_collections<T>(
+    client: &Sync15StorageClient<T>,
+    state: &GlobalState,
+    downloads: Vec<CollectionDownload>,
+    options: DownloadOptions,
+) -> error::Result<Vec<DownloadedCollection>>
+where
+    T: HttpTransport + Clone + Send + 'static,
+{
+    let count = downloads.len();
+    if count == 0 {
+        return Ok(vec![]);
+    }
+
+    // Look everything we need from the global state up front, so the worker
+    // threads don't need it.
+    let mut jobs = Vec::with_capacity(count);
+    for (index, download) in downloads.into_iter().enumerate() {
+        let collection = download.request.collection.clone();
+        let timestamp = state.last_modified_or_zero(&collection);
+        let key = state.key_for_collection(&collection)?.clone();
+        jobs.push((index, download, timestamp, key));
+    }
+    // Workers pop from the end, so reverse to start in the requested order.
+    jobs.reverse();
+    let jobs = Arc::new(Mutex::new(jobs));
+    let aborted = Arc::new(AtomicBool::new(false));
+
+    let (decrypt_sender, decrypt_receiver) = mpsc::channel::<DecryptJob>();
+    let decrypt_receiver = Arc::new(Mutex::new(decrypt_receiver));
+    let (result_sender, result_receiver) = mpsc::channel::<FetchResult>();
+
+    let mut threads = vec![];
+
+    for _ in 0..options.decrypt_threads.max(1) {
+        let decrypt_receiver = decrypt_receiver.clone();
+        let result_sender = result_sender.clone();
+        threads.push(thread::spawn(move || {
+            loop {
+                // Only hold the lock while waiting for the next job.
+                let job = match decrypt_receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    // All the fetchers are done.
+                    Err(_) => return,
+                };
+                let result = IncomingChangeset::decrypt_validated(
+                    job.collection,
+                    job.timestamp,
+                    &job.key,
+                    job.records,
+                    job.schema.as_ref(),
+                ).map(|(changeset, quarantined)| DownloadedCollection { changeset, quarantined });
+                if result_sender.send((job.index, result)).is_err() {
+                    return;
+                }
+            }
+        }));
+    }
+
+    for _ in 0..options.max_concurrent_requests.max(1).min(count) {
+        let jobs = jobs.clone();
+        let aborted = aborted.clone();
+        let decrypt_sender = decrypt_sender.clone();
+        let result_sender = result_sender.clone();
+        let worker = Sync15StorageClient::with_token_provider(
+            client.async_client().token_provider().clone(),
+            client.async_client().transport().clone(),
+        ).with_retry_policy(client.async_client().retry_policy().clone())
+         .with_telemetry(client.telemetry().clone());
+        threads.push(thread::spawn(move || {
+            while !aborted.load(Ordering::SeqCst) {
+                let (index, download, timestamp, key) = match jobs.lock().unwrap().pop() {
+                    Some(job) => job,
+                    None => return,
+                };
+                info!("Downloading {}", download.request.collection);
+                match changeset::fetch_with_retries(&worker, &download.request, &download.retry_ids) {
+                    Ok(records) => {
+                        let job = DecryptJob {
+                            index,
+                            collection: download.request.collection,
+                            timestamp,
+                            key,
+                            schema: download.schema,
+                            records,
+                        };
+                        if decrypt_sender.send(job).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        aborted.store(true, Ordering::SeqCst);
+                        let _ = result_sender.send((index, Err(e)));
+                        return;
+                    }
+                }
+            }
+        }));
+    }
+
+    // The workers hold the only remaining senders, so the channels close once
+    // they're done.
+    drop(decrypt_sender);
+    drop(result_sender);
+
+    let mut results: Vec<Option<DownloadedCollection>> = (0..count).map(|_| None).collect();
+    let mut first_error = None;
+    for (index, result) in result_receiver.iter() {
+        match result {
+            Ok(downloaded) => results[index] = Some(downloaded),
+            Err(e) => {
+                aborted.store(true, Ordering::SeqCst);
+                if first_error.is_none() {
+                    first_error = Some(e);
+                }
+            }
+        }
+    }
+
+    for thread in threads {
+        if thread.join().is_err() {
+            error!("Download worker thread panicked");
+        }
+    }
+
+    if let Some(e) = first_error {
+        return Err(e);
+    }
+    results.into_iter().map(|result| result.ok_or_else(||
+        ErrorKind::DownloadIncomplete.into())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use hyper::StatusCode;
+    use serde_json;
+    use bso_record::Payload;
+    use collection_keys::CollectionKeys;
+    use mock_server::{init, MockServer};
+    use request::InfoCollections;
+    use retry::RetryPolicy;
+
+    // Serves collections of records from several threads, tracking how many
+    // requests are in flight now, and at most.
+    fn setup(names: &[&str]) -> (MockServer, Arc<Mutex<(usize, usize)>>, GlobalState) {
+        let keys = CollectionKeys::new_random().unwrap();
+        let mut collections = HashMap::new();
+        let mut modified = HashMap::new();
+        for (i, name) in names.iter().enumerate() {
+            let key = keys.key_for_collection(name);
+            let records: Vec<EncryptedBso> = (0..i + 1).map(|n| {
+                Payload::from_json(json!({ "id": format!("record{}", n) })).unwrap()
+                    .into_bso(name.to_string())
+                    .encrypt(key).unwrap()
+            }).collect();
+            collections.insert(name.to_string(), records);
+            modified.insert(name.to_string(), ServerTimestamp(1_000_000 + i as i64 * 1000));
+        }
+        let in_flight = Arc::new(Mutex::new((0, 0)));
+        let server_in_flight = in_flight.clone();
+        let server = MockServer::new(move |request| {
+            {
+                let mut in_flight = server_in_flight.lock().unwrap();
+                in_flight.0 += 1;
+                in_flight.1 = in_flight.1.max(in_flight.0);
+            }
+            // Make sure requests overlap.
+            thread::sleep(Duration::from_millis(50));
+            let name = request.url.path_segments().unwrap().last().unwrap();
+            let response = match collections.get(name) {
+                Some(records) => (StatusCode::Ok, serde_json::to_string(records).unwrap()),
+                None => (StatusCode::InternalServerError, "".into()),
+            };
+            server_in_flight.lock().unwrap().0 -= 1;
+            response
+        });
+        let state = GlobalState {
+            collections: InfoCollections::new(modified),
+            keys: Some(keys),
+            .. GlobalState::default()
+        };
+        (server, in_flight, state)
+    }
+
+    fn download(name: &str) -> CollectionDownload {
+        CollectionDownload::new(CollectionRequest::new(name).full().clone())
+    }
+
+    #[test]
+    fn test_fetch_collections() {
+        let names = ["bookmarks", "history", "passwords", "tabs", "addons"];
+        let (server, in_flight, state) = setup(&names);
+        let client = Sync15StorageClient::with_transport(init(), server);
+
+        let options = DownloadOptions { max_concurrent_requests: 3, decrypt_threads: 2 };
+        let downloads = names.iter().map(|name| download(name)).collect();
+        let results = fetch_collections(&client, &state, downloads, options).unwrap();
+
+        assert_eq!(results.len(), names.len());
+        for (i, (result, name)) in results.iter().zip(names.iter()).enumerate() {
+            assert_eq!(&result.changeset.collection, name);
+            assert_eq!(result.changeset.changes.len(), i + 1);
+            assert_eq!(result.changeset.timestamp, ServerTimestamp(1_000_000 + i as i64 * 1000));
+            assert!(result.quarantined.is_empty());
+        }
+        let max_in_flight = in_flight.lock().unwrap().1;
+        assert!(max_in_flight > 1, "Downloads should overlap");
+        assert!(max_in_flight <= 3, "Too many concurrent downloads");
+    }
+
+    #[test]
+    fn test_fetch_collections_error() {
+        let (server, _, state) = setup(&["bookmarks", "history"]);
+        let client = Sync15StorageClient::with_transport(init(), server)
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 2,
+                base_delay: Duration::from_millis(1),
+                .. RetryPolicy::default()
+            });
+
+        let downloads = vec![download("bookmarks"), download("missing"), download("history")];
+        let err = fetch_collections(&client, &state, downloads, DownloadOptions::default())
+            .expect_err("should fail");
+        assert!(match err.kind() {
+            ErrorKind::StorageHttpError { code: StatusCode::InternalServerError, .. } => true,
+            _ => false,
+        });
+
+        // The workers' retries are recorded in our telemetry.
+        let retries = client.telemetry().retries();
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries[0].route, "/1.5/12345/storage/missing");
+    }
+}
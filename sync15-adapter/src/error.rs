@@ -2,11 +2,12 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use reqwest::{self, StatusCode as HttpStatusCode};
 use failure::{self, Fail, Context, Backtrace, SyncFailure};
 use std::{fmt, result, string};
 use std::boxed::Box;
+#[cfg(feature = "crypto-openssl")]
 use openssl;
 use base64;
 use serde_json;
@@ -48,6 +49,85 @@ impl Error {
             _ => false
         }
     }
+
+    #[inline]
+    pub fn class(&self) -> ErrorClass {
+        self.kind().class()
+    }
+
+    #[inline]
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+
+    #[inline]
+    pub fn retry_delay(&self) -> Option<Duration> {
+        self.kind().retry_delay()
+    }
+}
+
+/// How long (in seconds) we suggest waiting before retrying after a
+/// transient error.
+pub const TRANSIENT_RETRY_DELAY_SECS: u64 = 30;
+
+/// A coarse, stable classification of errors, so that apps can decide what
+/// to do about a failed sync without matching on every `ErrorKind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// A network failure, or a server error that will probably go away by
+    /// itself. The sync can be retried.
+    Transient,
+    /// The tokenserver rejected our credentials, or `crypto/keys` is
+    /// encrypted with a key we don't have (usually because the password
+    /// changed). The user needs to reauthenticate (or the app needs to get a
+    /// new OAuth token and sync key) before syncing again.
+    AuthNeedsReauth,
+    /// The server asked us to stop syncing for a while.
+    Backoff,
+    /// The server sent something we didn't expect.
+    ServerBug,
+    /// The data on the server is newer than this client understands.
+    ClientUpgradeRequired,
+    /// Data on the server (records or keys) couldn't be decrypted or parsed.
+    DataCorruption,
+    /// The engine's `Store` failed.
+    Store,
+    /// Anything else, e.g. a local crypto failure or a bug in this crate.
+    Other,
+}
+
+impl ErrorClass {
+    /// A short string for logging and telemetry.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ErrorClass::Transient => "transient",
+            ErrorClass::AuthNeedsReauth => "auth",
+            ErrorClass::Backoff => "backoff",
+            ErrorClass::ServerBug => "server",
+            ErrorClass::ClientUpgradeRequired => "upgrade-required",
+            ErrorClass::DataCorruption => "corruption",
+            ErrorClass::Store => "store",
+            ErrorClass::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn classify_status(code: HttpStatusCode) -> ErrorClass {
+    match code {
+        // A 412 means another client wrote to the collection while we were
+        // syncing; the next sync will pick up its changes.
+        HttpStatusCode::PreconditionFailed => ErrorClass::Transient,
+        HttpStatusCode::TooManyRequests => ErrorClass::Backoff,
+        code if code.is_server_error() => ErrorClass::Transient,
+        _ => ErrorClass::ServerBug,
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -69,13 +149,16 @@ pub enum ErrorKind {
     #[fail(display = "Key {} had wrong length, got {}, expected {}", _0, _1, _2)]
     BadKeyLength(&'static str, usize, usize),
 
-    #[fail(display = "SHA256 HMAC Mismatch error")]
-    HmacMismatch,
+    #[fail(display = "Failed to decrypt record {} in {}: {}", id, collection, reason)]
+    DecryptionError { id: String, collection: String, reason: DecryptFailure },
 
     // TODO: it would be nice if this were _0.to_u16(), but we cant have an expression there...
     #[fail(display = "HTTP status {} when requesting a token from the tokenserver", _0)]
     TokenserverHttpError(HttpStatusCode),
 
+    #[fail(display = "Failed to fetch a token while another request was waiting on it: {}", description)]
+    SharedTokenFetchError { description: String, class: ErrorClass },
+
     #[fail(display = "HTTP status {} during a storage request to \"{}\"", code, route)]
     StorageHttpError { code: HttpStatusCode, route: String },
 
@@ -115,6 +198,9 @@ pub enum ErrorKind {
     #[fail(display = "Error reported by storage: {}", _0)]
     StoreError(#[fail(cause)] failure::Error),
 
+    #[fail(display = "A collection download stopped without a result")]
+    DownloadIncomplete,
+
     #[fail(display = "Setup state machine cycle detected")]
     SetupStateCycleError,
 
@@ -126,9 +212,13 @@ pub enum ErrorKind {
 
     // Basically reimplement error_chain's foreign_links. (Ugh, this sucks)
 
+    #[cfg(feature = "crypto-openssl")]
     #[fail(display = "OpenSSL error: {}", _0)]
     OpensslError(#[fail(cause)] openssl::error::ErrorStack),
 
+    #[fail(display = "Crypto error: {}", _0)]
+    CryptoError(String),
+
     #[fail(display = "Base64 decode error: {}", _0)]
     Base64Decode(#[fail(cause)] base64::DecodeError),
 
@@ -148,6 +238,143 @@ pub enum ErrorKind {
     MalformedUrl(#[fail(cause)] reqwest::UrlError),
 }
 
+impl ErrorKind {
+    pub fn class(&self) -> ErrorClass {
+        match *self {
+            ErrorKind::TokenserverHttpError(HttpStatusCode::Unauthorized) |
+            ErrorKind::TokenserverHttpError(HttpStatusCode::Forbidden) => ErrorClass::AuthNeedsReauth,
+            ErrorKind::TokenserverHttpError(code) => classify_status(code),
+            // The storage server rejects tokens that expired sooner than we
+            // expected. We drop the token, so the next try gets a new one.
+            ErrorKind::StorageHttpError { code: HttpStatusCode::Unauthorized, .. } => {
+                ErrorClass::Transient
+            }
+            ErrorKind::StorageHttpError { code, .. } => classify_status(code),
+            ErrorKind::SharedTokenFetchError { class, .. } => class,
+
+            ErrorKind::RequestError(_) |
+            ErrorKind::BatchInterrupted |
+            ErrorKind::RecordUploadFailed |
+            // The app needs to start over with a new client after a node
+            // reassignment, but that's all it needs to do.
+            ErrorKind::StorageResetError => ErrorClass::Transient,
+
+            ErrorKind::BackoffError(_) => ErrorClass::Backoff,
+
+            ErrorKind::ClientUpgradeRequired => ErrorClass::ClientUpgradeRequired,
+
+            ErrorKind::UnacceptableUrl(_) |
+            ErrorKind::MalformedUrl(_) |
+            ErrorKind::MissingServerTimestamp |
+            ErrorKind::ServerBatchProblem(_) |
+            ErrorKind::JsonError(_) |
+            ErrorKind::SetupStateCycleError => ErrorClass::ServerBug,
+
+            // If we can't read `crypto/keys`, our sync key is out of date.
+            // Other records that fail their HMAC are just corrupt.
+            ErrorKind::DecryptionError { ref collection, ref id, reason }
+                if reason.is_wrong_key() && collection == "crypto" && id == "keys" => {
+                ErrorClass::AuthNeedsReauth
+            }
+
+            ErrorKind::DecryptionError { .. } |
+            ErrorKind::BadKeyLength(..) |
+            ErrorKind::Base64Decode(_) |
+            ErrorKind::BadCleartextUtf8(_) |
+            ErrorKind::NoCryptoKeys => ErrorClass::DataCorruption,
+
+            ErrorKind::StoreError(_) => ErrorClass::Store,
+
+            // A fresh server has no `meta/global`, which isn't a problem
+            // unless we're not allowed to upload one.
+            ErrorKind::NoMetaGlobal |
+            ErrorKind::RecordTooLargeError |
+            ErrorKind::DownloadIncomplete |
+            ErrorKind::DisallowedStateError(_) |
+            ErrorKind::CryptoError(_) |
+            ErrorKind::HawkError(_) => ErrorClass::Other,
+
+            #[cfg(feature = "crypto-openssl")]
+            ErrorKind::OpensslError(_) => ErrorClass::Other,
+        }
+    }
+
+    /// Whether the same operation might succeed if tried again later, without
+    /// the user or app doing anything first.
+    #[inline]
+    pub fn is_retryable(&self) -> bool {
+        match self.class() {
+            ErrorClass::Transient | ErrorClass::Backoff => true,
+            _ => false,
+        }
+    }
+
+    /// How long to wait before retrying, or None if retrying won't help.
+    /// For backoff errors, this is however long the server asked us to wait.
+    pub fn retry_delay(&self) -> Option<Duration> {
+        match *self {
+            ErrorKind::BackoffError(until) => {
+                Some(until.duration_since(SystemTime::now()).unwrap_or(Duration::from_secs(0)))
+            }
+            _ if self.is_retryable() => Some(Duration::from_secs(TRANSIENT_RETRY_DELAY_SECS)),
+            _ => None,
+        }
+    }
+}
+
+/// Why an incoming record couldn't be decrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecryptFailure {
+    /// The record's HMAC didn't match. This usually means the record was
+    /// encrypted with different keys than ours.
+    HmacMismatch,
+    /// The IV or ciphertext wasn't valid base64.
+    BadBase64,
+    /// The IV wasn't 16 bytes.
+    BadIvLength(usize),
+    /// The ciphertext had the wrong length or bad padding.
+    BadPadding,
+    /// The cleartext wasn't UTF-8.
+    BadUtf8,
+    /// The cleartext wasn't valid JSON.
+    BadJson,
+}
+
+impl DecryptFailure {
+    /// Whether this failure suggests we have the wrong keys, as opposed to
+    /// the record being corrupt. Only an HMAC mismatch can tell us that,
+    /// since the other checks happen after the HMAC has been verified.
+    #[inline]
+    pub fn is_wrong_key(&self) -> bool {
+        *self == DecryptFailure::HmacMismatch
+    }
+
+    /// A short string for telemetry.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DecryptFailure::HmacMismatch => "hmac",
+            DecryptFailure::BadBase64 => "base64",
+            DecryptFailure::BadIvLength(_) => "iv-length",
+            DecryptFailure::BadPadding => "padding",
+            DecryptFailure::BadUtf8 => "utf8",
+            DecryptFailure::BadJson => "json",
+        }
+    }
+}
+
+impl fmt::Display for DecryptFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecryptFailure::HmacMismatch => write!(f, "HMAC mismatch"),
+            DecryptFailure::BadBase64 => write!(f, "bad base64"),
+            DecryptFailure::BadIvLength(len) => write!(f, "IV had length {}, expected 16", len),
+            DecryptFailure::BadPadding => write!(f, "bad ciphertext length or padding"),
+            DecryptFailure::BadUtf8 => write!(f, "cleartext is not UTF-8"),
+            DecryptFailure::BadJson => write!(f, "cleartext is not valid JSON"),
+        }
+    }
+}
+
 macro_rules! impl_from_error {
     ($(($variant:ident, $type:ty)),+) => ($(
         impl From<$type> for ErrorKind {
@@ -166,8 +393,12 @@ macro_rules! impl_from_error {
     )*);
 }
 
+#[cfg(feature = "crypto-openssl")]
+impl_from_error! {
+    (OpensslError, ::openssl::error::ErrorStack)
+}
+
 impl_from_error! {
-    (OpensslError, ::openssl::error::ErrorStack),
     (Base64Decode, ::base64::DecodeError),
     (JsonError, ::serde_json::Error),
     (BadCleartextUtf8, ::std::string::FromUtf8Error),
@@ -190,3 +421,68 @@ impl From<hawk::Error> for Error {
         ErrorKind::from(e).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_classes() {
+        let unauthorized = Error::from(ErrorKind::TokenserverHttpError(HttpStatusCode::Unauthorized));
+        assert_eq!(unauthorized.class(), ErrorClass::AuthNeedsReauth);
+        assert!(!unauthorized.is_retryable());
+        assert_eq!(unauthorized.retry_delay(), None);
+
+        let forbidden = Error::from(ErrorKind::TokenserverHttpError(HttpStatusCode::Forbidden));
+        assert_eq!(forbidden.class(), ErrorClass::AuthNeedsReauth);
+
+        let storage = |code| Error::from(ErrorKind::StorageHttpError { code, route: "/".into() });
+        assert_eq!(storage(HttpStatusCode::Unauthorized).class(), ErrorClass::Transient);
+        assert_eq!(storage(HttpStatusCode::ServiceUnavailable).class(), ErrorClass::Transient);
+        assert_eq!(storage(HttpStatusCode::PreconditionFailed).class(), ErrorClass::Transient);
+        assert_eq!(storage(HttpStatusCode::BadRequest).class(), ErrorClass::ServerBug);
+
+        let server_error = storage(HttpStatusCode::InternalServerError);
+        assert!(server_error.is_retryable());
+        assert_eq!(server_error.retry_delay(), Some(Duration::from_secs(TRANSIENT_RETRY_DELAY_SECS)));
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let until = SystemTime::now() + Duration::from_secs(600);
+        let backoff = Error::from(ErrorKind::BackoffError(until));
+        assert_eq!(backoff.class(), ErrorClass::Backoff);
+        assert!(backoff.is_retryable());
+        let delay = backoff.retry_delay().unwrap();
+        assert!(delay > Duration::from_secs(590) && delay <= Duration::from_secs(600));
+
+        // A backoff that's already over can be retried right away.
+        let past = Error::from(ErrorKind::BackoffError(SystemTime::now() - Duration::from_secs(1)));
+        assert_eq!(past.retry_delay(), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn test_other_classes() {
+        let decryption = |collection: &str, id: &str, reason| Error::from(ErrorKind::DecryptionError {
+            id: id.into(),
+            collection: collection.into(),
+            reason,
+        });
+        let wrong_key = decryption("crypto", "keys", DecryptFailure::HmacMismatch);
+        assert_eq!(wrong_key.class(), ErrorClass::AuthNeedsReauth);
+        assert!(!wrong_key.is_retryable());
+        let bad_hmac = decryption("passwords", "record", DecryptFailure::HmacMismatch);
+        assert_eq!(bad_hmac.class(), ErrorClass::DataCorruption);
+        let corrupt = decryption("passwords", "record", DecryptFailure::BadPadding);
+        assert_eq!(corrupt.class(), ErrorClass::DataCorruption);
+        assert!(!corrupt.is_retryable());
+
+        assert_eq!(Error::from(ErrorKind::NoMetaGlobal).class(), ErrorClass::Other);
+
+        let store = Error::from(ErrorKind::StoreError(failure::err_msg("disk full")));
+        assert_eq!(store.class(), ErrorClass::Store);
+        assert_eq!(Error::from(ErrorKind::ClientUpgradeRequired).class(),
+                   ErrorClass::ClientUpgradeRequired);
+        assert_eq!(Error::from(ErrorKind::StorageResetError).class(), ErrorClass::Transient);
+    }
+}
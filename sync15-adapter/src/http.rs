@@ -0,0 +1,135 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Future};
+use hyper::{Method, StatusCode};
+use hyper::header::{Header, Headers};
+use reqwest::{Client, Url};
+use serde;
+use serde_json;
+
+use error::{self, Result};
+
+/// The futures returned throughout the async parts of this crate.
+pub type BoxFuture<'a, T> = Box<Future<Item = T, Error = error::Error> + 'a>;
+
+/// An HTTP request that hasn't been sent yet. Unlike `reqwest::Request`, this
+/// isn't tied to any particular HTTP library, so it can be handed to any
+/// `HttpTransport`.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: Url,
+    pub headers: Headers,
+    pub body: Option<Vec<u8>>,
+}
+
+impl HttpRequest {
+    #[inline]
+    pub fn new(method: Method, url: Url) -> HttpRequest {
+        HttpRequest { method, url, headers: Headers::new(), body: None }
+    }
+
+    #[inline]
+    pub fn header<H: Header>(mut self, header: H) -> HttpRequest {
+        self.headers.set(header);
+        self
+    }
+
+    #[inline]
+    pub fn body(mut self, body: Vec<u8>) -> HttpRequest {
+        self.body = Some(body);
+        self
+    }
+}
+
+/// A response whose body has been read in full.
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub url: Url,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn json<T>(&self) -> Result<T> where for<'a> T: serde::de::Deserialize<'a> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+
+    /// The body as a string, for logging.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Sends HTTP requests on behalf of the storage client and the token
+/// provider. Applications with their own networking stack (or event loop)
+/// can implement this to drive syncs asynchronously.
+pub trait HttpTransport {
+    fn execute<'a>(&'a self, request: HttpRequest) -> BoxFuture<'a, HttpResponse>;
+
+    /// Returns a future that resolves after `duration`, used to wait between
+    /// retries. Transports running on an event loop should use a timer here,
+    /// so that waiting doesn't block the loop.
+    fn sleep<'a>(&'a self, duration: Duration) -> BoxFuture<'a, ()>;
+}
+
+impl<'t, T: HttpTransport + ?Sized> HttpTransport for &'t T {
+    #[inline]
+    fn execute<'a>(&'a self, request: HttpRequest) -> BoxFuture<'a, HttpResponse> {
+        (**self).execute(request)
+    }
+
+    #[inline]
+    fn sleep<'a>(&'a self, duration: Duration) -> BoxFuture<'a, ()> {
+        (**self).sleep(duration)
+    }
+}
+
+/// The default transport, which performs requests synchronously using
+/// reqwest. The returned futures are always already resolved.
+#[derive(Debug, Clone)]
+pub struct BlockingTransport {
+    client: Client,
+}
+
+impl BlockingTransport {
+    pub fn new() -> Result<BlockingTransport> {
+        let client = Client::builder().timeout(Duration::from_secs(30)).build()?;
+        Ok(BlockingTransport { client })
+    }
+
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let mut builder = self.client.request(request.method, request.url);
+        builder.headers(request.headers);
+        if let Some(body) = request.body {
+            builder.body(body);
+        }
+        let mut resp = builder.send()?;
+        let mut body = Vec::new();
+        resp.copy_to(&mut body)?;
+        Ok(HttpResponse {
+            status: resp.status(),
+            url: resp.url().clone(),
+            headers: resp.headers().clone(),
+            body,
+        })
+    }
+}
+
+impl HttpTransport for BlockingTransport {
+    fn execute<'a>(&'a self, request: HttpRequest) -> BoxFuture<'a, HttpResponse> {
+        Box::new(future::result(self.send(request)))
+    }
+
+    // Our requests block anyway, so waiting can too.
+    fn sleep<'a>(&'a self, duration: Duration) -> BoxFuture<'a, ()> {
+        thread::sleep(duration);
+        Box::new(future::ok(()))
+    }
+}
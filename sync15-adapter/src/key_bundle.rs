@@ -5,15 +5,15 @@
 use error::{Result, ErrorKind};
 use base16;
 use base64;
-use openssl::{self, symm};
-use openssl::hash::MessageDigest;
-use openssl::pkey::PKey;
-use openssl::sign::Signer;
+use crypto;
+use secret::SecretBytes;
 
+/// A pair of AES and HMAC keys. These are held as `SecretBytes`, so they
+/// are zeroed when the bundle is dropped and never show up in `Debug` output.
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct KeyBundle {
-    enc_key: Vec<u8>,
-    mac_key: Vec<u8>,
+    enc_key: SecretBytes,
+    mac_key: SecretBytes,
 }
 
 impl KeyBundle {
@@ -29,12 +29,12 @@ impl KeyBundle {
             error!("Bad key length (mac_key): {} != 32", mac.len());
             return Err(ErrorKind::BadKeyLength("mac_key", mac.len(), 32).into());
         }
-        Ok(KeyBundle { enc_key: enc, mac_key: mac })
+        Ok(KeyBundle { enc_key: enc.into(), mac_key: mac.into() })
     }
 
     pub fn new_random() -> Result<KeyBundle> {
         let mut buffer = [0u8; 64];
-        openssl::rand::rand_bytes(&mut buffer)?;
+        crypto::rand_bytes(&mut buffer)?;
         KeyBundle::from_ksync_bytes(&buffer)
     }
 
@@ -45,13 +45,13 @@ impl KeyBundle {
         }
         Ok(KeyBundle {
             enc_key: ksync[0..32].into(),
-            mac_key: ksync[32..64].into()
+            mac_key: ksync[32..64].into(),
         })
     }
 
     pub fn from_ksync_base64(ksync: &str) -> Result<KeyBundle> {
-        let bytes = base64::decode_config(&ksync, base64::URL_SAFE_NO_PAD)?;
-        KeyBundle::from_ksync_bytes(&bytes)
+        let bytes = SecretBytes::from(base64::decode_config(&ksync, base64::URL_SAFE_NO_PAD)?);
+        KeyBundle::from_ksync_bytes(bytes.expose())
     }
 
     pub fn from_base64(enc: &str, mac: &str) -> Result<KeyBundle> {
@@ -62,30 +62,23 @@ impl KeyBundle {
 
     #[inline]
     pub fn encryption_key(&self) -> &[u8] {
-        &self.enc_key
+        self.enc_key.expose()
     }
 
     #[inline]
     pub fn hmac_key(&self) -> &[u8] {
-        &self.mac_key
+        self.mac_key.expose()
     }
 
     #[inline]
     pub fn to_b64_array(&self) -> [String; 2] {
-        [base64::encode(&self.enc_key), base64::encode(&self.mac_key)]
+        [base64::encode(self.encryption_key()), base64::encode(self.hmac_key())]
     }
 
     /// Returns the 32 byte digest by value since it's small enough to be passed
     /// around cheaply, and easily convertable into a slice or vec if you want.
     fn hmac(&self, ciphertext: &[u8]) -> Result<[u8; 32]> {
-        let mut out = [0u8; 32];
-        let key = PKey::hmac(self.hmac_key())?;
-        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
-        signer.update(ciphertext)?;
-        let size = signer.sign(&mut out)?;
-        // This isn't an Err since it really should not be possible.
-        assert!(size == 32, "Somehow the 256 bits from sha256 do not add up into 32 bytes...");
-        Ok(out)
+        crypto::hmac_sha256(self.hmac_key(), ciphertext)
     }
 
     /// Important! Don't compare against this directly! use `verify_hmac` or `verify_hmac_string`!
@@ -93,17 +86,18 @@ impl KeyBundle {
         Ok(base16::encode_lower(&self.hmac(ciphertext)?))
     }
 
+    /// Checks `expected_hmac` against the HMAC of `ciphertext_base64`. The
+    /// comparison takes the same time wherever the two differ.
     pub fn verify_hmac(&self, expected_hmac: &[u8], ciphertext_base64: &str) -> Result<bool> {
         let computed_hmac = self.hmac(ciphertext_base64.as_bytes())?;
-        // I suspect this is unnecessary for our case, but the rust-openssl docs
-        // want us to use this over == to avoid sidechannels, and who am I to argue?
-        Ok(openssl::memcmp::eq(&expected_hmac, &computed_hmac))
+        // I suspect this is unnecessary for our case, but comparing in constant
+        // time avoids sidechannels, and who am I to argue?
+        Ok(crypto::constant_time_eq(&expected_hmac, &computed_hmac))
     }
 
     pub fn verify_hmac_string(&self, expected_hmac: &str, ciphertext_base64: &str) -> Result<bool> {
         let computed_hmac = self.hmac(ciphertext_base64.as_bytes())?;
-        // Note: openssl::memcmp::eq panics if the sizes aren't the same. Desktop returns that it
-        // was a verification failure, so we will too.
+        // Desktop treats a wrong length as a verification failure, so we will too.
         if expected_hmac.len() != 64 {
             warn!("Garbage HMAC verification string: Wrong length");
             return Ok(false);
@@ -118,34 +112,31 @@ impl KeyBundle {
             return Ok(false);
         }
 
-        Ok(openssl::memcmp::eq(&decoded_hmac, &computed_hmac))
+        Ok(crypto::constant_time_eq(&decoded_hmac, &computed_hmac))
     }
 
     /// Decrypt the provided ciphertext with the given iv, and decodes the
     /// result as a utf8 string.  Important: Caller must check verify_hmac first!
     pub fn decrypt(&self, ciphertext: &[u8], iv: &[u8]) -> Result<String> {
-        let cleartext_bytes = symm::decrypt(symm::Cipher::aes_256_cbc(),
-                                            self.encryption_key(),
-                                            Some(iv),
-                                            ciphertext)?;
-        let cleartext = String::from_utf8(cleartext_bytes)?;
+        let cleartext = String::from_utf8(self.decrypt_bytes(ciphertext, iv)?)?;
         Ok(cleartext)
     }
 
+    /// Like `decrypt`, but doesn't decode the result.
+    pub fn decrypt_bytes(&self, ciphertext: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+        crypto::aes_256_cbc_decrypt(self.encryption_key(), iv, ciphertext)
+    }
+
     /// Encrypt using the provided IV.
     pub fn encrypt_bytes_with_iv(&self, cleartext_bytes: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
-        let ciphertext = symm::encrypt(symm::Cipher::aes_256_cbc(),
-                                       self.encryption_key(),
-                                       Some(iv),
-                                       cleartext_bytes)?;
-        Ok(ciphertext)
+        crypto::aes_256_cbc_encrypt(self.encryption_key(), iv, cleartext_bytes)
     }
 
     /// Generate a random iv and encrypt with it. Return both the encrypted bytes
     /// and the generated iv.
     pub fn encrypt_bytes_rand_iv(&self, cleartext_bytes: &[u8]) -> Result<(Vec<u8>, [u8; 16])> {
         let mut iv = [0u8; 16];
-        openssl::rand::rand_bytes(&mut iv)?;
+        crypto::rand_bytes(&mut iv)?;
         let ciphertext = self.encrypt_bytes_with_iv(cleartext_bytes, &iv)?;
         Ok((ciphertext, iv))
     }
@@ -194,6 +185,26 @@ mod test {
         assert!(key_bundle.verify_hmac_string(HMAC_B16, &ciphertext_base64).unwrap());
     }
 
+    #[test]
+    fn test_hmac_rejects_garbage() {
+        let key_bundle = KeyBundle::from_base64(ENC_KEY_B64, HMAC_KEY_B64).unwrap();
+        let ciphertext_base64 = CIPHERTEXT_B64_PIECES.join("");
+        // Desktop accepts uppercase hex.
+        assert!(key_bundle.verify_hmac_string(&HMAC_B16.to_uppercase(), &ciphertext_base64).unwrap());
+        // Wrong length, non-hex, and a single flipped bit at either end are all rejected.
+        assert!(!key_bundle.verify_hmac_string(&HMAC_B16[1..], &ciphertext_base64).unwrap());
+        assert!(!key_bundle.verify_hmac_string(&HMAC_B16.replace("b", "x"), &ciphertext_base64).unwrap());
+        let mut expected = base16::decode(HMAC_B16).unwrap();
+        expected[0] ^= 1;
+        assert!(!key_bundle.verify_hmac(&expected, &ciphertext_base64).unwrap());
+        expected[0] ^= 1;
+        expected[31] ^= 0x80;
+        assert!(!key_bundle.verify_hmac(&expected, &ciphertext_base64).unwrap());
+        expected[31] ^= 0x80;
+        assert!(key_bundle.verify_hmac(&expected, &ciphertext_base64).unwrap());
+        assert!(!key_bundle.verify_hmac(&expected[..31], &ciphertext_base64).unwrap());
+    }
+
     #[test]
     fn test_decrypt() {
         let key_bundle = KeyBundle::from_base64(ENC_KEY_B64, HMAC_KEY_B64).unwrap();
@@ -224,4 +235,13 @@ mod test {
         let s = key_bundle.decrypt(&enc_bytes2, &iv2).unwrap();
         assert_eq!(&cleartext_bytes, &s.as_bytes());
     }
+
+    #[test]
+    fn test_debug_redacts_keys() {
+        let key_bundle = KeyBundle::from_base64(ENC_KEY_B64, HMAC_KEY_B64).unwrap();
+        let debug = format!("{:?}", key_bundle);
+        assert!(debug.contains("[REDACTED]"));
+        assert!(!debug.contains("244")); // The first byte of the encryption key.
+        assert_eq!(key_bundle.to_b64_array(), [ENC_KEY_B64.to_string(), HMAC_KEY_B64.to_string()]);
+    }
 }
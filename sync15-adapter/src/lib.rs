@@ -4,9 +4,18 @@
 
 extern crate serde;
 extern crate base64;
+#[cfg(feature = "crypto-openssl")]
 extern crate openssl;
+#[cfg(feature = "crypto-rust")]
+extern crate aes;
+#[cfg(feature = "crypto-rust")]
+extern crate block_modes;
+#[cfg(feature = "crypto-rust")]
+extern crate ring;
 extern crate reqwest;
 extern crate hawk;
+extern crate futures;
+extern crate secret;
 
 extern crate failure;
 
@@ -32,6 +41,7 @@ extern crate url;
 extern crate base16;
 
 // TODO: Some of these don't need to be pub...
+pub mod crypto;
 pub mod key_bundle;
 pub mod error;
 pub mod bso_record;
@@ -41,16 +51,35 @@ pub mod collection_keys;
 pub mod util;
 pub mod request;
 pub mod changeset;
+pub mod download;
 pub mod sync;
 pub mod client;
+pub mod async_client;
+pub mod http;
 pub mod state;
+pub mod validation;
+pub mod policy;
+pub mod retry;
+pub mod telemetry;
+pub mod reconcile;
+#[cfg(test)]
+mod mock_server;
 
 // Re-export some of the types callers are likely to want for convenience.
 pub use bso_record::{BsoRecord, EncryptedBso, Payload, CleartextBso};
 pub use changeset::{RecordChangeset, IncomingChangeset, OutgoingChangeset};
-pub use error::{Result, Error, ErrorKind};
-pub use sync::{synchronize, Store};
+pub use error::{Result, Error, ErrorKind, ErrorClass, DecryptFailure};
+pub use sync::{synchronize, synchronize_many, Store, StoreSync, SyncInfo};
+pub use download::{fetch_collections, CollectionDownload, DownloadOptions};
 pub use util::{ServerTimestamp, SERVER_EPOCH};
 pub use key_bundle::KeyBundle;
 pub use client::{Sync15StorageClientInit, Sync15StorageClient};
-pub use state::{GlobalState, SetupStateMachine};
+pub use async_client::AsyncStorageClient;
+pub use http::{BlockingTransport, BoxFuture, HttpRequest, HttpResponse, HttpTransport};
+pub use state::{GlobalState, SetupStateMachine, Transition};
+pub use policy::{CollectionPolicy, OutgoingPolicy};
+pub use retry::RetryPolicy;
+pub use reconcile::{merge, Decision, MergePolicy, Merged, Newer, Resolution, Strategy};
+pub use telemetry::{RetryEvent, RetryReason, SyncTelemetry};
+pub use secret::{Secret, SecretBytes, SecretString};
+pub use validation::{FieldType, QuarantinedRecord, RecordSchema, ValidationProblem};
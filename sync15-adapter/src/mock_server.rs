@@ -0,0 +1,122 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An in-process stand-in for the tokenserver and storage server, for tests
+//! that drive the storage clients. Every token request gets the same token;
+//! storage requests are answered by a handler the test provides.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future;
+use hyper::StatusCode;
+use hyper::header::Headers;
+use reqwest::Url;
+
+use client::Sync15StorageClientInit;
+use http::{BoxFuture, HttpRequest, HttpResponse, HttpTransport};
+use request::{XLastModified, XWeaveTimestamp};
+use token::XTimestamp;
+use util::ServerTimestamp;
+
+/// The timestamp the server reports for every storage request.
+pub const SERVER_TIMESTAMP: ServerTimestamp = ServerTimestamp(1_234_500);
+
+type Handler = Fn(&HttpRequest, &mut Headers) -> (StatusCode, String) + Send + Sync;
+
+/// Clones share the handler and the requests they've seen, so a test can
+/// hand one to a client that sends requests from other threads.
+#[derive(Clone)]
+pub struct MockServer {
+    handler: Arc<Handler>,
+    requests: Arc<Mutex<Vec<String>>>,
+    token_requests: Arc<Mutex<usize>>,
+    sleeps: Arc<Mutex<Vec<Duration>>>,
+}
+
+impl MockServer {
+    pub fn new<F>(handler: F) -> MockServer
+    where F: Fn(&HttpRequest) -> (StatusCode, String) + Send + Sync + 'static {
+        MockServer::with_headers(move |req, _| handler(req))
+    }
+
+    /// Like `new`, but the handler can also set response headers.
+    pub fn with_headers<F>(handler: F) -> MockServer
+    where F: Fn(&HttpRequest, &mut Headers) -> (StatusCode, String) + Send + Sync + 'static {
+        MockServer {
+            handler: Arc::new(handler),
+            requests: Arc::new(Mutex::new(vec![])),
+            token_requests: Arc::new(Mutex::new(0)),
+            sleeps: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// "METHOD path?query" for each storage request made, in order.
+    pub fn storage_requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// How many tokens we handed out.
+    pub fn token_requests(&self) -> usize {
+        *self.token_requests.lock().unwrap()
+    }
+
+    /// How long we were asked to wait between retries, in order.
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.sleeps.lock().unwrap().clone()
+    }
+}
+
+impl HttpTransport for MockServer {
+    fn execute<'a>(&'a self, request: HttpRequest) -> BoxFuture<'a, HttpResponse> {
+        let mut headers = Headers::new();
+        if request.url.host_str() == Some("token.example.com") {
+            *self.token_requests.lock().unwrap() += 1;
+            headers.set(XTimestamp(ServerTimestamp(1_000_000)));
+            let body = json!({
+                "id": "id",
+                "key": "key",
+                "api_endpoint": "https://storage.example.com/1.5/12345",
+                "uid": 12345,
+                "duration": 3600,
+                "hashed_fxa_uid": "hash",
+            });
+            return Box::new(future::ok(HttpResponse {
+                status: StatusCode::Ok,
+                url: request.url,
+                headers,
+                body: body.to_string().into_bytes(),
+            }));
+        }
+        let path = match request.url.query() {
+            Some(q) => format!("{} {}?{}", request.method, request.url.path(), q),
+            None => format!("{} {}", request.method, request.url.path()),
+        };
+        self.requests.lock().unwrap().push(path);
+        let (status, body) = (self.handler)(&request, &mut headers);
+        headers.set(XWeaveTimestamp(SERVER_TIMESTAMP));
+        headers.set(XLastModified(SERVER_TIMESTAMP));
+        Box::new(future::ok(HttpResponse {
+            status,
+            url: request.url,
+            headers,
+            body: body.into_bytes(),
+        }))
+    }
+
+    // Record how long we were asked to wait, instead of waiting.
+    fn sleep<'a>(&'a self, duration: Duration) -> BoxFuture<'a, ()> {
+        self.sleeps.lock().unwrap().push(duration);
+        Box::new(future::ok(()))
+    }
+}
+
+/// Client parameters that point at the mock tokenserver.
+pub fn init() -> Sync15StorageClientInit {
+    Sync15StorageClientInit {
+        key_id: "key-id".into(),
+        access_token: "access-token".into(),
+        tokenserver_url: Url::parse("https://token.example.com/1.0/sync/1.5").unwrap(),
+    }
+}
@@ -0,0 +1,192 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::fmt;
+
+use bso_record::Payload;
+
+/// How long the server should keep tombstones around by default. Other
+/// clients that haven't synced in this long will have to do a full sync
+/// anyway, so there's little point keeping them longer.
+pub const DEFAULT_TOMBSTONE_TTL: u32 = 60 * 60 * 24 * 60; // 60 days, in seconds.
+
+/// Decides the `ttl` and `sortindex` of each outgoing record in a collection.
+/// This is applied while encrypting an `OutgoingChangeset`.
+pub trait OutgoingPolicy {
+    /// The number of seconds the server should keep the record for, or None
+    /// to keep it forever.
+    fn ttl(&self, payload: &Payload) -> Option<u32>;
+
+    /// The record's priority. Requests using `sort=index` return records with
+    /// the highest sortindex first.
+    fn sortindex(&self, payload: &Payload) -> Option<i32>;
+}
+
+/// An `OutgoingPolicy` configured with fixed TTLs and an optional function
+/// for computing sortindexes, e.g. from frecency for history, or from last
+/// use for tabs.
+///
+/// The default policy sets neither a TTL nor a sortindex.
+#[derive(Default)]
+pub struct CollectionPolicy<'a> {
+    tombstone_ttl: Option<u32>,
+    record_ttl: Option<u32>,
+    tombstone_sortindex: Option<i32>,
+    sortindex: Option<Box<Fn(&Payload) -> Option<i32> + 'a>>,
+}
+
+impl<'a> CollectionPolicy<'a> {
+    #[inline]
+    pub fn new() -> CollectionPolicy<'a> {
+        CollectionPolicy::default()
+    }
+
+    /// Sets the TTL (in seconds) to use for tombstones.
+    pub fn tombstone_ttl(mut self, ttl: u32) -> CollectionPolicy<'a> {
+        self.tombstone_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the TTL (in seconds) to use for non-tombstone records.
+    pub fn record_ttl(mut self, ttl: u32) -> CollectionPolicy<'a> {
+        self.record_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the sortindex to use for tombstones, which otherwise get none.
+    pub fn tombstone_sortindex(mut self, sortindex: i32) -> CollectionPolicy<'a> {
+        self.tombstone_sortindex = Some(sortindex);
+        self
+    }
+
+    /// Computes the sortindex of non-tombstone records with `f`.
+    pub fn sortindex_with<F>(mut self, f: F) -> CollectionPolicy<'a>
+    where F: Fn(&Payload) -> Option<i32> + 'a {
+        self.sortindex = Some(Box::new(f));
+        self
+    }
+
+    /// Uses the (integer) value of the record's `field` as its sortindex.
+    pub fn sortindex_from_field<S: Into<String>>(self, field: S) -> CollectionPolicy<'a> {
+        let field = field.into();
+        self.sortindex_with(move |payload| {
+            payload.data.get(&field)
+                   .and_then(|v| v.as_i64())
+                   .map(clamp_sortindex)
+        })
+    }
+}
+
+impl<'a> OutgoingPolicy for CollectionPolicy<'a> {
+    fn ttl(&self, payload: &Payload) -> Option<u32> {
+        if payload.is_tombstone() {
+            self.tombstone_ttl
+        } else {
+            self.record_ttl
+        }
+    }
+
+    fn sortindex(&self, payload: &Payload) -> Option<i32> {
+        if payload.is_tombstone() {
+            self.tombstone_sortindex
+        } else {
+            self.sortindex.as_ref().and_then(|f| f(payload))
+        }
+    }
+}
+
+// The boxed closure doesn't implement Debug.
+impl<'a> fmt::Debug for CollectionPolicy<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CollectionPolicy")
+         .field("tombstone_ttl", &self.tombstone_ttl)
+         .field("record_ttl", &self.record_ttl)
+         .field("tombstone_sortindex", &self.tombstone_sortindex)
+         .field("sortindex", &self.sortindex.as_ref().map(|_| "(closure)"))
+         .finish()
+    }
+}
+
+/// Sortindexes are 32 bit on the server, but values like frecency and
+/// timestamps might not fit.
+#[inline]
+pub fn clamp_sortindex(v: i64) -> i32 {
+    if v > i32::max_value() as i64 {
+        i32::max_value()
+    } else if v < i32::min_value() as i64 {
+        i32::min_value()
+    } else {
+        v as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use changeset::OutgoingChangeset;
+    use key_bundle::KeyBundle;
+    use util::ServerTimestamp;
+
+    fn record(frecency: i64) -> Payload {
+        Payload::from_json(json!({ "id": "aaaaaaaaaaaa", "frecency": frecency })).unwrap()
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = CollectionPolicy::default();
+        let tombstone = Payload::new_tombstone("aaaaaaaaaaaa".into());
+        assert_eq!(policy.ttl(&tombstone), None);
+        assert_eq!(policy.sortindex(&tombstone), None);
+        assert_eq!(policy.ttl(&record(10)), None);
+        assert_eq!(policy.sortindex(&record(10)), None);
+    }
+
+    #[test]
+    fn test_tombstone_ttl() {
+        let policy = CollectionPolicy::new()
+            .tombstone_ttl(DEFAULT_TOMBSTONE_TTL)
+            .tombstone_sortindex(-1);
+        let tombstone = Payload::new_tombstone("aaaaaaaaaaaa".into());
+        assert_eq!(policy.ttl(&tombstone), Some(DEFAULT_TOMBSTONE_TTL));
+        assert_eq!(policy.sortindex(&tombstone), Some(-1));
+        assert_eq!(policy.ttl(&record(10)), None);
+    }
+
+    #[test]
+    fn test_sortindex() {
+        let policy = CollectionPolicy::new().sortindex_from_field("frecency");
+        assert_eq!(policy.sortindex(&record(1500)), Some(1500));
+        assert_eq!(policy.sortindex(&record(i64::max_value())), Some(i32::max_value()));
+        assert_eq!(policy.sortindex(&Payload::new_tombstone("aaaaaaaaaaaa".into())), None);
+
+        let policy = CollectionPolicy::new().record_ttl(3600).sortindex_with(|p| {
+            if p.id() == "important" { Some(100) } else { None }
+        });
+        let important = Payload::from_json(json!({ "id": "important" })).unwrap();
+        assert_eq!(policy.sortindex(&important), Some(100));
+        assert_eq!(policy.ttl(&important), Some(3600));
+        assert_eq!(policy.sortindex(&record(5)), None);
+    }
+
+    #[test]
+    fn test_encrypt_with_policy() {
+        let mut changeset = OutgoingChangeset::new("history".into(), ServerTimestamp(0));
+        changeset.changes.push(record(50));
+        changeset.changes.push(Payload::new_tombstone("bbbbbbbbbbbb".into()));
+
+        let policy = CollectionPolicy::new()
+            .tombstone_ttl(DEFAULT_TOMBSTONE_TTL)
+            .sortindex_from_field("frecency");
+        let key = KeyBundle::new_random().unwrap();
+        let encrypted = changeset.encrypt_with_policy(&key, &policy).unwrap();
+
+        assert_eq!(encrypted[0].id, "aaaaaaaaaaaa");
+        assert_eq!(encrypted[0].sortindex, Some(50));
+        assert_eq!(encrypted[0].ttl, None);
+
+        assert_eq!(encrypted[1].id, "bbbbbbbbbbbb");
+        assert_eq!(encrypted[1].sortindex, None);
+        assert_eq!(encrypted[1].ttl, Some(DEFAULT_TOMBSTONE_TTL));
+    }
+}
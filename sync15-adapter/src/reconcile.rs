@@ -0,0 +1,452 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Three-way merging of records, for engines that need to reconcile a local
+//! record with an incoming one.
+//!
+//! Given the local and remote versions of a record, and (if the engine keeps
+//! one) the "shared parent" they were both last synced as, `merge` takes each
+//! field from whichever side changed it. Fields changed differently on both
+//! sides are resolved with a per-field `Strategy`. Every decision is recorded,
+//! so engines can report how often (and how) records conflict.
+
+use std::collections::{BTreeSet, HashMap};
+
+use serde_json::{Map, Value as JsonValue};
+
+use bso_record::Payload;
+
+/// How to resolve a field that was changed differently on both sides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Strategy {
+    /// Take the value from whichever record was modified more recently.
+    TakeNewest,
+    /// Take the server's value.
+    TakeRemote,
+    /// For arrays, keep every item added on either side, and drop items
+    /// removed on either side. Other values are resolved with `TakeNewest`.
+    Union,
+    /// For numbers, take the larger value, e.g. for counters like
+    /// `timesUsed`. Other values are resolved with `TakeNewest`.
+    Max,
+}
+
+/// Which of the two records was modified more recently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Newer {
+    Local,
+    Remote,
+}
+
+impl Newer {
+    /// Compares the records' modification times. The local record wins ties,
+    /// so that its changes are uploaded rather than lost.
+    pub fn from_times<T: PartialOrd>(local: T, remote: T) -> Newer {
+        if remote > local {
+            Newer::Remote
+        } else {
+            Newer::Local
+        }
+    }
+}
+
+/// The strategies `merge` uses for each field. Fields without a strategy of
+/// their own use the default strategy, which is `TakeNewest` unless changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergePolicy {
+    default: Strategy,
+    fields: HashMap<String, Strategy>,
+}
+
+impl Default for MergePolicy {
+    fn default() -> MergePolicy {
+        MergePolicy {
+            default: Strategy::TakeNewest,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+impl MergePolicy {
+    #[inline]
+    pub fn new() -> MergePolicy {
+        MergePolicy::default()
+    }
+
+    /// Sets the strategy for fields that don't have their own.
+    pub fn default_strategy(mut self, strategy: Strategy) -> MergePolicy {
+        self.default = strategy;
+        self
+    }
+
+    /// Sets the strategy for `field`.
+    pub fn field(mut self, field: &str, strategy: Strategy) -> MergePolicy {
+        self.fields.insert(field.to_string(), strategy);
+        self
+    }
+
+    pub fn strategy_for(&self, field: &str) -> Strategy {
+        self.fields.get(field).cloned().unwrap_or(self.default)
+    }
+}
+
+/// How `merge` chose a field's value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Resolution {
+    /// Only the local record changed the field, so we kept its value.
+    LocalOnly,
+    /// Only the remote record changed the field, so we took its value.
+    RemoteOnly,
+    /// Both records changed the field (or there was no shared parent to tell
+    /// which did), and we resolved it with this strategy.
+    Conflict(Strategy),
+}
+
+/// A field on which the local and remote records disagreed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    /// The field's name. Deleting a record on one side and changing it on the
+    /// other is reported as a decision about the `deleted` field.
+    pub field: String,
+    pub resolution: Resolution,
+}
+
+/// The result of merging two records.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Merged {
+    pub payload: Payload,
+    /// One entry for each field the two records disagreed on, in field order.
+    pub decisions: Vec<Decision>,
+    /// True if `payload` differs from the remote record, so it needs to be
+    /// uploaded.
+    pub upload: bool,
+    /// True if `payload` differs from the local record, so it needs to be
+    /// applied locally.
+    pub apply: bool,
+}
+
+impl Merged {
+    /// True if the records changed the same field in different ways.
+    pub fn has_conflicts(&self) -> bool {
+        self.decisions.iter().any(|decision| match decision.resolution {
+            Resolution::Conflict(_) => true,
+            _ => false,
+        })
+    }
+}
+
+/// Merges `local` and `remote`, which should have the same id.
+///
+/// `parent` is the record as it was when the two last agreed, if the engine
+/// keeps track of it. Without a parent, we can't tell which side changed a
+/// field, so every difference is treated as a conflict.
+pub fn merge(
+    parent: Option<&Payload>,
+    local: &Payload,
+    remote: &Payload,
+    newer: Newer,
+    policy: &MergePolicy,
+) -> Merged {
+    // A tombstone has no fields to merge with.
+    let parent = parent.and_then(|parent| if parent.is_tombstone() { None } else { Some(parent) });
+    let mut decisions = Vec::new();
+
+    let payload = if local.is_tombstone() || remote.is_tombstone() {
+        merge_deletion(parent, local, remote, newer, policy, &mut decisions)
+    } else {
+        let empty = Map::new();
+        let parent_data = parent.map(|parent| &parent.data);
+        let names: BTreeSet<&String> = local.data.keys()
+            .chain(remote.data.keys())
+            .chain(parent_data.unwrap_or(&empty).keys())
+            .collect();
+
+        let mut data = Map::new();
+        for name in names {
+            let p = parent_data.and_then(|data| data.get(name));
+            let l = local.data.get(name);
+            let r = remote.data.get(name);
+            let value = if l == r {
+                l.cloned()
+            } else if parent.is_some() && l == p {
+                decisions.push(Decision { field: name.clone(), resolution: Resolution::RemoteOnly });
+                r.cloned()
+            } else if parent.is_some() && r == p {
+                decisions.push(Decision { field: name.clone(), resolution: Resolution::LocalOnly });
+                l.cloned()
+            } else {
+                let (value, strategy) = resolve(policy.strategy_for(name), p, l, r, newer);
+                decisions.push(Decision { field: name.clone(), resolution: Resolution::Conflict(strategy) });
+                value
+            };
+            if let Some(value) = value {
+                data.insert(name.clone(), value);
+            }
+        }
+        Payload { id: local.id.clone(), deleted: false, data }
+    };
+
+    Merged {
+        upload: payload != *remote,
+        apply: payload != *local,
+        payload,
+        decisions,
+    }
+}
+
+// Merges records where at least one side is a tombstone. A deletion on one
+// side wins if the other side didn't change the record; otherwise, it's a
+// conflict, resolved with the default strategy.
+fn merge_deletion(
+    parent: Option<&Payload>,
+    local: &Payload,
+    remote: &Payload,
+    newer: Newer,
+    policy: &MergePolicy,
+    decisions: &mut Vec<Decision>,
+) -> Payload {
+    if local.is_tombstone() && remote.is_tombstone() {
+        return local.clone();
+    }
+    let unchanged = |payload: &Payload| parent.map_or(false, |parent| parent.data == payload.data);
+    let (take_local, resolution) = if local.is_tombstone() && unchanged(remote) {
+        (true, Resolution::LocalOnly)
+    } else if remote.is_tombstone() && unchanged(local) {
+        (false, Resolution::RemoteOnly)
+    } else {
+        let strategy = match policy.default {
+            Strategy::TakeRemote => Strategy::TakeRemote,
+            _ => Strategy::TakeNewest,
+        };
+        let take_local = strategy == Strategy::TakeNewest && newer == Newer::Local;
+        (take_local, Resolution::Conflict(strategy))
+    };
+    decisions.push(Decision { field: "deleted".into(), resolution });
+    if take_local {
+        local.clone()
+    } else {
+        Payload { id: local.id.clone(), .. remote.clone() }
+    }
+}
+
+// Resolves a conflicting field, returning the merged value (or None to
+// remove the field), and the strategy actually used.
+fn resolve(
+    strategy: Strategy,
+    parent: Option<&JsonValue>,
+    local: Option<&JsonValue>,
+    remote: Option<&JsonValue>,
+    newer: Newer,
+) -> (Option<JsonValue>, Strategy) {
+    match (strategy, local, remote) {
+        (Strategy::TakeRemote, _, _) => (remote.cloned(), Strategy::TakeRemote),
+        (Strategy::Max, Some(l), Some(r)) if l.is_number() && r.is_number() => {
+            let larger = if r.as_f64() > l.as_f64() { r } else { l };
+            (Some(larger.clone()), Strategy::Max)
+        }
+        (Strategy::Union, Some(&JsonValue::Array(ref l)), Some(&JsonValue::Array(ref r))) => {
+            let no_items = Vec::new();
+            let p = match parent {
+                Some(&JsonValue::Array(ref p)) => p,
+                _ => &no_items,
+            };
+            let mut items: Vec<JsonValue> = Vec::new();
+            for item in l.iter().chain(r.iter()) {
+                // Items in the parent were removed if they're missing from
+                // either side.
+                let removed = p.contains(item) && !(l.contains(item) && r.contains(item));
+                if !removed && !items.contains(item) {
+                    items.push(item.clone());
+                }
+            }
+            (Some(JsonValue::Array(items)), Strategy::Union)
+        }
+        _ => {
+            let newest = match newer {
+                Newer::Local => local,
+                Newer::Remote => remote,
+            };
+            (newest.cloned(), Strategy::TakeNewest)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(value: JsonValue) -> Payload {
+        Payload::from_json(value).unwrap()
+    }
+
+    #[test]
+    fn test_merge_login() {
+        let policy = MergePolicy::new()
+            .field("timesUsed", Strategy::Max)
+            .field("tags", Strategy::Union);
+        let parent = payload(json!({
+            "id": "login1", "password": "hunter2", "timesUsed": 5, "tags": ["a", "b"],
+        }));
+        let local = payload(json!({
+            "id": "login1", "password": "hunter3", "timesUsed": 7, "tags": ["a", "b", "c"],
+        }));
+        let remote = payload(json!({
+            "id": "login1", "password": "hunter2", "timesUsed": 6, "tags": ["b", "d"],
+            "username": "me",
+        }));
+
+        let merged = merge(Some(&parent), &local, &remote, Newer::Remote, &policy);
+        assert_eq!(merged.payload, payload(json!({
+            "id": "login1", "password": "hunter3", "timesUsed": 7, "tags": ["b", "c", "d"],
+            "username": "me",
+        })));
+        assert_eq!(merged.decisions, vec![
+            Decision { field: "password".into(), resolution: Resolution::LocalOnly },
+            Decision { field: "tags".into(), resolution: Resolution::Conflict(Strategy::Union) },
+            Decision { field: "timesUsed".into(), resolution: Resolution::Conflict(Strategy::Max) },
+            Decision { field: "username".into(), resolution: Resolution::RemoteOnly },
+        ]);
+        assert!(merged.upload && merged.apply && merged.has_conflicts());
+
+        // Without a parent, every difference is a conflict.
+        let merged = merge(None, &local, &remote, Newer::Remote, &policy);
+        assert_eq!(merged.payload.data["password"], json!("hunter2"));
+        assert_eq!(merged.payload.data["tags"], json!(["a", "b", "c", "d"]));
+    }
+
+    #[test]
+    fn test_merge_deletion() {
+        let policy = MergePolicy::new();
+        let parent = payload(json!({ "id": "record1", "value": 1 }));
+        let changed = payload(json!({ "id": "record1", "value": 2 }));
+        let deleted = Payload::new_tombstone("record1".into());
+
+        let merged = merge(Some(&parent), &deleted, &parent, Newer::Remote, &policy);
+        assert!(merged.payload.is_tombstone() && merged.upload && !merged.apply);
+
+        // Changing the record on one side conflicts with deleting it on the
+        // other.
+        let merged = merge(Some(&parent), &deleted, &changed, Newer::Remote, &policy);
+        assert_eq!(merged.payload, changed);
+        assert_eq!(merged.decisions, vec![Decision {
+            field: "deleted".into(),
+            resolution: Resolution::Conflict(Strategy::TakeNewest),
+        }]);
+        let merged = merge(Some(&parent), &changed, &deleted, Newer::Local, &policy);
+        assert_eq!(merged.payload, changed);
+    }
+
+    // A tiny xorshift generator, so the property tests below are repeatable.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+
+        fn payload(&mut self) -> Payload {
+            if self.below(10) == 0 {
+                return Payload::new_tombstone("record".into());
+            }
+            self.live_payload()
+        }
+
+        fn live_payload(&mut self) -> Payload {
+            let mut data = Map::new();
+            for name in &["a", "b", "c", "d"] {
+                let value = match self.below(4) {
+                    0 => continue,
+                    1 => json!(self.below(4)),
+                    2 => json!(format!("s{}", self.below(3))),
+                    _ => JsonValue::Array((0..4).filter(|_| self.below(2) == 0).map(|i| json!(i)).collect()),
+                };
+                data.insert(name.to_string(), value);
+            }
+            Payload { id: "record".into(), deleted: false, data }
+        }
+
+        fn policy(&mut self) -> MergePolicy {
+            let strategies = [Strategy::TakeNewest, Strategy::TakeRemote, Strategy::Union, Strategy::Max];
+            let mut policy = MergePolicy::new().default_strategy(strategies[self.below(4) as usize]);
+            for name in &["a", "b", "c", "d"] {
+                policy = policy.field(name, strategies[self.below(4) as usize]);
+            }
+            policy
+        }
+
+        fn newer(&mut self) -> Newer {
+            if self.below(2) == 0 { Newer::Local } else { Newer::Remote }
+        }
+    }
+
+    #[test]
+    fn test_merge_properties() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let (parent, local, remote) = (rng.live_payload(), rng.payload(), rng.payload());
+            let policy = rng.policy();
+            let newer = rng.newer();
+
+            // Records that agree merge to themselves, without decisions.
+            let merged = merge(Some(&parent), &local, &local, newer, &policy);
+            assert_eq!(merged.payload, local);
+            assert!(merged.decisions.is_empty() && !merged.upload && !merged.apply);
+
+            // Changes made on only one side always win.
+            assert_eq!(merge(Some(&parent), &parent, &remote, newer, &policy).payload, remote);
+            assert_eq!(merge(Some(&parent), &local, &parent, newer, &policy).payload, local);
+
+            let merged = merge(Some(&parent), &local, &remote, newer, &policy);
+            assert_eq!(merged.upload, merged.payload != remote);
+            assert_eq!(merged.apply, merged.payload != local);
+            if local.is_tombstone() || remote.is_tombstone() {
+                continue;
+            }
+
+            // There's a decision for exactly the fields that differ, and
+            // every merged value comes from one side (or is a union).
+            let differing: Vec<&String> = local.data.keys().chain(remote.data.keys())
+                .collect::<BTreeSet<_>>().into_iter()
+                .filter(|name| local.data.get(*name) != remote.data.get(*name))
+                .collect();
+            let decided: Vec<&String> = merged.decisions.iter().map(|d| &d.field).collect();
+            assert_eq!(decided, differing);
+
+            for decision in &merged.decisions {
+                let name = &decision.field;
+                let (l, r, m) = (local.data.get(name), remote.data.get(name), merged.payload.data.get(name));
+                match decision.resolution {
+                    Resolution::LocalOnly => assert_eq!(m, l),
+                    Resolution::RemoteOnly | Resolution::Conflict(Strategy::TakeRemote) => assert_eq!(m, r),
+                    Resolution::Conflict(Strategy::TakeNewest) => {
+                        assert_eq!(m, if newer == Newer::Local { l } else { r });
+                    }
+                    Resolution::Conflict(Strategy::Max) => {
+                        let m = m.unwrap().as_f64().unwrap();
+                        assert!(m >= l.unwrap().as_f64().unwrap() && m >= r.unwrap().as_f64().unwrap());
+                    }
+                    Resolution::Conflict(Strategy::Union) => {
+                        let items = |v: Option<&JsonValue>| v.and_then(|v| v.as_array()).cloned().unwrap_or_default();
+                        let (p, l, r, m) = (items(parent.data.get(name)), items(l), items(r), items(m));
+                        for item in &m {
+                            assert!(l.contains(item) || r.contains(item));
+                        }
+                        for item in l.iter().chain(r.iter()).filter(|item| !p.contains(item)) {
+                            assert!(m.contains(item), "Added item {} should be kept", item);
+                        }
+                    }
+                }
+            }
+
+            // Taking the newest is symmetric.
+            let newest = MergePolicy::new();
+            let swapped = if newer == Newer::Local { Newer::Remote } else { Newer::Local };
+            assert_eq!(merge(Some(&parent), &local, &remote, newer, &newest).payload,
+                       merge(Some(&parent), &remote, &local, swapped, &newest).payload);
+        }
+    }
+}
@@ -4,16 +4,20 @@
 
 use util::ServerTimestamp;
 use bso_record::{EncryptedBso};
+use http::HttpResponse;
 
 use serde_json;
 use std::fmt;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::default::Default;
 use std::ops::Deref;
 use url::{Url, UrlQuery, form_urlencoded::Serializer};
 use error::{self, Result, ErrorKind};
 use hyper::{StatusCode};
-use reqwest::Response;
+
+/// The storage server rejects `ids` queries with more than this many ids.
+pub const MAX_IDS_PER_REQUEST: usize = 100;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum RequestOrder { Oldest, Newest, Index }
@@ -21,6 +25,8 @@ pub enum RequestOrder { Oldest, Newest, Index }
 header! { (XIfUnmodifiedSince, "X-If-Unmodified-Since") => [ServerTimestamp] }
 header! { (XLastModified, "X-Last-Modified") => [ServerTimestamp] }
 header! { (XWeaveTimestamp, "X-Weave-Timestamp") => [ServerTimestamp] }
+/// How long (in seconds) the server wants clients to stop syncing for.
+header! { (XWeaveBackoff, "X-Weave-Backoff") => [u64] }
 
 impl fmt::Display for RequestOrder {
     #[inline]
@@ -284,12 +290,12 @@ pub struct PostResponse {
 }
 
 impl PostResponse {
-    pub fn from_response(r: &mut Response) -> Result<PostResponse> {
+    pub fn from_response(r: &HttpResponse) -> Result<PostResponse> {
         let result: UploadResult = r.json()?;
         // TODO Can this happen in error cases?
-        let last_modified = r.headers().get::<XLastModified>().map(|h| **h).ok_or_else(||
+        let last_modified = r.headers.get::<XLastModified>().map(|h| **h).ok_or_else(||
             ErrorKind::MissingServerTimestamp)?;
-        let status = r.status();
+        let status = r.status;
         Ok(PostResponse { status, result, last_modified })
     }
 }
@@ -302,6 +308,94 @@ pub enum BatchState {
     InBatch(String),
 }
 
+impl BatchState {
+    #[inline]
+    pub fn in_batch(&self) -> bool {
+        match self {
+            &BatchState::Unsupported |
+            &BatchState::NoBatch => false,
+            _ => true
+        }
+    }
+
+    /// The `batch` parameter to use for the next POST.
+    pub fn next_batch_id(&self) -> Option<String> {
+        match self {
+            // Not the first post and we know we have no batch semantics.
+            &BatchState::Unsupported => None,
+            // First commit in possible batch
+            &BatchState::NoBatch => Some("true".into()),
+            // In a batch and we have a batch id.
+            &BatchState::InBatch(ref s) => Some(s.clone())
+        }
+    }
+}
+
+/// Updates the batch state and XIUS timestamp from the server's response to a
+/// POST, and passes the response along to `on_response`. This is shared by
+/// `PostQueue` and the async uploader, so both treat responses identically.
+pub(crate) fn handle_post_response<OnResponse: PostResponseHandler>(
+    batch: &mut BatchState,
+    last_modified: &mut ServerTimestamp,
+    on_response: &mut OnResponse,
+    resp: PostResponse,
+    want_commit: bool,
+) -> Result<()> {
+    if !resp.status.is_success() {
+        let code = resp.status;
+        on_response.handle_response(resp, !want_commit)?;
+        error!("Bug: expected OnResponse to have bailed out!");
+        // Should we assert here instead?
+        return Err(ErrorKind::StorageHttpError { code, route: "Client bug!".into() }.into());
+    }
+
+    if want_commit || *batch == BatchState::Unsupported {
+        *last_modified = resp.last_modified;
+    }
+
+    if want_commit {
+        debug!("Committed batch {:?}", batch);
+        *batch = BatchState::NoBatch;
+        on_response.handle_response(resp, false)?;
+        return Ok(());
+    }
+
+    if resp.status != StatusCode::Accepted {
+        if batch.in_batch() {
+            return Err(ErrorKind::ServerBatchProblem(
+                "Server responded non-202 success code while a batch was in progress").into());
+        }
+        *last_modified = resp.last_modified;
+        *batch = BatchState::Unsupported;
+        on_response.handle_response(resp, false)?;
+        return Ok(());
+    }
+
+    let batch_id = resp.result.batch.as_ref().ok_or_else(||
+        ErrorKind::ServerBatchProblem("Invalid server response: 202 without a batch ID"))?.clone();
+
+    match &*batch {
+        &BatchState::Unsupported => {
+            warn!("Server changed it's mind about supporting batching mid-batch...");
+        },
+
+        &BatchState::InBatch(ref cur_id) => {
+            if cur_id != &batch_id {
+                return Err(ErrorKind::ServerBatchProblem(
+                    "Invalid server response: 202 without a batch ID").into());
+            }
+        },
+        _ => {}
+    }
+
+    *batch = BatchState::InBatch(batch_id);
+    *last_modified = resp.last_modified;
+
+    on_response.handle_response(resp, true)?;
+
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct PostQueue<Post, OnResponse> {
     poster: Post,
@@ -409,11 +503,7 @@ where
 
     #[inline]
     fn in_batch(&self) -> bool {
-        match &self.batch {
-            &BatchState::Unsupported |
-            &BatchState::NoBatch => false,
-            _ => true
-        }
+        self.batch.in_batch()
     }
 
     pub fn enqueue(&mut self, record: &EncryptedBso) -> Result<bool> {
@@ -490,14 +580,7 @@ where
         }
 
         self.queued.push(b']');
-        let batch_id = match &self.batch {
-            // Not the first post and we know we have no batch semantics.
-            &BatchState::Unsupported => None,
-            // First commit in possible batch
-            &BatchState::NoBatch => Some("true".into()),
-            // In a batch and we have a batch id.
-            &BatchState::InBatch(ref s) => Some(s.clone())
-        };
+        let batch_id = self.batch.next_batch_id();
 
         info!("Posting {} records of {} bytes", self.post_limits.cur_records, self.queued.len());
 
@@ -518,60 +601,16 @@ where
 
         let resp = resp_or_error?;
 
-        if !resp.status.is_success() {
-            let code = resp.status;
-            self.on_response.handle_response(resp, !want_commit)?;
-            error!("Bug: expected OnResponse to have bailed out!");
-            // Should we assert here instead?
-            return Err(ErrorKind::StorageHttpError { code, route: "Client bug!".into() }.into());
-        }
-
-        if want_commit || self.batch == BatchState::Unsupported {
-            self.last_modified = resp.last_modified;
-        }
-
-        if want_commit {
-            debug!("Committed batch {:?}", self.batch);
-            self.batch = BatchState::NoBatch;
-            self.on_response.handle_response(resp, false)?;
-            return Ok(());
-        }
+        handle_post_response(&mut self.batch,
+                             &mut self.last_modified,
+                             &mut self.on_response,
+                             resp,
+                             want_commit)?;
 
-        if resp.status != StatusCode::Accepted {
-            if self.in_batch() {
-                return Err(ErrorKind::ServerBatchProblem(
-                    "Server responded non-202 success code while a batch was in progress").into());
-            }
-            self.last_modified = resp.last_modified;
-            self.batch = BatchState::Unsupported;
+        if self.batch == BatchState::Unsupported {
             self.batch_limits.clear();
-            self.on_response.handle_response(resp, false)?;
-            return Ok(());
-        }
-
-        let batch_id = resp.result.batch.as_ref().ok_or_else(||
-            ErrorKind::ServerBatchProblem("Invalid server response: 202 without a batch ID"))?.clone();
-
-        match &self.batch {
-            &BatchState::Unsupported => {
-                warn!("Server changed it's mind about supporting batching mid-batch...");
-            },
-
-            &BatchState::InBatch(ref cur_id) => {
-                if cur_id != &batch_id {
-                    return Err(ErrorKind::ServerBatchProblem(
-                        "Invalid server response: 202 without a batch ID").into());
-                }
-            },
-            _ => {}
         }
 
-        // Can't change this in match arms without NLL
-        self.batch = BatchState::InBatch(batch_id);
-        self.last_modified = resp.last_modified;
-
-        self.on_response.handle_response(resp, true)?;
-
         Ok(())
     }
 }
@@ -583,25 +622,96 @@ pub struct UploadInfo {
     pub modified_timestamp: ServerTimestamp,
 }
 
+impl NormalResponseHandler {
+    pub fn upload_info(&mut self, modified_timestamp: ServerTimestamp) -> UploadInfo {
+        let mut result = UploadInfo {
+            successful_ids: Vec::with_capacity(self.successful_ids.len()),
+            failed_ids: Vec::with_capacity(self.failed_ids.len() +
+                                           self.pending_failed.len() +
+                                           self.pending_success.len()),
+            modified_timestamp,
+        };
+
+        result.successful_ids.append(&mut self.successful_ids);
+
+        result.failed_ids.append(&mut self.failed_ids);
+        result.failed_ids.append(&mut self.pending_failed);
+        result.failed_ids.append(&mut self.pending_success);
+
+        result
+    }
+}
+
 impl<Poster> PostQueue<Poster, NormalResponseHandler> {
     // TODO: should take by move
     pub fn completed_upload_info(&mut self) -> UploadInfo {
-        let mut result = UploadInfo {
-            successful_ids: Vec::with_capacity(self.on_response.successful_ids.len()),
-            failed_ids: Vec::with_capacity(self.on_response.failed_ids.len() +
-                                           self.on_response.pending_failed.len() +
-                                           self.on_response.pending_success.len()),
-            modified_timestamp: self.last_modified
+        let last_modified = self.last_modified;
+        self.on_response.upload_info(last_modified)
+    }
+}
+
+/// A POST worked out ahead of time by `plan_posts`.
+#[derive(Debug, Clone)]
+pub(crate) struct PlannedPost {
+    pub body: Vec<u8>,
+    /// Whether this is the last POST of a batch.
+    pub commit: bool,
+}
+
+// A BatchPoster that records what the PostQueue would send instead of sending
+// it, pretending that the server supports batching.
+#[derive(Debug, Default)]
+struct PostRecorder {
+    posts: RefCell<Vec<PlannedPost>>,
+}
+
+impl BatchPoster for PostRecorder {
+    fn post<P, O>(&self,
+                  body: &[u8],
+                  xius: ServerTimestamp,
+                  _: Option<String>,
+                  commit: bool,
+                  _: &PostQueue<P, O>) -> Result<PostResponse> {
+        self.posts.borrow_mut().push(PlannedPost { body: body.to_vec(), commit });
+        let (status, batch) = if commit {
+            (StatusCode::Ok, None)
+        } else {
+            (StatusCode::Accepted, Some("planned".to_string()))
         };
+        Ok(PostResponse {
+            status,
+            last_modified: xius,
+            result: UploadResult { batch, failed: HashMap::new(), success: vec![] },
+        })
+    }
+}
 
-        result.successful_ids.append(&mut self.on_response.successful_ids);
+struct IgnoreResponses;
 
-        result.failed_ids.append(&mut self.on_response.failed_ids);
-        result.failed_ids.append(&mut self.on_response.pending_failed);
-        result.failed_ids.append(&mut self.on_response.pending_success);
+impl PostResponseHandler for IgnoreResponses {
+    fn handle_response(&mut self, _: PostResponse, _: bool) -> Result<()> {
+        Ok(())
+    }
+}
 
-        result
+/// Splits `records` into POST bodies (and batches) exactly as a `PostQueue`
+/// would, without sending anything. This lets uploaders that can't block on
+/// each response (like the async client) reuse the queue's limit tracking.
+/// Also returns the ids of records that are too large to ever upload.
+pub(crate) fn plan_posts(
+    config: &InfoConfiguration,
+    records: &[EncryptedBso],
+) -> Result<(Vec<PlannedPost>, Vec<String>)> {
+    let mut queue = PostQueue::new(config, ServerTimestamp::default(),
+                                   PostRecorder::default(), IgnoreResponses);
+    let mut too_large = vec![];
+    for record in records {
+        if !queue.enqueue(record)? {
+            too_large.push(record.id.clone());
+        }
     }
+    queue.flush(true)?;
+    Ok((queue.poster.posts.into_inner(), too_large))
 }
 
 #[cfg(test)]
@@ -630,8 +740,8 @@ mod test {
         assert_eq!(idreq.as_str(), "https://example.com/sync/storage/wutang?full=1&ids=rza%2Cgza");
 
         let complex = CollectionRequest::new("specific").full().limit(10).sort_by(RequestOrder::Oldest)
-                                                        .older_than(ServerTimestamp(9876.54))
-                                                        .newer_than(ServerTimestamp(1234.56))
+                                                        .older_than(ServerTimestamp(9_876_540))
+                                                        .newer_than(ServerTimestamp(1_234_560))
                                                         .build_url(base.clone()).unwrap();
         assert_eq!(complex.as_str(),
             "https://example.com/sync/storage/specific?full=1&limit=10&older=9876.54&newer=1234.56&sort=oldest");
@@ -791,13 +901,13 @@ mod test {
 
     type MockedPostQueue = PostQueue<TestPosterRef, TestPosterRef>;
 
-    fn pq_test_setup(cfg: InfoConfiguration, lm: f64, resps: Vec<PostResponse>) -> (MockedPostQueue, TestPosterRef) {
+    fn pq_test_setup(cfg: InfoConfiguration, lm: i64, resps: Vec<PostResponse>) -> (MockedPostQueue, TestPosterRef) {
         let tester = TestPoster::new(&cfg, resps);
         let pq = PostQueue::new(&cfg, ServerTimestamp(lm), tester.clone(), tester.clone());
         (pq, tester)
     }
 
-    fn fake_response<'a, T: Into<Option<&'a str>>>(status: StatusCode, lm: f64, batch: T) -> PostResponse {
+    fn fake_response<'a, T: Into<Option<&'a str>>>(status: StatusCode, lm: i64, batch: T) -> PostResponse {
         PostResponse {
             status,
             last_modified: ServerTimestamp(lm),
@@ -824,7 +934,7 @@ mod test {
             let val = serde_json::to_value(BsoRecord {
                 id: "".into(),
                 collection: "".into(),
-                modified: ServerTimestamp(0.0),
+                modified: ServerTimestamp(0),
                 sortindex: None,
                 ttl: None,
                 payload: EncryptedPayload {
@@ -851,7 +961,7 @@ mod test {
         BsoRecord {
             id: "".into(),
             collection: "".into(),
-            modified: ServerTimestamp(0.0),
+            modified: ServerTimestamp(0),
             sortindex: None,
             ttl: None,
             payload: EncryptedPayload {
@@ -873,9 +983,9 @@ mod test {
             max_record_payload_bytes: 1000,
             ..InfoConfiguration::default()
         };
-        let time = 11111111.0;
+        let time = 11_111_111_000;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::Ok, time + 100.0, None),
+            fake_response(StatusCode::Ok, time + 100_000, None),
         ]);
 
         pq.enqueue(&make_record(100)).unwrap();
@@ -898,10 +1008,10 @@ mod test {
             max_request_bytes: 250,
             ..InfoConfiguration::default()
         };
-        let time = 11111111.0;
+        let time = 11_111_111_000;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::Ok, time + 100.0, None),
-            fake_response(StatusCode::Ok, time + 200.0, None),
+            fake_response(StatusCode::Ok, time + 100_000, None),
+            fake_response(StatusCode::Ok, time + 200_000, None),
         ]);
 
         // Note that the total record overhead is around 85 bytes
@@ -939,10 +1049,10 @@ mod test {
             max_request_bytes: 350,
             ..InfoConfiguration::default()
         };
-        let time = 11111111.0;
+        let time = 11_111_111_000;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::Ok, time + 100.0, None),
-            fake_response(StatusCode::Ok, time + 200.0, None),
+            fake_response(StatusCode::Ok, time + 100_000, None),
+            fake_response(StatusCode::Ok, time + 200_000, None),
         ]);
 
         // Note that the total record overhead is around 85 bytes
@@ -967,9 +1077,9 @@ mod test {
     #[test]
     fn test_pq_single_batch() {
         let cfg = InfoConfiguration::default();
-        let time = 11111111.0;
+        let time = 11_111_111_000;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::Accepted, time + 100.0, Some("1234")),
+            fake_response(StatusCode::Accepted, time + 100_000, Some("1234")),
         ]);
 
         let payload_size = 100 - *NON_PAYLOAD_OVERHEAD;
@@ -997,10 +1107,10 @@ mod test {
             max_post_bytes: 200,
             ..InfoConfiguration::default()
         };
-        let time = 11111111.0;
+        let time = 11_111_111_000;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
             fake_response(StatusCode::Accepted, time, Some("1234")),
-            fake_response(StatusCode::Accepted, time + 100.0, Some("1234")),
+            fake_response(StatusCode::Accepted, time + 100_000, Some("1234")),
         ]);
 
         pq.enqueue(&make_record(100)).unwrap();
@@ -1039,11 +1149,11 @@ mod test {
             max_post_records: 3,
             ..InfoConfiguration::default()
         };
-        let time = 11111111.0;
+        let time = 11_111_111_000;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
             fake_response(StatusCode::Accepted, time, Some("1234")),
             fake_response(StatusCode::Accepted, time, Some("1234")),
-            fake_response(StatusCode::Accepted, time + 100.0, Some("1234")),
+            fake_response(StatusCode::Accepted, time + 100_000, Some("1234")),
         ]);
 
         pq.enqueue(&make_record(100)).unwrap();
@@ -1094,12 +1204,12 @@ mod test {
             max_total_records: 5,
             ..InfoConfiguration::default()
         };
-        let time = 11111111.0;
+        let time = 11_111_111_000;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
             fake_response(StatusCode::Accepted, time, Some("1234")),
-            fake_response(StatusCode::Accepted, time + 100.0, Some("1234")),
-            fake_response(StatusCode::Accepted, time + 100.0, Some("abcd")),
-            fake_response(StatusCode::Accepted, time + 200.0, Some("abcd")),
+            fake_response(StatusCode::Accepted, time + 100_000, Some("1234")),
+            fake_response(StatusCode::Accepted, time + 100_000, Some("abcd")),
+            fake_response(StatusCode::Accepted, time + 200_000, Some("abcd")),
         ]);
 
         pq.enqueue(&make_record(100)).unwrap();
@@ -1166,12 +1276,12 @@ mod test {
             max_total_bytes: 500,
             ..InfoConfiguration::default()
         };
-        let time = 11111111.0;
+        let time = 11_111_111_000;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
             fake_response(StatusCode::Accepted, time, Some("1234")),
-            fake_response(StatusCode::Accepted, time + 100.0, Some("1234")), // should commit
-            fake_response(StatusCode::Accepted, time + 100.0, Some("abcd")),
-            fake_response(StatusCode::Accepted, time + 200.0, Some("abcd")), // should commit
+            fake_response(StatusCode::Accepted, time + 100_000, Some("1234")), // should commit
+            fake_response(StatusCode::Accepted, time + 100_000, Some("abcd")),
+            fake_response(StatusCode::Accepted, time + 200_000, Some("abcd")), // should commit
         ]);
 
         pq.enqueue(&make_record(100)).unwrap();
@@ -1183,16 +1293,16 @@ mod test {
         pq.enqueue(&make_record(100)).unwrap();
         // POST + COMMIT
         pq.enqueue(&make_record(100)).unwrap();
-        assert_eq!(pq.last_modified.0, time + 100.0);
+        assert_eq!(pq.last_modified.0, time + 100_000);
         pq.enqueue(&make_record(100)).unwrap();
         pq.enqueue(&make_record(100)).unwrap();
 
         // POST
         pq.enqueue(&make_record(100)).unwrap();
-        assert_eq!(pq.last_modified.0, time + 100.0);
+        assert_eq!(pq.last_modified.0, time + 100_000);
         pq.flush(true).unwrap(); // COMMIT
 
-        assert_eq!(pq.last_modified.0, time + 200.0);
+        assert_eq!(pq.last_modified.0, time + 200_000);
 
         let t = tester.borrow();
         assert!(t.cur_batch.is_none());
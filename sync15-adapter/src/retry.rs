@@ -0,0 +1,167 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::time::Duration;
+
+use hyper::{Method, StatusCode};
+use hyper::header::Headers;
+
+use crypto;
+use http::HttpRequest;
+use request::XWeaveBackoff;
+use token::RetryAfter;
+
+/// Controls how the storage client retries requests that failed because of
+/// a network error or a 5xx or 429 response.
+///
+/// Only idempotent requests are retried: GETs, PUTs and DELETEs, and POSTs
+/// that are part of a batch. A POST outside a batch writes its records
+/// immediately, so repeating one that reached the server could clobber
+/// changes made by other clients in between.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// The total number of times to try each request, including the first.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. Each later retry waits twice
+    /// as long as the one before.
+    pub base_delay: Duration,
+    /// The longest we'll wait between attempts. If the server asks us to
+    /// back off for longer than this, we give up with a `BackoffError`
+    /// instead.
+    pub max_delay: Duration,
+    /// How much of each delay to randomize, from 0.0 (none) to 1.0 (anywhere
+    /// between zero and the full delay), so that clients that failed together
+    /// don't all retry together.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy { max_attempts: 1, .. RetryPolicy::default() }
+    }
+
+    /// How long to wait after the `attempt`th attempt (starting from 1)
+    /// fails, if the server didn't say.
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let delay = self.exponential_delay(attempt);
+        let jitter = self.jitter.max(0.0).min(1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        let mut buf = [0u8; 4];
+        if crypto::rand_bytes(&mut buf).is_err() {
+            return delay;
+        }
+        let random = u32::from(buf[0]) << 24 | u32::from(buf[1]) << 16 |
+                     u32::from(buf[2]) << 8 | u32::from(buf[3]);
+        let fraction = f64::from(random) / f64::from(u32::max_value());
+        let millis = duration_millis(delay);
+        Duration::from_millis(millis - (millis as f64 * jitter * fraction) as u64)
+    }
+
+    fn exponential_delay(&self, attempt: u32) -> Duration {
+        let max = duration_millis(self.max_delay);
+        let exponent = attempt.max(1) - 1;
+        let millis = if exponent >= 32 {
+            max
+        } else {
+            duration_millis(self.base_delay).saturating_mul(1u64 << exponent).min(max)
+        };
+        Duration::from_millis(millis)
+    }
+}
+
+fn duration_millis(d: Duration) -> u64 {
+    d.as_secs().saturating_mul(1000).saturating_add(u64::from(d.subsec_millis()))
+}
+
+/// Whether `request` can safely be sent again if we don't know whether the
+/// first attempt reached the server.
+pub fn is_idempotent(request: &HttpRequest) -> bool {
+    match request.method {
+        Method::Get | Method::Head | Method::Put | Method::Delete => true,
+        Method::Post => request.url.query_pairs().any(|(name, _)| name == "batch"),
+        _ => false,
+    }
+}
+
+/// Whether a response with this status is worth retrying.
+#[inline]
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TooManyRequests || status.is_server_error()
+}
+
+/// How long the server asked us to wait, from the `Retry-After` or
+/// `X-Weave-Backoff` headers, whichever is longer.
+pub fn server_backoff(headers: &Headers) -> Option<Duration> {
+    let retry_after = headers.get::<RetryAfter>()
+                             .map(|h| **h)
+                             .and_then(|secs| if secs >= 0.0 { Some(secs) } else { None })
+                             .map(|secs| Duration::from_millis((secs * 1000.0) as u64));
+    let backoff = headers.get::<XWeaveBackoff>().map(|h| Duration::from_secs(**h));
+    match (retry_after, backoff) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Url;
+
+    #[test]
+    fn test_delay_doubles_up_to_max() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter: 0.0,
+        };
+        let delays: Vec<_> = (1..7).map(|attempt| duration_millis(policy.delay_for(attempt))).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.delay_for(100), Duration::from_millis(1000));
+
+        let jittered = RetryPolicy { jitter: 0.5, .. policy };
+        for _ in 0..20 {
+            let delay = jittered.delay_for(3);
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400),
+                    "{:?} out of range", delay);
+        }
+    }
+
+    #[test]
+    fn test_idempotency() {
+        let request = |method, url: &str| HttpRequest::new(method, Url::parse(url).unwrap());
+        let base = "https://storage.example.com/1.5/12345/storage/passwords";
+        assert!(is_idempotent(&request(Method::Get, base)));
+        assert!(is_idempotent(&request(Method::Put, base)));
+        assert!(is_idempotent(&request(Method::Delete, base)));
+        assert!(is_idempotent(&request(Method::Post, &format!("{}?batch=true", base))));
+        assert!(is_idempotent(&request(Method::Post, &format!("{}?batch=b1&commit=true", base))));
+        assert!(!is_idempotent(&request(Method::Post, base)));
+    }
+
+    #[test]
+    fn test_server_backoff() {
+        let mut headers = Headers::new();
+        assert_eq!(server_backoff(&headers), None);
+        headers.set(RetryAfter(1.5));
+        assert_eq!(server_backoff(&headers), Some(Duration::from_millis(1500)));
+        headers.set(XWeaveBackoff(60));
+        assert_eq!(server_backoff(&headers), Some(Duration::from_secs(60)));
+    }
+}
@@ -3,6 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use bso_record::BsoRecord;
 use client::SetupStorageClient;
@@ -63,6 +64,32 @@ impl GlobalState {
         self.collections.get(coll).cloned().unwrap_or(SERVER_EPOCH)
     }
 
+    /// The version of `engine`'s records listed in `meta/global`, if any.
+    pub fn engine_version(&self, engine: &str) -> Option<usize> {
+        self.global
+            .as_ref()
+            .and_then(|global| global.engines.get(engine))
+            .map(|engine| engine.version)
+    }
+
+    /// The sync ID of `engine` listed in `meta/global`, if any.
+    pub fn engine_sync_id(&self, engine: &str) -> Option<&str> {
+        self.global
+            .as_ref()
+            .and_then(|global| global.engines.get(engine))
+            .map(|engine| engine.sync_id.as_str())
+    }
+
+    /// The server's previous storage version and ours, if we wiped the
+    /// server because it had an older one. Every engine is reset when this
+    /// happens, but apps may have other local state to migrate.
+    pub fn storage_version_change(&self) -> Option<(usize, usize)> {
+        self.engine_state_changes.iter().filter_map(|change| match change {
+            EngineStateChange::StorageVersionChanged { from, to } => Some((*from, *to)),
+            _ => None,
+        }).last()
+    }
+
     /// Returns a set of all engine names that should be reset locally.
     pub fn engines_that_need_local_reset(&self) -> HashSet<String> {
         let all_engines = self.global
@@ -247,6 +274,7 @@ pub struct SetupStateMachine<'client, 'keys> {
     root_key: &'keys KeyBundle,
     allowed_states: Vec<&'static str>,
     sequence: Vec<&'static str>,
+    trace: Vec<Transition>,
 }
 
 impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
@@ -328,11 +356,26 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
             client,
             root_key,
             sequence: Vec::new(),
+            trace: Vec::new(),
             allowed_states,
         }
     }
 
-    fn advance(&self, from: SetupState) -> error::Result<SetupState> {
+    /// The transitions made by `to_ready` so far, in order.
+    #[inline]
+    pub fn trace(&self) -> &[Transition] {
+        &self.trace
+    }
+
+    /// Renders `trace()` as a Graphviz DOT graph, for debugging. See
+    /// `trace_to_dot`.
+    #[inline]
+    pub fn trace_as_dot(&self) -> String {
+        trace_to_dot(&self.trace)
+    }
+
+    // Returns the next state, and why we moved to it.
+    fn advance(&self, from: SetupState) -> error::Result<(SetupState, &'static str)> {
         match from {
             // Fetch `info/configuration` with current server limits, and
             // `info/collections` with collection last modified times.
@@ -340,24 +383,24 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 let config = self.client
                     .fetch_info_configuration()
                     .unwrap_or(state.config);
-                Ok(InitialWithLiveTokenAndConfig(GlobalState {
+                Ok((InitialWithLiveTokenAndConfig(GlobalState {
                     config,
                     collections: state.collections,
                     global: state.global,
                     keys: state.keys,
                     engine_state_changes: Vec::new(),
-                }))
+                }), "fetched info/configuration"))
             }
 
             InitialWithLiveTokenAndConfig(state) => {
                 let collections = self.client.fetch_info_collections()?;
-                Ok(InitialWithLiveTokenAndInfo(GlobalState {
+                Ok((InitialWithLiveTokenAndInfo(GlobalState {
                     config: state.config,
                     collections,
                     global: state.global,
                     keys: state.keys,
                     engine_state_changes: state.engine_state_changes,
-                }))
+                }), "fetched info/collections"))
             }
 
             // Compare local and remote `meta/global` timestamps to determine
@@ -371,10 +414,10 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 Ok(match action {
                     // Hooray, we don't need to fetch `meta/global`. Skip to
                     // the next state.
-                    FetchAction::Skip => HasMetaGlobal(state),
+                    FetchAction::Skip => (HasMetaGlobal(state), "cached meta/global is up-to-date"),
                     // Our `meta/global` is out of date, or isn't cached
                     // locally, so we need to fetch it from the server.
-                    FetchAction::Fetch => NeedsFreshMetaGlobal(state),
+                    FetchAction::Fetch => (NeedsFreshMetaGlobal(state), "cached meta/global is stale or missing"),
                     // We have a `meta/global` record in our cache, but not on
                     // the server. This likely means we're the first client to
                     // sync after a node reassignment. Invalidate our cached
@@ -382,28 +425,28 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     // `meta/global` from the server anyway. If another client
                     // wins the race, we'll fetch its `meta/global`; if not,
                     // we'll fail and upload our own.
-                    FetchAction::InvalidateThenUpload => NeedsFreshMetaGlobal(GlobalState {
+                    FetchAction::InvalidateThenUpload => (NeedsFreshMetaGlobal(GlobalState {
                         config: state.config,
                         collections: state.collections,
                         global: None,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
-                    }),
+                    }), "meta/global missing from server; invalidated cache"),
                 })
             }
 
             // Fetch `meta/global` from the server.
             NeedsFreshMetaGlobal(state) => match self.client.fetch_meta_global() {
-                Ok(new_global) => Ok(ResolveMetaGlobal(state, new_global)),
+                Ok(new_global) => Ok((ResolveMetaGlobal(state, new_global), "fetched meta/global")),
                 Err(err) => match err.kind() {
-                    ErrorKind::NoMetaGlobal { .. } => Ok(FreshStartRequired(state)),
+                    ErrorKind::NoMetaGlobal { .. } => Ok((FreshStartRequired(state), "no meta/global on server")),
                     _ => Err(err),
                 },
             },
 
             // Reconcile the server's `meta/global` with our locally cached
             // `meta/global`, if any.
-            ResolveMetaGlobal(state, new_global) => {
+            ResolveMetaGlobal(mut state, new_global) => {
                 // If the server has a newer storage version, we can't
                 // sync until our client is updated.
                 if new_global.payload.storage_version > STORAGE_VERSION {
@@ -413,11 +456,15 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 // If the server has an older storage version, wipe and
                 // reupload.
                 if new_global.payload.storage_version < STORAGE_VERSION {
-                    return Ok(FreshStartRequired(state));
+                    state.engine_state_changes.push(EngineStateChange::StorageVersionChanged {
+                        from: new_global.payload.storage_version,
+                        to: STORAGE_VERSION,
+                    });
+                    return Ok((FreshStartRequired(state), "server storage version is outdated"));
                 }
 
                 let new_state = resolve_global(state, new_global);
-                Ok(HasMetaGlobal(new_state))
+                Ok((HasMetaGlobal(new_state), "resolved meta/global"))
             }
 
             // Check if our locally cached `crypto/keys` collection is
@@ -432,19 +479,19 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 };
                 Ok(match action {
                     // If `crypto/keys` is up-to-date, we're ready to go!
-                    FetchAction::Skip => Ready(state),
+                    FetchAction::Skip => (Ready(state), "cached crypto/keys are up-to-date"),
                     // We need to fetch and cache new keys.
-                    FetchAction::Fetch => NeedsFreshCryptoKeys(state),
+                    FetchAction::Fetch => (NeedsFreshCryptoKeys(state), "cached crypto/keys are stale or missing"),
                     // We need to invalidate our locally cached `crypto/keys`,
                     // then try to fetch new keys, and reupload if fetching
                     // fails.
-                    FetchAction::InvalidateThenUpload => NeedsFreshCryptoKeys(GlobalState {
+                    FetchAction::InvalidateThenUpload => (NeedsFreshCryptoKeys(GlobalState {
                         config: state.config,
                         collections: state.collections,
                         global: state.global,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
-                    }),
+                    }), "crypto/keys missing from server; invalidated cache"),
                 })
             }
 
@@ -454,18 +501,18 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         let new_keys =
                             CollectionKeys::from_encrypted_bso(encrypted_bso, self.root_key)?;
                         let new_state = resolve_keys(state, new_keys);
-                        Ok(Ready(new_state))
+                        Ok((Ready(new_state), "fetched crypto/keys"))
                     }
                     Err(err) => match err.kind() {
                         // If the server doesn't have a `crypto/keys`, start over
                         // and reupload our `meta/global` and `crypto/keys`.
-                        ErrorKind::NoCryptoKeys { .. } => Ok(FreshStartRequired(state)),
+                        ErrorKind::NoCryptoKeys { .. } => Ok((FreshStartRequired(state), "no crypto/keys on server")),
                         _ => Err(err),
                     },
                 }
             }
 
-            Ready(state) => Ok(Ready(state)),
+            Ready(state) => Ok((Ready(state), "ready")),
 
             FreshStartRequired(state) => {
                 // Wipe the server.
@@ -485,21 +532,33 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 let new_keys = CollectionKeys::new_random()?.to_encrypted_bso(&self.root_key)?;
                 self.client.put_crypto_keys(&new_keys)?;
 
+                // Everything else we knew about the server is gone, but
+                // keep why we started over.
+                let mut engine_state_changes: Vec<_> = state.engine_state_changes
+                    .into_iter()
+                    .filter(|change| match change {
+                        EngineStateChange::StorageVersionChanged { .. } => true,
+                        _ => false,
+                    })
+                    .collect();
+                engine_state_changes.push(EngineStateChange::ResetAll);
+
                 // TODO(lina): Can we pass along server timestamps from the PUTs
                 // above, and avoid re-fetching the `m/g` and `c/k` we just
                 // uploaded?
-                Ok(InitialWithLiveTokenAndConfig(GlobalState {
+                Ok((InitialWithLiveTokenAndConfig(GlobalState {
                     config: state.config,
                     collections: InfoCollections::default(),
                     global: None,
                     keys: None,
-                    engine_state_changes: vec![EngineStateChange::ResetAll],
-                }))
+                    engine_state_changes,
+                }), "wiped server and uploaded fresh meta/global and crypto/keys"))
             }
         }
     }
 
-    /// Runs through the state machine to the ready state.
+    /// Runs through the state machine to the ready state. Each transition is
+    /// recorded in `trace()`, including those made before an error.
     pub fn to_ready(&mut self, state: GlobalState) -> error::Result<GlobalState> {
         let mut s = InitialWithLiveToken(state);
         loop {
@@ -522,13 +581,61 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         return Err(ErrorKind::DisallowedStateError(&label).into());
                     }
                     self.sequence.push(label);
-                    s = self.advance(previous_s)?;
+                    let started = Instant::now();
+                    let (next_s, reason) = self.advance(previous_s)?;
+                    debug!("Setup state {} -> {}: {}", label, next_s.label(), reason);
+                    self.trace.push(Transition {
+                        from: label,
+                        to: next_s.label(),
+                        reason,
+                        elapsed: started.elapsed(),
+                    });
+                    s = next_s;
                 }
             }
         }
     }
 }
 
+/// A move from one setup state to the next, recorded by `to_ready`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub from: &'static str,
+    pub to: &'static str,
+    /// Why we moved to `to`, for logging and debugging.
+    pub reason: &'static str,
+    /// How long we spent in `from`, including any requests we made.
+    pub elapsed: Duration,
+}
+
+/// Renders `trace` as a Graphviz DOT graph, using the same node names and
+/// styles as the diagram in `docs/sync/state-machines.md`. Edges are
+/// numbered in the order they were taken, and labeled with their reason and
+/// elapsed time, so the output can be pasted next to the full diagram to see
+/// the path a sync took.
+pub fn trace_to_dot(trace: &[Transition]) -> String {
+    let mut dot = String::from("digraph setup {\n    node [shape=box];\n");
+    if let Some(first) = trace.first() {
+        dot.push_str(&format!("    {} [style=bold];\n", first.from));
+    }
+    for (i, transition) in trace.iter().enumerate() {
+        let elapsed = transition.elapsed;
+        dot.push_str(&format!(
+            "    {} -> {} [label=\"{}. {} ({}ms)\"];\n",
+            transition.from,
+            transition.to,
+            i + 1,
+            transition.reason,
+            elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+        ));
+    }
+    if trace.last().map_or(false, |last| last.to == "Ready") {
+        dot.push_str("    Ready [peripheries=2];\n");
+    }
+    dot.push_str("}\n");
+    dot
+}
+
 /// States in the remote setup process.
 /// TODO(lina): Add link once #56 is merged.
 #[derive(Debug)]
@@ -597,11 +704,14 @@ pub enum EngineStateChange {
     Enable(String),
     Disable(String),
     Reset(String),
+    /// We wiped the server because its storage version was older than ours.
+    StorageVersionChanged { from: usize, to: usize },
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::{Cell, RefCell};
     use reqwest;
 
     use bso_record::{BsoRecord, EncryptedBso, EncryptedPayload};
@@ -676,25 +786,126 @@ mod tests {
         }
     }
 
+    // A storage server that keeps `meta/global` and `crypto/keys` in memory,
+    // and bumps their last modified times when they're written.
+    struct InMemoryServer {
+        collections: RefCell<HashMap<String, ServerTimestamp>>,
+        meta_global: RefCell<Option<BsoRecord<MetaGlobalRecord>>>,
+        crypto_keys: RefCell<Option<EncryptedBso>>,
+        now: Cell<i64>,
+    }
+
+    impl InMemoryServer {
+        fn new() -> InMemoryServer {
+            InMemoryServer {
+                collections: RefCell::new(HashMap::new()),
+                meta_global: RefCell::new(None),
+                crypto_keys: RefCell::new(None),
+                now: Cell::new(1_000_000),
+            }
+        }
+
+        // A server with a `meta/global` of `storage_version`, and fresh keys
+        // encrypted with `root_key`.
+        fn with_records(storage_version: usize, root_key: &KeyBundle) -> InMemoryServer {
+            let client = InMemoryServer::new();
+            client.put_meta_global(&BsoRecord::new_record("global".into(), "meta".into(), MetaGlobalRecord {
+                sync_id: "syncIDAAAAAA".to_owned(),
+                storage_version,
+                engines: vec![(
+                    "bookmarks".to_owned(),
+                    MetaGlobalEngine { version: 1, sync_id: "syncIDBBBBBB".to_owned() },
+                )].into_iter().collect(),
+                declined: vec![],
+            })).unwrap();
+            let keys = CollectionKeys::new_random().unwrap().to_encrypted_bso(root_key).unwrap();
+            client.put_crypto_keys(&keys).unwrap();
+            client
+        }
+
+        fn tick(&self, collection: &str) -> ServerTimestamp {
+            self.now.set(self.now.get() + 1000);
+            let now = ServerTimestamp(self.now.get());
+            self.collections.borrow_mut().insert(collection.to_owned(), now);
+            now
+        }
+    }
+
+    impl SetupStorageClient for InMemoryServer {
+        fn fetch_info_configuration(&self) -> error::Result<InfoConfiguration> {
+            Ok(InfoConfiguration::default())
+        }
+
+        fn fetch_info_collections(&self) -> error::Result<InfoCollections> {
+            Ok(InfoCollections::new(self.collections.borrow().clone()))
+        }
+
+        fn fetch_meta_global(&self) -> error::Result<BsoRecord<MetaGlobalRecord>> {
+            self.meta_global.borrow().clone().ok_or_else(|| ErrorKind::NoMetaGlobal.into())
+        }
+
+        fn put_meta_global(&self, global: &BsoRecord<MetaGlobalRecord>) -> error::Result<()> {
+            let mut global = global.clone();
+            global.modified = self.tick("meta");
+            *self.meta_global.borrow_mut() = Some(global);
+            Ok(())
+        }
+
+        fn fetch_crypto_keys(&self) -> error::Result<EncryptedBso> {
+            self.crypto_keys.borrow().clone().ok_or_else(|| ErrorKind::NoCryptoKeys.into())
+        }
+
+        fn put_crypto_keys(&self, keys: &EncryptedBso) -> error::Result<()> {
+            let mut keys = keys.clone();
+            keys.modified = self.tick("crypto");
+            *self.crypto_keys.borrow_mut() = Some(keys);
+            Ok(())
+        }
+
+        fn wipe_all_remote(&self) -> error::Result<()> {
+            self.collections.borrow_mut().clear();
+            *self.meta_global.borrow_mut() = None;
+            *self.crypto_keys.borrow_mut() = None;
+            Ok(())
+        }
+    }
+
+    fn path(trace: &[Transition]) -> Vec<(&'static str, &'static str, &'static str)> {
+        trace.iter().map(|t| (t.from, t.to, t.reason)).collect()
+    }
+
+    // The transitions after a fresh start, which are the same however we got
+    // there.
+    const AFTER_FRESH_START: &[(&str, &str, &str)] = &[
+        ("FreshStartRequired", "InitialWithLiveTokenAndConfig",
+         "wiped server and uploaded fresh meta/global and crypto/keys"),
+        ("InitialWithLiveTokenAndConfig", "InitialWithLiveTokenAndInfo", "fetched info/collections"),
+        ("InitialWithLiveTokenAndInfo", "NeedsFreshMetaGlobal", "cached meta/global is stale or missing"),
+        ("NeedsFreshMetaGlobal", "ResolveMetaGlobal", "fetched meta/global"),
+        ("ResolveMetaGlobal", "HasMetaGlobal", "resolved meta/global"),
+        ("HasMetaGlobal", "NeedsFreshCryptoKeys", "cached crypto/keys are stale or missing"),
+        ("NeedsFreshCryptoKeys", "Ready", "fetched crypto/keys"),
+    ];
+
     #[test]
     fn test_state_machine_ready_from_empty() {
         let root_key = KeyBundle::new_random().unwrap();
         let keys = CollectionKeys {
-            timestamp: 123.4.into(),
+            timestamp: ServerTimestamp(123_400),
             default: KeyBundle::new_random().unwrap(),
             collections: HashMap::new(),
         };
         let client = InMemoryClient {
             info_configuration: Ok(InfoConfiguration::default()),
             info_collections: Ok(InfoCollections::new(
-                vec![("meta", 123.456), ("crypto", 145.0)]
+                vec![("meta", 123_456), ("crypto", 145_000)]
                     .into_iter()
-                    .map(|(key, value)| (key.to_owned(), value.into()))
+                    .map(|(key, value)| (key.to_owned(), ServerTimestamp(value)))
                     .collect(),
             )),
             meta_global: Ok(BsoRecord {
                 id: "global".into(),
-                modified: ServerTimestamp(999.0),
+                modified: ServerTimestamp(999_000),
                 collection: "meta".into(),
                 sortindex: None,
                 ttl: None,
@@ -739,4 +950,155 @@ mod tests {
             "Should cycle through all states"
         );
     }
+
+    #[test]
+    fn test_trace_ready_from_empty() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = InMemoryServer::with_records(5, &root_key);
+
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        state_machine.to_ready(GlobalState::default()).unwrap();
+        assert_eq!(path(state_machine.trace()), vec![
+            ("InitialWithLiveToken", "InitialWithLiveTokenAndConfig", "fetched info/configuration"),
+            ("InitialWithLiveTokenAndConfig", "InitialWithLiveTokenAndInfo", "fetched info/collections"),
+            ("InitialWithLiveTokenAndInfo", "NeedsFreshMetaGlobal", "cached meta/global is stale or missing"),
+            ("NeedsFreshMetaGlobal", "ResolveMetaGlobal", "fetched meta/global"),
+            ("ResolveMetaGlobal", "HasMetaGlobal", "resolved meta/global"),
+            ("HasMetaGlobal", "NeedsFreshCryptoKeys", "cached crypto/keys are stale or missing"),
+            ("NeedsFreshCryptoKeys", "Ready", "fetched crypto/keys"),
+        ]);
+    }
+
+    #[test]
+    fn test_trace_fresh_start() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = InMemoryServer::new();
+
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        let state = state_machine.to_ready(GlobalState::default()).unwrap();
+        assert_eq!(state.storage_version_change(), None);
+        assert_eq!(state.global.unwrap().payload.storage_version, STORAGE_VERSION);
+
+        let mut expected = vec![
+            ("InitialWithLiveToken", "InitialWithLiveTokenAndConfig", "fetched info/configuration"),
+            ("InitialWithLiveTokenAndConfig", "InitialWithLiveTokenAndInfo", "fetched info/collections"),
+            ("InitialWithLiveTokenAndInfo", "NeedsFreshMetaGlobal", "cached meta/global is stale or missing"),
+            ("NeedsFreshMetaGlobal", "FreshStartRequired", "no meta/global on server"),
+        ];
+        expected.extend_from_slice(AFTER_FRESH_START);
+        assert_eq!(path(state_machine.trace()), expected);
+    }
+
+    #[test]
+    fn test_trace_node_reassignment() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = InMemoryServer::with_records(5, &root_key);
+        let state = SetupStateMachine::for_full_sync(&client, &root_key)
+            .to_ready(GlobalState::default()).unwrap();
+
+        // Syncing again against the same server uses our cached records.
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        let state = state_machine.to_ready(state).unwrap();
+        assert_eq!(path(state_machine.trace()), vec![
+            ("InitialWithLiveToken", "InitialWithLiveTokenAndConfig", "fetched info/configuration"),
+            ("InitialWithLiveTokenAndConfig", "InitialWithLiveTokenAndInfo", "fetched info/collections"),
+            ("InitialWithLiveTokenAndInfo", "HasMetaGlobal", "cached meta/global is up-to-date"),
+            ("HasMetaGlobal", "Ready", "cached crypto/keys are up-to-date"),
+        ]);
+
+        // After a node reassignment, the new node is empty, so we're the
+        // first client to sync to it.
+        let new_node = InMemoryServer::new();
+        let mut state_machine = SetupStateMachine::for_full_sync(&new_node, &root_key);
+        state_machine.to_ready(state).unwrap();
+        let mut expected = vec![
+            ("InitialWithLiveToken", "InitialWithLiveTokenAndConfig", "fetched info/configuration"),
+            ("InitialWithLiveTokenAndConfig", "InitialWithLiveTokenAndInfo", "fetched info/collections"),
+            ("InitialWithLiveTokenAndInfo", "NeedsFreshMetaGlobal",
+             "meta/global missing from server; invalidated cache"),
+            ("NeedsFreshMetaGlobal", "FreshStartRequired", "no meta/global on server"),
+        ];
+        expected.extend_from_slice(AFTER_FRESH_START);
+        assert_eq!(path(state_machine.trace()), expected);
+    }
+
+    #[test]
+    fn test_trace_storage_version_upgrade() {
+        let root_key = KeyBundle::new_random().unwrap();
+
+        // An older storage version gets wiped and replaced with ours.
+        let client = InMemoryServer::with_records(STORAGE_VERSION - 1, &root_key);
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        let state = state_machine.to_ready(GlobalState::default()).unwrap();
+        assert_eq!(state.storage_version_change(), Some((STORAGE_VERSION - 1, STORAGE_VERSION)));
+        assert!(state.engines_that_need_local_reset().contains("bookmarks"));
+        assert_eq!(state.global.unwrap().payload.storage_version, STORAGE_VERSION);
+        let mut expected = vec![
+            ("InitialWithLiveToken", "InitialWithLiveTokenAndConfig", "fetched info/configuration"),
+            ("InitialWithLiveTokenAndConfig", "InitialWithLiveTokenAndInfo", "fetched info/collections"),
+            ("InitialWithLiveTokenAndInfo", "NeedsFreshMetaGlobal", "cached meta/global is stale or missing"),
+            ("NeedsFreshMetaGlobal", "ResolveMetaGlobal", "fetched meta/global"),
+            ("ResolveMetaGlobal", "FreshStartRequired", "server storage version is outdated"),
+        ];
+        expected.extend_from_slice(AFTER_FRESH_START);
+        assert_eq!(path(state_machine.trace()), expected);
+
+        // A newer one means we need to be updated, but the trace still shows
+        // how far we got.
+        let client = InMemoryServer::with_records(STORAGE_VERSION + 1, &root_key);
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        let err = state_machine.to_ready(GlobalState::default()).unwrap_err();
+        assert_eq!(err.class(), error::ErrorClass::ClientUpgradeRequired);
+        assert_eq!(path(state_machine.trace()).last(),
+                   Some(&("NeedsFreshMetaGlobal", "ResolveMetaGlobal", "fetched meta/global")));
+    }
+
+    #[test]
+    fn test_trace_to_dot() {
+        let trace = vec![
+            Transition {
+                from: "HasMetaGlobal",
+                to: "NeedsFreshCryptoKeys",
+                reason: "cached crypto/keys are stale or missing",
+                elapsed: Duration::from_millis(3),
+            },
+            Transition {
+                from: "NeedsFreshCryptoKeys",
+                to: "Ready",
+                reason: "fetched crypto/keys",
+                elapsed: Duration::new(1, 250_000_000),
+            },
+        ];
+        assert_eq!(trace_to_dot(&trace), "digraph setup {
+    node [shape=box];
+    HasMetaGlobal [style=bold];
+    HasMetaGlobal -> NeedsFreshCryptoKeys [label=\"1. cached crypto/keys are stale or missing (3ms)\"];
+    NeedsFreshCryptoKeys -> Ready [label=\"2. fetched crypto/keys (1250ms)\"];
+    Ready [peripheries=2];
+}
+");
+        assert_eq!(trace_to_dot(&[]), "digraph setup {\n    node [shape=box];\n}\n");
+    }
+
+    #[test]
+    fn test_engine_version() {
+        let engine = |version| MetaGlobalEngine { version, sync_id: "syncIDBBBBBB".to_owned() };
+        let global = |version| BsoRecord::new_record("global".into(), "meta".into(), MetaGlobalRecord {
+            sync_id: "syncIDAAAAAA".to_owned(),
+            storage_version: STORAGE_VERSION,
+            engines: vec![
+                ("bookmarks".to_owned(), engine(version)),
+                ("passwords".to_owned(), engine(1)),
+            ].into_iter().collect(),
+            declined: vec![],
+        });
+        let previous = GlobalState { global: Some(global(1)), .. GlobalState::default() };
+        let state = resolve_global(previous, global(2));
+
+        assert_eq!(state.engine_version("bookmarks"), Some(2));
+        assert_eq!(state.engine_version("history"), None);
+        assert_eq!(state.engine_sync_id("bookmarks"), Some("syncIDBBBBBB"));
+        assert_eq!(state.storage_version_change(), None);
+        assert!(state.engines_that_need_local_reset().is_empty());
+    }
 }
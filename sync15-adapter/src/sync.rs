@@ -4,9 +4,13 @@
 
 use changeset::{CollectionUpdate, IncomingChangeset, OutgoingChangeset};
 use client::Sync15StorageClient;
+use download::{fetch_collections, CollectionDownload, DownloadOptions};
 use error;
+use policy::{CollectionPolicy, OutgoingPolicy};
+use request::{CollectionRequest, RequestOrder};
 use state::GlobalState;
-use util::ServerTimestamp;
+use util::{ServerTimestamp, SERVER_EPOCH};
+use validation::{QuarantinedRecord, RecordSchema};
 
 /// Low-level store functionality. Stores that need custom reconciliation logic should use this.
 ///
@@ -28,6 +32,114 @@ pub trait Store {
         new_timestamp: ServerTimestamp,
         records_synced: &[String],
     ) -> Result<(), Self::Error>;
+
+    /// Describes what this store's incoming records must look like. Records
+    /// that don't match (or aren't valid JSON, or have no id) are quarantined
+    /// instead of being passed to `apply_incoming`. By default we only require
+    /// that records parse and have an id.
+    fn record_schema(&self) -> Option<RecordSchema> {
+        None
+    }
+
+    /// Returns the ids of records quarantined by a previous sync, so they
+    /// can be fetched and validated again.
+    fn quarantined_ids(&self) -> Result<Vec<String>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    /// Replaces the store's set of quarantined records. This is called once
+    /// per sync, after `apply_incoming`, with every record that failed
+    /// validation (including retried records that failed again), so records
+    /// which now validate drop out of the quarantine. Stores that want
+    /// quarantined records to be retried need to persist these.
+    fn set_quarantined(&mut self, _records: Vec<QuarantinedRecord>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Returns the policy used to set the `ttl` and `sortindex` of outgoing
+    /// records. The default sets neither.
+    fn outgoing_policy<'a>(&'a self) -> Box<OutgoingPolicy + 'a> {
+        Box::new(CollectionPolicy::default())
+    }
+
+    /// The order incoming records should be downloaded in. Stores that set a
+    /// sortindex on their records can use `RequestOrder::Index` to see the
+    /// most important records first.
+    fn download_order(&self) -> Option<RequestOrder> {
+        None
+    }
+
+    /// The newest version of this engine's records (as listed in
+    /// `meta/global`) that the store understands. If another client bumps
+    /// the version past this, we stop syncing this engine, but keep syncing
+    /// the others, until the store is updated.
+    fn engine_version(&self) -> usize {
+        1
+    }
+
+    /// The version of this engine's records that the store's local data is
+    /// in: the `to_version` of the last successful `migrate`. Stores that
+    /// don't keep track can leave this as None, and are never migrated.
+    fn local_engine_version(&self) -> Result<Option<usize>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Called before syncing whenever the engine's version in `meta/global`
+    /// (which is at most `engine_version()`) differs from
+    /// `local_engine_version()`, so the store can migrate its local data from
+    /// `from_version` to `to_version`. The store should persist `to_version`
+    /// as its local engine version along with the migrated data. If this
+    /// fails, or we decline the engine because its version is too new, we
+    /// try again on the next sync.
+    fn migrate(&mut self, _from_version: usize, _to_version: usize) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Returns the engine's sync ID (from `meta/global`) that the store last
+    /// synced against, or None if it hasn't synced yet. When this differs
+    /// from the sync ID in `meta/global`, another client reset the engine,
+    /// and we call `reset` before syncing. Stores that don't persist their
+    /// sync ID can leave this as None, and are never reset.
+    fn sync_id(&self) -> Result<Option<String>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Stores the engine's sync ID. This is called before syncing whenever
+    /// `sync_id` doesn't match `meta/global`, after any reset.
+    fn set_sync_id(&mut self, _sync_id: String) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Forgets the store's last sync timestamp, and marks every local record
+    /// as changed, so that the next sync downloads and uploads everything.
+    /// There's no default, because a store that ignored this would keep
+    /// syncing against a timestamp from before the engine was reset.
+    fn reset(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Summarizes what happened while syncing a single collection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncInfo {
+    /// Number of incoming records passed to the store.
+    pub applied: usize,
+    /// Number of incoming records that failed validation.
+    pub quarantined: usize,
+    /// Number of previously quarantined records we tried to fetch again.
+    pub retried: usize,
+    pub uploaded: usize,
+    pub failed_uploads: usize,
+    /// True if we didn't sync the collection, because `meta/global` lists a
+    /// newer version of the engine than the store supports.
+    pub declined: bool,
+    /// True if we reset the store before syncing, because the engine's sync
+    /// ID changed.
+    pub reset: bool,
+}
+
+impl SyncInfo {
+    fn declined() -> SyncInfo {
+        SyncInfo { declined: true, .. SyncInfo::default() }
+    }
 }
 
 pub fn synchronize<E>(client: &Sync15StorageClient,
@@ -35,16 +147,174 @@ pub fn synchronize<E>(client: &Sync15StorageClient,
                    store: &mut Store<Error=E>,
                    collection: String,
                    timestamp: ServerTimestamp,
-                   fully_atomic: bool) -> Result<(), E>
+                   fully_atomic: bool) -> Result<SyncInfo, E>
 where E: From<error::Error>
 {
 
     info!("Syncing collection {}", collection);
-    let incoming_changes = IncomingChangeset::fetch(client, state, collection.clone(), timestamp)?;
+    let prepared = match prepare_engine(state, store, &collection, timestamp)? {
+        Some(prepared) => prepared,
+        None => return Ok(SyncInfo::declined()),
+    };
+    let download = prepare_download(store, collection, prepared.timestamp)?;
+    let retried = download.retry_ids.len();
+
+    let (incoming_changes, quarantined) = IncomingChangeset::fetch_validated(
+        client, state, &download.request, download.schema.as_ref(), &download.retry_ids)?;
+
+    let mut info = apply_and_upload(client, state, store, incoming_changes, quarantined,
+                                    prepared.timestamp, retried, fully_atomic)?;
+    info.reset = prepared.reset;
+    Ok(info)
+}
+
+/// A store to sync with `synchronize_many`, and the arguments we'd otherwise
+/// pass to `synchronize` for it.
+pub struct StoreSync<'a, E: 'a> {
+    pub store: &'a mut Store<Error=E>,
+    pub collection: String,
+    pub timestamp: ServerTimestamp,
+}
+
+/// Like calling `synchronize` for each store, except that the collections
+/// are downloaded (and decrypted) concurrently before any changes are
+/// applied. Incoming changes are still applied, and outgoing changes
+/// uploaded, one store at a time, in order. Stops at the first error.
+///
+/// Stores for engines with a newer version in `meta/global` than they
+/// support are skipped, and get a `SyncInfo` with `declined` set.
+pub fn synchronize_many<E>(client: &Sync15StorageClient,
+                           state: &GlobalState,
+                           mut stores: Vec<StoreSync<E>>,
+                           fully_atomic: bool,
+                           options: DownloadOptions) -> Result<Vec<SyncInfo>, E>
+where E: From<error::Error>
+{
+    let mut prepared = Vec::with_capacity(stores.len());
+    let mut downloads = Vec::with_capacity(stores.len());
+    for store_sync in &mut stores {
+        let p = prepare_engine(state, &mut *store_sync.store, &store_sync.collection,
+                               store_sync.timestamp)?;
+        if let Some(ref p) = p {
+            downloads.push(prepare_download(&*store_sync.store,
+                                            store_sync.collection.clone(),
+                                            p.timestamp)?);
+        }
+        prepared.push(p);
+    }
+    let retried: Vec<usize> = downloads.iter().map(|d| d.retry_ids.len()).collect();
+
+    info!("Downloading {} collections", downloads.len());
+    let mut downloaded = fetch_collections(client, state, downloads, options)?
+        .into_iter()
+        .zip(retried);
+
+    let mut infos = Vec::with_capacity(stores.len());
+    for (store_sync, prepared) in stores.into_iter().zip(prepared) {
+        let prepared = match prepared {
+            Some(prepared) => prepared,
+            None => {
+                infos.push(SyncInfo::declined());
+                continue;
+            }
+        };
+        let (download, retried) = downloaded.next().expect("one download per syncable store");
+        info!("Syncing collection {}", store_sync.collection);
+        let mut info = apply_and_upload(client, state, store_sync.store, download.changeset,
+                                        download.quarantined, prepared.timestamp, retried,
+                                        fully_atomic)?;
+        info.reset = prepared.reset;
+        infos.push(info);
+    }
+    Ok(infos)
+}
+
+// What `prepare_engine` did to a store that we're going to sync.
+struct PreparedEngine {
+    // The timestamp to sync from, which is zero if we reset the store.
+    timestamp: ServerTimestamp,
+    reset: bool,
+}
+
+/// Checks that the store understands the version of `collection` in
+/// `meta/global`, resets it if another client reset the engine, and migrates
+/// it if the version changed. Returns None if we should skip the collection.
+fn prepare_engine<E>(state: &GlobalState,
+                     store: &mut Store<Error=E>,
+                     collection: &str,
+                     timestamp: ServerTimestamp) -> Result<Option<PreparedEngine>, E>
+{
+    let supported = store.engine_version();
+    let version = state.engine_version(collection);
+    if let Some(version) = version {
+        if version > supported {
+            warn!("Not syncing {}: meta/global has version {}, but we only support {}",
+                  collection, version, supported);
+            return Ok(None);
+        }
+    }
+
+    let mut prepared = PreparedEngine { timestamp, reset: false };
+    if let Some(sync_id) = state.engine_sync_id(collection) {
+        let local_sync_id = store.sync_id()?;
+        if local_sync_id.as_ref().map(|id| id.as_str()) != Some(sync_id) {
+            if local_sync_id.is_some() {
+                info!("Sync ID for {} changed to {}; resetting", collection, sync_id);
+                store.reset()?;
+                prepared = PreparedEngine { timestamp: SERVER_EPOCH, reset: true };
+            }
+            store.set_sync_id(sync_id.to_string())?;
+        }
+    }
+
+    // We compare against the store's own version, rather than our cached
+    // `meta/global`, so that a migration we skipped or failed is retried.
+    if let (Some(local_version), Some(version)) = (store.local_engine_version()?, version) {
+        if local_version != version {
+            info!("Migrating {} from version {} to {}", collection, local_version, version);
+            store.migrate(local_version, version)?;
+        }
+    }
+    Ok(Some(prepared))
+}
+
+fn prepare_download<E>(store: &Store<Error=E>,
+                       collection: String,
+                       timestamp: ServerTimestamp) -> Result<CollectionDownload, E>
+{
+    let mut request = CollectionRequest::new(collection);
+    request.full().newer_than(timestamp);
+    if let Some(order) = store.download_order() {
+        request.sort_by(order);
+    }
+    Ok(CollectionDownload {
+        request,
+        schema: store.record_schema(),
+        retry_ids: store.quarantined_ids()?,
+    })
+}
+
+fn apply_and_upload<E>(client: &Sync15StorageClient,
+                       state: &GlobalState,
+                       store: &mut Store<Error=E>,
+                       incoming_changes: IncomingChangeset,
+                       quarantined: Vec<QuarantinedRecord>,
+                       timestamp: ServerTimestamp,
+                       retried: usize,
+                       fully_atomic: bool) -> Result<SyncInfo, E>
+where E: From<error::Error>
+{
+    let mut sync_info = SyncInfo::default();
+    sync_info.retried = retried;
     let last_changed_remote = incoming_changes.timestamp;
 
-    info!("Downloaded {} remote changes", incoming_changes.changes.len());
+    info!("Downloaded {} remote changes ({} quarantined)",
+          incoming_changes.changes.len(), quarantined.len());
+    sync_info.applied = incoming_changes.changes.len();
+    sync_info.quarantined = quarantined.len();
+
     let mut outgoing = store.apply_incoming(incoming_changes)?;
+    store.set_quarantined(quarantined)?;
 
     assert_eq!(outgoing.timestamp, timestamp,
         "last sync timestamp should never change unless we change it");
@@ -52,15 +322,161 @@ where E: From<error::Error>
     outgoing.timestamp = last_changed_remote;
 
     info!("Uploading {} outgoing changes", outgoing.changes.len());
-    let upload_info =
-        CollectionUpdate::new_from_changeset(client, state, outgoing, fully_atomic)?.upload()?;
+    let upload_info = {
+        let policy = store.outgoing_policy();
+        CollectionUpdate::new_from_changeset_with_policy(
+            client, state, outgoing, &*policy, fully_atomic)?.upload()?
+    };
 
     info!("Upload success ({} records success, {} records failed)",
           upload_info.successful_ids.len(),
           upload_info.failed_ids.len());
 
+    sync_info.uploaded = upload_info.successful_ids.len();
+    sync_info.failed_uploads = upload_info.failed_ids.len();
+
     store.sync_finished(upload_info.modified_timestamp, &upload_info.successful_ids)?;
 
     info!("Sync finished!");
-    Ok(())
+    Ok(sync_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bso_record::BsoRecord;
+    use record_types::{MetaGlobalEngine, MetaGlobalRecord};
+
+    #[derive(Default)]
+    struct TestStore {
+        version: usize,
+        local_version: Option<usize>,
+        fail_migrations: bool,
+        migrations: Vec<(usize, usize)>,
+        sync_id: Option<String>,
+        resets: usize,
+    }
+
+    impl Store for TestStore {
+        type Error = error::Error;
+
+        fn apply_incoming(&mut self, inbound: IncomingChangeset) -> error::Result<OutgoingChangeset> {
+            Ok(OutgoingChangeset::new(inbound.collection, inbound.timestamp))
+        }
+
+        fn sync_finished(&mut self, _: ServerTimestamp, _: &[String]) -> error::Result<()> {
+            Ok(())
+        }
+
+        fn engine_version(&self) -> usize {
+            self.version
+        }
+
+        fn local_engine_version(&self) -> error::Result<Option<usize>> {
+            Ok(self.local_version)
+        }
+
+        fn migrate(&mut self, from_version: usize, to_version: usize) -> error::Result<()> {
+            self.migrations.push((from_version, to_version));
+            if self.fail_migrations {
+                let err = ::failure::err_msg("migration failed");
+                return Err(error::ErrorKind::StoreError(err).into());
+            }
+            self.local_version = Some(to_version);
+            Ok(())
+        }
+
+        fn sync_id(&self) -> error::Result<Option<String>> {
+            Ok(self.sync_id.clone())
+        }
+
+        fn set_sync_id(&mut self, sync_id: String) -> error::Result<()> {
+            self.sync_id = Some(sync_id);
+            Ok(())
+        }
+
+        fn reset(&mut self) -> error::Result<()> {
+            self.resets += 1;
+            Ok(())
+        }
+    }
+
+    fn state_with_bookmarks(version: usize, sync_id: &str) -> GlobalState {
+        let engines = vec![(
+            "bookmarks".to_owned(),
+            MetaGlobalEngine { version, sync_id: sync_id.to_owned() },
+        )].into_iter().collect();
+        GlobalState {
+            global: Some(BsoRecord::new_record("global".into(), "meta".into(), MetaGlobalRecord {
+                sync_id: "syncIDAAAAAA".to_owned(),
+                storage_version: 5,
+                engines,
+                declined: vec![],
+            })),
+            .. GlobalState::default()
+        }
+    }
+
+    #[test]
+    fn test_prepare_engine_versions() {
+        let mut store = TestStore { version: 2, local_version: Some(1), .. TestStore::default() };
+        let ts = ServerTimestamp(1_000_000);
+
+        // Another client bumped the version past ours, so we decline the
+        // engine, and keep our data as it is.
+        let too_new = state_with_bookmarks(3, "syncIDBBBBBB");
+        assert!(prepare_engine(&too_new, &mut store, "bookmarks", ts).unwrap().is_none());
+        assert!(store.migrations.is_empty());
+
+        // If the migration fails, we try again next time.
+        let upgraded = state_with_bookmarks(2, "syncIDBBBBBB");
+        store.fail_migrations = true;
+        assert!(prepare_engine(&upgraded, &mut store, "bookmarks", ts).is_err());
+        store.fail_migrations = false;
+        assert!(prepare_engine(&upgraded, &mut store, "bookmarks", ts).unwrap().is_some());
+        assert_eq!(store.migrations, vec![(1, 2), (1, 2)]);
+        assert_eq!(store.local_version, Some(2));
+
+        // Once we've migrated, there's nothing more to do.
+        assert!(prepare_engine(&upgraded, &mut store, "bookmarks", ts).unwrap().is_some());
+        assert_eq!(store.migrations.len(), 2);
+
+        // Other engines aren't affected.
+        assert!(prepare_engine(&upgraded, &mut store, "passwords", ts).unwrap().is_some());
+        assert_eq!(store.migrations.len(), 2);
+
+        // Once the store is updated, it catches up with the engine we
+        // declined.
+        store.version = 3;
+        assert!(prepare_engine(&too_new, &mut store, "bookmarks", ts).unwrap().is_some());
+        assert_eq!(store.migrations.last(), Some(&(2, 3)));
+
+        // Stores that don't track their version are never migrated.
+        let mut store = TestStore { version: 2, .. TestStore::default() };
+        assert!(prepare_engine(&upgraded, &mut store, "bookmarks", ts).unwrap().is_some());
+        assert!(store.migrations.is_empty());
+    }
+
+    #[test]
+    fn test_prepare_engine_sync_ids() {
+        let mut store = TestStore { version: 1, .. TestStore::default() };
+        let ts = ServerTimestamp(1_000_000);
+
+        // The first sync just remembers the sync ID.
+        let state = state_with_bookmarks(1, "syncIDBBBBBB");
+        let prepared = prepare_engine(&state, &mut store, "bookmarks", ts).unwrap().unwrap();
+        assert_eq!((prepared.timestamp, prepared.reset), (ts, false));
+        assert_eq!(store.sync_id, Some("syncIDBBBBBB".to_owned()));
+
+        let prepared = prepare_engine(&state, &mut store, "bookmarks", ts).unwrap().unwrap();
+        assert!(!prepared.reset);
+        assert_eq!(store.resets, 0);
+
+        // Another client reset the engine, so we start over.
+        let state = state_with_bookmarks(1, "syncIDCCCCCC");
+        let prepared = prepare_engine(&state, &mut store, "bookmarks", ts).unwrap().unwrap();
+        assert_eq!((prepared.timestamp, prepared.reset), (SERVER_EPOCH, true));
+        assert_eq!(store.resets, 1);
+        assert_eq!(store.sync_id, Some("syncIDCCCCCC".to_owned()));
+    }
 }
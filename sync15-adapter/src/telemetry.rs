@@ -0,0 +1,61 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::mem;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::{Method, StatusCode};
+
+/// Why a storage request was retried.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RetryReason {
+    /// The server responded with a 5xx or 429.
+    Status(StatusCode),
+    /// The request failed before we got a response.
+    Network(String),
+}
+
+/// A storage request that failed, and which we're about to try again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryEvent {
+    pub method: Method,
+    /// The path of the request, without the query.
+    pub route: String,
+    /// Which attempt failed, starting from 1.
+    pub attempt: u32,
+    /// How long we waited before the next attempt.
+    pub delay: Duration,
+    pub reason: RetryReason,
+}
+
+/// Collects events during a sync for the app to report. Clients share one of
+/// these (through an `Arc`) with the clients they spawn, so that events from
+/// every thread end up in the same place.
+#[derive(Debug, Default)]
+pub struct SyncTelemetry {
+    retries: Mutex<Vec<RetryEvent>>,
+}
+
+impl SyncTelemetry {
+    #[inline]
+    pub fn new() -> SyncTelemetry {
+        SyncTelemetry::default()
+    }
+
+    pub fn record_retry(&self, event: RetryEvent) {
+        self.retries.lock().unwrap().push(event);
+    }
+
+    /// The retries recorded so far.
+    pub fn retries(&self) -> Vec<RetryEvent> {
+        self.retries.lock().unwrap().clone()
+    }
+
+    /// Removes and returns the retries recorded so far, e.g. once they've
+    /// been reported at the end of a sync.
+    pub fn take_retries(&self) -> Vec<RetryEvent> {
+        mem::replace(&mut *self.retries.lock().unwrap(), Vec::new())
+    }
+}
@@ -4,15 +4,24 @@
 
 use hawk;
 
-use reqwest::{Client, Request, Url};
+use futures::{future, task, Async, Future, Poll};
+use reqwest::Url;
+use secret::SecretString;
+use hyper::Method;
 use hyper::header::{Authorization, Bearer};
-use error::{self, Result, ErrorKind};
+use error::{self, Result, ErrorClass, ErrorKind};
+use http::{BoxFuture, HttpRequest, HttpResponse, HttpTransport};
 use std::fmt;
 use std::borrow::{Borrow, Cow};
-use std::time::{SystemTime, Duration};
-use std::cell::{RefCell};
+use std::time::{SystemTime, Duration, UNIX_EPOCH};
+use std::sync::{Mutex, MutexGuard};
 use util::ServerTimestamp;
 
+/// We treat tokens as expired this long before the tokenserver says they
+/// do, so that we don't present a token that expires in flight. For short
+/// lived tokens we use half of their duration instead.
+const TOKEN_EXPIRY_MARGIN_SECS: u64 = 60;
+
 /// Tokenserver's timestamp is X-Timestamp and not X-Weave-Timestamp.
 header! { (RetryAfter, "Retry-After") => [f64] }
 
@@ -27,7 +36,7 @@ header! { (XKeyID, "X-KeyID") => [String] }
 #[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 struct TokenserverToken {
     id: String,
-    key: String,
+    key: SecretString,
     api_endpoint: String,
     uid: u64,
     duration: u64,
@@ -44,7 +53,7 @@ struct TokenFetchResult {
 // The trait for fetching tokens - we'll provide a "real" implementation but
 // tests will re-implement it.
 trait TokenFetcher {
-    fn fetch_token(&self, request_client: &Client) -> super::Result<TokenFetchResult>;
+    fn fetch_token<'a>(&'a self, transport: &'a HttpTransport) -> BoxFuture<'a, TokenFetchResult>;
     // We allow the trait to tell us what the time is so tests can get funky.
     fn now(&self) -> SystemTime;
 }
@@ -55,43 +64,45 @@ trait TokenFetcher {
 struct TokenServerFetcher {
     // The stuff needed to fetch a token.
     server_url: Url,
-    access_token: String,
+    access_token: SecretString,
     key_id: String,
 }
 
 impl TokenServerFetcher {
-    fn new(server_url: Url, access_token: String, key_id: String) -> TokenServerFetcher {
+    fn new(server_url: Url, access_token: SecretString, key_id: String) -> TokenServerFetcher {
         TokenServerFetcher { server_url, access_token, key_id }
     }
-}
 
-impl TokenFetcher for TokenServerFetcher {
-    fn fetch_token(&self, request_client: &Client) -> Result<TokenFetchResult> {
-        let mut resp = request_client.get(self.server_url.clone())
-                                     .header(Authorization(Bearer { token: self.access_token.clone() }))
-                                     .header(XKeyID(self.key_id.clone()))
-                                     .send()?;
-
-        if !resp.status().is_success() {
-            warn!("Non-success status when fetching token: {}", resp.status());
+    fn result_from_response(&self, resp: HttpResponse) -> Result<TokenFetchResult> {
+        if !resp.status.is_success() {
+            warn!("Non-success status when fetching token: {}", resp.status);
             // TODO: the body should be JSON and contain a status parameter we might need?
-            debug!("  Response body {}", resp.text().unwrap_or_else(|_| "???".into()));
+            debug!("  Response body {}", resp.text());
             // XXX - shouldn't we "chain" these errors - ie, a BackoffError could
             // have a TokenserverHttpError as its cause?
-            if let Some(ms) = resp.headers().get::<RetryAfter>().map(|h| (**h * 1000f64) as u64) {
+            if let Some(ms) = resp.headers.get::<RetryAfter>().map(|h| (**h * 1000f64) as u64) {
                 let when = self.now() + Duration::from_millis(ms);
                 return Err(ErrorKind::BackoffError(when).into());
             }
-            return Err(ErrorKind::TokenserverHttpError(resp.status()).into());
+            return Err(ErrorKind::TokenserverHttpError(resp.status).into());
         }
 
         let token: TokenserverToken = resp.json()?;
-        let server_timestamp = resp.headers()
+        let server_timestamp = resp.headers
                     .get::<XTimestamp>()
                     .map(|h| **h)
                     .ok_or_else(|| ErrorKind::MissingServerTimestamp)?;
         Ok(TokenFetchResult { token, server_timestamp })
     }
+}
+
+impl TokenFetcher for TokenServerFetcher {
+    fn fetch_token<'a>(&'a self, transport: &'a HttpTransport) -> BoxFuture<'a, TokenFetchResult> {
+        let request = HttpRequest::new(Method::Get, self.server_url.clone())
+            .header(Authorization(Bearer { token: self.access_token.expose().clone() }))
+            .header(XKeyID(self.key_id.clone()));
+        Box::new(transport.execute(request).and_then(move |resp| self.result_from_response(resp)))
+    }
 
     fn now(&self) -> SystemTime {
         SystemTime::now()
@@ -135,8 +146,7 @@ impl TokenContext {
         now < self.valid_until
     }
 
-    fn authorization(&self, req: &Request) -> Result<Authorization<String>> {
-        let url = req.url();
+    fn authorization(&self, method: &Method, url: &Url) -> Result<Authorization<String>> {
 
         let path_and_query = match url.query() {
             None => Cow::from(url.path()),
@@ -152,7 +162,7 @@ impl TokenContext {
                 "Storage URL has no port and no default port is known for the protocol".into()))?;
 
         let header = hawk::RequestBuilder::new(
-            req.method().as_ref(),
+            method.as_ref(),
             host,
             port,
             path_and_query.borrow()
@@ -169,10 +179,13 @@ enum TokenState {
     NoToken,
     // Have a token and last we checked it remained valid.
     Token(TokenContext),
-    // We failed to fetch a token. First elt is the error, second elt is
-    // the api_endpoint we had before we failed to fetch a new token (or
-    // None if the very first attempt at fetching a token failed)
-    Failed(Option<error::Error>, Option<String>),
+    // We failed to fetch a token. First elt is the error, second and third
+    // elts are its description and class (the error itself can only be
+    // returned to one caller, and other callers waiting on the same fetch get
+    // these instead), fourth elt is the api_endpoint we had before we failed
+    // to fetch a new token (or None if the very first attempt at fetching a
+    // token failed)
+    Failed(Option<error::Error>, String, ErrorClass, Option<String>),
     // Previously failed and told to back-off for SystemTime duration. Second
     // elt is the api_endpoint we had before we hit the backoff error.
     // XXX - should we roll Backoff and Failed together?
@@ -182,43 +195,135 @@ enum TokenState {
     NodeReassigned,
 }
 
+// Everything our TokenProvider guards with its lock.
+#[derive(Debug)]
+struct SharedState {
+    // Our token state (ie, whether we have a token, and if not, why not)
+    token: TokenState,
+    // True while some caller is fetching a new token. Other callers wait for
+    // it to finish rather than making their own request.
+    refreshing: bool,
+    // Bumped each time a fetch finishes, so that callers that waited on it
+    // know to use its result instead of fetching again.
+    generation: u64,
+    // The tasks waiting on the in-flight fetch.
+    waiters: Vec<task::Task>,
+}
+
 /// The generic TokenProvider implementation - long lived and fetches tokens
 /// on demand (eg, when first needed, or when an existing one expires.)
 #[derive(Debug)]
 struct TokenProviderImpl<TF: TokenFetcher> {
     fetcher: TF,
-    // Our token state (ie, whether we have a token, and if not, why not)
-    current_state: RefCell<TokenState>,
+    shared: Mutex<SharedState>,
+}
+
+// A future that resolves once its caller can use the current token state,
+// or to a `Refresh` if the caller should fetch a new token first.
+struct AcquireToken<'a, TF: TokenFetcher + 'a> {
+    provider: &'a TokenProviderImpl<TF>,
+    // The generation that was in flight when we started waiting, if any.
+    waited_for: Option<u64>,
+}
+
+impl<'a, TF: TokenFetcher + 'a> Future for AcquireToken<'a, TF> {
+    type Item = Option<Refresh<'a, TF>>;
+    type Error = error::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, error::Error> {
+        let mut shared = self.provider.lock_shared();
+        if shared.refreshing {
+            if self.waited_for.is_none() {
+                self.waited_for = Some(shared.generation);
+            }
+            shared.waiters.push(task::current());
+            return Ok(Async::NotReady);
+        }
+        if let Some(generation) = self.waited_for {
+            if generation != shared.generation {
+                // Someone else fetched a token while we waited - use the
+                // result, even if it failed.
+                return Ok(Async::Ready(None));
+            }
+        }
+        match self.provider.fetch_needed(&shared.token) {
+            None => Ok(Async::Ready(None)),
+            Some(previous_endpoint) => {
+                shared.refreshing = true;
+                Ok(Async::Ready(Some(Refresh { provider: self.provider, previous_endpoint })))
+            }
+        }
+    }
+}
+
+// Held by the one caller fetching a new token. Dropping it (whether or not
+// the fetch finished) lets the waiting callers proceed.
+struct Refresh<'a, TF: TokenFetcher + 'a> {
+    provider: &'a TokenProviderImpl<TF>,
+    previous_endpoint: Option<String>,
+}
+
+impl<'a, TF: TokenFetcher + 'a> Refresh<'a, TF> {
+    fn finish(self, result: Result<TokenContext>) {
+        let new_state = self.provider.state_after_fetch(result, self.previous_endpoint.clone());
+        {
+            let mut shared = self.provider.lock_shared();
+            shared.token = new_state;
+            shared.generation += 1;
+        }
+        // Dropping `self` wakes any waiters.
+    }
+}
+
+impl<'a, TF: TokenFetcher + 'a> Drop for Refresh<'a, TF> {
+    fn drop(&mut self) {
+        let mut shared = self.provider.lock_shared();
+        shared.refreshing = false;
+        for waiter in shared.waiters.drain(..) {
+            waiter.notify();
+        }
+    }
 }
 
 impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     fn new(fetcher: TF) -> Self {
         TokenProviderImpl {
             fetcher,
-            current_state: RefCell::new(TokenState::NoToken),
+            shared: Mutex::new(SharedState {
+                token: TokenState::NoToken,
+                refreshing: false,
+                generation: 0,
+                waiters: Vec::new(),
+            }),
         }
     }
 
+    fn lock_shared(&self) -> MutexGuard<SharedState> {
+        self.shared.lock().expect("Token state lock poisoned")
+    }
+
     // Uses our fetcher to grab a new token and if successfull, derives other
     // info from that token into a usable TokenContext.
-    fn fetch_context(&self, request_client: &Client) -> Result<TokenContext> {
-        let result = self.fetcher.fetch_token(request_client)?;
-        let token = result.token;
-        let valid_until = SystemTime::now() + Duration::from_secs(token.duration);
-
-        let credentials = hawk::Credentials {
-            id: token.id.clone(),
-            key: hawk::Key::new(token.key.as_bytes(), hawk::Digest::sha256())?,
-        };
-
-        Ok(TokenContext::new(token, credentials, result.server_timestamp, valid_until))
+    fn fetch_context<'a>(&'a self, transport: &'a HttpTransport) -> BoxFuture<'a, TokenContext> {
+        Box::new(self.fetcher.fetch_token(transport).and_then(move |result| {
+            let token = result.token;
+            let margin = ::std::cmp::min(TOKEN_EXPIRY_MARGIN_SECS, token.duration / 2);
+            let valid_until = self.fetcher.now() + Duration::from_secs(token.duration - margin);
+
+            let credentials = hawk::Credentials {
+                id: token.id.clone(),
+                key: hawk::Key::new(token.key.expose().as_bytes(), hawk::Digest::sha256())?,
+            };
+
+            Ok(TokenContext::new(token, credentials, result.server_timestamp, valid_until))
+        }))
     }
 
-    // Attempt to fetch a new token and return a new state reflecting that
-    // operation. If it worked a TokenState will be returned, but errors may
-    // cause other states.
-    fn fetch_token(&self, request_client: &Client, previous_endpoint: Option<&str>) -> TokenState {
-        match self.fetch_context(request_client) {
+    // Given the result of trying to fetch a new token, return a new state
+    // reflecting that operation. If it worked a TokenState will be returned,
+    // but errors may cause other states.
+    fn state_after_fetch(&self, result: Result<TokenContext>, previous_endpoint: Option<String>) -> TokenState {
+        match result {
             Ok(tc) => {
                 // We got a new token - check that the endpoint is the same
                 // as a previous endpoint we saw (if any)
@@ -240,30 +345,32 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
             Err(e) => {
                 // Early to avoid nll issues...
                 if let ErrorKind::BackoffError(be) = e.kind() {
-                    return TokenState::Backoff(*be, previous_endpoint.map(|s| s.to_string()));
+                    return TokenState::Backoff(*be, previous_endpoint);
                 }
-                TokenState::Failed(Some(e), previous_endpoint.map(|s| s.to_string()))
+                let description = e.to_string();
+                let class = e.class();
+                TokenState::Failed(Some(e), description, class, previous_endpoint)
             }
         }
     }
 
-    // Given the state we are currently in, return a new current state.
-    // Returns None if the current state should be used (eg, if we are
-    // holding a token that remains valid) or Some() if the state has changed
-    // (which may have changed to a state with a token or an error state)
-    fn advance_state(&self, request_client: &Client, state: &TokenState) -> Option<TokenState> {
+    // Given the state we are currently in, decide whether we need a new
+    // token. Returns None if the current state should be used (eg, if we are
+    // holding a token that remains valid) or Some() with the api_endpoint we
+    // had previously (if any) if we should fetch a new one.
+    fn fetch_needed(&self, state: &TokenState) -> Option<Option<String>> {
         match state {
             TokenState::NoToken => {
-                Some(self.fetch_token(request_client, None))
+                Some(None)
             },
-            TokenState::Failed(_, existing_endpoint) => {
-                Some(self.fetch_token(request_client, existing_endpoint.as_ref().map(|e| e.as_str())))
+            TokenState::Failed(_, _, _, existing_endpoint) => {
+                Some(existing_endpoint.clone())
             },
             TokenState::Token(existing_context) => {
                 if existing_context.is_valid(self.fetcher.now()) {
                     None
                 } else {
-                    Some(self.fetch_token(request_client, Some(existing_context.token.api_endpoint.as_str())))
+                    Some(Some(existing_context.token.api_endpoint.clone()))
                 }
             },
             TokenState::Backoff(ref until, ref existing_endpoint) => {
@@ -272,7 +379,7 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
                     None
                 } else {
                     // backoff period is over
-                    Some(self.fetch_token(request_client, existing_endpoint.as_ref().map(|e| e.as_str())))
+                    Some(existing_endpoint.clone())
                 }
             },
             TokenState::NodeReassigned => {
@@ -282,85 +389,129 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         }
     }
 
-    fn with_token<T, F>(&self, request_client: &Client, func: F) -> Result<T>
-            where F: FnOnce(&TokenContext) -> Result<T> {
-
-        // first get a mutable ref to our existing state, advance to the
-        // state we will use, then re-stash that state for next time.
-        let state: &mut TokenState = &mut self.current_state.borrow_mut();
-        match self.advance_state(request_client, state) {
-            Some(new_state) => *state = new_state,
-            None => ()
-        }
-
-        // Now re-fetch the state we should use for this call - if it's
-        // anything other than TokenState::Token we will fail.
-        match state {
-            TokenState::NoToken => {
-                // it should be impossible to get here.
-                panic!("Can't be in NoToken state after advancing");
-            }
-            TokenState::Token(ref token_context) => {
-                // make the call.
-                func(token_context)
-            }
-            TokenState::Failed(e, _) => {
-                // We swap the error out of the state enum and return it.
-                return Err(e.take().unwrap());
-            }
-            TokenState::NodeReassigned => {
-                // this is unrecoverable.
-                return Err(ErrorKind::StorageResetError.into());
+    fn with_token<'a, T, F>(&'a self, transport: &'a HttpTransport, func: F) -> BoxFuture<'a, T>
+            where T: 'a, F: FnOnce(&TokenContext) -> Result<T> + 'a {
+
+        // First advance to the state we will use - either by fetching a new
+        // token ourselves, or by waiting for whoever is already fetching one.
+        let acquire = AcquireToken { provider: self, waited_for: None };
+        let ready = acquire.and_then(move |refresh| -> BoxFuture<'a, ()> {
+            match refresh {
+                None => Box::new(future::ok(())),
+                Some(refresh) => {
+                    Box::new(self.fetch_context(transport).then(move |result| -> Result<()> {
+                        refresh.finish(result);
+                        Ok(())
+                    }))
+                }
             }
-            TokenState::Backoff(ref remaining, _) => {
-                return Err(ErrorKind::BackoffError(*remaining).into());
+        });
+
+        // Now use that state - if it's anything other than TokenState::Token
+        // we will fail.
+        Box::new(ready.and_then(move |()| {
+            let mut shared = self.lock_shared();
+            match shared.token {
+                TokenState::NoToken => {
+                    // it should be impossible to get here.
+                    panic!("Can't be in NoToken state after advancing");
+                }
+                TokenState::Token(ref token_context) => {
+                    // make the call.
+                    func(token_context)
+                }
+                TokenState::Failed(ref mut e, ref description, class, _) => {
+                    // We swap the error out of the state enum and return it.
+                    // Anybody else who was waiting on the same fetch gets
+                    // its description and class.
+                    return Err(e.take().unwrap_or_else(||
+                        ErrorKind::SharedTokenFetchError {
+                            description: description.clone(),
+                            class,
+                        }.into()));
+                }
+                TokenState::NodeReassigned => {
+                    // this is unrecoverable.
+                    return Err(ErrorKind::StorageResetError.into());
+                }
+                TokenState::Backoff(ref remaining, _) => {
+                    return Err(ErrorKind::BackoffError(*remaining).into());
+                }
             }
-        }
+        }))
     }
 
-    fn authorization(&self, http_client: &Client, req: &Request) -> Result<Authorization<String>> {
-        self.with_token(http_client, |ctx| ctx.authorization(req))
+    fn authorization<'a>(&'a self, transport: &'a HttpTransport, req: &HttpRequest) -> BoxFuture<'a, Authorization<String>> {
+        let method = req.method.clone();
+        let url = req.url.clone();
+        self.with_token(transport, move |ctx| ctx.authorization(&method, &url))
     }
 
-    fn api_endpoint(&self, http_client: &Client) -> Result<String> {
-        self.with_token(http_client, |ctx| Ok(ctx.token.api_endpoint.clone()))
+    fn api_endpoint<'a>(&'a self, transport: &'a HttpTransport) -> BoxFuture<'a, String> {
+        self.with_token(transport, |ctx| Ok(ctx.token.api_endpoint.clone()))
+    }
+
+    // Treats the token we have as expired, so the next caller fetches a new
+    // one. We keep its api_endpoint, so that we still notice if the new
+    // token moves us to another node.
+    fn drop_token(&self) {
+        let mut shared = self.lock_shared();
+        if let TokenState::Token(ref mut context) = shared.token {
+            context.valid_until = UNIX_EPOCH;
+        }
     }
-    // TODO: we probably want a "drop_token/context" type method so that when
-    // using a token with some validity fails the caller can force a new one
-    // (in which case the new token request will probably fail with a 401)
 }
 
-// The public concrete object exposed by this module
+/// The public concrete object exposed by this module. A `TokenProvider` is
+/// `Send` and `Sync`, so it can be shared (in an `Arc`) by storage clients
+/// syncing on different threads. If several of them need a new token at
+/// once, only one request is made to the tokenserver, and the others wait
+/// for its result.
 #[derive(Debug)]
 pub struct TokenProvider {
     imp: TokenProviderImpl<TokenServerFetcher>,
 }
 
 impl TokenProvider {
-    pub fn new(url: Url, access_token: String, key_id: String) -> Self {
+    pub fn new(url: Url, access_token: SecretString, key_id: String) -> Self {
         let fetcher = TokenServerFetcher::new(url, access_token, key_id);
         Self {
             imp: TokenProviderImpl::new(fetcher),
         }
     }
 
-    pub fn authorization(&self, http_client: &Client, req: &Request) -> Result<Authorization<String>> {
-        self.imp.authorization(http_client, req)
+    /// Resolves to the Hawk `Authorization` header for `req`, fetching a
+    /// token first if we don't have a valid one.
+    pub fn authorization<'a>(&'a self, transport: &'a HttpTransport, req: &HttpRequest)
+            -> BoxFuture<'a, Authorization<String>> {
+        self.imp.authorization(transport, req)
     }
 
-    pub fn api_endpoint(&self, http_client: &Client) -> Result<String> {
-        self.imp.api_endpoint(http_client)
+    pub fn api_endpoint<'a>(&'a self, transport: &'a HttpTransport) -> BoxFuture<'a, String> {
+        self.imp.api_endpoint(transport)
+    }
+
+    /// Forgets the current token, e.g. because the storage server rejected
+    /// it, so that the next request fetches a new one.
+    pub fn drop_token(&self) {
+        self.imp.drop_token()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::cell::Cell;
-    use reqwest::Client;
-
-    fn make_client() -> Client {
-        Client::builder().timeout(Duration::from_secs(30)).build().expect("can't build client")
+    use std::cell::{Cell, RefCell};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use futures::executor::{self, Notify, NotifyHandle};
+    use futures::sync::oneshot;
+    use hyper::StatusCode;
+    use http::BlockingTransport;
+
+    fn make_transport() -> BlockingTransport {
+        BlockingTransport::new().expect("can't build transport")
     }
 
     struct TestFetcher<FF, FN>
@@ -372,8 +523,8 @@ mod tests {
     impl<FF, FN> TokenFetcher for TestFetcher<FF, FN>
         where FF: Fn() -> Result<TokenFetchResult>,
               FN: Fn() -> SystemTime {
-        fn fetch_token(&self, _: &Client) -> Result<TokenFetchResult> {
-            (self.fetch)()
+        fn fetch_token<'a>(&'a self, _: &'a HttpTransport) -> BoxFuture<'a, TokenFetchResult> {
+            Box::new(future::result((self.fetch)()))
         }
         fn now(&self) -> SystemTime {
             (self.now)()
@@ -399,23 +550,23 @@ mod tests {
             Ok(TokenFetchResult {
                 token: TokenserverToken {
                     id: "id".to_string(),
-                    key: "key".to_string(),
+                    key: "key".into(),
                     api_endpoint: "api_endpoint".to_string(),
                     uid: 1,
                     duration: 1000,
                     hashed_fxa_uid: "hash".to_string(),
                 },
-                server_timestamp: ServerTimestamp(0f64),
+                server_timestamp: ServerTimestamp(0),
             })
         };
 
         let tsc = make_tsc(fetch, || {SystemTime::now()});
 
-        let e = tsc.api_endpoint(&make_client()).expect("should work");
+        let e = tsc.api_endpoint(&make_transport()).wait().expect("should work");
         assert_eq!(e, "api_endpoint".to_string());
         assert_eq!(counter.get(), 1);
 
-        let e2 = tsc.api_endpoint(&make_client()).expect("should work");
+        let e2 = tsc.api_endpoint(&make_transport()).wait().expect("should work");
         assert_eq!(e2, "api_endpoint".to_string());
         // should not have re-fetched.
         assert_eq!(counter.get(), 1);
@@ -432,12 +583,12 @@ mod tests {
         let now: Cell<SystemTime> = Cell::new(SystemTime::now());
         let tsc = make_tsc(fetch, || {now.get()});
 
-        tsc.api_endpoint(&make_client()).expect_err("should bail");
+        tsc.api_endpoint(&make_transport()).wait().expect_err("should bail");
         // XXX - check error type.
         assert_eq!(counter.get(), 1);
         // try and get another token - should not re-fetch as backoff is still
         // in progress.
-        tsc.api_endpoint(&make_client()).expect_err("should bail");
+        tsc.api_endpoint(&make_transport()).wait().expect_err("should bail");
         assert_eq!(counter.get(), 1);
 
         // Advance the clock.
@@ -445,7 +596,7 @@ mod tests {
 
         // Our token fetch mock is still returning a backoff error, so we
         // still fail, but should have re-hit the fetch function.
-        tsc.api_endpoint(&make_client()).expect_err("should bail");
+        tsc.api_endpoint(&make_transport()).wait().expect_err("should bail");
         assert_eq!(counter.get(), 2);
     }
 
@@ -457,31 +608,178 @@ mod tests {
             Ok(TokenFetchResult {
                 token: TokenserverToken {
                     id: "id".to_string(),
-                    key: "key".to_string(),
+                    key: "key".into(),
                     api_endpoint: "api_endpoint".to_string(),
                     uid: 1,
                     duration: 10,
                     hashed_fxa_uid: "hash".to_string(),
                 },
-                server_timestamp: ServerTimestamp(0f64),
+                server_timestamp: ServerTimestamp(0),
             })
         };
         let now: Cell<SystemTime> = Cell::new(SystemTime::now());
         let tsc = make_tsc(fetch, || {now.get()});
 
-        tsc.api_endpoint(&make_client()).expect("should get a valid token");
+        tsc.api_endpoint(&make_transport()).wait().expect("should get a valid token");
         assert_eq!(counter.get(), 1);
 
         // try and get another token - should not re-fetch as the old one
         // remains valid.
-        tsc.api_endpoint(&make_client()).expect("should reuse existing token");
+        tsc.api_endpoint(&make_transport()).wait().expect("should reuse existing token");
         assert_eq!(counter.get(), 1);
 
         // Advance the clock.
         now.set(now.get() + Duration::new(20, 0));
 
         // We should discard our token and fetch a new one.
-        tsc.api_endpoint(&make_client()).expect("should re-fetch");
+        tsc.api_endpoint(&make_transport()).wait().expect("should re-fetch");
         assert_eq!(counter.get(), 2);
     }
+
+    fn token_result(duration: u64) -> TokenFetchResult {
+        TokenFetchResult {
+            token: TokenserverToken {
+                id: "id".to_string(),
+                key: "key".into(),
+                api_endpoint: "api_endpoint".to_string(),
+                uid: 1,
+                duration,
+                hashed_fxa_uid: "hash".to_string(),
+            },
+            server_timestamp: ServerTimestamp(0),
+        }
+    }
+
+    #[test]
+    fn test_expiry_margin() {
+        let counter: Cell<u32> = Cell::new(0);
+        let fetch = || {
+            counter.set(counter.get() + 1);
+            Ok(token_result(600))
+        };
+        let now: Cell<SystemTime> = Cell::new(SystemTime::now());
+        let tsc = make_tsc(fetch, || {now.get()});
+
+        tsc.api_endpoint(&make_transport()).wait().expect("should get a valid token");
+        assert_eq!(counter.get(), 1);
+
+        // Still well within the token's lifetime.
+        now.set(now.get() + Duration::new(530, 0));
+        tsc.api_endpoint(&make_transport()).wait().expect("should reuse existing token");
+        assert_eq!(counter.get(), 1);
+
+        // The token hasn't quite expired, but it's within the margin.
+        now.set(now.get() + Duration::new(15, 0));
+        tsc.api_endpoint(&make_transport()).wait().expect("should re-fetch");
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test]
+    fn test_drop_token() {
+        let counter: Cell<u32> = Cell::new(0);
+        let fetch = || {
+            counter.set(counter.get() + 1);
+            Ok(token_result(600))
+        };
+        let tsc = make_tsc(fetch, || {SystemTime::now()});
+
+        tsc.api_endpoint(&make_transport()).wait().expect("should get a valid token");
+        tsc.drop_token();
+        let e = tsc.api_endpoint(&make_transport()).wait().expect("should re-fetch");
+        assert_eq!(e, "api_endpoint".to_string());
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<TokenProvider>();
+    }
+
+    #[test]
+    fn test_concurrent_refresh() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let fetch_counter = counter.clone();
+        let fetch = move || {
+            fetch_counter.fetch_add(1, Ordering::SeqCst);
+            // Give the other threads time to pile up behind us.
+            thread::sleep(Duration::from_millis(100));
+            Ok(token_result(1000))
+        };
+        let tsc = Arc::new(make_tsc(fetch, || {SystemTime::now()}));
+        let transport = Arc::new(make_transport());
+
+        let threads: Vec<_> = (0..8).map(|_| {
+            let tsc = tsc.clone();
+            let transport = transport.clone();
+            thread::spawn(move || {
+                tsc.api_endpoint(&*transport).wait().expect("should work")
+            })
+        }).collect();
+
+        for t in threads {
+            assert_eq!(t.join().unwrap(), "api_endpoint");
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    // A fetcher whose (single) fetch stays in flight until the test sends
+    // its result.
+    struct ChannelFetcher {
+        receiver: RefCell<Option<oneshot::Receiver<Result<TokenFetchResult>>>>,
+        fetches: Cell<u32>,
+    }
+
+    impl TokenFetcher for ChannelFetcher {
+        fn fetch_token<'a>(&'a self, _: &'a HttpTransport) -> BoxFuture<'a, TokenFetchResult> {
+            self.fetches.set(self.fetches.get() + 1);
+            let receiver = self.receiver.borrow_mut().take().expect("should only fetch once");
+            Box::new(receiver.then(|r| r.expect("sender dropped")))
+        }
+        fn now(&self) -> SystemTime {
+            SystemTime::now()
+        }
+    }
+
+    struct NoopNotify;
+
+    impl Notify for NoopNotify {
+        fn notify(&self, _: usize) {}
+    }
+
+    #[test]
+    fn test_waiters_share_failure() {
+        let (sender, receiver) = oneshot::channel();
+        let tsc = TokenProviderImpl::new(ChannelFetcher {
+            receiver: RefCell::new(Some(receiver)),
+            fetches: Cell::new(0),
+        });
+        let transport = make_transport();
+
+        let first = tsc.api_endpoint(&transport).then(|r| Ok::<_, ()>(r));
+        let second = tsc.api_endpoint(&transport).then(|r| Ok::<_, ()>(r));
+        let mut both = executor::spawn(first.join(second));
+
+        // The first request starts a fetch, and the second waits on it.
+        let notify = NotifyHandle::from(Arc::new(NoopNotify));
+        assert!(both.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+        assert_eq!(tsc.fetcher.fetches.get(), 1);
+
+        let error = ErrorKind::TokenserverHttpError(StatusCode::InternalServerError);
+        sender.send(Err(error.into())).ok().unwrap();
+
+        let (first, second) = both.wait_future().unwrap();
+        match first.expect_err("should fail").kind() {
+            ErrorKind::TokenserverHttpError(StatusCode::InternalServerError) => {},
+            e => panic!("Wrong error: {}", e),
+        }
+        let second = second.expect_err("should fail");
+        match second.kind() {
+            ErrorKind::SharedTokenFetchError { class: ErrorClass::Transient, .. } => {},
+            e => panic!("Wrong error: {}", e),
+        }
+        assert!(second.is_retryable());
+        // Only the one fetch was made.
+        assert_eq!(tsc.fetcher.fetches.get(), 1);
+    }
 }
@@ -2,98 +2,287 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::convert::From;
+use std::error as std_error;
+use std::ops::{Add, Sub};
 use std::time::Duration;
-use std::{fmt, num};
+use std::fmt;
 use std::str::FromStr;
-use openssl;
 use base64;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use crypto;
+use error;
 
-pub fn random_guid() -> Result<String, openssl::error::ErrorStack> {
+pub fn random_guid() -> error::Result<String> {
     let mut bytes = vec![0u8; 9];
-    openssl::rand::rand_bytes(&mut bytes)?;
+    crypto::rand_bytes(&mut bytes)?;
     Ok(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
 }
 
 /// Typesafe way to manage server timestamps without accidentally mixing them up with
 /// local ones.
 ///
-/// TODO: We should probably store this as milliseconds (or something) for stability and to get
-/// Eq/Ord. The server guarantees that these are formatted to the hundreds place (not sure if this
-/// is documented but the code does it intentionally...). This would also let us throw out negative
-/// and NaN timestamps, which the server certainly won't send, but the guarantee would make me feel
-/// better.
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Deserialize, Serialize, Default)]
-pub struct ServerTimestamp(pub f64);
-
-impl From<ServerTimestamp> for f64 {
-    #[inline]
-    fn from(ts: ServerTimestamp) -> Self { ts.0 }
-}
+/// The server sends timestamps as seconds with two decimal places (e.g.
+/// `1234567890.12`). We store them as integer milliseconds instead of as an
+/// `f64`, so that parsing and formatting them is lossless, and comparing them
+/// (e.g. for `X-If-Unmodified-Since`) never goes wrong due to rounding.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ServerTimestamp(pub i64);
 
-impl From<f64> for ServerTimestamp {
+impl ServerTimestamp {
     #[inline]
-    fn from(ts: f64) -> Self {
-        assert!(ts >= 0.0);
-        ServerTimestamp(ts)
+    pub fn from_millis(ms: i64) -> ServerTimestamp {
+        ServerTimestamp(ms)
     }
-}
 
-// This lets us use these in hyper header! blocks.
-impl FromStr for ServerTimestamp {
-    type Err = num::ParseFloatError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(ServerTimestamp(f64::from_str(s)?))
+    /// Converts from (fractional) seconds, rounding to the nearest
+    /// millisecond. Returns None for negative, NaN or infinite values.
+    pub fn from_float_seconds(secs: f64) -> Option<ServerTimestamp> {
+        let ms = (secs * 1000.0).round();
+        if ms.is_finite() && ms >= 0.0 && ms <= i64::max_value() as f64 {
+            Some(ServerTimestamp(ms as i64))
+        } else {
+            None
+        }
     }
-}
 
-impl fmt::Display for ServerTimestamp {
+    /// Get the milliseconds for the timestamp.
     #[inline]
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+    pub fn as_millis(self) -> i64 {
+        self.0
     }
-}
 
-pub const SERVER_EPOCH: ServerTimestamp = ServerTimestamp(0.0);
+    /// The timestamp in seconds, for APIs that want a float. Converting the
+    /// result back with `from_float_seconds` gives the same timestamp.
+    #[inline]
+    pub fn as_float_seconds(self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
 
-impl ServerTimestamp {
     /// Returns None if `other` is later than `self` (Duration may not represent
     /// negative timespans in rust).
     #[inline]
     pub fn duration_since(self, other: ServerTimestamp) -> Option<Duration> {
-        let delta = self.0 - other.0;
-        if delta < 0.0 {
+        let delta = self.0.checked_sub(other.0)?;
+        if delta < 0 {
             None
         } else {
-            let secs = delta.floor();
-            // We don't want to round here, since it could round up, and
-            // Duration::new will panic if it rounds up to 1e9 nanoseconds.
-            let nanos = ((delta - secs) * 1_000_000_000.0).floor() as u32;
-            Some(Duration::new(secs as u64, nanos))
+            Some(Duration::from_millis(delta as u64))
         }
     }
 
-    /// Get the milliseconds for the timestamp.
+    /// Returns None on overflow.
+    #[inline]
+    pub fn checked_add(self, d: Duration) -> Option<ServerTimestamp> {
+        duration_millis(d).and_then(|ms| self.0.checked_add(ms)).map(ServerTimestamp)
+    }
+
+    /// Returns None if the result would be before the epoch.
     #[inline]
-    pub fn as_millis(self) -> u64 {
-        (self.0 * 1000.0).floor() as u64
+    pub fn checked_sub(self, d: Duration) -> Option<ServerTimestamp> {
+        duration_millis(d)
+            .and_then(|ms| self.0.checked_sub(ms))
+            .and_then(|ms| if ms < 0 { None } else { Some(ServerTimestamp(ms)) })
+    }
+}
+
+fn duration_millis(d: Duration) -> Option<i64> {
+    let ms = d.as_secs()
+              .checked_mul(1000)?
+              .checked_add(u64::from(d.subsec_millis()))?;
+    if ms > i64::max_value() as u64 { None } else { Some(ms as i64) }
+}
+
+impl Add<Duration> for ServerTimestamp {
+    type Output = ServerTimestamp;
+    /// Panics on overflow, like `SystemTime`. See also `checked_add`.
+    #[inline]
+    fn add(self, d: Duration) -> ServerTimestamp {
+        self.checked_add(d).expect("overflow when adding duration to timestamp")
+    }
+}
+
+impl Sub<Duration> for ServerTimestamp {
+    type Output = ServerTimestamp;
+    /// Panics if the result is before the epoch. See also `checked_sub`.
+    #[inline]
+    fn sub(self, d: Duration) -> ServerTimestamp {
+        self.checked_sub(d).expect("subtracting duration from timestamp went below zero")
+    }
+}
+
+/// Returned when a timestamp string isn't a non-negative decimal number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseTimestampError(String);
+
+impl fmt::Display for ParseTimestampError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid server timestamp: {:?}", self.0)
+    }
+}
+
+impl std_error::Error for ParseTimestampError {
+    fn description(&self) -> &str {
+        "invalid server timestamp"
+    }
+}
+
+// This lets us use these in hyper header! blocks. We parse the decimal
+// string ourselves rather than going through an f64, so that "1234.56" is
+// exactly 1234560ms. Digits past the millisecond are truncated.
+impl FromStr for ServerTimestamp {
+    type Err = ParseTimestampError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseTimestampError(s.to_string());
+        let trimmed = s.trim();
+        let (whole, frac) = match trimmed.find('.') {
+            Some(pos) => (&trimmed[..pos], &trimmed[pos + 1..]),
+            None => (trimmed, ""),
+        };
+        if whole.is_empty() || !whole.bytes().all(|b| b.is_ascii_digit())
+                || !frac.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(err());
+        }
+        let mut ms = whole.parse::<i64>().map_err(|_| err())?
+                          .checked_mul(1000).ok_or_else(err)?;
+        let mut scale = 100;
+        for digit in frac.bytes().take(3) {
+            ms = ms.checked_add(i64::from(digit - b'0') * scale).ok_or_else(err)?;
+            scale /= 10;
+        }
+        Ok(ServerTimestamp(ms))
     }
 }
 
+/// Formats the timestamp as seconds, the way the server does: with two
+/// decimal places, or three if the timestamp has millisecond precision.
+impl fmt::Display for ServerTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0 / 1000;
+        let ms = self.0 % 1000;
+        if ms % 10 == 0 {
+            write!(f, "{}.{:02}", secs, ms / 10)
+        } else {
+            write!(f, "{}.{:03}", secs, ms)
+        }
+    }
+}
+
+// We write timestamps out as numbers of seconds, which is what the server
+// sends (and what we persisted before switching to milliseconds).
+impl Serialize for ServerTimestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.as_float_seconds())
+    }
+}
+
+// Accepts numbers (integer or fractional seconds), as well as strings, since
+// some of the server's responses quote them.
+impl<'de> Deserialize<'de> for ServerTimestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TimestampVisitor;
+
+        impl<'de> de::Visitor<'de> for TimestampVisitor {
+            type Value = ServerTimestamp;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a non-negative number of seconds, or a string containing one")
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<ServerTimestamp, E> {
+                ServerTimestamp::from_float_seconds(v).ok_or_else(||
+                    E::invalid_value(de::Unexpected::Float(v), &self))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<ServerTimestamp, E> {
+                v.checked_mul(1000)
+                 .and_then(|ms| if ms > i64::max_value() as u64 { None } else { Some(ms as i64) })
+                 .map(ServerTimestamp)
+                 .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<ServerTimestamp, E> {
+                if v < 0 {
+                    return Err(E::invalid_value(de::Unexpected::Signed(v), &self));
+                }
+                self.visit_u64(v as u64)
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<ServerTimestamp, E> {
+                v.parse().map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+            }
+        }
+
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+pub const SERVER_EPOCH: ServerTimestamp = ServerTimestamp(0);
+
 #[cfg(test)]
 mod test {
     use super::*;
     use std::collections::HashSet;
 
+    use serde_json;
+
     #[test]
     fn test_server_timestamp() {
-        let t0 = ServerTimestamp(10300.15);
-        let t1 = ServerTimestamp(10100.05);
+        let t0 = ServerTimestamp(10_300_150);
+        let t1 = ServerTimestamp(10_100_050);
         assert!(t1.duration_since(t0).is_none());
         assert!(t0.duration_since(t1).is_some());
         let dur = t0.duration_since(t1).unwrap();
         assert_eq!(dur.as_secs(), 200);
         assert_eq!(dur.subsec_nanos(), 100_000_000);
+        assert!(t1 < t0);
+        assert_eq!(t1 + dur, t0);
+        assert_eq!(t0 - dur, t1);
+        assert_eq!(t1.checked_sub(Duration::from_secs(20_000)), None);
+    }
+
+    #[test]
+    fn test_parse_and_format() {
+        // 1531154400.57 * 1000.0 is 1531154400569.9998 as an f64.
+        let ts: ServerTimestamp = "1531154400.57".parse().unwrap();
+        assert_eq!(ts, ServerTimestamp(1_531_154_400_570));
+        assert_eq!(ts.to_string(), "1531154400.57");
+
+        assert_eq!("1234".parse::<ServerTimestamp>().unwrap(), ServerTimestamp(1_234_000));
+        assert_eq!("1234.5".parse::<ServerTimestamp>().unwrap(), ServerTimestamp(1_234_500));
+        assert_eq!("1234.5678".parse::<ServerTimestamp>().unwrap(), ServerTimestamp(1_234_567));
+        assert_eq!(ServerTimestamp(1_234_000).to_string(), "1234.00");
+        assert_eq!(ServerTimestamp(1_234_567).to_string(), "1234.567");
+        assert_eq!(ServerTimestamp(5).to_string(), "0.005");
+
+        for bad in &["", ".5", "-1", "1.2.3", "abc", "1e5", "99999999999999999999"] {
+            assert!(bad.parse::<ServerTimestamp>().is_err(), "{:?} should fail", bad);
+        }
+    }
+
+    #[test]
+    fn test_float_seconds() {
+        let ts = ServerTimestamp::from_float_seconds(1531154400.57).unwrap();
+        assert_eq!(ts, ServerTimestamp(1_531_154_400_570));
+        assert_eq!(ServerTimestamp::from_float_seconds(ts.as_float_seconds()), Some(ts));
+        assert_eq!(ServerTimestamp::from_float_seconds(-1.0), None);
+        assert_eq!(ServerTimestamp::from_float_seconds(::std::f64::NAN), None);
+    }
+
+    #[test]
+    fn test_serde() {
+        let from_number: ServerTimestamp = serde_json::from_str("1531154400.57").unwrap();
+        let from_string: ServerTimestamp = serde_json::from_str("\"1531154400.57\"").unwrap();
+        let from_int: ServerTimestamp = serde_json::from_str("1531154400").unwrap();
+        assert_eq!(from_number, ServerTimestamp(1_531_154_400_570));
+        assert_eq!(from_string, from_number);
+        assert_eq!(from_int, ServerTimestamp(1_531_154_400_000));
+        assert!(serde_json::from_str::<ServerTimestamp>("-5").is_err());
+        assert!(serde_json::from_str::<ServerTimestamp>("\"soon\"").is_err());
+
+        let json = serde_json::to_string(&from_number).unwrap();
+        assert_eq!(json, "1531154400.57");
+        assert_eq!(serde_json::from_str::<ServerTimestamp>(&json).unwrap(), from_number);
     }
 
     #[test]
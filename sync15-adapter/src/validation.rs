@@ -0,0 +1,399 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::fmt;
+
+use serde_json::{self, Value as JsonValue, Map};
+
+use bso_record::{EncryptedBso, Payload};
+use error;
+use key_bundle::KeyBundle;
+use util::ServerTimestamp;
+
+/// The JSON type a field in an incoming record is expected to have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FieldType {
+    String,
+    /// Any JSON number, including floats.
+    Number,
+    /// A JSON number without a fractional part.
+    Integer,
+    Bool,
+    Array,
+    Object,
+    /// Anything goes, we only care that the field is present.
+    Any,
+}
+
+impl FieldType {
+    fn matches(self, value: &JsonValue) -> bool {
+        match self {
+            FieldType::String => value.is_string(),
+            FieldType::Number => value.is_number(),
+            FieldType::Integer => value.is_i64() || value.is_u64(),
+            FieldType::Bool => value.is_boolean(),
+            FieldType::Array => value.is_array(),
+            FieldType::Object => value.is_object(),
+            FieldType::Any => true,
+        }
+    }
+}
+
+impl fmt::Display for FieldType {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FieldType::String => "string",
+            FieldType::Number => "number",
+            FieldType::Integer => "integer",
+            FieldType::Bool => "bool",
+            FieldType::Array => "array",
+            FieldType::Object => "object",
+            FieldType::Any => "any",
+        })
+    }
+}
+
+fn json_type_name(value: &JsonValue) -> &'static str {
+    match value {
+        JsonValue::Null => "null",
+        JsonValue::Bool(_) => "bool",
+        JsonValue::Number(_) => "number",
+        JsonValue::String(_) => "string",
+        JsonValue::Array(_) => "array",
+        JsonValue::Object(_) => "object",
+    }
+}
+
+/// Why an incoming record was quarantined instead of being handed to the store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Fail)]
+pub enum ValidationProblem {
+    #[fail(display = "Record payload is not valid JSON: {}", _0)]
+    MalformedJson(String),
+
+    #[fail(display = "Record payload is a JSON {}, not an object", _0)]
+    NotAnObject(String),
+
+    #[fail(display = "Record payload has no id")]
+    MissingId,
+
+    #[fail(display = "Record payload is missing required field \"{}\"", _0)]
+    MissingField(String),
+
+    #[fail(display = "Record field \"{}\" should be {}, but is {}", field, expected, found)]
+    WrongType { field: String, expected: FieldType, found: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FieldSpec {
+    name: String,
+    field_type: FieldType,
+    required: bool,
+}
+
+/// Describes the fields a store expects in the (non-tombstone) records it
+/// receives. Fields not mentioned are ignored.
+///
+/// Optional fields may be missing or `null`, but if present must have the
+/// declared type. Required fields must be present and non-null.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecordSchema {
+    fields: Vec<FieldSpec>,
+}
+
+impl RecordSchema {
+    #[inline]
+    pub fn new() -> RecordSchema {
+        RecordSchema::default()
+    }
+
+    pub fn required<S: Into<String>>(mut self, name: S, field_type: FieldType) -> RecordSchema {
+        self.fields.push(FieldSpec { name: name.into(), field_type, required: true });
+        self
+    }
+
+    pub fn optional<S: Into<String>>(mut self, name: S, field_type: FieldType) -> RecordSchema {
+        self.fields.push(FieldSpec { name: name.into(), field_type, required: false });
+        self
+    }
+
+    pub fn check(&self, data: &Map<String, JsonValue>) -> Result<(), ValidationProblem> {
+        for spec in &self.fields {
+            match data.get(&spec.name) {
+                None | Some(JsonValue::Null) => {
+                    if spec.required {
+                        return Err(ValidationProblem::MissingField(spec.name.clone()));
+                    }
+                }
+                Some(value) => {
+                    if !spec.field_type.matches(value) {
+                        return Err(ValidationProblem::WrongType {
+                            field: spec.name.clone(),
+                            expected: spec.field_type,
+                            found: json_type_name(value).into(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A record that failed validation. We don't keep the cleartext around (it may
+/// well contain passwords), so retrying a quarantined record means fetching it
+/// from the server again by id.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuarantinedRecord {
+    /// The id from the BSO envelope, which is present even when the payload's
+    /// id is missing.
+    pub id: String,
+    pub collection: String,
+    pub modified: ServerTimestamp,
+    pub problem: ValidationProblem,
+}
+
+/// The result of decrypting and validating a single incoming record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidatedRecord {
+    Valid(Payload, ServerTimestamp),
+    Quarantined(QuarantinedRecord),
+}
+
+/// Parses a decrypted payload, checking it against `schema` (if provided).
+/// Tombstones only need a valid id.
+pub fn validate_cleartext(
+    cleartext: &str,
+    schema: Option<&RecordSchema>,
+) -> Result<Payload, ValidationProblem> {
+    let value: JsonValue = serde_json::from_str(cleartext)
+        .map_err(|e| ValidationProblem::MalformedJson(e.to_string()))?;
+    validate_json(value, schema)
+}
+
+pub fn validate_json(
+    value: JsonValue,
+    schema: Option<&RecordSchema>,
+) -> Result<Payload, ValidationProblem> {
+    let data = match value {
+        JsonValue::Object(data) => data,
+        other => return Err(ValidationProblem::NotAnObject(json_type_name(&other).into())),
+    };
+
+    match data.get("id") {
+        Some(JsonValue::String(_)) => {}
+        None | Some(JsonValue::Null) => return Err(ValidationProblem::MissingId),
+        Some(other) => return Err(ValidationProblem::WrongType {
+            field: "id".into(),
+            expected: FieldType::String,
+            found: json_type_name(other).into(),
+        }),
+    }
+
+    let is_tombstone = match data.get("deleted") {
+        None | Some(JsonValue::Null) => false,
+        Some(JsonValue::Bool(b)) => *b,
+        Some(other) => return Err(ValidationProblem::WrongType {
+            field: "deleted".into(),
+            expected: FieldType::Bool,
+            found: json_type_name(other).into(),
+        }),
+    };
+
+    if !is_tombstone {
+        if let Some(schema) = schema {
+            schema.check(&data)?;
+        }
+    }
+
+    Payload::from_json(JsonValue::Object(data))
+        .map_err(|e| ValidationProblem::MalformedJson(e.to_string()))
+}
+
+/// Decrypts `record` and validates its payload. Crypto failures (e.g. an HMAC
+/// mismatch) are still returned as `ErrorKind::DecryptionError`s, since they
+/// usually mean we have the wrong keys rather than that the record is bad.
+pub fn decrypt_and_validate(
+    record: EncryptedBso,
+    key: &KeyBundle,
+    schema: Option<&RecordSchema>,
+) -> error::Result<ValidatedRecord> {
+    let cleartext = record.decrypt_cleartext(key)?;
+    Ok(match validate_cleartext(&cleartext, schema) {
+        Ok(payload) => ValidatedRecord::Valid(payload, record.modified),
+        Err(problem) => {
+            warn!("Quarantining record {} in {}: {}", record.id, record.collection, problem);
+            ValidatedRecord::Quarantined(QuarantinedRecord {
+                id: record.id,
+                collection: record.collection,
+                modified: record.modified,
+                problem,
+            })
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bso_record::{BsoRecord, EncryptedPayload};
+    use base64;
+
+    fn password_schema() -> RecordSchema {
+        RecordSchema::new()
+            .required("hostname", FieldType::String)
+            .required("password", FieldType::String)
+            .optional("timesUsed", FieldType::Integer)
+    }
+
+    fn encrypt_raw(id: &str, cleartext: &str, key: &KeyBundle) -> EncryptedBso {
+        let (enc_bytes, iv) = key.encrypt_rand_iv(cleartext).unwrap();
+        let ciphertext = base64::encode(&enc_bytes);
+        let hmac = key.hmac_string(ciphertext.as_bytes()).unwrap();
+        BsoRecord {
+            id: id.into(),
+            collection: "passwords".into(),
+            modified: ServerTimestamp(1_234_500),
+            sortindex: None,
+            ttl: None,
+            payload: EncryptedPayload {
+                iv: base64::encode(&iv),
+                hmac,
+                ciphertext,
+            },
+        }
+    }
+
+    #[test]
+    fn test_valid_record() {
+        let payload = validate_cleartext(
+            r#"{"id": "aaaa", "hostname": "https://example.com", "password": "hunter2", "timesUsed": 3}"#,
+            Some(&password_schema()),
+        ).unwrap();
+        assert_eq!(payload.id(), "aaaa");
+        assert!(!payload.is_tombstone());
+    }
+
+    #[test]
+    fn test_tombstone_skips_schema() {
+        let payload = validate_cleartext(
+            r#"{"id": "aaaa", "deleted": true}"#,
+            Some(&password_schema()),
+        ).unwrap();
+        assert!(payload.is_tombstone());
+    }
+
+    #[test]
+    fn test_truncated_json() {
+        match validate_cleartext(r#"{"id": "aaaa", "hostname": "https://exa"#, None) {
+            Err(ValidationProblem::MalformedJson(_)) => {}
+            other => panic!("Expected MalformedJson, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_not_an_object() {
+        assert_eq!(validate_cleartext("[1, 2, 3]", None).unwrap_err(),
+                   ValidationProblem::NotAnObject("array".into()));
+    }
+
+    #[test]
+    fn test_missing_id() {
+        assert_eq!(validate_cleartext(r#"{"hostname": "https://example.com"}"#, None).unwrap_err(),
+                   ValidationProblem::MissingId);
+        assert_eq!(validate_cleartext(r#"{"id": null, "deleted": true}"#, None).unwrap_err(),
+                   ValidationProblem::MissingId);
+    }
+
+    #[test]
+    fn test_wrong_types() {
+        assert_eq!(validate_cleartext(r#"{"id": 1234}"#, None).unwrap_err(),
+                   ValidationProblem::WrongType {
+                       field: "id".into(),
+                       expected: FieldType::String,
+                       found: "number".into(),
+                   });
+        assert_eq!(validate_cleartext(r#"{"id": "aaaa", "deleted": "yes"}"#, None).unwrap_err(),
+                   ValidationProblem::WrongType {
+                       field: "deleted".into(),
+                       expected: FieldType::Bool,
+                       found: "string".into(),
+                   });
+        assert_eq!(validate_cleartext(
+                       r#"{"id": "aaaa", "hostname": "https://example.com", "password": 5}"#,
+                       Some(&password_schema())
+                   ).unwrap_err(),
+                   ValidationProblem::WrongType {
+                       field: "password".into(),
+                       expected: FieldType::String,
+                       found: "number".into(),
+                   });
+        assert_eq!(validate_cleartext(
+                       r#"{"id": "aaaa", "hostname": "https://example.com", "password": "x", "timesUsed": 1.5}"#,
+                       Some(&password_schema())
+                   ).unwrap_err(),
+                   ValidationProblem::WrongType {
+                       field: "timesUsed".into(),
+                       expected: FieldType::Integer,
+                       found: "number".into(),
+                   });
+    }
+
+    #[test]
+    fn test_missing_and_null_fields() {
+        assert_eq!(validate_cleartext(r#"{"id": "aaaa", "hostname": "https://example.com"}"#,
+                                      Some(&password_schema())).unwrap_err(),
+                   ValidationProblem::MissingField("password".into()));
+        assert_eq!(validate_cleartext(r#"{"id": "aaaa", "hostname": null, "password": "x"}"#,
+                                      Some(&password_schema())).unwrap_err(),
+                   ValidationProblem::MissingField("hostname".into()));
+        // Optional fields may be null.
+        assert!(validate_cleartext(
+            r#"{"id": "aaaa", "hostname": "https://example.com", "password": "x", "timesUsed": null}"#,
+            Some(&password_schema())
+        ).is_ok());
+    }
+
+    #[test]
+    fn test_decrypt_and_validate() {
+        let key = KeyBundle::new_random().unwrap();
+
+        let good = encrypt_raw(
+            "good", r#"{"id": "good", "hostname": "https://example.com", "password": "x"}"#, &key);
+        match decrypt_and_validate(good, &key, Some(&password_schema())).unwrap() {
+            ValidatedRecord::Valid(payload, modified) => {
+                assert_eq!(payload.id(), "good");
+                assert_eq!(modified, ServerTimestamp(1_234_500));
+            }
+            other => panic!("Expected a valid record, got {:?}", other),
+        }
+
+        let truncated = encrypt_raw("truncated", r#"{"id": "truncated", "hostn"#, &key);
+        match decrypt_and_validate(truncated, &key, Some(&password_schema())).unwrap() {
+            ValidatedRecord::Quarantined(q) => {
+                assert_eq!(q.id, "truncated");
+                assert_eq!(q.collection, "passwords");
+                match q.problem {
+                    ValidationProblem::MalformedJson(_) => {}
+                    other => panic!("Expected MalformedJson, got {:?}", other),
+                }
+            }
+            other => panic!("Expected a quarantined record, got {:?}", other),
+        }
+
+        let no_id = encrypt_raw("no_id", r#"{"hostname": "https://example.com"}"#, &key);
+        match decrypt_and_validate(no_id, &key, None).unwrap() {
+            ValidatedRecord::Quarantined(q) => {
+                assert_eq!(q.id, "no_id");
+                assert_eq!(q.problem, ValidationProblem::MissingId);
+            }
+            other => panic!("Expected a quarantined record, got {:?}", other),
+        }
+
+        // Using the wrong key is still an error rather than a quarantine.
+        let other_key = KeyBundle::new_random().unwrap();
+        let good = encrypt_raw("good", r#"{"id": "good"}"#, &key);
+        assert!(decrypt_and_validate(good, &other_key, None).is_err());
+    }
+}
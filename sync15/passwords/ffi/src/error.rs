@@ -20,10 +20,7 @@ use sync15_passwords::{
     Sync15PasswordsErrorKind,
 };
 
-use reqwest::StatusCode;
-use sync::{
-    ErrorKind as Sync15ErrorKind
-};
+use sync::ErrorClass;
 
 pub unsafe fn with_translated_result<F, T>(error: *mut ExternError, callback: F) -> *mut T
 where F: FnOnce() -> Result<T> {
@@ -132,10 +129,8 @@ impl Default for ExternError {
 fn get_code(err: &Sync15PasswordsError) -> ExternErrorCode {
     match err.kind() {
         Sync15PasswordsErrorKind::Sync15AdapterError(e) => {
-            match e.kind() {
-                Sync15ErrorKind::TokenserverHttpError(StatusCode::Unauthorized) => {
-                    ExternErrorCode::AuthInvalidError
-                }
+            match e.class() {
+                ErrorClass::AuthNeedsReauth => ExternErrorCode::AuthInvalidError,
                 _ => ExternErrorCode::OtherError,
             }
         }
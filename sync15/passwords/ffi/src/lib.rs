@@ -66,6 +66,16 @@ pub struct SyncInfo {
     last_client_init: Sync15StorageClientInit,
 }
 
+/// What a sync did that the caller might want to report.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct PasswordSyncCounts {
+    /// Incoming records we couldn't read, and will try again next sync.
+    pub quarantined: u32,
+    /// 1 if we didn't sync because a newer client owns the collection.
+    pub declined: u8,
+}
+
 pub struct PasswordState {
     engine: PasswordEngine,
     sync: Option<SyncInfo>,
@@ -147,8 +157,8 @@ pub unsafe extern "C" fn sync15_passwords_sync(
     sync_key: *const c_char,
     tokenserver_url: *const c_char,
     error: *mut ExternError
-) {
-    with_translated_void_result(error, || {
+) -> PasswordSyncCounts {
+    with_translated_value_result(error, || {
         assert_pointer_not_null!(state);
         let state = &mut *state;
 
@@ -199,8 +209,12 @@ pub unsafe extern "C" fn sync15_passwords_sync(
         // fails, we don't forget the sync_state.
         let result = state.engine.sync(&sync_info.client, &sync_info.state);
         state.sync = Some(sync_info);
-        result
-    });
+        let info = result?;
+        Ok(PasswordSyncCounts {
+            quarantined: info.quarantined as u32,
+            declined: info.declined as u8,
+        })
+    })
 }
 
 #[no_mangle]
@@ -13,6 +13,10 @@ use self::sync::{
     ServerTimestamp,
     OutgoingChangeset,
     Payload,
+    FieldType,
+    QuarantinedRecord,
+    RecordSchema,
+    SyncInfo,
 };
 
 use mentat::{
@@ -42,6 +46,8 @@ use errors::{
 // TODO: These probably don't all need to be public!
 pub struct PasswordEngine {
     pub last_server_timestamp: ServerTimestamp,
+    pub sync_id: Option<String>,
+    pub quarantined_ids: Vec<String>,
     pub current_tx_id: Option<mentat::Entid>,
     pub store: mentat::store::Store,
 }
@@ -49,21 +55,27 @@ pub struct PasswordEngine {
 impl PasswordEngine {
 
     pub fn new(mut store: mentat::store::Store) -> Result<PasswordEngine> {
-        let last_server_timestamp: ServerTimestamp = { // Scope borrow of `store`.
+        let (last_server_timestamp, sync_id, quarantined_ids) = { // Scope borrow of `store`.
             let mut in_progress = store.begin_transaction()?;
 
             ensure_vocabulary(&mut in_progress)?;
 
             let timestamp = passwords::get_last_server_timestamp(&in_progress)?;
+            let sync_id = passwords::get_sync_id(&in_progress)?;
+            let quarantined_ids = passwords::get_quarantined_uuids(&in_progress)?;
 
             in_progress.commit()?;
 
-            ServerTimestamp(timestamp.unwrap_or_default())
+            (timestamp.map(ServerTimestamp).unwrap_or_default(),
+             sync_id,
+             quarantined_ids.into_iter().map(|x| x.0).collect())
         };
 
         Ok(PasswordEngine {
             current_tx_id: None,
             last_server_timestamp,
+            sync_id,
+            quarantined_ids,
             store,
         })
     }
@@ -99,10 +111,9 @@ impl PasswordEngine {
         &mut self,
         client: &sync::Sync15StorageClient,
         state: &sync::GlobalState,
-    ) -> Result<()> {
+    ) -> Result<SyncInfo> {
         let ts = self.last_server_timestamp;
-        sync::synchronize(client, state, self, "passwords".into(), ts, true)?;
-        Ok(())
+        sync::synchronize(client, state, self, "passwords".into(), ts, true)
     }
 
     pub fn reset(&mut self) -> Result<()> {
@@ -112,13 +123,13 @@ impl PasswordEngine {
             in_progress.commit()?;
         }
 
-        self.last_server_timestamp = 0.0.into();
+        self.last_server_timestamp = ServerTimestamp(0);
 
         Ok(())
     }
 
     pub fn wipe(&mut self) -> Result<()> {
-        self.last_server_timestamp = 0.0.into();
+        self.last_server_timestamp = ServerTimestamp(0);
 
         // let mut in_progress = store.begin_transaction().map_err(|_| "failed to begin_transaction")?;
         // // reset_client(&mut in_progress).map_err(|_| "failed to reset_client")?;
@@ -154,6 +165,42 @@ impl PasswordEngine {
 impl sync::Store for PasswordEngine {
     type Error = Sync15PasswordsError;
 
+    fn record_schema(&self) -> Option<RecordSchema> {
+        // Mirrors the fields `ServerPassword` requires when deserializing.
+        Some(RecordSchema::new()
+            .required("hostname", FieldType::String)
+            .required("password", FieldType::String)
+            .optional("username", FieldType::String)
+            .optional("formSubmitURL", FieldType::String)
+            .optional("httpRealm", FieldType::String)
+            .optional("usernameField", FieldType::String)
+            .optional("passwordField", FieldType::String)
+            .optional("timeCreated", FieldType::Integer)
+            .optional("timePasswordChanged", FieldType::Integer)
+            .optional("timeLastUsed", FieldType::Integer)
+            .optional("timesUsed", FieldType::Integer))
+    }
+
+    fn quarantined_ids(&self) -> Result<Vec<String>> {
+        Ok(self.quarantined_ids.clone())
+    }
+
+    fn set_quarantined(&mut self, records: Vec<QuarantinedRecord>) -> Result<()> {
+        for record in &records {
+            warn!("Quarantined password {}: {:?}", record.id, record.problem);
+        }
+        let ids: Vec<String> = records.into_iter().map(|x| x.id).collect();
+
+        { // Scope borrow of self.
+            let mut in_progress = self.store.begin_transaction()?;
+            passwords::set_quarantined_uuids(&mut in_progress, ids.iter().cloned().map(SyncGuid).collect())?;
+            in_progress.commit()?;
+        }
+
+        self.quarantined_ids = ids;
+        Ok(())
+    }
+
     fn apply_incoming(
         &mut self,
         inbound: sync::IncomingChangeset
@@ -177,7 +224,7 @@ impl sync::Store for PasswordEngine {
                     debug!("Applying: {:?}", payload);
 
                     let mut server_password: ServerPassword = payload.clone().into_record()?;
-                    server_password.modified = DateTime::<Utc>::from_millis(server_timestamp.as_millis() as i64);
+                    server_password.modified = DateTime::<Utc>::from_millis(server_timestamp.as_millis());
 
                     passwords::apply_password(&mut in_progress, server_password)?;
                 }
@@ -223,7 +270,7 @@ impl sync::Store for PasswordEngine {
             passwords::mark_synced_by_sync_uuids(&mut in_progress, uploaded.into_iter().map(SyncGuid).collect(), current_tx_id)?;
             passwords::delete_by_sync_uuids(&mut in_progress, deleted.into_iter().map(SyncGuid).collect())?;
 
-            passwords::set_last_server_timestamp(&mut in_progress, new_last_server_timestamp.0)?;
+            passwords::set_last_server_timestamp(&mut in_progress, new_last_server_timestamp.as_millis())?;
 
             in_progress.commit()?;
         };
@@ -231,4 +278,23 @@ impl sync::Store for PasswordEngine {
         self.last_server_timestamp = new_last_server_timestamp;
         Ok(())
     }
+
+    fn sync_id(&self) -> Result<Option<String>> {
+        Ok(self.sync_id.clone())
+    }
+
+    fn set_sync_id(&mut self, sync_id: String) -> Result<()> {
+        { // Scope borrow of self.
+            let mut in_progress = self.store.begin_transaction()?;
+            passwords::set_sync_id(&mut in_progress, sync_id.clone())?;
+            in_progress.commit()?;
+        }
+
+        self.sync_id = Some(sync_id);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        PasswordEngine::reset(self)
+    }
 }
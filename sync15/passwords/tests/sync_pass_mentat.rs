@@ -287,7 +287,7 @@ fn main() -> Result<(), Error> {
 
     let client = sync::Sync15StorageClient::new(sync::Sync15StorageClientInit {
         key_id: scope.kid.clone(),
-        access_token: oauth_data.access_token.clone(),
+        access_token: oauth_data.access_token.clone().into(),
         tokenserver_url: url::Url::parse("https://oauth-sync.dev.lcip.org/syncserver/token/1.0/sync/1.5")?,
     })?;
     let mut sync_state = sync::GlobalState::default();